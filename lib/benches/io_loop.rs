@@ -0,0 +1,94 @@
+//! Measures the device I/O loop against the emulated transport.
+//!
+//! Reports how long inputs and requests take to get through, and how much CPU a connected
+//! but idle device costs. Run with `cargo bench -p loupedeck --bench io_loop`.
+
+use cpu_time::ProcessTime;
+use loupedeck::*;
+use std::time::{Duration, Instant};
+
+const EVENT_SAMPLES: usize = 2000;
+const REQUEST_SAMPLES: usize = 500;
+const IDLE_PERIOD: Duration = Duration::from_secs(3);
+
+fn info() -> DeviceInfo {
+    DeviceInfo {
+        serial: "LDD0123456789".to_string(),
+        version: "0.2.5".to_string(),
+    }
+}
+
+fn report(name: &str, mut samples: Vec<Duration>) {
+    samples.sort();
+    let total: Duration = samples.iter().sum();
+    let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+
+    println!(
+        "{:<16} mean {:>10.2?}  p50 {:>10.2?}  p99 {:>10.2?}  max {:>10.2?}",
+        name,
+        total / samples.len() as u32,
+        percentile(50),
+        percentile(99),
+        samples[samples.len() - 1]
+    );
+}
+
+async fn event_latency() {
+    let (device, virtual_device) = connect_virtual_device(info()).await.unwrap();
+    let mut rx_event = device.tx_event.as_ref().unwrap().subscribe();
+    let mut samples = Vec::with_capacity(EVENT_SAMPLES);
+
+    for _ in 0..EVENT_SAMPLES {
+        let started = Instant::now();
+        virtual_device
+            .inject(ScriptedEvent::KnobRotate {
+                knob: Knob::Knob0,
+                value: 1,
+            })
+            .await
+            .unwrap();
+
+        while !matches!(rx_event.recv().await, Ok(Event::KnobRotate(_))) {}
+        samples.push(started.elapsed());
+    }
+
+    report("event", samples);
+}
+
+async fn request_latency() {
+    let (mut device, _virtual_device) = connect_virtual_device(info()).await.unwrap();
+    let mut samples = Vec::with_capacity(REQUEST_SAMPLES);
+
+    for _ in 0..REQUEST_SAMPLES {
+        let started = Instant::now();
+        device.get_mcu_id().await.unwrap();
+        samples.push(started.elapsed());
+    }
+
+    report("request", samples);
+    device.disconnect();
+}
+
+async fn idle_cpu() {
+    let (mut device, _virtual_device) = connect_virtual_device(info()).await.unwrap();
+    device.get_info().await.unwrap();
+
+    let mut controller = Controller::new();
+    let serial = controller.start(device).unwrap();
+
+    let cpu_started = ProcessTime::now();
+    let started = Instant::now();
+    tokio::time::sleep(IDLE_PERIOD).await;
+
+    let cpu = cpu_started.elapsed().as_secs_f64() / started.elapsed().as_secs_f64();
+    println!("{:<16} {:.2}% of a core", "idle cpu", cpu * 100.0);
+
+    controller.stop(&serial);
+}
+
+#[tokio::main]
+async fn main() {
+    event_latency().await;
+    request_latency().await;
+    idle_cpu().await;
+}
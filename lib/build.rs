@@ -1,4 +0,0 @@
-fn main() {
-  let version = rustc_version::version().unwrap();
-  println!("cargo:rustc-env=RUSTC_VERSION={}", version);
-}
\ No newline at end of file
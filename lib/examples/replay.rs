@@ -0,0 +1,66 @@
+//! Replays a capture recorded with `Device::capture_to`.
+//!
+//! Prints every event the device sent, then plays the capture back through a `Controller`
+//! with the plugins in the given directories loaded.
+//!
+//! Usage: cargo run --example replay -- <capture.jsonl> [plugin dir...]
+
+use loupedeck::*;
+use std::fs::File;
+use std::io::BufReader;
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let path = args
+        .next()
+        .expect("Usage: replay <capture.jsonl> [plugin dir...]");
+
+    let file = File::open(&path).expect("Failed to open capture");
+    let capture = read_capture(BufReader::new(file)).expect("Failed to read capture");
+
+    // Captures from before headers were recorded are all from a Live
+    let model = match &capture.header {
+        Some(header) => header
+            .device_model()
+            .expect("Failed to read capture header"),
+        None => &LOUPEDECK_LIVE,
+    };
+
+    for event in parse_capture(&capture.frames, model) {
+        println!("{:?}", event);
+    }
+
+    let mut controller = Controller::new();
+    for plugin_dir in args {
+        controller
+            .load_plugin(&plugin_dir)
+            .expect("Failed to load plugin");
+    }
+
+    let mut device = Device::with_model("replay".to_string(), model);
+    device
+        .connect_transport(replay_transport(capture.frames, true))
+        .await
+        .expect("Failed to connect to replay");
+    match capture.header.and_then(|header| header.info) {
+        Some(info) => device.set_info(info),
+        None => {
+            device.get_info().await.expect("Failed to get device info");
+        }
+    }
+
+    let mut rx_connected = device.watch_connection().unwrap();
+    let serial = controller
+        .start(device)
+        .expect("Failed to start controller");
+    println!("Replaying {} {} as {}", model.name, path, serial);
+
+    while *rx_connected.borrow() {
+        if rx_connected.changed().await.is_err() {
+            break;
+        }
+    }
+
+    controller.stop(&serial);
+}
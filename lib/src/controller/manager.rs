@@ -0,0 +1,480 @@
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinSet;
+use tokio::time::{self, Duration, Instant};
+
+use crate::{
+    get_loupedeck_devices, get_loupedeck_ports, open_serial_transport, Controller, Device,
+    DeviceConnectionStatus, DeviceModel, LoupedeckError, Result, Transport, LOUPEDECK_LIVE,
+};
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Finds Loupedeck ports and opens transports to them.
+pub trait DeviceDiscovery: Send + Sync + 'static {
+    fn available_ports(&self) -> Vec<String>;
+
+    fn open(&self, port: &str) -> Result<Box<dyn Transport>>;
+
+    /// The model of the device on a port.
+    fn model(&self, _port: &str) -> &'static DeviceModel {
+        &LOUPEDECK_LIVE
+    }
+}
+
+/// Discovers devices attached over USB serial.
+#[derive(Debug, Clone, Default)]
+pub struct SerialDiscovery;
+
+impl DeviceDiscovery for SerialDiscovery {
+    fn available_ports(&self) -> Vec<String> {
+        get_loupedeck_ports()
+    }
+
+    fn open(&self, port: &str) -> Result<Box<dyn Transport>> {
+        Ok(Box::new(open_serial_transport(port)?))
+    }
+
+    fn model(&self, port: &str) -> &'static DeviceModel {
+        get_loupedeck_devices()
+            .into_iter()
+            .find(|device| device.port == port)
+            .map_or(&LOUPEDECK_LIVE, |device| device.model)
+    }
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct DeviceStatusChange {
+    pub port: String,
+    pub serial: Option<String>,
+    pub status: DeviceConnectionStatus,
+}
+
+/// Exponential delay between failed connection attempts.
+#[derive(Debug, Clone)]
+struct Backoff {
+    current: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            current: initial,
+            max,
+        }
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+}
+
+struct ActiveDevice {
+    serial: String,
+    rx_connected: watch::Receiver<bool>,
+}
+
+struct Retry {
+    backoff: Backoff,
+    next_attempt: Instant,
+}
+
+/// Keeps the controller connected to every Loupedeck that is plugged in.
+///
+/// Ports are polled for devices appearing and disappearing. A failed connection is
+/// retried with backoff, and the controller restores each device's page once it is back.
+pub struct DeviceManager<D: DeviceDiscovery = SerialDiscovery> {
+    controller: Arc<Mutex<Controller>>,
+    discovery: D,
+    tx_status: broadcast::Sender<DeviceStatusChange>,
+    poll_interval: Duration,
+    backoff: Backoff,
+}
+
+impl DeviceManager<SerialDiscovery> {
+    pub fn new(controller: Arc<Mutex<Controller>>) -> Self {
+        Self::with_discovery(controller, SerialDiscovery)
+    }
+}
+
+impl<D: DeviceDiscovery> DeviceManager<D> {
+    pub fn with_discovery(controller: Arc<Mutex<Controller>>, discovery: D) -> Self {
+        let (tx_status, _) = broadcast::channel(16);
+
+        Self {
+            controller,
+            discovery,
+            tx_status,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            backoff: Backoff::new(DEFAULT_INITIAL_BACKOFF, DEFAULT_MAX_BACKOFF),
+        }
+    }
+
+    pub fn set_poll_interval(&mut self, poll_interval: Duration) {
+        self.poll_interval = poll_interval;
+    }
+
+    pub fn set_backoff(&mut self, initial: Duration, max: Duration) {
+        self.backoff = Backoff::new(initial, max);
+    }
+
+    /// Subscribes to connection status transitions.
+    pub fn subscribe(&self) -> broadcast::Receiver<DeviceStatusChange> {
+        self.tx_status.subscribe()
+    }
+
+    /// Watches for devices until the returned future is dropped.
+    ///
+    /// Every connection attempt runs on its own, so a port that never answers can't hold
+    /// up the others.
+    pub async fn run(self) {
+        let manager = Arc::new(self);
+        let mut active: HashMap<String, ActiveDevice> = HashMap::new();
+        let mut retries: HashMap<String, Retry> = HashMap::new();
+        let mut connecting: HashSet<String> = HashSet::new();
+        let mut attempts = JoinSet::new();
+
+        loop {
+            let ports = manager.discovery.available_ports();
+            retries.retain(|port, _| ports.contains(port));
+
+            let lost: Vec<String> = active
+                .iter()
+                .filter(|(port, device)| !ports.contains(port) || !*device.rx_connected.borrow())
+                .map(|(port, _)| port.clone())
+                .collect();
+
+            for port in lost {
+                let device = active.remove(&port).unwrap();
+                manager.controller.lock().unwrap().stop(&device.serial);
+                manager.emit(
+                    &port,
+                    Some(device.serial),
+                    DeviceConnectionStatus::Disconnected,
+                );
+            }
+
+            for port in ports {
+                let waiting = retries
+                    .get(&port)
+                    .is_some_and(|retry| Instant::now() < retry.next_attempt);
+
+                if active.contains_key(&port) || connecting.contains(&port) || waiting {
+                    continue;
+                }
+
+                manager.emit(&port, None, DeviceConnectionStatus::Connecting);
+                connecting.insert(port.clone());
+
+                let attempt = manager.clone();
+                attempts.spawn(async move {
+                    let result = attempt.connect(&port).await;
+                    (port, result)
+                });
+            }
+
+            tokio::select! {
+                _ = time::sleep(manager.poll_interval) => {}
+                Some(joined) = attempts.join_next() => {
+                    let (port, result) = match joined {
+                        Ok(attempt) => attempt,
+                        Err(e) => {
+                            println!("A connection attempt stopped early: {}", e);
+                            continue;
+                        }
+                    };
+                    connecting.remove(&port);
+
+                    match result {
+                        Ok(device) => {
+                            retries.remove(&port);
+                            manager.emit(
+                                &port,
+                                Some(device.serial.clone()),
+                                DeviceConnectionStatus::Connected,
+                            );
+                            active.insert(port, device);
+                        }
+                        Err(e) => {
+                            println!("Failed to connect to loupedeck on {}: {}", port, e);
+
+                            let retry = retries.entry(port.clone()).or_insert_with(|| Retry {
+                                backoff: manager.backoff.clone(),
+                                next_attempt: Instant::now(),
+                            });
+                            retry.next_attempt = Instant::now() + retry.backoff.next_delay();
+
+                            manager.emit(&port, None, DeviceConnectionStatus::Disconnected);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn connect(&self, port: &str) -> Result<ActiveDevice> {
+        let transport = self.discovery.open(port)?;
+
+        let mut device = Device::with_model(port.to_string(), self.discovery.model(port));
+        device.connect_transport(transport).await?;
+        device.get_info().await?;
+
+        let rx_connected = device
+            .watch_connection()
+            .ok_or(LoupedeckError::Disconnected)?;
+        let serial = self.controller.lock().unwrap().start(device)?;
+
+        Ok(ActiveDevice {
+            serial,
+            rx_connected,
+        })
+    }
+
+    fn emit(&self, port: &str, serial: Option<String>, status: DeviceConnectionStatus) {
+        let _ = self.tx_status.send(DeviceStatusChange {
+            port: port.to_string(),
+            serial,
+            status,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backoff, DeviceDiscovery, DeviceManager, DeviceStatusChange};
+    use crate::{
+        memory_transport, Controller, DeviceConnectionStatus, DeviceInfo, LoupedeckError, Result,
+        Transport, VirtualDevice,
+    };
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::DuplexStream;
+    use tokio::sync::broadcast;
+
+    #[derive(Clone, Default)]
+    struct VirtualDiscovery {
+        ports: Arc<Mutex<Vec<String>>>,
+        fail: Arc<Mutex<bool>>,
+        // The other ends of ports that never answer, kept open
+        silent: Arc<Mutex<Vec<DuplexStream>>>,
+    }
+
+    impl VirtualDiscovery {
+        fn plug_in(&self, port: &str) {
+            self.ports.lock().unwrap().push(port.to_string());
+        }
+
+        fn unplug(&self, port: &str) {
+            self.ports.lock().unwrap().retain(|p| p != port);
+        }
+    }
+
+    impl DeviceDiscovery for VirtualDiscovery {
+        fn available_ports(&self) -> Vec<String> {
+            self.ports.lock().unwrap().clone()
+        }
+
+        fn open(&self, port: &str) -> Result<Box<dyn Transport>> {
+            if *self.fail.lock().unwrap() {
+                return Err(LoupedeckError::UnknownDevice(port.to_string()));
+            }
+
+            let (host, virtual_end) = memory_transport();
+            if port.starts_with("silent") {
+                self.silent.lock().unwrap().push(virtual_end);
+                return Ok(Box::new(host));
+            }
+
+            VirtualDevice::spawn(
+                virtual_end,
+                DeviceInfo {
+                    serial: port.to_string(),
+                    version: "0.1.0".to_string(),
+                },
+            );
+
+            Ok(Box::new(host))
+        }
+    }
+
+    async fn next_status(
+        rx_status: &mut broadcast::Receiver<DeviceStatusChange>,
+    ) -> DeviceConnectionStatus {
+        tokio::time::timeout(Duration::from_secs(2), rx_status.recv())
+            .await
+            .expect("Timed out waiting for a status change")
+            .unwrap()
+            .status
+    }
+
+    fn manager(
+        discovery: VirtualDiscovery,
+    ) -> (DeviceManager<VirtualDiscovery>, Arc<Mutex<Controller>>) {
+        let controller = Arc::new(Mutex::new(Controller::new()));
+        let mut manager = DeviceManager::with_discovery(controller.clone(), discovery);
+        manager.set_poll_interval(Duration::from_millis(10));
+        manager.set_backoff(Duration::from_millis(10), Duration::from_millis(40));
+
+        (manager, controller)
+    }
+
+    #[test]
+    fn it_backs_off_exponentially() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(500));
+
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(200));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(400));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(500));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn it_reconnects_when_a_device_is_replugged() {
+        let discovery = VirtualDiscovery::default();
+        let (manager, controller) = manager(discovery.clone());
+        let mut rx_status = manager.subscribe();
+        let task = tokio::spawn(manager.run());
+
+        discovery.plug_in("virtual-0");
+        assert_eq!(
+            next_status(&mut rx_status).await,
+            DeviceConnectionStatus::Connecting
+        );
+        assert_eq!(
+            next_status(&mut rx_status).await,
+            DeviceConnectionStatus::Connected
+        );
+
+        discovery.unplug("virtual-0");
+        assert_eq!(
+            next_status(&mut rx_status).await,
+            DeviceConnectionStatus::Disconnected
+        );
+        assert_eq!(
+            controller.lock().unwrap().get_connection_status().unwrap(),
+            DeviceConnectionStatus::Disconnected
+        );
+
+        discovery.plug_in("virtual-0");
+        assert_eq!(
+            next_status(&mut rx_status).await,
+            DeviceConnectionStatus::Connecting
+        );
+        assert_eq!(
+            next_status(&mut rx_status).await,
+            DeviceConnectionStatus::Connected
+        );
+        assert_eq!(
+            controller.lock().unwrap().get_connection_status().unwrap(),
+            DeviceConnectionStatus::Connected
+        );
+
+        task.abort();
+        controller.lock().unwrap().stop_all();
+    }
+
+    #[tokio::test]
+    async fn it_manages_several_devices() {
+        let discovery = VirtualDiscovery::default();
+        let (manager, controller) = manager(discovery.clone());
+        let mut rx_status = manager.subscribe();
+        let task = tokio::spawn(manager.run());
+
+        discovery.plug_in("virtual-0");
+        discovery.plug_in("virtual-1");
+        for _ in 0..4 {
+            next_status(&mut rx_status).await;
+        }
+
+        let mut devices = controller.lock().unwrap().list_devices();
+        devices.sort();
+        assert_eq!(devices, vec!["virtual-0", "virtual-1"]);
+
+        discovery.unplug("virtual-1");
+        let change = tokio::time::timeout(Duration::from_secs(2), rx_status.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(change.serial.as_deref(), Some("virtual-1"));
+        assert_eq!(change.status, DeviceConnectionStatus::Disconnected);
+        assert_eq!(controller.lock().unwrap().list_devices(), vec!["virtual-0"]);
+
+        task.abort();
+        controller.lock().unwrap().stop_all();
+    }
+
+    #[tokio::test]
+    async fn it_connects_other_devices_while_a_port_hangs() {
+        let discovery = VirtualDiscovery::default();
+        let (manager, controller) = manager(discovery.clone());
+        let mut rx_status = manager.subscribe();
+        let task = tokio::spawn(manager.run());
+
+        discovery.plug_in("silent-0");
+        discovery.plug_in("virtual-0");
+
+        let connected = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                let change = rx_status.recv().await.unwrap();
+                if change.status == DeviceConnectionStatus::Connected {
+                    return change.port;
+                }
+            }
+        })
+        .await
+        .expect("A silent port held up connecting the others");
+        assert_eq!(connected, "virtual-0");
+
+        // The silent port gives up once its upgrade times out
+        let change = tokio::time::timeout(Duration::from_secs(5), rx_status.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(change.port, "silent-0");
+        assert_eq!(change.status, DeviceConnectionStatus::Disconnected);
+
+        task.abort();
+        controller.lock().unwrap().stop_all();
+    }
+
+    #[tokio::test]
+    async fn it_retries_failed_connections() {
+        let discovery = VirtualDiscovery::default();
+        *discovery.fail.lock().unwrap() = true;
+
+        let (manager, controller) = manager(discovery.clone());
+        let mut rx_status = manager.subscribe();
+        let task = tokio::spawn(manager.run());
+
+        discovery.plug_in("virtual-0");
+        assert_eq!(
+            next_status(&mut rx_status).await,
+            DeviceConnectionStatus::Connecting
+        );
+        assert_eq!(
+            next_status(&mut rx_status).await,
+            DeviceConnectionStatus::Disconnected
+        );
+
+        *discovery.fail.lock().unwrap() = false;
+        assert_eq!(
+            next_status(&mut rx_status).await,
+            DeviceConnectionStatus::Connecting
+        );
+        assert_eq!(
+            next_status(&mut rx_status).await,
+            DeviceConnectionStatus::Connected
+        );
+
+        task.abort();
+        controller.lock().unwrap().stop_all();
+    }
+}
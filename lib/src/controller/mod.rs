@@ -1,52 +1,189 @@
 use crate::{
-    Button, ButtonPressEvent, Device, Event, ExternalDeviceEventEmitter, Haptic, KeyLocation,
-    PluginRegistrar, PluginScreenContext, PressDirection, ScreenPlugin,
+    Button, ButtonPlugin, Device, Event, ExternalDeviceEventEmitter, KeyLocation, Knob, KnobPlugin,
+    Lifecycle, LoupedeckError, PageView, PluginButtonContext, PluginKnobContext,
+    PluginScreenContext, PluginType, Region, RegionLayout, RenderStats, Result, Screen,
+    ScreenPlugin, Snapshot, Spawner, TouchEvent,
 };
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use std::collections::HashMap;
-use std::io::Result;
-use tokio::runtime::Runtime;
-use tokio::sync::mpsc;
-use tokio::time;
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
 
 mod plugin;
 use plugin::*;
 
-pub use plugin::PluginIdentifier;
+mod manager;
+pub use manager::*;
+
+mod wasm;
+pub use wasm::*;
+
+mod process;
+pub use process::*;
+
+pub use plugin::{LoadedPlugin, PluginIdentifier};
 
 struct Page {
     name: String,
-    screen: HashMap<KeyLocation, ScreenPluginProxy>,
+    screen: HashMap<Region, ScreenPluginProxy>,
+    buttons: HashMap<Button, ButtonPluginProxy>,
+    knobs: HashMap<Knob, KnobPluginProxy>,
+    view: Option<Arc<PageView>>,
 }
 
-impl From<PageConfig> for Page {
-    fn from(config: PageConfig) -> Self {
+impl Page {
+    fn new(config: &PageConfig, event_emitter: Option<&ExternalDeviceEventEmitter>) -> Self {
         Page {
-            name: config.name,
+            name: config.name.clone(),
             screen: HashMap::new(),
+            buttons: HashMap::new(),
+            knobs: HashMap::new(),
+            view: event_emitter.map(|emitter| PageView::new(emitter.clone())),
+        }
+    }
+
+    /// Runs a lifecycle hook on every plugin on the page.
+    fn notify(&self, hook: Lifecycle) {
+        let screens = self
+            .screen
+            .values()
+            .map(|proxy| proxy.plugin.on_lifecycle(hook));
+        let buttons = self
+            .buttons
+            .values()
+            .map(|proxy| proxy.plugin.on_lifecycle(hook));
+        let knobs = self
+            .knobs
+            .values()
+            .map(|proxy| proxy.plugin.on_lifecycle(hook));
+
+        for result in screens.chain(buttons).chain(knobs) {
+            if let Err(e) = result {
+                println!("Error running {:?} on page {}: {}", hook, self.name, e);
+            }
+        }
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        // Revoke the plugins' access to the device before they get to run their hook
+        if let Some(view) = &self.view {
+            view.unmount();
         }
+        self.notify(Lifecycle::Unmount);
     }
 }
 
 pub struct ScreenPluginProxy {
     plugin: Box<dyn ScreenPlugin>,
+    layout: RegionLayout,
+}
+
+impl ScreenPluginProxy {
+    fn contains(&self, touch_event: &TouchEvent) -> bool {
+        match (&touch_event.screen, self.layout.surface) {
+            (Screen::Wheel, surface) => {
+                surface.is_none() && self.layout.parts[0].screen == Screen::Wheel
+            }
+            (_, Some(surface)) => surface.contains(touch_event.x, touch_event.y),
+            (_, None) => false,
+        }
+    }
+}
+
+impl Page {
+    /// The plugin a touch belongs to. Where regions overlap, the smallest one gets it.
+    fn plugin_at(&self, touch_event: &TouchEvent) -> Option<&ScreenPluginProxy> {
+        self.screen
+            .values()
+            .filter(|proxy| proxy.contains(touch_event))
+            .min_by_key(|proxy| proxy.layout.width as u32 * proxy.layout.height as u32)
+    }
 }
 
 unsafe impl Send for ScreenPluginProxy {}
 unsafe impl Sync for ScreenPluginProxy {}
 
+pub struct ButtonPluginProxy {
+    plugin: Box<dyn ButtonPlugin>,
+}
+
+pub struct KnobPluginProxy {
+    plugin: Box<dyn KnobPlugin>,
+}
+
 #[serde_as]
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PageConfig {
     pub name: String,
+    #[serde(default)]
+    pub device: DeviceTarget,
     #[serde_as(as = "Vec<(_, _)>")]
     pub screen: HashMap<KeyLocation, PluginIdentifier>,
+    /// Plugins for the side strips, blocks of keys or the whole display.
+    #[serde(default)]
+    #[serde_as(as = "Vec<(_, _)>")]
+    pub regions: HashMap<Region, PluginIdentifier>,
+    /// Plugins for the physical buttons.
+    #[serde(default)]
+    #[serde_as(as = "Vec<(_, _)>")]
+    pub buttons: HashMap<Button, PluginIdentifier>,
+    /// Plugins for the knobs, which also draw next to their knob where there's a screen.
+    #[serde(default)]
+    #[serde_as(as = "Vec<(_, _)>")]
+    pub knobs: HashMap<Knob, PluginIdentifier>,
 }
 
 pub struct ControllerState {
-    current_page: Option<Page>,
-    notify: mpsc::Sender<Page>,
+    notify: mpsc::Sender<Arc<Page>>,
+}
+
+/// A page to hand to a device's event loop, once the controller is no longer borrowed.
+type PendingPage = (mpsc::Sender<Arc<Page>>, Arc<Page>);
+
+async fn send_pages(pending: Vec<PendingPage>) -> Result<()> {
+    for (notify, page) in pending {
+        let _ = notify.send(page).await;
+    }
+    Ok(())
+}
+
+/// Everything the controller keeps for one connected device.
+struct DeviceSession {
+    task: JoinHandle<()>,
+    state: ControllerState,
+    event_emitter: Option<ExternalDeviceEventEmitter>,
+    // Pages that have been shown, kept so their plugins pick up where they left off
+    pages: HashMap<String, Arc<Page>>,
+}
+
+impl Drop for DeviceSession {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Which devices a page can be shown on.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeviceTarget {
+    #[default]
+    Any,
+    Serial(String),
+}
+
+impl DeviceTarget {
+    pub fn matches(&self, serial: &str) -> bool {
+        match self {
+            DeviceTarget::Any => true,
+            DeviceTarget::Serial(target) => target == serial,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -63,60 +200,214 @@ pub enum DeviceConnectionStatus {
 
 pub struct Controller {
     plugin_registry: PluginRegistry,
-    state: Option<ControllerState>,
     config: ControllerConfig,
-    runtime: Option<Runtime>,
-    event_emitter: Option<ExternalDeviceEventEmitter>,
+    devices: HashMap<String, DeviceSession>,
+    // Remembered per serial so a page survives the device reconnecting
+    current_pages: HashMap<String, String>,
+    default_page: Option<String>,
+    runtime: Option<Handle>,
+}
+
+impl Default for Controller {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Controller {
     pub fn new() -> Controller {
-        return Controller {
+        Controller {
             plugin_registry: PluginRegistry::new(),
-            state: None,
+            devices: HashMap::new(),
+            current_pages: HashMap::new(),
+            default_page: None,
             runtime: None,
-            event_emitter: None,
             config: ControllerConfig {
                 pages: HashMap::default(),
             },
-        };
+        }
     }
 
+    /// Runs devices and plugins on `runtime` instead of the one the controller is used from.
+    pub fn set_runtime(&mut self, runtime: Handle) {
+        self.runtime = Some(runtime);
+    }
+
+    fn runtime(&self) -> Result<Handle> {
+        match &self.runtime {
+            Some(runtime) => Ok(runtime.clone()),
+            None => Handle::try_current().map_err(|_| LoupedeckError::NoRuntime),
+        }
+    }
+
+    /// Loads a native plugin library, a sandboxed `.wasm` plugin, or starts the executable
+    /// plugin a `.plugin` file names. Anything else is refused, so stray files in a plugins
+    /// directory are never run.
     pub fn load_plugin(&mut self, plugin_path: &str) -> Result<()> {
-        unsafe { self.plugin_registry.load_from_path(plugin_path.to_string()) }
+        let extension = Path::new(plugin_path)
+            .extension()
+            .and_then(|extension| extension.to_str());
+
+        match extension {
+            Some("wasm") => self.plugin_registry.load_wasm_from_path(plugin_path),
+            // Windows adds the `.dll` itself
+            None | Some("so" | "dylib" | "dll") => unsafe {
+                self.plugin_registry.load_from_path(plugin_path)
+            },
+            Some("plugin") => ProcessCommand::load(plugin_path)
+                .map_err(|e| LoupedeckError::PluginLoad(format!("{}: {}", plugin_path, e)))
+                .and_then(|command| self.load_process_plugin(command)),
+            Some(_) => Err(LoupedeckError::PluginLoad(format!(
+                "{}: not a plugin library, .wasm or .plugin file",
+                plugin_path
+            ))),
+        }
+    }
+
+    /// Starts an executable plugin.
+    pub fn load_process_plugin(&mut self, command: ProcessCommand) -> Result<()> {
+        self.plugin_registry.load_process(command)
     }
 
     pub fn get_connection_status(&self) -> Result<DeviceConnectionStatus> {
-        if self.runtime.is_none() {
+        if self.devices.is_empty() {
             return Ok(DeviceConnectionStatus::Disconnected);
         }
 
-        return Ok(DeviceConnectionStatus::Connected);
+        Ok(DeviceConnectionStatus::Connected)
     }
 
-    pub async fn set_current_page(&mut self, page_name: String) -> Result<()> {
-        let page_config = self.config.pages.get(&page_name);
-        if page_config.is_none() {
-            return Ok(());
-        }
+    /// Serial numbers of the devices the controller is driving.
+    pub fn list_devices(&self) -> Vec<String> {
+        self.devices.keys().cloned().collect()
+    }
 
-        let current_page = self.create_page_instance(page_config.unwrap()).unwrap();
+    /// Shows a page on every connected device it targets.
+    ///
+    /// Devices that connect later show it too, unless they were given a page of their own.
+    /// The returned future doesn't borrow the controller, so it can be awaited after
+    /// unlocking it.
+    pub fn set_current_page(
+        &mut self,
+        page_name: String,
+    ) -> impl Future<Output = Result<()>> + Send + 'static {
+        let pending = self.current_page_instances(page_name);
+        async move { send_pages(pending?).await }
+    }
 
-        let state = self.state.as_ref();
+    fn current_page_instances(&mut self, page_name: String) -> Result<Vec<PendingPage>> {
+        let page_config = match self.config.pages.get(&page_name) {
+            Some(page_config) => page_config.clone(),
+            None => return Ok(Vec::new()),
+        };
+
+        let serials: Vec<String> = self
+            .devices
+            .keys()
+            .filter(|serial| page_config.device.matches(serial))
+            .cloned()
+            .collect();
 
-        if state.is_some() {
-            let state = state.unwrap();
-            state.notify.send(current_page).await;
+        let mut pending = Vec::new();
+        for serial in serials {
+            pending.extend(self.show_page(&serial, &page_config)?);
         }
 
         println!("Set page to {}", page_name);
-        return Ok(());
+        self.default_page = Some(page_name);
+        Ok(pending)
     }
 
-    fn create_page_instance(&self, page_config: &PageConfig) -> Result<Page> {
-        let mut page_instance = Page::from(page_config.clone());
+    /// Shows a page on a single device. Like with `set_current_page`, the returned future
+    /// doesn't borrow the controller.
+    pub fn set_device_page(
+        &mut self,
+        serial: &str,
+        page_name: String,
+    ) -> impl Future<Output = Result<()>> + Send + 'static {
+        let pending = self.device_page_instance(serial, page_name);
+        async move { send_pages(pending?).await }
+    }
+
+    fn device_page_instance(
+        &mut self,
+        serial: &str,
+        page_name: String,
+    ) -> Result<Vec<PendingPage>> {
+        let page_config = match self.config.pages.get(&page_name) {
+            Some(page_config) => page_config.clone(),
+            None => return Ok(Vec::new()),
+        };
 
-        for (key, plugin_identifier) in page_config.screen.iter() {
+        if !page_config.device.matches(serial) {
+            return Err(LoupedeckError::InvalidInput(format!(
+                "Page {} can't be shown on device {}",
+                page_name, serial
+            )));
+        }
+
+        let pending = self.show_page(serial, &page_config)?;
+
+        println!("Set page on {} to {}", serial, page_name);
+        self.current_pages.insert(serial.to_string(), page_name);
+        Ok(pending.into_iter().collect())
+    }
+
+    /// The page a device is showing, or will show once it connects.
+    pub fn get_current_page_name(&self, serial: &str) -> Option<String> {
+        self.current_pages
+            .get(serial)
+            .or(self.default_page.as_ref())
+            .filter(|name| {
+                self.config
+                    .pages
+                    .get(*name)
+                    .is_some_and(|page| page.device.matches(serial))
+            })
+            .cloned()
+    }
+
+    /// Makes a device's instance of a page current, and returns it to hand to the device.
+    fn show_page(&mut self, serial: &str, page_config: &PageConfig) -> Result<Option<PendingPage>> {
+        let session = match self.devices.get(serial) {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+
+        let page = match session.pages.get(&page_config.name) {
+            Some(page) => page.clone(),
+            None => {
+                Arc::new(self.create_page_instance(page_config, session.event_emitter.as_ref())?)
+            }
+        };
+
+        let session = self.devices.get_mut(serial).unwrap();
+        session.pages.insert(page_config.name.clone(), page.clone());
+
+        self.current_pages
+            .insert(serial.to_string(), page_config.name.clone());
+        Ok(Some((session.state.notify.clone(), page)))
+    }
+
+    fn create_page_instance(
+        &self,
+        page_config: &PageConfig,
+        event_emitter: Option<&ExternalDeviceEventEmitter>,
+    ) -> Result<Page> {
+        let mut page_instance = Page::new(page_config, event_emitter);
+        let spawner = Spawner::new(self.runtime()?);
+
+        let keys = page_config
+            .screen
+            .iter()
+            .map(|(key, plugin_identifier)| (Region::Key(*key), plugin_identifier));
+
+        for (region, plugin_identifier) in keys.chain(
+            page_config
+                .regions
+                .iter()
+                .map(|(region, plugin_identifier)| (region.clone(), plugin_identifier)),
+        ) {
             let plugin_id = &plugin_identifier.plugin_id;
 
             let plugin = self.plugin_registry.plugins.get(plugin_id);
@@ -125,142 +416,502 @@ impl Controller {
             }
 
             let plugin = plugin.unwrap();
-            let screen = plugin.screens.get(&plugin_identifier.plugin_ref);
-
-            if screen.is_none() {
+            if !plugin.has_screen(&plugin_identifier.plugin_ref) {
                 continue;
             }
 
-            let screen = screen.unwrap();
-
-            if self.event_emitter.is_some() {
-                let event_emitter_copy = self.event_emitter.clone().unwrap();
-                let plugin_context = PluginScreenContext::new(
-                    event_emitter_copy,
-                    crate::Screen::Center,
-                    (*key).clone(),
-                );
+            if let Some(view) = &page_instance.view {
+                let plugin_context =
+                    match PluginScreenContext::new(view.clone(), region.clone(), spawner.clone()) {
+                        Ok(plugin_context) => plugin_context,
+                        Err(e) => {
+                            println!("Skipping {:?}: {}", plugin_identifier.plugin_ref, e);
+                            continue;
+                        }
+                    };
 
                 println!(
                     "Creating screen plugin instance for {:?} at {:?}",
-                    plugin_identifier.plugin_ref, *key
+                    plugin_identifier.plugin_ref, region
                 );
 
-                let screen_instance = screen(plugin_context);
+                let layout = match plugin_context.layout() {
+                    Some(layout) => layout.clone(),
+                    None => continue,
+                };
+                let screen_instance =
+                    match plugin.create_screen(&plugin_identifier.plugin_ref, plugin_context) {
+                        Ok(screen_instance) => screen_instance,
+                        Err(e) => {
+                            println!("Skipping {:?}: {}", plugin_identifier.plugin_ref, e);
+                            continue;
+                        }
+                    };
 
                 page_instance.screen.insert(
-                    *key,
+                    region,
                     ScreenPluginProxy {
                         plugin: screen_instance,
+                        layout,
                     },
                 );
             }
         }
 
-        return Ok(page_instance);
+        let Some(view) = page_instance.view.clone() else {
+            return Ok(page_instance);
+        };
+
+        for (button, plugin_identifier) in &page_config.buttons {
+            let Some(plugin) = self.find_plugin(plugin_identifier, PluginType::Button) else {
+                continue;
+            };
+
+            println!(
+                "Creating button plugin instance for {:?} at {:?}",
+                plugin_identifier.plugin_ref, button
+            );
+
+            let button_instance = PluginButtonContext::new(view.clone(), *button, spawner.clone())
+                .and_then(|ctx| plugin.create_button(&plugin_identifier.plugin_ref, ctx));
+            match button_instance {
+                Ok(plugin) => {
+                    page_instance
+                        .buttons
+                        .insert(*button, ButtonPluginProxy { plugin });
+                }
+                Err(e) => println!("Skipping {:?}: {}", plugin_identifier.plugin_ref, e),
+            }
+        }
+
+        for (knob, plugin_identifier) in &page_config.knobs {
+            let Some(plugin) = self.find_plugin(plugin_identifier, PluginType::Knob) else {
+                continue;
+            };
+
+            println!(
+                "Creating knob plugin instance for {:?} at {:?}",
+                plugin_identifier.plugin_ref, knob
+            );
+
+            let knob_instance = PluginKnobContext::new(view.clone(), *knob, spawner.clone())
+                .and_then(|ctx| plugin.create_knob(&plugin_identifier.plugin_ref, ctx));
+            match knob_instance {
+                Ok(plugin) => {
+                    page_instance
+                        .knobs
+                        .insert(*knob, KnobPluginProxy { plugin });
+                }
+                Err(e) => println!("Skipping {:?}: {}", plugin_identifier.plugin_ref, e),
+            }
+        }
+
+        page_instance.notify(Lifecycle::Mount);
+        Ok(page_instance)
+    }
+
+    /// The loaded plugin an identifier refers to, if it has a plugin of that type by that name.
+    fn find_plugin(
+        &self,
+        plugin_identifier: &PluginIdentifier,
+        plugin_type: PluginType,
+    ) -> Option<&LoadedPlugin> {
+        self.plugin_registry
+            .plugins
+            .get(&plugin_identifier.plugin_id)
+            .filter(|plugin| plugin.has(plugin_type, &plugin_identifier.plugin_ref))
     }
 
     pub fn set_page(&mut self, page: PageConfig) -> Result<()> {
+        // Plugins set up for the old config are replaced the next time the page is shown
+        for session in self.devices.values_mut() {
+            session.pages.remove(&page.name);
+        }
+
         self.config.pages.insert(page.name.clone(), page);
 
-        return Ok(());
+        Ok(())
     }
 
-    pub fn start(&mut self, mut device: Device) {
-        self.runtime = Some(Runtime::new().unwrap());
-        let runtime = self.runtime.as_ref().unwrap();
+    /// Starts driving the given device, replacing any device with the same serial number.
+    ///
+    /// The device's info must have been fetched with `get_info` first. The page it was
+    /// last showing, if there was one, is shown again straight away.
+    pub fn start(&mut self, device: Device) -> Result<String> {
+        let serial = match device.info() {
+            Some(info) => info.serial.clone(),
+            None => {
+                return Err(LoupedeckError::InvalidInput(format!(
+                    "No device info for the device on {}",
+                    device.port
+                )))
+            }
+        };
 
-        let (tx_pending_send, mut rx_pending_send) = mpsc::channel(10);
+        let mut rx_event = match &device.tx_event {
+            Some(tx_event) => tx_event.subscribe(),
+            None => return Err(LoupedeckError::Disconnected),
+        };
 
-        self.event_emitter = device.create_external_event_emitter();
+        self.stop(&serial);
 
-        let current_state = ControllerState {
-            current_page: None,
+        let runtime = self.runtime()?;
+        let (tx_pending_send, mut rx_pending_send) = mpsc::channel(10);
+
+        let event_emitter = device.create_external_event_emitter();
+        let state = ControllerState {
             notify: tx_pending_send,
         };
+        let mut pages = HashMap::new();
+
+        if let Some(page_config) = self
+            .get_current_page_name(&serial)
+            .and_then(|name| self.config.pages.get(&name))
+        {
+            match self.create_page_instance(page_config, event_emitter.as_ref()) {
+                Ok(page) => {
+                    let page = Arc::new(page);
+                    pages.insert(page_config.name.clone(), page.clone());
+                    let _ = state.notify.try_send(page);
+                }
+                Err(e) => println!("Error restoring page {}: {}", page_config.name, e),
+            }
+        }
 
-        self.state = Some(current_state);
-
-        runtime.spawn(async move {
-            let mut rx_event = device.tx_event.clone().unwrap().subscribe();
-
-            let mut current_page: Option<Page> = None;
+        let task = runtime.spawn(async move {
+            // Keeps the connection open for as long as the device is driven
+            let _device = device;
+            let mut current_page: Option<Arc<Page>> = None;
 
             loop {
-                while let Ok(next_event) = rx_event.try_recv() {
-                    match next_event {
-                        Event::ButtonPress(ButtonPressEvent {
-                            tx_id: _,
-                            button: _,
-                            dir: PressDirection::Down,
-                        }) => {
-                            device.vibrate(Haptic::ShortLow).await;
+                tokio::select! {
+                    next_event = rx_event.recv() => match next_event {
+                        Ok(Event::ButtonPress(press_event)) => {
+                            let button = current_page
+                                .as_ref()
+                                .and_then(|page| page.buttons.get(&press_event.button));
+
+                            if let Some(button) = button {
+                                if let Err(e) = button.plugin.on_press(press_event) {
+                                    println!("Error handling button press: {:?}", e);
+                                }
+                            }
                         }
 
-                        Event::TouchEvent(touch_event) => {
-                            if current_page.is_some() {
-                                let key_location =
-                                    KeyLocation::from_location(touch_event.x, touch_event.y);
+                        Ok(Event::KnobRotate(rotate_event)) => {
+                            let knob = current_page
+                                .as_ref()
+                                .and_then(|page| page.knobs.get(&rotate_event.knob));
 
+                            if let Some(knob) = knob {
+                                if let Err(e) = knob.plugin.on_rotate(rotate_event) {
+                                    println!("Error handling knob rotation: {:?}", e);
+                                }
+                            }
+                        }
+
+                        Ok(Event::TouchEvent(touch_event)) => {
+                            if let Some(page) = current_page.as_ref() {
                                 println!(
                                     "Touch event: {:?} ({}, {})",
-                                    key_location, touch_event.x, touch_event.y
+                                    touch_event.screen, touch_event.x, touch_event.y
                                 );
 
-                                let page = current_page.as_ref().unwrap();
-
-                                let screen = page.screen.get(&key_location);
-
-                                if screen.is_some() {
-                                    let screen = screen.unwrap();
-                                    screen.plugin.on_touch(touch_event);
+                                if let Some(screen) = page.plugin_at(&touch_event) {
+                                    if let Err(e) = screen.plugin.on_touch(touch_event) {
+                                        println!("Error handling touch event: {:?}", e);
+                                    }
                                 }
                             }
                         }
-                        _ => {}
-                    }
+                        Ok(_) => {}
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            println!("Dropped {} events from the device", skipped);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
 
-                    time::sleep(time::Duration::from_millis(1)).await
-                }
+                    next_page = rx_pending_send.recv() => {
+                        let Some(next_page) = next_page else {
+                            break;
+                        };
 
-                while let Ok(next_page) = rx_pending_send.try_recv() {
-                    println!("Updating page");
-                    println!(
-                        "Name {:?}, Keys: {:?}",
-                        next_page.name,
-                        next_page.screen.keys()
-                    );
-                    current_page = Some(next_page);
+                        println!("Updating page");
+                        println!(
+                            "Name {:?}, Keys: {:?}",
+                            next_page.name,
+                            next_page.screen.keys()
+                        );
+
+                        if current_page.as_ref().is_some_and(|page| Arc::ptr_eq(page, &next_page)) {
+                            continue;
+                        }
+
+                        let previous_view = current_page.as_ref().and_then(|page| page.view.clone());
+                        if let Some(view) = previous_view.as_ref() {
+                            view.hide();
+                        }
+                        if let Some(page) = current_page.as_ref() {
+                            page.notify(Lifecycle::Hidden);
+                        }
+                        if let Some(view) = next_page.view.as_ref() {
+                            if let Err(e) = view.show() {
+                                println!("Error showing page {}: {}", next_page.name, e);
+                            }
+                            if let Err(e) = view.show_colors(previous_view.as_deref()).await {
+                                println!("Error lighting buttons for page {}: {}", next_page.name, e);
+                            }
+                        }
+                        next_page.notify(Lifecycle::Visible);
+                        current_page = Some(next_page);
+                    }
                 }
             }
         });
+
+        self.devices.insert(
+            serial.clone(),
+            DeviceSession {
+                task,
+                state,
+                event_emitter,
+                pages,
+            },
+        );
+
+        Ok(serial)
     }
 
-    pub fn get_page(&self, page_name: String) -> Option<PageConfig> {
-        let page_config = self.config.pages.get(&page_name);
-        if page_config.is_none() {
-            return None;
+    /// How drawing to a device has been going, for monitoring.
+    pub fn render_stats(&self, serial: &str) -> Option<RenderStats> {
+        self.devices
+            .get(serial)?
+            .event_emitter
+            .as_ref()
+            .map(|emitter| emitter.render_stats())
+    }
+
+    /// Renders what a device shows for its current page, e.g. for golden image tests.
+    ///
+    /// Plugins draw asynchronously, so this only includes what they've drawn so far.
+    pub fn snapshot_page(&self, serial: &str) -> Result<Snapshot> {
+        self.devices
+            .get(serial)
+            .and_then(|session| session.event_emitter.as_ref())
+            .map(|emitter| emitter.snapshot())
+            .ok_or_else(|| LoupedeckError::InvalidInput(format!("No device {} connected", serial)))
+    }
+
+    /// Stops driving a device, e.g. because it was unplugged.
+    pub fn stop(&mut self, serial: &str) {
+        self.devices.remove(serial);
+    }
+
+    pub fn stop_all(&mut self) {
+        self.devices.clear();
+    }
+
+    /// Lets every plugin know the controller is going away, then stops driving all devices.
+    pub fn shutdown(&mut self) {
+        for session in self.devices.values() {
+            for page in session.pages.values() {
+                page.notify(Lifecycle::Shutdown);
+            }
         }
 
-        return Some(page_config.unwrap().clone());
+        self.stop_all();
+    }
+
+    pub fn get_page(&self, page_name: String) -> Option<PageConfig> {
+        self.config.pages.get(&page_name).cloned()
     }
 
     pub fn get_page_names(&self) -> Vec<String> {
-        return self.config.pages.keys().map(|x| x.to_string()).collect();
+        self.config.pages.keys().map(|x| x.to_string()).collect()
     }
 
     pub fn list_plugins(&self) -> Result<Vec<PluginIdentifier>> {
+        Ok(self.list_plugins_of(PluginType::Screen))
+    }
+
+    /// Plugins of one type, e.g. the ones a page's buttons can be bound to.
+    pub fn list_plugins_of(&self, plugin_type: PluginType) -> Vec<PluginIdentifier> {
         let mut plugins: Vec<PluginIdentifier> = Vec::new();
 
         for (key, plugin) in &self.plugin_registry.plugins {
-            for (screen_key, _) in &plugin.screens {
+            for plugin_ref in plugin.names(plugin_type) {
                 plugins.push(PluginIdentifier {
                     plugin_id: key.clone(),
-                    plugin_ref: screen_key.clone(),
+                    plugin_ref: plugin_ref.to_string(),
                 });
             }
         }
 
-        return Ok(plugins);
+        plugins
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Controller, DeviceTarget, PageConfig};
+    use crate::{
+        connect_test_page, connect_virtual_device, Device, DeviceInfo, LoupedeckError,
+        VirtualDevice,
+    };
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    async fn virtual_device(serial: &str) -> (Device, VirtualDevice) {
+        let (mut device, virtual_device) = connect_virtual_device(DeviceInfo {
+            serial: serial.to_string(),
+            version: "0.1.0".to_string(),
+        })
+        .await
+        .unwrap();
+
+        device.get_info().await.unwrap();
+        (device, virtual_device)
+    }
+
+    fn page(name: &str, device: DeviceTarget) -> PageConfig {
+        PageConfig {
+            regions: HashMap::new(),
+            buttons: HashMap::new(),
+            knobs: HashMap::new(),
+            name: name.to_string(),
+            device,
+            screen: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn it_matches_device_targets() {
+        assert!(DeviceTarget::Any.matches("LDD1"));
+        assert!(DeviceTarget::Serial("LDD1".to_string()).matches("LDD1"));
+        assert!(!DeviceTarget::Serial("LDD1".to_string()).matches("LDD2"));
+    }
+
+    #[tokio::test]
+    async fn it_keeps_a_page_per_device() {
+        let mut controller = Controller::new();
+        controller
+            .set_page(page("home", DeviceTarget::Any))
+            .unwrap();
+        controller
+            .set_page(page("obs", DeviceTarget::Serial("LDD1".to_string())))
+            .unwrap();
+
+        let (first, _first_virtual) = virtual_device("LDD1").await;
+        let (second, _second_virtual) = virtual_device("LDD2").await;
+        assert_eq!(controller.start(first).unwrap(), "LDD1");
+        assert_eq!(controller.start(second).unwrap(), "LDD2");
+
+        controller
+            .set_current_page("home".to_string())
+            .await
+            .unwrap();
+        controller
+            .set_device_page("LDD1", "obs".to_string())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            controller.get_current_page_name("LDD1").as_deref(),
+            Some("obs")
+        );
+        assert_eq!(
+            controller.get_current_page_name("LDD2").as_deref(),
+            Some("home")
+        );
+
+        assert!(matches!(
+            controller.set_device_page("LDD2", "obs".to_string()).await,
+            Err(LoupedeckError::InvalidInput(_))
+        ));
+
+        controller.stop_all();
+    }
+
+    #[test]
+    fn it_runs_on_a_provided_runtime() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (first, _first_virtual) = runtime.block_on(virtual_device("LDD1"));
+        let (second, _second_virtual) = runtime.block_on(virtual_device("LDD2"));
+
+        // Outside of a runtime there's nothing to run on unless one is provided
+        let mut controller = Controller::new();
+        assert!(matches!(
+            controller.start(first),
+            Err(LoupedeckError::NoRuntime)
+        ));
+
+        controller.set_runtime(runtime.handle().clone());
+        assert_eq!(controller.start(second).unwrap(), "LDD2");
+        assert_eq!(controller.list_devices(), vec!["LDD2".to_string()]);
+
+        let snapshot = controller.snapshot_page("LDD2").unwrap();
+        assert_eq!((snapshot.width, snapshot.height), (480, 270));
+        assert!(matches!(
+            controller.snapshot_page("LDD1"),
+            Err(LoupedeckError::InvalidInput(_))
+        ));
+
+        // Pages can be switched from a thread that's not on the runtime, like a UI's, with
+        // the lock let go before the page is handed to the device
+        controller
+            .set_page(page("home", DeviceTarget::Any))
+            .unwrap();
+        let controller = Arc::new(Mutex::new(controller));
+        let shown = controller
+            .lock()
+            .unwrap()
+            .set_current_page("home".to_string());
+        runtime.block_on(runtime.spawn(shown)).unwrap().unwrap();
+        assert_eq!(
+            controller
+                .lock()
+                .unwrap()
+                .get_current_page_name("LDD2")
+                .as_deref(),
+            Some("home")
+        );
+
+        controller.lock().unwrap().stop_all();
+    }
+
+    #[tokio::test]
+    async fn it_requires_device_info_to_start() {
+        let mut controller = Controller::new();
+        let (device, _virtual_device, _emitter, _view) = connect_test_page().await;
+        assert!(matches!(
+            controller.start(device),
+            Err(LoupedeckError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn it_only_loads_plugin_files() {
+        let mut controller = Controller::new();
+
+        for path in ["plugins/README.md", "plugins/.DS_Store", "plugins/start.sh"] {
+            assert!(matches!(
+                controller.load_plugin(path),
+                Err(LoupedeckError::PluginLoad(_))
+            ));
+        }
+        assert!(controller.list_plugins().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_refuses_to_start_disconnected_devices() {
+        let mut controller = Controller::new();
+        let (mut device, _virtual_device) = virtual_device("LDD1").await;
+        device.disconnect();
+
+        assert!(matches!(
+            controller.start(device),
+            Err(LoupedeckError::Disconnected)
+        ));
+        assert!(controller.list_devices().is_empty());
     }
 }
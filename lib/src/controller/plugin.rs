@@ -1,9 +1,17 @@
-use crate::{PluginDeclaration, PluginRegistrar, ScreenPluginFactory, ScreenPluginOptions};
+use super::{ProcessCommand, ProcessPlugin, WasmPlugin};
+use crate::{
+    ButtonPlugin, ButtonPressEvent, FfiControlPlugin, FfiControlPluginFactory, FfiRegistrar,
+    FfiScreenPlugin, FfiScreenPluginFactory, FfiStatus, FfiStr, KnobPlugin, KnobRotateEvent,
+    Lifecycle, LoupedeckError, PluginButtonContext, PluginDeclaration, PluginKnobContext,
+    PluginLifecycle, PluginScreenContext, PluginType, Result, ScreenPlugin, ScreenPluginOptions,
+    TouchEvent, PLUGIN_ABI_VERSION,
+};
 use libloading::Library;
 use serde::{Deserialize, Serialize};
-use std::io::Result;
+use std::ffi::c_void;
+use std::path::Path;
 use std::sync::Arc;
-use std::{alloc::System, collections::HashMap, env, ffi::OsStr, io, sync::Mutex};
+use std::{collections::HashMap, ffi::OsStr};
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PluginIdentifier {
@@ -11,15 +19,223 @@ pub struct PluginIdentifier {
     pub plugin_ref: String,
 }
 
+/// A plugin in the registry, from a native library, a WASM module or an executable.
+#[derive(Debug, Clone)]
+pub enum LoadedPlugin {
+    Library(LocalLoadedPlugin),
+    Wasm(WasmPlugin),
+    Process(ProcessPlugin),
+}
+
+impl LoadedPlugin {
+    pub fn plugin_id(&self) -> &str {
+        match self {
+            LoadedPlugin::Library(plugin) => &plugin.plugin_id,
+            LoadedPlugin::Wasm(plugin) => &plugin.plugin_id,
+            LoadedPlugin::Process(plugin) => &plugin.plugin_id,
+        }
+    }
+
+    /// Names of the plugins of one type the plugin registered.
+    pub fn names(&self, plugin_type: PluginType) -> Vec<&str> {
+        match (self, plugin_type) {
+            (LoadedPlugin::Library(plugin), PluginType::Screen) => {
+                plugin.screens.keys().map(String::as_str).collect()
+            }
+            (LoadedPlugin::Library(plugin), PluginType::Button) => {
+                plugin.buttons.keys().map(String::as_str).collect()
+            }
+            (LoadedPlugin::Library(plugin), PluginType::Knob) => {
+                plugin.knobs.keys().map(String::as_str).collect()
+            }
+            (LoadedPlugin::Wasm(plugin), PluginType::Screen) => {
+                plugin.screens.iter().map(String::as_str).collect()
+            }
+            (LoadedPlugin::Process(plugin), PluginType::Screen) => {
+                plugin.screens.iter().map(String::as_str).collect()
+            }
+            (LoadedPlugin::Process(plugin), PluginType::Button) => {
+                plugin.buttons.iter().map(String::as_str).collect()
+            }
+            (LoadedPlugin::Process(plugin), PluginType::Knob) => {
+                plugin.knobs.iter().map(String::as_str).collect()
+            }
+            // WASM plugins only have screens so far
+            _ => Vec::new(),
+        }
+    }
+
+    /// Names of the screens the plugin registered.
+    pub fn screen_names(&self) -> Vec<&str> {
+        self.names(PluginType::Screen)
+    }
+
+    pub fn has(&self, plugin_type: PluginType, name: &str) -> bool {
+        self.names(plugin_type).contains(&name)
+    }
+
+    pub fn has_screen(&self, name: &str) -> bool {
+        self.has(PluginType::Screen, name)
+    }
+
+    /// Starts one of the plugin's screens on the given context.
+    pub fn create_screen(
+        &self,
+        name: &str,
+        ctx: PluginScreenContext,
+    ) -> Result<Box<dyn ScreenPlugin>> {
+        match self {
+            LoadedPlugin::Library(plugin) => plugin.create_screen(name, ctx),
+            LoadedPlugin::Wasm(plugin) => plugin.create_screen(name, ctx),
+            LoadedPlugin::Process(plugin) => plugin.create_screen(name, ctx),
+        }
+    }
+
+    /// Starts one of the plugin's buttons on the given context.
+    pub fn create_button(
+        &self,
+        name: &str,
+        ctx: PluginButtonContext,
+    ) -> Result<Box<dyn ButtonPlugin>> {
+        match self {
+            LoadedPlugin::Library(plugin) => plugin.create_button(name, ctx),
+            LoadedPlugin::Process(plugin) => plugin.create_button(name, ctx),
+            LoadedPlugin::Wasm(_) => Err(LoupedeckError::InvalidInput(format!(
+                "{} has no {:?} button",
+                self.plugin_id(),
+                name
+            ))),
+        }
+    }
+
+    /// Starts one of the plugin's knobs on the given context.
+    pub fn create_knob(&self, name: &str, ctx: PluginKnobContext) -> Result<Box<dyn KnobPlugin>> {
+        match self {
+            LoadedPlugin::Library(plugin) => plugin.create_knob(name, ctx),
+            LoadedPlugin::Process(plugin) => plugin.create_knob(name, ctx),
+            LoadedPlugin::Wasm(_) => Err(LoupedeckError::InvalidInput(format!(
+                "{} has no {:?} knob",
+                self.plugin_id(),
+                name
+            ))),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct LocalLoadedPlugin {
     lib: Arc<Library>,
     pub plugin_id: String,
-    pub screens: HashMap<String, ScreenPluginFactory>,
+    pub screens: HashMap<String, FfiScreenPluginFactory>,
+    pub buttons: HashMap<String, FfiControlPluginFactory>,
+    pub knobs: HashMap<String, FfiControlPluginFactory>,
+}
+
+impl LocalLoadedPlugin {
+    /// Starts one of the plugin's screens on the given context.
+    pub fn create_screen(
+        &self,
+        name: &str,
+        ctx: PluginScreenContext,
+    ) -> Result<Box<dyn ScreenPlugin>> {
+        let factory = self.screens.get(name).ok_or_else(|| {
+            LoupedeckError::InvalidInput(format!("{} has no {:?} screen", self.plugin_id, name))
+        })?;
+
+        Ok(Box::new(LibraryScreenPlugin {
+            plugin: factory.create(ctx)?,
+            _lib: Arc::clone(&self.lib),
+        }))
+    }
+
+    /// Starts one of the plugin's buttons on the given context.
+    pub fn create_button(
+        &self,
+        name: &str,
+        ctx: PluginButtonContext,
+    ) -> Result<Box<dyn ButtonPlugin>> {
+        let factory = self.buttons.get(name).ok_or_else(|| {
+            LoupedeckError::InvalidInput(format!("{} has no {:?} button", self.plugin_id, name))
+        })?;
+
+        Ok(Box::new(LibraryControlPlugin {
+            plugin: factory.create_button(ctx)?,
+            _lib: Arc::clone(&self.lib),
+        }))
+    }
+
+    /// Starts one of the plugin's knobs on the given context.
+    pub fn create_knob(&self, name: &str, ctx: PluginKnobContext) -> Result<Box<dyn KnobPlugin>> {
+        let factory = self.knobs.get(name).ok_or_else(|| {
+            LoupedeckError::InvalidInput(format!("{} has no {:?} knob", self.plugin_id, name))
+        })?;
+
+        Ok(Box::new(LibraryControlPlugin {
+            plugin: factory.create_knob(ctx)?,
+            _lib: Arc::clone(&self.lib),
+        }))
+    }
+}
+
+/// A screen plugin running in a plugin library.
+struct LibraryScreenPlugin {
+    plugin: FfiScreenPlugin,
+    // Keeps the library loaded for as long as the plugin is running
+    _lib: Arc<Library>,
+}
+
+impl PluginLifecycle for LibraryScreenPlugin {
+    fn on_lifecycle(&self, hook: Lifecycle) -> Result<()> {
+        self.plugin.on_lifecycle(hook)
+    }
+}
+
+impl ScreenPlugin for LibraryScreenPlugin {
+    fn on_touch(&self, position: TouchEvent) -> Result<()> {
+        self.plugin.on_touch(&position)
+    }
+}
+
+impl Drop for LibraryScreenPlugin {
+    fn drop(&mut self) {
+        unsafe { self.plugin.release() }
+    }
+}
+
+/// A button or knob plugin running in a plugin library.
+struct LibraryControlPlugin {
+    plugin: FfiControlPlugin,
+    _lib: Arc<Library>,
+}
+
+impl PluginLifecycle for LibraryControlPlugin {
+    fn on_lifecycle(&self, hook: Lifecycle) -> Result<()> {
+        self.plugin.on_lifecycle(hook)
+    }
+}
+
+impl ButtonPlugin for LibraryControlPlugin {
+    fn on_press(&self, event: ButtonPressEvent) -> Result<()> {
+        self.plugin.on_press(&event)
+    }
+}
+
+impl KnobPlugin for LibraryControlPlugin {
+    fn on_rotate(&self, event: KnobRotateEvent) -> Result<()> {
+        self.plugin.on_rotate(&event)
+    }
+}
+
+impl Drop for LibraryControlPlugin {
+    fn drop(&mut self) {
+        unsafe { self.plugin.release() }
+    }
 }
 
 pub struct TempPluginRegistrar {
-    screens: HashMap<String, ScreenPluginFactory>,
+    screens: HashMap<String, FfiScreenPluginFactory>,
+    buttons: HashMap<String, FfiControlPluginFactory>,
+    knobs: HashMap<String, FfiControlPluginFactory>,
     lib: Arc<Library>,
 }
 
@@ -27,34 +243,78 @@ impl TempPluginRegistrar {
     pub fn new(lib: Arc<Library>) -> TempPluginRegistrar {
         TempPluginRegistrar {
             screens: HashMap::default(),
+            buttons: HashMap::default(),
+            knobs: HashMap::default(),
             lib,
         }
     }
 
-    pub fn to_local_plugin(self, plugin_id: String) -> LocalLoadedPlugin {
+    pub fn into_local_plugin(self, plugin_id: String) -> LocalLoadedPlugin {
         LocalLoadedPlugin {
             lib: self.lib,
             plugin_id,
             screens: self.screens,
+            buttons: self.buttons,
+            knobs: self.knobs,
         }
     }
-}
 
-impl PluginRegistrar for TempPluginRegistrar {
-    fn register_screen(
-        &mut self,
-        name: &str,
-        options: ScreenPluginOptions,
-        create: ScreenPluginFactory,
-    ) -> Result<()> {
-        self.screens.insert(name.to_string(), create);
+    /// The registrar as a plugin library's `register` function takes it.
+    fn as_ffi(&mut self) -> FfiRegistrar {
+        FfiRegistrar {
+            registrar: self as *mut Self as *mut c_void,
+            register_screen,
+            register_button,
+            register_knob,
+        }
+    }
+}
 
-        return Ok(());
+/// Adds a factory under the name a plugin library gave it.
+unsafe fn insert_factory<F>(
+    factories: &mut HashMap<String, F>,
+    name: FfiStr,
+    factory: F,
+) -> FfiStatus {
+    match name.as_str() {
+        Ok(name) => {
+            factories.insert(name.to_string(), factory);
+            FfiStatus::OK
+        }
+        Err(_) => FfiStatus::INVALID_INPUT,
     }
 }
 
+unsafe extern "C" fn register_screen(
+    registrar: *mut c_void,
+    name: FfiStr,
+    _options: ScreenPluginOptions,
+    factory: FfiScreenPluginFactory,
+) -> FfiStatus {
+    let registrar = &mut *(registrar as *mut TempPluginRegistrar);
+    insert_factory(&mut registrar.screens, name, factory)
+}
+
+unsafe extern "C" fn register_button(
+    registrar: *mut c_void,
+    name: FfiStr,
+    factory: FfiControlPluginFactory,
+) -> FfiStatus {
+    let registrar = &mut *(registrar as *mut TempPluginRegistrar);
+    insert_factory(&mut registrar.buttons, name, factory)
+}
+
+unsafe extern "C" fn register_knob(
+    registrar: *mut c_void,
+    name: FfiStr,
+    factory: FfiControlPluginFactory,
+) -> FfiStatus {
+    let registrar = &mut *(registrar as *mut TempPluginRegistrar);
+    insert_factory(&mut registrar.knobs, name, factory)
+}
+
 pub struct PluginRegistry {
-    pub plugins: HashMap<String, LocalLoadedPlugin>,
+    pub plugins: HashMap<String, LoadedPlugin>,
 }
 
 impl PluginRegistry {
@@ -65,28 +325,84 @@ impl PluginRegistry {
     }
 
     pub unsafe fn load_from_path<P: AsRef<OsStr>>(&mut self, path: P) -> Result<()> {
-        let library = Arc::new(Library::new(path)?);
-        let decl = library
-            .get::<*mut PluginDeclaration>(b"plugin_declaration\0")?
-            .read();
+        let plugin_path = path.as_ref().to_string_lossy().to_string();
+        let library = Arc::new(
+            Library::new(path)
+                .map_err(|e| LoupedeckError::PluginLoad(format!("{}: {}", plugin_path, e)))?,
+        );
+        let decl = *library
+            .get::<*const PluginDeclaration>(b"plugin_declaration\0")
+            .map_err(|e| LoupedeckError::PluginLoad(format!("{}: {}", plugin_path, e)))?;
 
-        if decl.rustc_version != crate::RUSTC_VERSION || decl.core_version != crate::CORE_VERSION {
-            return Err(io::Error::new(io::ErrorKind::Other, "Version mismatch"));
+        // Everything past the version may have moved, so check it before reading the rest
+        let abi_version = (decl as *const u32).read();
+        if abi_version != PLUGIN_ABI_VERSION {
+            return Err(LoupedeckError::PluginLoad(format!(
+                "{}: built for plugin ABI version {} but expected {}",
+                plugin_path, abi_version, PLUGIN_ABI_VERSION
+            )));
         }
 
+        let decl = &*decl;
+        let plugin_id = decl
+            .plugin_id
+            .as_str()
+            .map_err(|e| LoupedeckError::PluginLoad(format!("{}: {}", plugin_path, e)))?;
+
         let mut registrar = TempPluginRegistrar::new(Arc::clone(&library));
-        (decl.register)(&mut registrar);
+        (decl.register)(registrar.as_ffi())
+            .into_result()
+            .map_err(|e| LoupedeckError::PluginLoad(format!("{}: {}", plugin_path, e)))?;
 
-        let local_plugin = registrar.to_local_plugin(decl.plugin_id.to_string());
+        let local_plugin = registrar.into_local_plugin(plugin_id.to_string());
 
         println!(
-            "Loaded plugin_id: {:?} with handlers: {:?}",
+            "Loaded plugin_id: {:?} (built against {}) with handlers: {:?}, buttons: {:?}, knobs: {:?}",
             local_plugin.plugin_id,
-            local_plugin.screens.keys()
+            decl.core_version.as_str().unwrap_or("an unknown version"),
+            local_plugin.screens.keys(),
+            local_plugin.buttons.keys(),
+            local_plugin.knobs.keys()
+        );
+
+        self.plugins
+            .insert(plugin_id.to_string(), LoadedPlugin::Library(local_plugin));
+
+        Ok(())
+    }
+
+    /// Loads a `.wasm` plugin, which runs sandboxed instead of in the host's process.
+    pub fn load_wasm_from_path<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let plugin_path = path.as_ref().to_string_lossy().to_string();
+        let plugin = std::fs::read(path)
+            .map_err(LoupedeckError::from)
+            .and_then(|wasm| WasmPlugin::load(&wasm))
+            .map_err(|e| LoupedeckError::PluginLoad(format!("{}: {}", plugin_path, e)))?;
+
+        println!(
+            "Loaded WASM plugin_id: {:?} with handlers: {:?}",
+            plugin.plugin_id, plugin.screens
+        );
+
+        self.plugins
+            .insert(plugin.plugin_id.clone(), LoadedPlugin::Wasm(plugin));
+
+        Ok(())
+    }
+
+    /// Starts an executable plugin, which the registry keeps running until it's dropped.
+    pub fn load_process(&mut self, command: ProcessCommand) -> Result<()> {
+        let plugin_path = command.command.to_string_lossy().to_string();
+        let plugin = ProcessPlugin::spawn(command)
+            .map_err(|e| LoupedeckError::PluginLoad(format!("{}: {}", plugin_path, e)))?;
+
+        println!(
+            "Started plugin_id: {:?} with screens: {:?}, buttons: {:?}, knobs: {:?}",
+            plugin.plugin_id, plugin.screens, plugin.buttons, plugin.knobs
         );
 
         self.plugins
-            .insert(decl.plugin_id.to_string(), local_plugin);
+            .insert(plugin.plugin_id.clone(), LoadedPlugin::Process(plugin));
 
         Ok(())
     }
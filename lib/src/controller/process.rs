@@ -0,0 +1,994 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    render_image, ButtonPlugin, ButtonPressEvent, Fit, Haptic, KnobPlugin, KnobRotateEvent,
+    Lifecycle, LoupedeckError, PluginButtonContext, PluginKnobContext, PluginLifecycle,
+    PluginScreenContext, Result, Rgb, ScreenPlugin, Spawner, TouchEvent,
+};
+
+/// Version of the protocol below, sent to the plugin with `initialize`.
+pub const PROCESS_PLUGIN_PROTOCOL_VERSION: u32 = 2;
+
+const INITIALIZE_TIMEOUT: Duration = Duration::from_secs(5);
+const FIRST_RESTART_DELAY: Duration = Duration::from_millis(100);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+/// How long a plugin has to stay up before it's restarted quickly again.
+const HEALTHY_UPTIME: Duration = Duration::from_secs(10);
+/// Messages that can wait for a plugin to read them, after which new ones are dropped.
+const WRITE_QUEUE_LEN: usize = 256;
+
+/// How to start a process plugin, as a `.plugin` file lists it, e.g.
+/// `{"command": "./obs-plugin", "args": ["--port", "4455"]}`.
+///
+/// A relative path for `command` is relative to the file, while a bare name is looked up
+/// on the `PATH`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ProcessCommand {
+    pub command: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+impl ProcessCommand {
+    pub fn new(command: impl Into<PathBuf>) -> Self {
+        Self {
+            command: command.into(),
+            args: Vec::new(),
+        }
+    }
+
+    /// Reads a `.plugin` file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::read(path)?;
+        let mut command: Self = serde_json::from_slice(&file)
+            .map_err(|e| LoupedeckError::InvalidInput(format!("{}: {}", path.display(), e)))?;
+
+        if command.command.is_relative() && command.command.components().count() > 1 {
+            let dir = path.parent().unwrap_or_else(|| Path::new(""));
+            command.command = dir.join(&command.command);
+        }
+
+        Ok(command)
+    }
+}
+
+/// A plugin that's an executable, talking JSON-RPC 2.0 over its stdin and stdout with one
+/// message per line. Its stderr goes to the host's, and it's restarted whenever it exits.
+///
+/// The host first sends the request `initialize` with `{"protocol_version": 2}`, and waits
+/// for a result of `{"plugin_id": "...", "screens": ["..."], "buttons": ["..."],
+/// "knobs": ["..."]}` naming the screens, buttons and knobs it has. `buttons` and `knobs`
+/// can be left out. After that it only sends notifications:
+/// - `create_screen` with `{"instance", "screen", "region", "width", "height"}`
+/// - `create_button` with `{"instance", "button", "control"}`, where `control` is the
+///   button it's bound to, e.g. `"Circle1"`
+/// - `create_knob` with `{"instance", "knob", "control", "strip"}`, where `strip` is the
+///   `{"region", "width", "height"}` of the screen next to the knob, or `null`
+/// - `destroy_screen`, `destroy_button` and `destroy_knob` with `{"instance"}`
+/// - `touch`, `press` and `rotate` with `{"instance", "event"}`
+/// - `lifecycle` with `{"instance", "hook"}`, e.g. `"visible"`. While hidden, drawing is
+///   buffered and vibrating is refused, and once unmounted both fail
+///
+/// Every running instance is created again after a restart, then sent `mount` and, if its
+/// page is showing, `visible`.
+///
+/// The plugin can call, as requests to get a result back or as notifications:
+/// - `draw_rgb565` with `{"instance", "data"}`, the base64 of the region's little-endian
+///   RGB565 pixels, for screens and knobs with a strip
+/// - `draw_image` with `{"instance", "data", "fit"}`, the base64 of a PNG, JPEG, GIF or SVG
+///   file, and optionally `"contain"`, `"fill"` or `"stretch"`
+/// - `vibrate` with `{"instance", "haptic"}`, e.g. `"Medium"`
+/// - `set_color` with `{"instance", "color"}`, e.g. `{"r": 255, "g": 0, "b": 0}`, for buttons
+///
+/// Regions, controls, events, haptics and colours are serialized like the Rust types.
+#[derive(Clone)]
+pub struct ProcessPlugin {
+    pub plugin_id: String,
+    pub screens: Vec<String>,
+    pub buttons: Vec<String>,
+    pub knobs: Vec<String>,
+    host: Arc<ProcessHost>,
+}
+
+impl fmt::Debug for ProcessPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProcessPlugin")
+            .field("plugin_id", &self.plugin_id)
+            .field("screens", &self.screens)
+            .field("buttons", &self.buttons)
+            .field("knobs", &self.knobs)
+            .field("command", &self.host.command)
+            .finish()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    plugin_id: String,
+    screens: Vec<String>,
+    #[serde(default)]
+    buttons: Vec<String>,
+    #[serde(default)]
+    knobs: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Message {
+    #[serde(default)]
+    id: Option<Value>,
+    #[serde(default)]
+    method: Option<String>,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    result: Option<Value>,
+    #[serde(default)]
+    error: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DrawParams {
+    instance: u64,
+    data: String,
+    #[serde(default)]
+    fit: Fit,
+}
+
+#[derive(Debug, Deserialize)]
+struct VibrateParams {
+    instance: u64,
+    haptic: Haptic,
+}
+
+#[derive(Debug, Deserialize)]
+struct ColorParams {
+    instance: u64,
+    color: Rgb,
+}
+
+/// Queues a message for the plugin without waiting, so one that stops reading its input
+/// can't hold up the host.
+fn lifecycle_message(instance: u64, hook: Lifecycle) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "lifecycle",
+        "params": { "instance": instance, "hook": hook },
+    })
+}
+
+fn write_message(writer: &SyncSender<Vec<u8>>, message: &Value) -> Result<()> {
+    let mut line =
+        serde_json::to_vec(message).map_err(|e| LoupedeckError::InvalidInput(e.to_string()))?;
+    line.push(b'\n');
+
+    match writer.try_send(line) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(_)) => {
+            println!("A plugin isn't reading its input, dropping a message for it");
+            Ok(())
+        }
+        Err(TrySendError::Disconnected(_)) => Err(LoupedeckError::Disconnected),
+    }
+}
+
+/// One run of the plugin's executable.
+struct Connection {
+    child: Child,
+    writer: SyncSender<Vec<u8>>,
+    lines: Receiver<String>,
+}
+
+impl Connection {
+    fn start(command: &ProcessCommand) -> Result<(Self, Manifest)> {
+        let mut child = Command::new(&command.command)
+            .args(&command.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        let (writer, rx_write) = mpsc::sync_channel::<Vec<u8>>(WRITE_QUEUE_LEN);
+        thread::spawn(move || {
+            for line in rx_write {
+                if stdin.write_all(&line).and_then(|_| stdin.flush()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let (tx_line, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                if tx_line.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut connection = Self {
+            child,
+            writer,
+            lines,
+        };
+        match connection.initialize() {
+            Ok(manifest) => Ok((connection, manifest)),
+            Err(e) => {
+                let _ = connection.child.kill();
+                let _ = connection.child.wait();
+                Err(e)
+            }
+        }
+    }
+
+    fn initialize(&mut self) -> Result<Manifest> {
+        write_message(
+            &self.writer,
+            &json!({
+                "jsonrpc": "2.0",
+                "id": 0,
+                "method": "initialize",
+                "params": { "protocol_version": PROCESS_PLUGIN_PROTOCOL_VERSION },
+            }),
+        )?;
+
+        let deadline = Instant::now() + INITIALIZE_TIMEOUT;
+        loop {
+            let line = self
+                .lines
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .map_err(|e| match e {
+                    RecvTimeoutError::Timeout => LoupedeckError::Timeout,
+                    RecvTimeoutError::Disconnected => {
+                        LoupedeckError::PluginLoad("exited before initializing".to_string())
+                    }
+                })?;
+
+            let message = match serde_json::from_str::<Message>(&line) {
+                Ok(message) if message.method.is_none() && message.id == Some(json!(0)) => message,
+                _ => continue,
+            };
+
+            if let Some(error) = message.error {
+                return Err(LoupedeckError::PluginLoad(format!(
+                    "initialize failed: {}",
+                    error
+                )));
+            }
+
+            return serde_json::from_value(message.result.unwrap_or_default()).map_err(|e| {
+                LoupedeckError::PluginLoad(format!("unexpected initialize result: {}", e))
+            });
+        }
+    }
+}
+
+/// A screen, button or knob running in the plugin, by the name the plugin gave it.
+#[derive(Clone)]
+enum Instance {
+    Screen(String, PluginScreenContext),
+    Button(String, PluginButtonContext),
+    Knob(String, PluginKnobContext),
+}
+
+impl Instance {
+    /// The notification that creates the instance in the plugin.
+    fn create_message(&self, instance: u64) -> Value {
+        fn screen_params(ctx: &PluginScreenContext) -> Value {
+            let (width, height) = ctx.size();
+            json!({ "region": ctx.region(), "width": width, "height": height })
+        }
+
+        let (method, params) = match self {
+            Instance::Screen(name, ctx) => {
+                let mut params = screen_params(ctx);
+                params["instance"] = json!(instance);
+                params["screen"] = json!(name);
+                ("create_screen", params)
+            }
+            Instance::Button(name, ctx) => (
+                "create_button",
+                json!({ "instance": instance, "button": name, "control": ctx.button() }),
+            ),
+            Instance::Knob(name, ctx) => (
+                "create_knob",
+                json!({
+                    "instance": instance,
+                    "knob": name,
+                    "control": ctx.knob(),
+                    "strip": ctx.strip().map(screen_params),
+                }),
+            ),
+        };
+
+        json!({ "jsonrpc": "2.0", "method": method, "params": params })
+    }
+
+    /// Where the instance draws, if anywhere.
+    fn screen(&self) -> Result<&PluginScreenContext> {
+        let screen = match self {
+            Instance::Screen(_, ctx) => Some(ctx),
+            Instance::Button(..) => None,
+            Instance::Knob(_, ctx) => ctx.strip(),
+        };
+        screen.ok_or_else(|| {
+            LoupedeckError::InvalidInput("Only screens and knobs with a strip can draw".to_string())
+        })
+    }
+
+    fn spawner(&self) -> &Spawner {
+        match self {
+            Instance::Screen(_, ctx) => ctx.spawner(),
+            Instance::Button(_, ctx) => ctx.spawner(),
+            Instance::Knob(_, ctx) => ctx.spawner(),
+        }
+    }
+
+    async fn vibrate(&self, haptic: Haptic) -> Result<()> {
+        match self {
+            Instance::Screen(_, ctx) => ctx.vibrate(haptic).await,
+            Instance::Button(_, ctx) => ctx.vibrate(haptic).await,
+            Instance::Knob(_, ctx) => ctx.vibrate(haptic).await,
+        }
+    }
+}
+
+/// The running executable, or nothing while it's being restarted.
+struct Shared {
+    child: Option<Child>,
+    writer: Option<SyncSender<Vec<u8>>>,
+    instances: HashMap<u64, Instance>,
+    /// The last lifecycle hook each instance got, to bring a restarted plugin back up to it.
+    hooks: HashMap<u64, Lifecycle>,
+}
+
+struct ProcessHost {
+    command: ProcessCommand,
+    plugin_id: String,
+    shared: Mutex<Shared>,
+    next_instance: AtomicU64,
+}
+
+impl ProcessHost {
+    fn notify(&self, method: &str, params: Value) -> Result<()> {
+        let shared = self.shared.lock().unwrap();
+        let writer = shared.writer.as_ref().ok_or(LoupedeckError::Disconnected)?;
+
+        write_message(
+            writer,
+            &json!({ "jsonrpc": "2.0", "method": method, "params": params }),
+        )
+    }
+
+    /// Starts an instance in the plugin, or once it's back if it's restarting.
+    fn create(&self, instance: Instance) -> u64 {
+        let id = self.next_instance.fetch_add(1, Ordering::Relaxed);
+        let message = instance.create_message(id);
+
+        let mut shared = self.shared.lock().unwrap();
+        shared.instances.insert(id, instance);
+        if let Some(writer) = shared.writer.as_ref() {
+            if let Err(e) = write_message(writer, &message) {
+                println!("Error creating an instance of {}: {}", self.plugin_id, e);
+            }
+        }
+
+        id
+    }
+
+    /// Forgets an instance, and lets the plugin know with `method`.
+    fn destroy(&self, instance: u64, method: &str) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.instances.remove(&instance);
+        shared.hooks.remove(&instance);
+        drop(shared);
+
+        let _ = self.notify(method, json!({ "instance": instance }));
+    }
+
+    /// Tells an instance about a lifecycle hook, and remembers it for after a restart.
+    fn lifecycle(&self, instance: u64, hook: Lifecycle) -> Result<()> {
+        let mut shared = self.shared.lock().unwrap();
+        shared.hooks.insert(instance, hook);
+
+        let writer = shared.writer.as_ref().ok_or(LoupedeckError::Disconnected)?;
+        write_message(writer, &lifecycle_message(instance, hook))
+    }
+
+    /// Takes over a fresh run of the executable, and starts the instances that were running.
+    fn attach(&self, connection: Connection) -> Receiver<String> {
+        let mut shared = self.shared.lock().unwrap();
+        let writer = connection.writer;
+
+        for (id, instance) in &shared.instances {
+            // Mounted instances are mounted again, and shown again if their page is showing
+            let hooks = match shared.hooks.get(id) {
+                Some(Lifecycle::Mount | Lifecycle::Hidden) => &[Lifecycle::Mount][..],
+                Some(Lifecycle::Visible) => &[Lifecycle::Mount, Lifecycle::Visible][..],
+                Some(Lifecycle::Unmount | Lifecycle::Shutdown) | None => &[][..],
+            };
+
+            let messages = std::iter::once(instance.create_message(*id))
+                .chain(hooks.iter().map(|hook| lifecycle_message(*id, *hook)));
+            for message in messages {
+                if let Err(e) = write_message(&writer, &message) {
+                    println!("Error recreating an instance of {}: {}", self.plugin_id, e);
+                }
+            }
+        }
+
+        shared.child = Some(connection.child);
+        shared.writer = Some(writer);
+        connection.lines
+    }
+
+    fn instance(&self, instance: u64) -> Result<Instance> {
+        let shared = self.shared.lock().unwrap();
+        shared
+            .instances
+            .get(&instance)
+            .cloned()
+            .ok_or_else(|| LoupedeckError::InvalidInput(format!("No instance {}", instance)))
+    }
+
+    fn handle_line(&self, line: &str) {
+        let message = match serde_json::from_str::<Message>(line) {
+            Ok(message) => message,
+            Err(_) => {
+                println!("[{}] {}", self.plugin_id, line);
+                return;
+            }
+        };
+        // The host doesn't send requests after initializing, so there's nothing to match
+        // responses to
+        let Some(method) = message.method else {
+            return;
+        };
+
+        let result = match method.as_str() {
+            "draw_rgb565" => params(message.params).and_then(|params: DrawParams| {
+                let data = decode(&params.data)?;
+                self.instance(params.instance)?
+                    .screen()?
+                    .draw_rgb565_now(data)
+            }),
+            "draw_image" => params(message.params).and_then(|params: DrawParams| {
+                let instance = self.instance(params.instance)?;
+                let ctx = instance.screen()?;
+                let (width, height) = ctx.size();
+                let data = render_image(decode(&params.data)?, width, height, params.fit)?;
+                ctx.draw_rgb565_now(data)
+            }),
+            "vibrate" => params(message.params).and_then(|params: VibrateParams| {
+                let instance = self.instance(params.instance)?;
+                instance.spawner().clone().spawn(async move {
+                    if let Err(e) = instance.vibrate(params.haptic).await {
+                        println!("Error vibrating for a plugin: {}", e);
+                    }
+                });
+                Ok(())
+            }),
+            "set_color" => params(message.params).and_then(|params: ColorParams| {
+                let Instance::Button(_, ctx) = self.instance(params.instance)? else {
+                    return Err(LoupedeckError::InvalidInput(
+                        "Only buttons have a colour".to_string(),
+                    ));
+                };
+                ctx.spawner().clone().spawn(async move {
+                    if let Err(e) = ctx.set_color(params.color).await {
+                        println!("Error setting a button's colour for a plugin: {}", e);
+                    }
+                });
+                Ok(())
+            }),
+            _ => {
+                self.respond(
+                    message.id,
+                    Err((-32601, format!("Unknown method {:?}", method))),
+                );
+                return;
+            }
+        };
+
+        self.respond(
+            message.id,
+            result.map_err(|e| match e {
+                LoupedeckError::InvalidInput(_) => (-32602, e.to_string()),
+                _ => (-32000, e.to_string()),
+            }),
+        );
+    }
+
+    /// Answers a request, or logs a failed notification.
+    fn respond(&self, id: Option<Value>, result: std::result::Result<(), (i32, String)>) {
+        let Some(id) = id else {
+            if let Err((_, message)) = result {
+                println!(
+                    "Error handling a notification from {}: {}",
+                    self.plugin_id, message
+                );
+            }
+            return;
+        };
+
+        let response = match result {
+            Ok(()) => json!({ "jsonrpc": "2.0", "id": id, "result": null }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        };
+
+        let shared = self.shared.lock().unwrap();
+        if let Some(writer) = shared.writer.as_ref() {
+            let _ = write_message(writer, &response);
+        }
+    }
+}
+
+impl Drop for ProcessHost {
+    fn drop(&mut self) {
+        let shared = self.shared.get_mut().unwrap();
+        if let Some(child) = shared.child.as_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T> {
+    serde_json::from_value(params).map_err(|e| LoupedeckError::InvalidInput(e.to_string()))
+}
+
+fn decode(data: &str) -> Result<Vec<u8>> {
+    BASE64
+        .decode(data)
+        .map_err(|e| LoupedeckError::InvalidInput(e.to_string()))
+}
+
+/// Handles the plugin's messages, and restarts it with a growing delay whenever it exits.
+fn supervise(host: Weak<ProcessHost>, mut lines: Receiver<String>) {
+    let mut started = Instant::now();
+    let mut delay = FIRST_RESTART_DELAY;
+
+    loop {
+        while let Ok(line) = lines.recv() {
+            match host.upgrade() {
+                Some(host) => host.handle_line(&line),
+                None => return,
+            }
+        }
+
+        let Some(command) = host.upgrade().map(|host| {
+            let mut shared = host.shared.lock().unwrap();
+            shared.writer = None;
+            if let Some(mut child) = shared.child.take() {
+                // It may have only closed its stdout
+                let _ = child.kill();
+                println!("Plugin {} exited with {:?}", host.plugin_id, child.wait());
+            }
+            host.command.clone()
+        }) else {
+            return;
+        };
+
+        if started.elapsed() > HEALTHY_UPTIME {
+            delay = FIRST_RESTART_DELAY;
+        }
+        thread::sleep(delay);
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+
+        let connection = Connection::start(&command);
+        let Some(host) = host.upgrade() else {
+            return;
+        };
+        match connection {
+            Ok((connection, manifest)) => {
+                if manifest.plugin_id != host.plugin_id {
+                    println!(
+                        "Plugin {} came back as {}",
+                        host.plugin_id, manifest.plugin_id
+                    );
+                }
+                started = Instant::now();
+                lines = host.attach(connection);
+            }
+            Err(e) => {
+                println!("Error restarting plugin {}: {}", host.plugin_id, e);
+                lines = mpsc::channel().1;
+            }
+        }
+    }
+}
+
+impl ProcessPlugin {
+    /// Starts the executable and asks it which screens, buttons and knobs it has.
+    pub fn spawn(command: ProcessCommand) -> Result<Self> {
+        let (connection, manifest) = Connection::start(&command)?;
+
+        let host = Arc::new(ProcessHost {
+            command,
+            plugin_id: manifest.plugin_id.clone(),
+            shared: Mutex::new(Shared {
+                child: None,
+                writer: None,
+                instances: HashMap::new(),
+                hooks: HashMap::new(),
+            }),
+            next_instance: AtomicU64::new(0),
+        });
+        let lines = host.attach(connection);
+
+        let supervised = Arc::downgrade(&host);
+        thread::spawn(move || supervise(supervised, lines));
+
+        Ok(Self {
+            plugin_id: manifest.plugin_id,
+            screens: manifest.screens,
+            buttons: manifest.buttons,
+            knobs: manifest.knobs,
+            host,
+        })
+    }
+
+    fn check_name(&self, names: &[String], kind: &str, name: &str) -> Result<()> {
+        if !names.iter().any(|n| n == name) {
+            return Err(LoupedeckError::InvalidInput(format!(
+                "{} has no {:?} {}",
+                self.plugin_id, name, kind
+            )));
+        }
+        Ok(())
+    }
+
+    /// Starts one of the plugin's screens on the given context.
+    pub fn create_screen(
+        &self,
+        name: &str,
+        ctx: PluginScreenContext,
+    ) -> Result<Box<dyn ScreenPlugin>> {
+        self.check_name(&self.screens, "screen", name)?;
+        let instance = self.host.create(Instance::Screen(name.to_string(), ctx));
+
+        Ok(Box::new(ProcessScreenPlugin {
+            host: Arc::clone(&self.host),
+            instance,
+        }))
+    }
+
+    /// Starts one of the plugin's buttons on the given context.
+    pub fn create_button(
+        &self,
+        name: &str,
+        ctx: PluginButtonContext,
+    ) -> Result<Box<dyn ButtonPlugin>> {
+        self.check_name(&self.buttons, "button", name)?;
+        let instance = self.host.create(Instance::Button(name.to_string(), ctx));
+
+        Ok(Box::new(ProcessControlPlugin {
+            host: Arc::clone(&self.host),
+            instance,
+            destroy: "destroy_button",
+        }))
+    }
+
+    /// Starts one of the plugin's knobs on the given context.
+    pub fn create_knob(&self, name: &str, ctx: PluginKnobContext) -> Result<Box<dyn KnobPlugin>> {
+        self.check_name(&self.knobs, "knob", name)?;
+        let instance = self.host.create(Instance::Knob(name.to_string(), ctx));
+
+        Ok(Box::new(ProcessControlPlugin {
+            host: Arc::clone(&self.host),
+            instance,
+            destroy: "destroy_knob",
+        }))
+    }
+}
+
+/// One of a process plugin's screens. Dropping it destroys the screen in the plugin.
+struct ProcessScreenPlugin {
+    host: Arc<ProcessHost>,
+    instance: u64,
+}
+
+impl ScreenPlugin for ProcessScreenPlugin {
+    fn on_touch(&self, position: TouchEvent) -> Result<()> {
+        self.host.notify(
+            "touch",
+            json!({ "instance": self.instance, "event": position }),
+        )
+    }
+}
+
+impl PluginLifecycle for ProcessScreenPlugin {
+    fn on_lifecycle(&self, hook: Lifecycle) -> Result<()> {
+        self.host.lifecycle(self.instance, hook)
+    }
+}
+
+impl Drop for ProcessScreenPlugin {
+    fn drop(&mut self) {
+        self.host.destroy(self.instance, "destroy_screen");
+    }
+}
+
+/// One of a process plugin's buttons or knobs. Dropping it destroys it in the plugin.
+struct ProcessControlPlugin {
+    host: Arc<ProcessHost>,
+    instance: u64,
+    destroy: &'static str,
+}
+
+impl ButtonPlugin for ProcessControlPlugin {
+    fn on_press(&self, event: ButtonPressEvent) -> Result<()> {
+        self.host.notify(
+            "press",
+            json!({ "instance": self.instance, "event": event }),
+        )
+    }
+}
+
+impl KnobPlugin for ProcessControlPlugin {
+    fn on_rotate(&self, event: KnobRotateEvent) -> Result<()> {
+        self.host.notify(
+            "rotate",
+            json!({ "instance": self.instance, "event": event }),
+        )
+    }
+}
+
+impl PluginLifecycle for ProcessControlPlugin {
+    fn on_lifecycle(&self, hook: Lifecycle) -> Result<()> {
+        self.host.lifecycle(self.instance, hook)
+    }
+}
+
+impl Drop for ProcessControlPlugin {
+    fn drop(&mut self) {
+        self.host.destroy(self.instance, self.destroy);
+    }
+}
+
+// The test plugin is a shell script
+#[cfg(all(test, unix))]
+mod tests {
+    use base64::Engine as _;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use std::{env, fs, process};
+    use tokio::runtime::Handle;
+    use tokio::time::sleep;
+
+    use super::{ProcessCommand, ProcessPlugin, BASE64};
+    use crate::{
+        connect_test_page, Button, ButtonPressEvent, KeyLocation, Lifecycle, PluginButtonContext,
+        PluginScreenContext, PressDirection, Region, Rgb, Screen, Spawner, TouchEvent,
+        VirtualDevice,
+    };
+
+    // Fills its key red once it's visible, and exits when touched
+    const PLUGIN: &str = r#"#!/bin/sh
+while read -r line; do
+  case "$line" in
+    *'"method":"initialize"'*)
+      echo '{"jsonrpc":"2.0","id":0,"result":{"plugin_id":"sh-plugin","screens":["fill"]}}' ;;
+    *'"hook":"visible"'*)
+      instance=$(echo "$line" | sed 's/.*"instance":\([0-9]*\).*/\1/')
+      echo "{\"jsonrpc\":\"2.0\",\"method\":\"draw_rgb565\",\"params\":{\"instance\":$instance,\"data\":\"RED\"}}" ;;
+    *'"method":"touch"'*)
+      exit 1 ;;
+  esac
+done
+"#;
+
+    // Lights its button red, and green once pressed
+    const CONTROL_PLUGIN: &str = r#"#!/bin/sh
+while read -r line; do
+  instance=$(echo "$line" | sed 's/.*"instance":\([0-9]*\).*/\1/')
+  case "$line" in
+    *'"method":"initialize"'*)
+      echo '{"jsonrpc":"2.0","id":0,"result":{"plugin_id":"sh-controls","screens":[],"buttons":["light"],"knobs":["dial"]}}' ;;
+    *'"method":"create_button"'*)
+      echo "{\"jsonrpc\":\"2.0\",\"method\":\"set_color\",\"params\":{\"instance\":$instance,\"color\":{\"r\":255,\"g\":0,\"b\":0}}}" ;;
+    *'"method":"press"'*)
+      echo "{\"jsonrpc\":\"2.0\",\"method\":\"set_color\",\"params\":{\"instance\":$instance,\"color\":{\"r\":0,\"g\":255,\"b\":0}}}" ;;
+  esac
+done
+"#;
+
+    // Initializes, then never reads again
+    const STUCK_PLUGIN: &str = r#"#!/bin/sh
+read -r line
+echo '{"jsonrpc":"2.0","id":0,"result":{"plugin_id":"stuck-plugin","screens":["fill"]}}'
+exec sleep 60
+"#;
+
+    async fn wait_for_pixel(snapshot: impl Fn() -> [u8; 4], pixel: [u8; 4]) {
+        let started = Instant::now();
+        while snapshot() != pixel {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn it_restarts_process_plugins() {
+        let (_device, _virtual_device, emitter, view) = connect_test_page().await;
+        view.show().unwrap();
+
+        let red = BASE64.encode([0x00, 0xf8].repeat(90 * 90));
+        let path = env::temp_dir().join(format!("loupedeck-plugin-{}.sh", process::id()));
+        fs::write(&path, PLUGIN.replace("RED", &red)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let plugin = ProcessPlugin::spawn(ProcessCommand::new(&path)).unwrap();
+        assert_eq!(plugin.plugin_id, "sh-plugin");
+        assert_eq!(plugin.screens, vec!["fill".to_string()]);
+
+        let ctx = PluginScreenContext::new(
+            view.clone(),
+            Region::Key(KeyLocation::new(1, 0)),
+            Spawner::new(Handle::current()),
+        )
+        .unwrap();
+        assert!(plugin.create_screen("missing", ctx.clone()).is_err());
+        let screen = plugin.create_screen("fill", ctx.clone()).unwrap();
+        screen.on_lifecycle(Lifecycle::Mount).unwrap();
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(emitter.snapshot().pixel(190, 45), [0, 0, 0, 0xff]);
+
+        screen.on_lifecycle(Lifecycle::Visible).unwrap();
+        wait_for_pixel(|| emitter.snapshot().pixel(190, 45), [0xff, 0, 0, 0xff]).await;
+
+        // Once it crashes, it's restarted, made visible again and draws its screen again
+        ctx.draw_rgb565([0x1f, 0x00].repeat(90 * 90)).await.unwrap();
+        assert_eq!(emitter.snapshot().pixel(190, 45), [0, 0, 0xff, 0xff]);
+        screen
+            .on_touch(TouchEvent {
+                tx_id: 0,
+                dir: PressDirection::Down,
+                touch_id: 1,
+                x: 100,
+                y: 10,
+                screen: Screen::Center,
+            })
+            .unwrap();
+        wait_for_pixel(|| emitter.snapshot().pixel(190, 45), [0xff, 0, 0, 0xff]).await;
+
+        drop(screen);
+        drop(ctx);
+        assert_eq!(Arc::strong_count(&view), 1);
+
+        drop(plugin);
+        fs::remove_file(&path).unwrap();
+    }
+
+    async fn wait_for_color(virtual_device: &VirtualDevice, button: Button, color: Rgb) {
+        let started = Instant::now();
+        while virtual_device.button_colors().get(&(button as u8)) != Some(&color) {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn it_runs_process_plugin_buttons() {
+        let (_device, virtual_device, _emitter, view) = connect_test_page().await;
+        view.show().unwrap();
+
+        let path = env::temp_dir().join(format!("loupedeck-controls-{}.sh", process::id()));
+        fs::write(&path, CONTROL_PLUGIN).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let plugin = ProcessPlugin::spawn(ProcessCommand::new(&path)).unwrap();
+        assert_eq!(plugin.buttons, vec!["light".to_string()]);
+        assert_eq!(plugin.knobs, vec!["dial".to_string()]);
+
+        let ctx = PluginButtonContext::new(view, Button::Circle1, Spawner::new(Handle::current()))
+            .unwrap();
+        assert!(plugin.create_button("missing", ctx.clone()).is_err());
+        let button = plugin.create_button("light", ctx).unwrap();
+        wait_for_color(&virtual_device, Button::Circle1, Rgb { r: 255, g: 0, b: 0 }).await;
+
+        button
+            .on_press(ButtonPressEvent {
+                tx_id: 0,
+                button: Button::Circle1,
+                dir: PressDirection::Down,
+            })
+            .unwrap();
+        wait_for_color(&virtual_device, Button::Circle1, Rgb { r: 0, g: 255, b: 0 }).await;
+
+        drop(button);
+        drop(plugin);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_drops_messages_for_plugins_that_stop_reading() {
+        let (_device, _virtual_device, _emitter, view) = connect_test_page().await;
+
+        let path = env::temp_dir().join(format!("loupedeck-stuck-plugin-{}.sh", process::id()));
+        fs::write(&path, STUCK_PLUGIN).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let plugin = ProcessPlugin::spawn(ProcessCommand::new(&path)).unwrap();
+        let ctx = PluginScreenContext::new(
+            view,
+            Region::Key(KeyLocation::new(1, 0)),
+            Spawner::new(Handle::current()),
+        )
+        .unwrap();
+        let screen = plugin.create_screen("fill", ctx).unwrap();
+
+        // Far more than fits in the pipe, which would block if written directly
+        let started = Instant::now();
+        for _ in 0..10_000 {
+            screen
+                .on_touch(TouchEvent {
+                    tx_id: 0,
+                    dir: PressDirection::Down,
+                    touch_id: 1,
+                    x: 100,
+                    y: 10,
+                    screen: Screen::Center,
+                })
+                .unwrap();
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        drop(screen);
+        drop(plugin);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_reads_plugin_files() {
+        let dir = env::temp_dir().join(format!("loupedeck-plugin-files-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("obs.plugin");
+
+        fs::write(
+            &file,
+            r#"{"command": "bin/obs", "args": ["--port", "4455"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            ProcessCommand::load(&file).unwrap(),
+            ProcessCommand {
+                command: dir.join("bin/obs"),
+                args: vec!["--port".to_string(), "4455".to_string()],
+            }
+        );
+
+        // Bare names are looked up on the PATH
+        fs::write(&file, r#"{"command": "python3"}"#).unwrap();
+        assert_eq!(
+            ProcessCommand::load(&file).unwrap(),
+            ProcessCommand::new("python3")
+        );
+
+        fs::write(&file, "#!/bin/sh").unwrap();
+        assert!(ProcessCommand::load(&file).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,618 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use wasmi::{
+    Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    TypedFunc,
+};
+
+use crate::{
+    FfiStatus, Haptic, Lifecycle, LoupedeckError, PluginLifecycle, PluginScreenContext, Result,
+    ScreenPlugin, TouchEvent,
+};
+
+/// Version of the imports and exports below, returned by a module's `loupedeck_abi_version`.
+pub const WASM_PLUGIN_ABI_VERSION: i32 = 1;
+
+/// Instructions a plugin gets for each call into it, so one stuck in a loop can't hang the
+/// host.
+const FUEL_PER_CALL: u64 = 20_000_000;
+
+const MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// A plugin compiled to WebAssembly, which runs sandboxed in the host.
+///
+/// Strings and pixels are passed as a pointer and length into the module's memory, and
+/// anything structured is JSON, like the native ABI. Calls that can fail return the native
+/// ABI's `FfiStatus` codes.
+///
+/// A module exports:
+/// - `memory`
+/// - `loupedeck_abi_version() -> i32`
+/// - `loupedeck_alloc(len) -> ptr`, for the host to pass data in, which the plugin then owns
+/// - `loupedeck_register()`, which calls `register_plugin` and `register_screen`
+/// - `loupedeck_create_screen(instance, name_ptr, name_len, region_ptr, region_len, width,
+///   height) -> status`
+/// - `loupedeck_on_touch(instance, event_ptr, event_len) -> status`
+/// - `loupedeck_on_timer(instance, timer)`
+/// - `loupedeck_destroy_screen(instance)`
+/// - optionally `loupedeck_on_lifecycle(instance, hook) -> status`, with `hook` a `Lifecycle`
+///   discriminant. Pending timeouts are cleared before the hidden and unmount hooks
+///
+/// And can import from `loupedeck`:
+/// - `register_plugin(id_ptr, id_len)` and `register_screen(name_ptr, name_len)`
+/// - `draw_rgb565(instance, ptr, len) -> status`
+/// - `is_visible(instance) -> i32`
+/// - `vibrate(instance, haptic_ptr, haptic_len) -> status`
+/// - `set_timeout(instance, delay_ms) -> timer`, or -1 for an unknown instance
+/// - `clear_timeout(timer)`
+/// - `log(ptr, len)`
+#[derive(Clone)]
+pub struct WasmPlugin {
+    pub plugin_id: String,
+    pub screens: Vec<String>,
+    module: Arc<WasmModule>,
+}
+
+impl fmt::Debug for WasmPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WasmPlugin")
+            .field("plugin_id", &self.plugin_id)
+            .field("screens", &self.screens)
+            .finish_non_exhaustive()
+    }
+}
+
+struct HostState {
+    plugin_id: Option<String>,
+    screens: Vec<String>,
+    contexts: HashMap<i32, PluginScreenContext>,
+    /// Pending timers, and the screen instance they're for.
+    timers: HashMap<i32, (i32, JoinHandle<()>)>,
+    next_timer: i32,
+    module: Weak<WasmModule>,
+    limits: StoreLimits,
+}
+
+impl HostState {
+    fn clear_timers(&mut self, instance: i32) {
+        self.timers.retain(|_, (timer_instance, task)| {
+            if *timer_instance == instance {
+                task.abort();
+            }
+            *timer_instance != instance
+        });
+    }
+}
+
+impl Drop for HostState {
+    fn drop(&mut self) {
+        for (_, task) in self.timers.values() {
+            task.abort();
+        }
+    }
+}
+
+/// `(instance, name_ptr, name_len, region_ptr, region_len, width, height)`
+type CreateScreenParams = (i32, i32, i32, i32, i32, i32, i32);
+
+struct Exports {
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    create_screen: TypedFunc<CreateScreenParams, i32>,
+    on_touch: TypedFunc<(i32, i32, i32), i32>,
+    on_timer: TypedFunc<(i32, i32), ()>,
+    destroy_screen: TypedFunc<i32, ()>,
+    on_lifecycle: Option<TypedFunc<(i32, i32), i32>>,
+}
+
+/// An instance of a plugin's module, shared by all of its screens.
+struct WasmModule {
+    store: Mutex<Store<HostState>>,
+    exports: Exports,
+    next_instance: AtomicI32,
+}
+
+type WasmResult<T> = std::result::Result<T, wasmi::Error>;
+
+impl WasmModule {
+    fn call<R>(
+        &self,
+        call: impl FnOnce(&mut Store<HostState>, &Exports) -> WasmResult<R>,
+    ) -> Result<R> {
+        let mut store = self.store.lock().unwrap();
+        store.set_fuel(FUEL_PER_CALL).map_err(plugin_error)?;
+
+        call(&mut store, &self.exports).map_err(plugin_error)
+    }
+
+    fn fire_timer(&self, instance: i32, timer: i32) -> Result<()> {
+        self.call(|store, exports| {
+            // The timer may have been cleared while this was waiting for the lock
+            if store.data_mut().timers.remove(&timer).is_none() {
+                return Ok(());
+            }
+
+            exports.on_timer.call(store, (instance, timer))
+        })
+    }
+}
+
+/// Copies bytes into the module's memory, returning their pointer and length.
+fn write_bytes(
+    store: &mut Store<HostState>,
+    exports: &Exports,
+    bytes: &[u8],
+) -> WasmResult<(i32, i32)> {
+    let ptr = exports.alloc.call(&mut *store, bytes.len() as i32)?;
+    exports
+        .memory
+        .write(&mut *store, ptr as u32 as usize, bytes)?;
+
+    Ok((ptr, bytes.len() as i32))
+}
+
+fn read_bytes(caller: &Caller<HostState>, ptr: i32, len: i32) -> WasmResult<Vec<u8>> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("the plugin doesn't export its memory"))?;
+
+    let start = ptr as u32 as usize;
+    memory
+        .data(caller)
+        .get(start..start.saturating_add(len as u32 as usize))
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| wasmi::Error::new("pointer out of bounds"))
+}
+
+fn read_string(caller: &Caller<HostState>, ptr: i32, len: i32) -> WasmResult<String> {
+    String::from_utf8(read_bytes(caller, ptr, len)?).map_err(|e| wasmi::Error::new(e.to_string()))
+}
+
+fn plugin_error(e: impl fmt::Display) -> LoupedeckError {
+    LoupedeckError::Plugin(e.to_string())
+}
+
+fn status(result: Result<()>) -> i32 {
+    FfiStatus::from_result(result).0 as i32
+}
+
+fn context(caller: &Caller<HostState>, instance: i32) -> Result<PluginScreenContext> {
+    caller
+        .data()
+        .contexts
+        .get(&instance)
+        .cloned()
+        .ok_or_else(|| LoupedeckError::InvalidInput(format!("No screen instance {}", instance)))
+}
+
+fn host_functions(engine: &Engine) -> WasmResult<Linker<HostState>> {
+    let mut linker = Linker::new(engine);
+
+    linker.func_wrap(
+        "loupedeck",
+        "register_plugin",
+        |mut caller: Caller<HostState>, ptr: i32, len: i32| -> WasmResult<()> {
+            let plugin_id = read_string(&caller, ptr, len)?;
+            caller.data_mut().plugin_id = Some(plugin_id);
+            Ok(())
+        },
+    )?;
+
+    linker.func_wrap(
+        "loupedeck",
+        "register_screen",
+        |mut caller: Caller<HostState>, ptr: i32, len: i32| -> WasmResult<()> {
+            let name = read_string(&caller, ptr, len)?;
+            caller.data_mut().screens.push(name);
+            Ok(())
+        },
+    )?;
+
+    linker.func_wrap(
+        "loupedeck",
+        "draw_rgb565",
+        |caller: Caller<HostState>, instance: i32, ptr: i32, len: i32| -> WasmResult<i32> {
+            let data = read_bytes(&caller, ptr, len)?;
+            Ok(status(
+                context(&caller, instance).and_then(|ctx| ctx.draw_rgb565_now(data)),
+            ))
+        },
+    )?;
+
+    linker.func_wrap(
+        "loupedeck",
+        "is_visible",
+        |caller: Caller<HostState>, instance: i32| -> i32 {
+            context(&caller, instance).is_ok_and(|ctx| ctx.is_visible()) as i32
+        },
+    )?;
+
+    linker.func_wrap(
+        "loupedeck",
+        "vibrate",
+        |caller: Caller<HostState>, instance: i32, ptr: i32, len: i32| -> WasmResult<i32> {
+            let level = read_string(&caller, ptr, len)?;
+            let vibration = context(&caller, instance).and_then(|ctx| {
+                let level = serde_json::from_str::<Haptic>(&level)
+                    .map_err(|e| LoupedeckError::InvalidInput(e.to_string()))?;
+                Ok((ctx, level))
+            });
+
+            Ok(status(vibration.map(|(ctx, level)| {
+                ctx.spawner().clone().spawn(async move {
+                    if let Err(e) = ctx.vibrate(level).await {
+                        println!("Error vibrating for a WASM plugin: {}", e);
+                    }
+                });
+            })))
+        },
+    )?;
+
+    linker.func_wrap(
+        "loupedeck",
+        "set_timeout",
+        |mut caller: Caller<HostState>, instance: i32, delay_ms: i32| -> i32 {
+            let ctx = match context(&caller, instance) {
+                Ok(ctx) => ctx,
+                Err(_) => return -1,
+            };
+            let state = caller.data_mut();
+            let timer = state.next_timer;
+            state.next_timer = state.next_timer.wrapping_add(1) & i32::MAX;
+            let module = state.module.clone();
+
+            let task = ctx.spawner().spawn(async move {
+                sleep(Duration::from_millis(delay_ms.max(0) as u64)).await;
+                if let Some(module) = module.upgrade() {
+                    if let Err(e) = module.fire_timer(instance, timer) {
+                        println!("Error running a WASM plugin's timer: {}", e);
+                    }
+                }
+            });
+
+            state.timers.insert(timer, (instance, task));
+            timer
+        },
+    )?;
+
+    linker.func_wrap(
+        "loupedeck",
+        "clear_timeout",
+        |mut caller: Caller<HostState>, timer: i32| {
+            if let Some((_, task)) = caller.data_mut().timers.remove(&timer) {
+                task.abort();
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        "loupedeck",
+        "log",
+        |caller: Caller<HostState>, ptr: i32, len: i32| -> WasmResult<()> {
+            let message = read_string(&caller, ptr, len)?;
+            let plugin_id = caller.data().plugin_id.as_deref().unwrap_or("wasm");
+            println!("[{}] {}", plugin_id, message);
+            Ok(())
+        },
+    )?;
+
+    Ok(linker)
+}
+
+impl WasmPlugin {
+    /// Instantiates a module and runs its registration.
+    pub fn load(wasm: &[u8]) -> Result<Self> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(plugin_error)?;
+
+        let mut store = Store::new(
+            &engine,
+            HostState {
+                plugin_id: None,
+                screens: Vec::new(),
+                contexts: HashMap::new(),
+                timers: HashMap::new(),
+                next_timer: 0,
+                module: Weak::new(),
+                limits: StoreLimitsBuilder::new().memory_size(MEMORY_LIMIT).build(),
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(FUEL_PER_CALL).map_err(plugin_error)?;
+
+        let instance = host_functions(&engine)
+            .and_then(|linker| linker.instantiate(&mut store, &module))
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(plugin_error)?;
+
+        let abi_version = instance
+            .get_typed_func::<(), i32>(&store, "loupedeck_abi_version")
+            .and_then(|version| version.call(&mut store, ()))
+            .map_err(plugin_error)?;
+        if abi_version != WASM_PLUGIN_ABI_VERSION {
+            return Err(LoupedeckError::PluginLoad(format!(
+                "built for WASM plugin ABI version {} but expected {}",
+                abi_version, WASM_PLUGIN_ABI_VERSION
+            )));
+        }
+
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| LoupedeckError::PluginLoad("no memory export".to_string()))?;
+        let exports = (|| {
+            Ok::<_, wasmi::Error>(Exports {
+                memory,
+                alloc: instance.get_typed_func(&store, "loupedeck_alloc")?,
+                create_screen: instance.get_typed_func(&store, "loupedeck_create_screen")?,
+                on_touch: instance.get_typed_func(&store, "loupedeck_on_touch")?,
+                on_timer: instance.get_typed_func(&store, "loupedeck_on_timer")?,
+                destroy_screen: instance.get_typed_func(&store, "loupedeck_destroy_screen")?,
+                on_lifecycle: instance
+                    .get_typed_func(&store, "loupedeck_on_lifecycle")
+                    .ok(),
+            })
+        })()
+        .map_err(plugin_error)?;
+
+        instance
+            .get_typed_func::<(), ()>(&store, "loupedeck_register")
+            .and_then(|register| register.call(&mut store, ()))
+            .map_err(plugin_error)?;
+
+        let plugin_id = store.data().plugin_id.clone().ok_or_else(|| {
+            LoupedeckError::PluginLoad("never called register_plugin".to_string())
+        })?;
+        let screens = store.data().screens.clone();
+
+        let module = Arc::new(WasmModule {
+            store: Mutex::new(store),
+            exports,
+            next_instance: AtomicI32::new(0),
+        });
+        module.store.lock().unwrap().data_mut().module = Arc::downgrade(&module);
+
+        Ok(Self {
+            plugin_id,
+            screens,
+            module,
+        })
+    }
+
+    /// Starts one of the plugin's screens on the given context.
+    pub fn create_screen(
+        &self,
+        name: &str,
+        ctx: PluginScreenContext,
+    ) -> Result<Box<dyn ScreenPlugin>> {
+        if !self.screens.iter().any(|screen| screen == name) {
+            return Err(LoupedeckError::InvalidInput(format!(
+                "{} has no {:?} screen",
+                self.plugin_id, name
+            )));
+        }
+
+        let instance = self.module.next_instance.fetch_add(1, Ordering::Relaxed);
+        let region = serde_json::to_string(ctx.region())
+            .map_err(|e| LoupedeckError::InvalidInput(e.to_string()))?;
+        let (width, height) = ctx.size();
+
+        let created = self.module.call(|store, exports| {
+            // Registered first, so the plugin can draw while it's being created
+            store.data_mut().contexts.insert(instance, ctx);
+            let (name_ptr, name_len) = write_bytes(store, exports, name.as_bytes())?;
+            let (region_ptr, region_len) = write_bytes(store, exports, region.as_bytes())?;
+
+            exports.create_screen.call(
+                store,
+                (
+                    instance,
+                    name_ptr,
+                    name_len,
+                    region_ptr,
+                    region_len,
+                    width as i32,
+                    height as i32,
+                ),
+            )
+        });
+
+        // Dropping this cleans up after a screen that failed to start, too
+        let screen = WasmScreenPlugin {
+            module: Arc::clone(&self.module),
+            instance,
+        };
+        FfiStatus(created? as u32).into_result()?;
+
+        Ok(Box::new(screen))
+    }
+}
+
+/// One of a WASM plugin's screens. Dropping it destroys the screen in the module.
+struct WasmScreenPlugin {
+    module: Arc<WasmModule>,
+    instance: i32,
+}
+
+impl ScreenPlugin for WasmScreenPlugin {
+    fn on_touch(&self, position: TouchEvent) -> Result<()> {
+        let event = serde_json::to_string(&position)
+            .map_err(|e| LoupedeckError::InvalidInput(e.to_string()))?;
+
+        let status = self.module.call(|store, exports| {
+            let (event_ptr, event_len) = write_bytes(store, exports, event.as_bytes())?;
+            exports
+                .on_touch
+                .call(store, (self.instance, event_ptr, event_len))
+        })?;
+
+        FfiStatus(status as u32).into_result()
+    }
+}
+
+impl PluginLifecycle for WasmScreenPlugin {
+    fn on_lifecycle(&self, hook: Lifecycle) -> Result<()> {
+        let status = self.module.call(|store, exports| {
+            if matches!(hook, Lifecycle::Hidden | Lifecycle::Unmount) {
+                store.data_mut().clear_timers(self.instance);
+            }
+
+            match &exports.on_lifecycle {
+                Some(on_lifecycle) => on_lifecycle.call(store, (self.instance, hook as i32)),
+                None => Ok(0),
+            }
+        })?;
+
+        FfiStatus(status as u32).into_result()
+    }
+}
+
+impl Drop for WasmScreenPlugin {
+    fn drop(&mut self) {
+        let destroyed = self.module.call(|store, exports| {
+            let destroyed = exports.destroy_screen.call(&mut *store, self.instance);
+
+            let state = store.data_mut();
+            state.contexts.remove(&self.instance);
+            state.clear_timers(self.instance);
+            destroyed
+        });
+
+        if let Err(e) = destroyed {
+            println!("Error destroying a WASM plugin's screen: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::runtime::Handle;
+    use tokio::time::sleep;
+
+    use super::WasmPlugin;
+    use crate::{
+        connect_test_page, KeyLocation, LoupedeckError, PluginScreenContext, PressDirection,
+        Region, Screen, Spawner, TouchEvent,
+    };
+
+    // Fills its key red, then blue on a timer, and hangs when touched
+    const PLUGIN: &str = r#"
+        (module
+          (import "loupedeck" "register_plugin" (func $register_plugin (param i32 i32)))
+          (import "loupedeck" "register_screen" (func $register_screen (param i32 i32)))
+          (import "loupedeck" "draw_rgb565" (func $draw (param i32 i32 i32) (result i32)))
+          (import "loupedeck" "set_timeout" (func $set_timeout (param i32 i32) (result i32)))
+          (memory (export "memory") 2)
+          (data (i32.const 0) "wasm-plugin")
+          (data (i32.const 16) "fill")
+          (global $heap (mut i32) (i32.const 1024))
+          (global $pixels (mut i32) (i32.const 0))
+
+          (func (export "loupedeck_abi_version") (result i32) (i32.const 1))
+
+          (func (export "loupedeck_alloc") (param $len i32) (result i32)
+            (global.get $heap)
+            (global.set $heap (i32.add (global.get $heap) (local.get $len))))
+
+          (func (export "loupedeck_register")
+            (call $register_plugin (i32.const 0) (i32.const 11))
+            (call $register_screen (i32.const 16) (i32.const 4)))
+
+          (func $fill (param $instance i32) (param $color i32) (result i32)
+            (local $i i32)
+            (block $done
+              (loop $pixel
+                (br_if $done (i32.ge_u (local.get $i) (global.get $pixels)))
+                (i32.store16
+                  (i32.add (i32.const 32768) (i32.shl (local.get $i) (i32.const 1)))
+                  (local.get $color))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $pixel)))
+            (call $draw
+              (local.get $instance)
+              (i32.const 32768)
+              (i32.shl (global.get $pixels) (i32.const 1))))
+
+          (func (export "loupedeck_create_screen")
+            (param $instance i32) (param i32 i32 i32 i32) (param $width i32) (param $height i32)
+            (result i32)
+            (global.set $pixels (i32.mul (local.get $width) (local.get $height)))
+            (drop (call $set_timeout (local.get $instance) (i32.const 10)))
+            (call $fill (local.get $instance) (i32.const 0xf800)))
+
+          (func (export "loupedeck_on_timer") (param $instance i32) (param i32)
+            (drop (call $fill (local.get $instance) (i32.const 0x001f))))
+
+          (func (export "loupedeck_on_touch") (param i32 i32 i32) (result i32)
+            (loop $forever (br $forever))
+            (i32.const 0))
+
+          (func (export "loupedeck_destroy_screen") (param i32)))
+    "#;
+
+    #[tokio::test]
+    async fn it_runs_wasm_plugins() {
+        let (_device, _virtual_device, emitter, view) = connect_test_page().await;
+        view.show().unwrap();
+
+        let plugin = WasmPlugin::load(&wat::parse_str(PLUGIN).unwrap()).unwrap();
+        assert_eq!(plugin.plugin_id, "wasm-plugin");
+        assert_eq!(plugin.screens, vec!["fill".to_string()]);
+
+        let ctx = PluginScreenContext::new(
+            view.clone(),
+            Region::Key(KeyLocation::new(1, 0)),
+            Spawner::new(Handle::current()),
+        )
+        .unwrap();
+        assert!(plugin.create_screen("missing", ctx.clone()).is_err());
+        let screen = plugin.create_screen("fill", ctx).unwrap();
+        assert_eq!(emitter.snapshot().pixel(190, 45), [0xff, 0, 0, 0xff]);
+
+        let started = Instant::now();
+        while emitter.snapshot().pixel(190, 45) != [0, 0, 0xff, 0xff] {
+            assert!(started.elapsed() < Duration::from_secs(1));
+            sleep(Duration::from_millis(1)).await;
+        }
+
+        // A plugin stuck in a loop runs out of fuel instead of hanging the host
+        let touch = TouchEvent {
+            tx_id: 0,
+            dir: PressDirection::Down,
+            touch_id: 1,
+            x: 100,
+            y: 10,
+            screen: Screen::Center,
+        };
+        assert!(matches!(
+            screen.on_touch(touch),
+            Err(LoupedeckError::Plugin(_))
+        ));
+
+        drop(screen);
+        assert_eq!(Arc::strong_count(&view), 1);
+    }
+
+    #[test]
+    fn it_rejects_other_abi_versions() {
+        let wasm = wat::parse_str(
+            r#"(module (func (export "loupedeck_abi_version") (result i32) (i32.const 2)))"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            WasmPlugin::load(&wasm),
+            Err(LoupedeckError::PluginLoad(_))
+        ));
+        assert!(WasmPlugin::load(b"not wasm").is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use thiserror::Error;
+
+use crate::Event;
+
+/// Everything that can go wrong while talking to a Loupedeck or running its plugins.
+#[derive(Debug, Error)]
+pub enum LoupedeckError {
+    #[error("transport error: {0}")]
+    Transport(#[from] std::io::Error),
+
+    #[error("websocket handshake failed: {0}")]
+    Handshake(String),
+
+    #[error("malformed message with header {header:#06x}: {bytes:02x?}")]
+    Protocol { header: u16, bytes: Vec<u8> },
+
+    #[error("unexpected response from device: {0:?}")]
+    UnexpectedResponse(Box<Event>),
+
+    #[error("timed out waiting for the device to respond")]
+    Timeout,
+
+    #[error("every transaction id is waiting on a response")]
+    NoFreeTransactionId,
+
+    #[error("device is disconnected")]
+    Disconnected,
+
+    #[error("no tokio runtime to run on")]
+    NoRuntime,
+
+    #[error("no Loupedeck found at {0}")]
+    UnknownDevice(String),
+
+    #[error("failed to load plugin: {0}")]
+    PluginLoad(String),
+
+    #[error("plugin call failed: {0}")]
+    Plugin(String),
+
+    #[error("the plugin's page is no longer active")]
+    Inactive,
+
+    #[error("image error: {0}")]
+    Image(String),
+
+    #[error("font error: {0}")]
+    Font(String),
+
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+}
+
+impl LoupedeckError {
+    pub(crate) fn protocol(bytes: &[u8]) -> Self {
+        let header = match bytes {
+            [high, low, ..] => u16::from_be_bytes([*high, *low]),
+            [high] => (*high as u16) << 8,
+            [] => 0,
+        };
+
+        LoupedeckError::Protocol {
+            header,
+            bytes: bytes.to_vec(),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, LoupedeckError>;
@@ -1,8 +1,9 @@
-pub static CORE_VERSION: &str = env!("CARGO_PKG_VERSION");
-pub static RUSTC_VERSION: &str = env!("RUSTC_VERSION");
+pub const CORE_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 mod controller;
+mod error;
 mod loupedeck;
 
 pub use controller::*;
+pub use error::*;
 pub use loupedeck::*;
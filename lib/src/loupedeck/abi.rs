@@ -0,0 +1,1156 @@
+use std::ffi::c_void;
+use std::fmt;
+use std::mem::ManuallyDrop;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::OnceLock;
+use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{
+    Button, ButtonPlugin, ButtonPluginFactory, ButtonPressEvent, ControlContext, Haptic, Knob,
+    KnobPlugin, KnobPluginFactory, KnobRotateEvent, Lifecycle, LoupedeckError, PluginButtonContext,
+    PluginKnobContext, PluginLifecycle, PluginRegistrar, PluginScreenContext, Result, Rgb,
+    ScreenPlugin, ScreenPluginFactory, ScreenPluginOptions, Spawner, TaskScope, TouchEvent,
+};
+
+/// Version of the C ABI between the host and plugin libraries.
+///
+/// Bumped whenever a type in this module changes shape. The host refuses plugins built
+/// against any other version, so it's the only thing a plugin binary has to match.
+pub const PLUGIN_ABI_VERSION: u32 = 3;
+
+/// A UTF-8 string borrowed across the plugin boundary.
+///
+/// Anything structured, like regions and touch events, crosses as JSON so either side can
+/// grow new fields without breaking the other.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FfiStr {
+    ptr: *const u8,
+    len: usize,
+}
+
+unsafe impl Send for FfiStr {}
+unsafe impl Sync for FfiStr {}
+
+impl FfiStr {
+    pub const fn from_static(value: &'static str) -> Self {
+        Self {
+            ptr: value.as_ptr(),
+            len: value.len(),
+        }
+    }
+
+    /// Borrows a string for the length of a call.
+    fn borrow(value: &str) -> Self {
+        Self {
+            ptr: value.as_ptr(),
+            len: value.len(),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The string has to still be alive.
+    pub unsafe fn as_str<'a>(&self) -> Result<&'a str> {
+        let bytes = std::slice::from_raw_parts(self.ptr, self.len);
+        std::str::from_utf8(bytes).map_err(|e| LoupedeckError::InvalidInput(e.to_string()))
+    }
+}
+
+fn to_json(value: &impl Serialize) -> Result<String> {
+    serde_json::to_string(value).map_err(|e| LoupedeckError::InvalidInput(e.to_string()))
+}
+
+/// # Safety
+///
+/// The string has to still be alive.
+unsafe fn from_json<T: DeserializeOwned>(value: FfiStr) -> Result<T> {
+    serde_json::from_str(value.as_str()?).map_err(|e| LoupedeckError::InvalidInput(e.to_string()))
+}
+
+/// How a call across the plugin boundary went.
+///
+/// Errors are logged on the side they happen, and only their kind crosses over.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FfiStatus(pub u32);
+
+impl FfiStatus {
+    pub const OK: FfiStatus = FfiStatus(0);
+    pub const INVALID_INPUT: FfiStatus = FfiStatus(1);
+    pub const DISCONNECTED: FfiStatus = FfiStatus(2);
+    pub const PANICKED: FfiStatus = FfiStatus(3);
+    pub const FAILED: FfiStatus = FfiStatus(4);
+    pub const INACTIVE: FfiStatus = FfiStatus(5);
+
+    pub(crate) fn from_result(result: Result<()>) -> Self {
+        match result {
+            Ok(()) => FfiStatus::OK,
+            Err(e) => {
+                println!("Error across the plugin boundary: {}", e);
+                match e {
+                    LoupedeckError::InvalidInput(_) => FfiStatus::INVALID_INPUT,
+                    LoupedeckError::Disconnected => FfiStatus::DISCONNECTED,
+                    LoupedeckError::Inactive => FfiStatus::INACTIVE,
+                    _ => FfiStatus::FAILED,
+                }
+            }
+        }
+    }
+
+    pub fn into_result(self) -> Result<()> {
+        match self {
+            FfiStatus::OK => Ok(()),
+            FfiStatus::INVALID_INPUT => Err(LoupedeckError::InvalidInput(
+                "rejected across the plugin boundary".to_string(),
+            )),
+            FfiStatus::DISCONNECTED => Err(LoupedeckError::Disconnected),
+            FfiStatus::PANICKED => Err(LoupedeckError::Plugin("panicked".to_string())),
+            FfiStatus::INACTIVE => Err(LoupedeckError::Inactive),
+            FfiStatus(code) => Err(LoupedeckError::Plugin(format!("failed with {}", code))),
+        }
+    }
+}
+
+/// Runs one side of a call, making sure no panic unwinds into the other.
+fn guard(call: impl FnOnce() -> Result<()>) -> FfiStatus {
+    match catch_unwind(AssertUnwindSafe(call)) {
+        Ok(result) => FfiStatus::from_result(result),
+        Err(_) => FfiStatus::PANICKED,
+    }
+}
+
+/// Called once when an asynchronous call across the plugin boundary finishes, or with
+/// `DISCONNECTED` if the call is dropped, e.g. because its page was hidden.
+#[repr(C)]
+pub struct FfiCallback {
+    data: *mut c_void,
+    call: unsafe extern "C" fn(data: *mut c_void, status: FfiStatus),
+}
+
+unsafe impl Send for FfiCallback {}
+
+impl FfiCallback {
+    /// A callback that completes the returned receiver.
+    fn channel() -> (Self, oneshot::Receiver<FfiStatus>) {
+        unsafe extern "C" fn send(data: *mut c_void, status: FfiStatus) {
+            let sender = Box::from_raw(data as *mut oneshot::Sender<FfiStatus>);
+            let _ = sender.send(status);
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let callback = Self {
+            data: Box::into_raw(Box::new(sender)) as *mut c_void,
+            call: send,
+        };
+        (callback, receiver)
+    }
+
+    fn complete(self, status: FfiStatus) {
+        let callback = ManuallyDrop::new(self);
+        unsafe { (callback.call)(callback.data, status) }
+    }
+}
+
+impl Drop for FfiCallback {
+    fn drop(&mut self) {
+        unsafe { (self.call)(self.data, FfiStatus::DISCONNECTED) }
+    }
+}
+
+/// The host's side of a `PluginScreenContext`, as handed to a plugin library.
+///
+/// The plugin owns it, and calls `release` once it's done with it.
+#[repr(C)]
+pub struct FfiScreenContext {
+    context: *const c_void,
+    /// The plugin's `Region`, as JSON.
+    region: FfiStr,
+    width: u16,
+    height: u16,
+    draw_rgb565:
+        unsafe extern "C" fn(context: *const c_void, data: *const u8, len: usize) -> FfiStatus,
+    is_visible: unsafe extern "C" fn(context: *const c_void) -> bool,
+    wait_until_visible: unsafe extern "C" fn(context: *const c_void, done: FfiCallback),
+    /// Takes the `Haptic` as JSON.
+    vibrate: unsafe extern "C" fn(context: *const c_void, level: FfiStr, done: FfiCallback),
+    release: unsafe extern "C" fn(context: *const c_void),
+}
+
+struct HostContext {
+    ctx: PluginScreenContext,
+    region: String,
+}
+
+impl FfiScreenContext {
+    /// Hands a context the host made over to a plugin library.
+    pub(crate) fn host(ctx: PluginScreenContext) -> Result<Self> {
+        let region = to_json(ctx.region())?;
+        let (width, height) = ctx.size();
+        let context = Box::new(HostContext { ctx, region });
+
+        Ok(Self {
+            region: FfiStr::borrow(&context.region),
+            context: Box::into_raw(context) as *const c_void,
+            width,
+            height,
+            draw_rgb565: host_draw_rgb565,
+            is_visible: host_is_visible,
+            wait_until_visible: host_wait_until_visible,
+            vibrate: host_vibrate,
+            release: host_release,
+        })
+    }
+}
+
+unsafe fn host_context<'a>(context: *const c_void) -> &'a PluginScreenContext {
+    &(*(context as *const HostContext)).ctx
+}
+
+unsafe extern "C" fn host_draw_rgb565(
+    context: *const c_void,
+    data: *const u8,
+    len: usize,
+) -> FfiStatus {
+    guard(|| {
+        let data = std::slice::from_raw_parts(data, len).to_vec();
+        host_context(context).draw_rgb565_now(data)
+    })
+}
+
+unsafe extern "C" fn host_is_visible(context: *const c_void) -> bool {
+    catch_unwind(AssertUnwindSafe(|| host_context(context).is_visible())).unwrap_or(false)
+}
+
+unsafe extern "C" fn host_wait_until_visible(context: *const c_void, done: FfiCallback) {
+    let ctx = host_context(context).clone();
+
+    ctx.spawner().clone().spawn(async move {
+        ctx.wait_until_visible().await;
+        done.complete(FfiStatus::OK);
+    });
+}
+
+unsafe extern "C" fn host_vibrate(context: *const c_void, level: FfiStr, done: FfiCallback) {
+    let level = match from_json::<Haptic>(level) {
+        Ok(level) => level,
+        Err(e) => return done.complete(FfiStatus::from_result(Err(e))),
+    };
+    let ctx = host_context(context).clone();
+
+    ctx.spawner().clone().spawn(async move {
+        done.complete(FfiStatus::from_result(ctx.vibrate(level).await));
+    });
+}
+
+unsafe extern "C" fn host_release(context: *const c_void) {
+    drop(Box::from_raw(context as *mut HostContext));
+}
+
+/// A plugin's handle on the host's `FfiScreenContext`, released when the last clone of the
+/// plugin's `PluginScreenContext` is dropped.
+pub(crate) struct RemoteHost(FfiScreenContext);
+
+unsafe impl Send for RemoteHost {}
+unsafe impl Sync for RemoteHost {}
+
+impl fmt::Debug for RemoteHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RemoteHost").field(&self.0.context).finish()
+    }
+}
+
+impl RemoteHost {
+    pub(crate) fn size(&self) -> (u16, u16) {
+        (self.0.width, self.0.height)
+    }
+
+    pub(crate) fn region(&self) -> Result<crate::Region> {
+        unsafe { from_json(self.0.region) }
+    }
+
+    pub(crate) fn draw_rgb565(&self, data: &[u8]) -> Result<()> {
+        unsafe { (self.0.draw_rgb565)(self.0.context, data.as_ptr(), data.len()) }.into_result()
+    }
+
+    pub(crate) fn is_visible(&self) -> bool {
+        unsafe { (self.0.is_visible)(self.0.context) }
+    }
+
+    pub(crate) async fn wait_until_visible(&self) {
+        let (done, finished) = FfiCallback::channel();
+        unsafe { (self.0.wait_until_visible)(self.0.context, done) };
+        let _ = finished.await;
+    }
+
+    pub(crate) async fn vibrate(&self, level: Haptic) -> Result<()> {
+        let level = to_json(&level)?;
+        let (done, finished) = FfiCallback::channel();
+        unsafe { (self.0.vibrate)(self.0.context, FfiStr::borrow(&level), done) };
+        finished
+            .await
+            .map_err(|_| LoupedeckError::Disconnected)?
+            .into_result()
+    }
+}
+
+impl Drop for RemoteHost {
+    fn drop(&mut self) {
+        unsafe { (self.0.release)(self.0.context) }
+    }
+}
+
+/// The host's side of a button or knob context, as handed to a plugin library.
+///
+/// The plugin owns it, and calls `release` once it's done with it.
+#[repr(C)]
+pub struct FfiControlContext {
+    context: *const c_void,
+    /// The plugin's `Button` or `Knob`, as JSON.
+    control: FfiStr,
+    is_visible: unsafe extern "C" fn(context: *const c_void) -> bool,
+    wait_until_visible: unsafe extern "C" fn(context: *const c_void, done: FfiCallback),
+    /// Takes the `Haptic` as JSON.
+    vibrate: unsafe extern "C" fn(context: *const c_void, level: FfiStr, done: FfiCallback),
+    /// Takes the `Rgb` as JSON. Only buttons have a colour.
+    set_color: unsafe extern "C" fn(context: *const c_void, color: FfiStr, done: FfiCallback),
+    release: unsafe extern "C" fn(context: *const c_void),
+}
+
+struct HostControl {
+    ctx: ControlContext,
+    button: Option<Button>,
+    control: String,
+}
+
+impl FfiControlContext {
+    /// Hands a context the host made over to a plugin library, with its control as JSON.
+    fn host(ctx: ControlContext, button: Option<Button>, control: String) -> Self {
+        let context = Box::new(HostControl {
+            ctx,
+            button,
+            control,
+        });
+
+        Self {
+            control: FfiStr::borrow(&context.control),
+            context: Box::into_raw(context) as *const c_void,
+            is_visible: host_control_is_visible,
+            wait_until_visible: host_control_wait_until_visible,
+            vibrate: host_control_vibrate,
+            set_color: host_control_set_color,
+            release: host_control_release,
+        }
+    }
+}
+
+unsafe fn host_control<'a>(context: *const c_void) -> &'a HostControl {
+    &*(context as *const HostControl)
+}
+
+unsafe extern "C" fn host_control_is_visible(context: *const c_void) -> bool {
+    catch_unwind(AssertUnwindSafe(|| host_control(context).ctx.is_visible())).unwrap_or(false)
+}
+
+unsafe extern "C" fn host_control_wait_until_visible(context: *const c_void, done: FfiCallback) {
+    let ctx = host_control(context).ctx.clone();
+
+    ctx.spawner().clone().spawn(async move {
+        ctx.wait_until_visible().await;
+        done.complete(FfiStatus::OK);
+    });
+}
+
+unsafe extern "C" fn host_control_vibrate(
+    context: *const c_void,
+    level: FfiStr,
+    done: FfiCallback,
+) {
+    let level = match from_json::<Haptic>(level) {
+        Ok(level) => level,
+        Err(e) => return done.complete(FfiStatus::from_result(Err(e))),
+    };
+    let ctx = host_control(context).ctx.clone();
+
+    ctx.spawner().clone().spawn(async move {
+        done.complete(FfiStatus::from_result(ctx.vibrate(level).await));
+    });
+}
+
+unsafe extern "C" fn host_control_set_color(
+    context: *const c_void,
+    color: FfiStr,
+    done: FfiCallback,
+) {
+    let host = host_control(context);
+    let request = from_json::<Rgb>(color).and_then(|color| match host.button {
+        Some(button) => Ok((button, color)),
+        None => Err(LoupedeckError::InvalidInput(format!(
+            "{} has no colour",
+            host.control
+        ))),
+    });
+    let (button, color) = match request {
+        Ok(request) => request,
+        Err(e) => return done.complete(FfiStatus::from_result(Err(e))),
+    };
+    let ctx = host.ctx.clone();
+
+    ctx.spawner().clone().spawn(async move {
+        done.complete(FfiStatus::from_result(ctx.set_color(button, color).await));
+    });
+}
+
+unsafe extern "C" fn host_control_release(context: *const c_void) {
+    drop(Box::from_raw(context as *mut HostControl));
+}
+
+/// A plugin's handle on the host's `FfiControlContext`, released when the last clone of the
+/// plugin's button or knob context is dropped.
+pub(crate) struct RemoteControl(FfiControlContext);
+
+unsafe impl Send for RemoteControl {}
+unsafe impl Sync for RemoteControl {}
+
+impl fmt::Debug for RemoteControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RemoteControl")
+            .field(&self.0.context)
+            .finish()
+    }
+}
+
+impl RemoteControl {
+    fn control<T: DeserializeOwned>(&self) -> Result<T> {
+        unsafe { from_json(self.0.control) }
+    }
+
+    pub(crate) fn is_visible(&self) -> bool {
+        unsafe { (self.0.is_visible)(self.0.context) }
+    }
+
+    pub(crate) async fn wait_until_visible(&self) {
+        let (done, finished) = FfiCallback::channel();
+        unsafe { (self.0.wait_until_visible)(self.0.context, done) };
+        let _ = finished.await;
+    }
+
+    pub(crate) async fn vibrate(&self, level: Haptic) -> Result<()> {
+        let level = to_json(&level)?;
+        let (done, finished) = FfiCallback::channel();
+        unsafe { (self.0.vibrate)(self.0.context, FfiStr::borrow(&level), done) };
+        finished
+            .await
+            .map_err(|_| LoupedeckError::Disconnected)?
+            .into_result()
+    }
+
+    pub(crate) async fn set_color(&self, color: Rgb) -> Result<()> {
+        let color = to_json(&color)?;
+        let (done, finished) = FfiCallback::channel();
+        unsafe { (self.0.set_color)(self.0.context, FfiStr::borrow(&color), done) };
+        finished
+            .await
+            .map_err(|_| LoupedeckError::Disconnected)?
+            .into_result()
+    }
+}
+
+impl Drop for RemoteControl {
+    fn drop(&mut self) {
+        unsafe { (self.0.release)(self.0.context) }
+    }
+}
+
+/// Spawns onto a runtime of the plugin library's own, since the host's can't cross over.
+pub(crate) fn plugin_spawner() -> Spawner {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+    let runtime = RUNTIME.get_or_init(|| Runtime::new().expect("plugin runtime starts"));
+    Spawner::new(runtime.handle().clone())
+}
+
+/// A screen plugin instance living in a plugin library.
+#[repr(C)]
+#[derive(Debug)]
+pub struct FfiScreenPlugin {
+    plugin: *mut c_void,
+    /// Takes the `TouchEvent` as JSON.
+    on_touch: unsafe extern "C" fn(plugin: *mut c_void, event: FfiStr) -> FfiStatus,
+    /// Takes the `Lifecycle` hook as JSON.
+    on_lifecycle: unsafe extern "C" fn(plugin: *mut c_void, hook: FfiStr) -> FfiStatus,
+    release: unsafe extern "C" fn(plugin: *mut c_void),
+}
+
+impl FfiScreenPlugin {
+    pub(crate) fn on_touch(&self, event: &TouchEvent) -> Result<()> {
+        let event = to_json(event)?;
+        unsafe { (self.on_touch)(self.plugin, FfiStr::borrow(&event)) }.into_result()
+    }
+
+    pub(crate) fn on_lifecycle(&self, hook: Lifecycle) -> Result<()> {
+        let hook = to_json(&hook)?;
+        unsafe { (self.on_lifecycle)(self.plugin, FfiStr::borrow(&hook)) }.into_result()
+    }
+
+    /// # Safety
+    ///
+    /// Only once, after which the plugin mustn't be used again.
+    pub(crate) unsafe fn release(&self) {
+        (self.release)(self.plugin)
+    }
+}
+
+/// A plugin instance in a plugin library, with the tasks it spawned through its context.
+struct Instance<P: ?Sized> {
+    tasks: TaskScope,
+    plugin: Box<P>,
+}
+
+impl<P: ?Sized> Instance<P> {
+    fn into_raw(tasks: TaskScope, plugin: Box<P>) -> *mut c_void {
+        Box::into_raw(Box::new(Self { tasks, plugin })) as *mut c_void
+    }
+
+    /// # Safety
+    ///
+    /// `plugin` has to come from `into_raw` with the same `P`, and not be released yet.
+    unsafe fn from_raw<'a>(plugin: *mut c_void) -> &'a Self {
+        &*(plugin as *const Self)
+    }
+}
+
+impl<P: ?Sized> Drop for Instance<P> {
+    fn drop(&mut self) {
+        self.tasks.cancel();
+    }
+}
+
+unsafe extern "C" fn plugin_on_touch(plugin: *mut c_void, event: FfiStr) -> FfiStatus {
+    guard(|| {
+        Instance::<dyn ScreenPlugin>::from_raw(plugin)
+            .plugin
+            .on_touch(from_json(event)?)
+    })
+}
+
+/// Cancels the instance's tasks like the host does for its own plugins, then runs the hook.
+unsafe extern "C" fn plugin_on_lifecycle<P: PluginLifecycle + ?Sized>(
+    plugin: *mut c_void,
+    hook: FfiStr,
+) -> FfiStatus {
+    guard(|| {
+        let instance = Instance::<P>::from_raw(plugin);
+        let hook = from_json(hook)?;
+        if matches!(hook, Lifecycle::Hidden | Lifecycle::Unmount) {
+            instance.tasks.cancel();
+        }
+
+        instance.plugin.on_lifecycle(hook)
+    })
+}
+
+/// Drops a plugin instance the library boxed up with `Instance::into_raw`.
+unsafe extern "C" fn plugin_release<P: ?Sized>(plugin: *mut c_void) {
+    let _ = catch_unwind(AssertUnwindSafe(|| {
+        drop(Box::from_raw(plugin as *mut Instance<P>))
+    }));
+}
+
+/// Starts screen plugins of one kind from a plugin library.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FfiScreenPluginFactory {
+    factory: *const c_void,
+    /// Always takes ownership of `context`, and only writes `plugin` when it succeeds.
+    create: unsafe extern "C" fn(
+        factory: *const c_void,
+        context: FfiScreenContext,
+        plugin: *mut FfiScreenPlugin,
+    ) -> FfiStatus,
+}
+
+unsafe impl Send for FfiScreenPluginFactory {}
+unsafe impl Sync for FfiScreenPluginFactory {}
+
+impl FfiScreenPluginFactory {
+    pub(crate) fn create(&self, ctx: PluginScreenContext) -> Result<FfiScreenPlugin> {
+        let context = FfiScreenContext::host(ctx)?;
+        let mut plugin = std::mem::MaybeUninit::uninit();
+
+        unsafe {
+            (self.create)(self.factory, context, plugin.as_mut_ptr()).into_result()?;
+            Ok(plugin.assume_init())
+        }
+    }
+}
+
+unsafe extern "C" fn plugin_create(
+    factory: *const c_void,
+    context: FfiScreenContext,
+    plugin: *mut FfiScreenPlugin,
+) -> FfiStatus {
+    guard(|| {
+        let factory = std::mem::transmute::<*const c_void, ScreenPluginFactory>(factory);
+        let tasks = TaskScope::default();
+        let ctx = PluginScreenContext::remote(RemoteHost(context), tasks.clone())?;
+        let instance: Box<dyn ScreenPlugin> = factory(ctx);
+
+        plugin.write(FfiScreenPlugin {
+            plugin: Instance::into_raw(tasks, instance),
+            on_touch: plugin_on_touch,
+            on_lifecycle: plugin_on_lifecycle::<dyn ScreenPlugin>,
+            release: plugin_release::<dyn ScreenPlugin>,
+        });
+        Ok(())
+    })
+}
+
+/// A button or knob plugin instance living in a plugin library.
+#[repr(C)]
+#[derive(Debug)]
+pub struct FfiControlPlugin {
+    plugin: *mut c_void,
+    /// Takes the `ButtonPressEvent` or `KnobRotateEvent` as JSON.
+    on_event: unsafe extern "C" fn(plugin: *mut c_void, event: FfiStr) -> FfiStatus,
+    /// Takes the `Lifecycle` hook as JSON.
+    on_lifecycle: unsafe extern "C" fn(plugin: *mut c_void, hook: FfiStr) -> FfiStatus,
+    release: unsafe extern "C" fn(plugin: *mut c_void),
+}
+
+// The plugin behind the pointer is a `ButtonPlugin` or `KnobPlugin`, which are Send and Sync
+unsafe impl Send for FfiControlPlugin {}
+unsafe impl Sync for FfiControlPlugin {}
+
+impl FfiControlPlugin {
+    pub(crate) fn on_press(&self, event: &ButtonPressEvent) -> Result<()> {
+        self.on_event(event)
+    }
+
+    pub(crate) fn on_rotate(&self, event: &KnobRotateEvent) -> Result<()> {
+        self.on_event(event)
+    }
+
+    fn on_event(&self, event: &impl Serialize) -> Result<()> {
+        let event = to_json(event)?;
+        unsafe { (self.on_event)(self.plugin, FfiStr::borrow(&event)) }.into_result()
+    }
+
+    pub(crate) fn on_lifecycle(&self, hook: Lifecycle) -> Result<()> {
+        let hook = to_json(&hook)?;
+        unsafe { (self.on_lifecycle)(self.plugin, FfiStr::borrow(&hook)) }.into_result()
+    }
+
+    /// # Safety
+    ///
+    /// Only once, after which the plugin mustn't be used again.
+    pub(crate) unsafe fn release(&self) {
+        (self.release)(self.plugin)
+    }
+}
+
+unsafe extern "C" fn plugin_on_press(plugin: *mut c_void, event: FfiStr) -> FfiStatus {
+    guard(|| {
+        Instance::<dyn ButtonPlugin>::from_raw(plugin)
+            .plugin
+            .on_press(from_json(event)?)
+    })
+}
+
+unsafe extern "C" fn plugin_on_rotate(plugin: *mut c_void, event: FfiStr) -> FfiStatus {
+    guard(|| {
+        Instance::<dyn KnobPlugin>::from_raw(plugin)
+            .plugin
+            .on_rotate(from_json(event)?)
+    })
+}
+
+/// Starts button or knob plugins of one kind from a plugin library.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FfiControlPluginFactory {
+    factory: *const c_void,
+    /// Always takes ownership of `context`, and of `*strip` unless it's null. Only writes
+    /// `plugin` when it succeeds.
+    create: unsafe extern "C" fn(
+        factory: *const c_void,
+        context: FfiControlContext,
+        strip: *mut FfiScreenContext,
+        plugin: *mut FfiControlPlugin,
+    ) -> FfiStatus,
+}
+
+unsafe impl Send for FfiControlPluginFactory {}
+unsafe impl Sync for FfiControlPluginFactory {}
+
+impl FfiControlPluginFactory {
+    pub(crate) fn create_button(&self, ctx: PluginButtonContext) -> Result<FfiControlPlugin> {
+        let control = to_json(&ctx.button())?;
+        let context = FfiControlContext::host(ctx.control().clone(), Some(ctx.button()), control);
+
+        self.create(context, None)
+    }
+
+    pub(crate) fn create_knob(&self, ctx: PluginKnobContext) -> Result<FfiControlPlugin> {
+        let control = to_json(&ctx.knob())?;
+        let strip = ctx
+            .strip()
+            .cloned()
+            .map(FfiScreenContext::host)
+            .transpose()?;
+        let context = FfiControlContext::host(ctx.control().clone(), None, control);
+
+        self.create(context, strip)
+    }
+
+    fn create(
+        &self,
+        context: FfiControlContext,
+        mut strip: Option<FfiScreenContext>,
+    ) -> Result<FfiControlPlugin> {
+        let strip = strip
+            .as_mut()
+            .map_or(std::ptr::null_mut(), |strip| strip as *mut FfiScreenContext);
+        let mut plugin = std::mem::MaybeUninit::uninit();
+
+        unsafe {
+            (self.create)(self.factory, context, strip, plugin.as_mut_ptr()).into_result()?;
+            Ok(plugin.assume_init())
+        }
+    }
+}
+
+unsafe extern "C" fn plugin_create_button(
+    factory: *const c_void,
+    context: FfiControlContext,
+    strip: *mut FfiScreenContext,
+    plugin: *mut FfiControlPlugin,
+) -> FfiStatus {
+    guard(|| {
+        let control = RemoteControl(context);
+        if !strip.is_null() {
+            drop(RemoteHost(strip.read()));
+        }
+
+        let factory = std::mem::transmute::<*const c_void, ButtonPluginFactory>(factory);
+        let button: Button = control.control()?;
+        let tasks = TaskScope::default();
+        let instance: Box<dyn ButtonPlugin> = factory(PluginButtonContext::remote(
+            button,
+            ControlContext::remote(control, tasks.clone()),
+        ));
+
+        plugin.write(FfiControlPlugin {
+            plugin: Instance::into_raw(tasks, instance),
+            on_event: plugin_on_press,
+            on_lifecycle: plugin_on_lifecycle::<dyn ButtonPlugin>,
+            release: plugin_release::<dyn ButtonPlugin>,
+        });
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn plugin_create_knob(
+    factory: *const c_void,
+    context: FfiControlContext,
+    strip: *mut FfiScreenContext,
+    plugin: *mut FfiControlPlugin,
+) -> FfiStatus {
+    guard(|| {
+        let control = RemoteControl(context);
+        let strip = match strip.is_null() {
+            true => None,
+            false => Some(RemoteHost(strip.read())),
+        };
+
+        let factory = std::mem::transmute::<*const c_void, KnobPluginFactory>(factory);
+        let knob: Knob = control.control()?;
+        let tasks = TaskScope::default();
+        let strip = strip
+            .map(|strip| PluginScreenContext::remote(strip, tasks.clone()))
+            .transpose()?;
+        let instance: Box<dyn KnobPlugin> = factory(PluginKnobContext::remote(
+            knob,
+            ControlContext::remote(control, tasks.clone()),
+            strip,
+        ));
+
+        plugin.write(FfiControlPlugin {
+            plugin: Instance::into_raw(tasks, instance),
+            on_event: plugin_on_rotate,
+            on_lifecycle: plugin_on_lifecycle::<dyn KnobPlugin>,
+            release: plugin_release::<dyn KnobPlugin>,
+        });
+        Ok(())
+    })
+}
+
+/// The host's registrar, as a plugin library's `register` sees it.
+#[repr(C)]
+pub struct FfiRegistrar {
+    pub registrar: *mut c_void,
+    pub register_screen: unsafe extern "C" fn(
+        registrar: *mut c_void,
+        name: FfiStr,
+        options: ScreenPluginOptions,
+        factory: FfiScreenPluginFactory,
+    ) -> FfiStatus,
+    pub register_button: unsafe extern "C" fn(
+        registrar: *mut c_void,
+        name: FfiStr,
+        factory: FfiControlPluginFactory,
+    ) -> FfiStatus,
+    pub register_knob: unsafe extern "C" fn(
+        registrar: *mut c_void,
+        name: FfiStr,
+        factory: FfiControlPluginFactory,
+    ) -> FfiStatus,
+}
+
+struct RemoteRegistrar(FfiRegistrar);
+
+impl PluginRegistrar for RemoteRegistrar {
+    fn register_screen(
+        &mut self,
+        name: &str,
+        options: ScreenPluginOptions,
+        create: ScreenPluginFactory,
+    ) -> Result<()> {
+        let factory = FfiScreenPluginFactory {
+            factory: create as *const c_void,
+            create: plugin_create,
+        };
+
+        unsafe {
+            (self.0.register_screen)(self.0.registrar, FfiStr::borrow(name), options, factory)
+        }
+        .into_result()
+    }
+    fn register_button(&mut self, name: &str, create: ButtonPluginFactory) -> Result<()> {
+        let factory = FfiControlPluginFactory {
+            factory: create as *const c_void,
+            create: plugin_create_button,
+        };
+
+        unsafe { (self.0.register_button)(self.0.registrar, FfiStr::borrow(name), factory) }
+            .into_result()
+    }
+
+    fn register_knob(&mut self, name: &str, create: KnobPluginFactory) -> Result<()> {
+        let factory = FfiControlPluginFactory {
+            factory: create as *const c_void,
+            create: plugin_create_knob,
+        };
+
+        unsafe { (self.0.register_knob)(self.0.registrar, FfiStr::borrow(name), factory) }
+            .into_result()
+    }
+}
+
+/// What a plugin library exports as `plugin_declaration`, through `export_plugin!`.
+///
+/// `abi_version` has to stay first, so the host can check it before reading the rest.
+#[repr(C)]
+pub struct PluginDeclaration {
+    pub abi_version: u32,
+    /// The version of this crate the plugin was built against, for diagnostics.
+    pub core_version: FfiStr,
+    pub plugin_id: FfiStr,
+    pub register: unsafe extern "C" fn(registrar: FfiRegistrar) -> FfiStatus,
+}
+
+/// Runs a plugin's `register` function against the host's registrar.
+#[doc(hidden)]
+pub fn register_plugin(
+    registrar: FfiRegistrar,
+    register: fn(&mut dyn PluginRegistrar),
+) -> FfiStatus {
+    guard(|| {
+        register(&mut RemoteRegistrar(registrar));
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::c_void;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::runtime::Handle;
+    use tokio::time::sleep;
+
+    use super::{
+        register_plugin, FfiControlPluginFactory, FfiRegistrar, FfiScreenPluginFactory, FfiStatus,
+        FfiStr,
+    };
+    use crate::{
+        connect_test_page, Button, ButtonPlugin, ButtonPressEvent, KeyLocation, Knob, KnobPlugin,
+        KnobRotateEvent, Lifecycle, PluginButtonContext, PluginKnobContext, PluginLifecycle,
+        PluginRegistrar, PluginScreenContext, PressDirection, Region, Result, Rgb, Screen,
+        ScreenPlugin, ScreenPluginOptions, Spawner, TouchEvent,
+    };
+
+    static TOUCHES: AtomicUsize = AtomicUsize::new(0);
+    static PRESSES: AtomicUsize = AtomicUsize::new(0);
+    static ROTATIONS: AtomicUsize = AtomicUsize::new(0);
+    static HIDDEN: AtomicUsize = AtomicUsize::new(0);
+
+    struct RedKey;
+
+    impl PluginLifecycle for RedKey {}
+
+    impl ScreenPlugin for RedKey {
+        fn on_touch(&self, event: TouchEvent) -> Result<()> {
+            assert_eq!((event.x, event.y), (10, 20));
+            TOUCHES.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    struct OrangeButton;
+
+    impl PluginLifecycle for OrangeButton {}
+
+    impl ButtonPlugin for OrangeButton {
+        fn on_press(&self, event: ButtonPressEvent) -> Result<()> {
+            assert_eq!(event.button, Button::Circle2);
+            PRESSES.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    struct Volume;
+
+    impl PluginLifecycle for Volume {
+        fn on_hidden(&self) -> Result<()> {
+            HIDDEN.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    impl KnobPlugin for Volume {
+        fn on_rotate(&self, event: KnobRotateEvent) -> Result<()> {
+            ROTATIONS.fetch_add(event.value as usize, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn register(registrar: &mut dyn PluginRegistrar) {
+        registrar
+            .register_screen("red", ScreenPluginOptions { exclusive: false }, |ctx| {
+                assert_eq!(ctx.region(), &Region::Key(KeyLocation::new(1, 0)));
+                assert_eq!(ctx.size(), (90, 90));
+
+                let draw_ctx = ctx.clone();
+                ctx.spawner().spawn(async move {
+                    draw_ctx.wait_until_visible().await;
+                    let (width, height) = draw_ctx.size();
+                    let red = [0x00, 0xf8].repeat(width as usize * height as usize);
+                    draw_ctx.draw_rgb565(red).await.unwrap();
+                    assert!(draw_ctx.draw_rgb565(vec![0; 2]).await.is_err());
+                });
+
+                Box::new(RedKey)
+            })
+            .unwrap();
+
+        registrar
+            .register_button("orange", |ctx| {
+                let color_ctx = ctx.clone();
+                ctx.spawner().spawn(async move {
+                    color_ctx.wait_until_visible().await;
+                    color_ctx.set_color(Rgb::new(0xff, 0x80, 0)).await.unwrap();
+                });
+
+                Box::new(OrangeButton)
+            })
+            .unwrap();
+
+        registrar
+            .register_knob("volume", |ctx| {
+                assert_eq!(ctx.knob(), Knob::Knob3);
+                let strip = ctx.strip().unwrap();
+                assert_eq!(strip.region(), &Region::Knob(Knob::Knob3));
+                assert_eq!(strip.size(), (60, 90));
+
+                // Runs until the page is hidden
+                let strip = strip.clone();
+                ctx.spawner().spawn(async move {
+                    let _strip = strip;
+                    std::future::pending::<()>().await
+                });
+
+                Box::new(Volume)
+            })
+            .unwrap();
+    }
+
+    #[derive(Default)]
+    struct Registered {
+        screens: Vec<(String, FfiScreenPluginFactory)>,
+        buttons: Vec<(String, FfiControlPluginFactory)>,
+        knobs: Vec<(String, FfiControlPluginFactory)>,
+    }
+
+    impl Registered {
+        fn collect() -> Self {
+            let mut registered = Registered::default();
+            let registrar = FfiRegistrar {
+                registrar: &mut registered as *mut _ as *mut c_void,
+                register_screen: collect_screen,
+                register_button: collect_button,
+                register_knob: collect_knob,
+            };
+
+            register_plugin(registrar, register).into_result().unwrap();
+            registered
+        }
+    }
+
+    unsafe extern "C" fn collect_screen(
+        registrar: *mut c_void,
+        name: FfiStr,
+        _options: ScreenPluginOptions,
+        factory: FfiScreenPluginFactory,
+    ) -> FfiStatus {
+        let registered = &mut *(registrar as *mut Registered);
+        registered
+            .screens
+            .push((name.as_str().unwrap().to_string(), factory));
+        FfiStatus::OK
+    }
+
+    unsafe extern "C" fn collect_button(
+        registrar: *mut c_void,
+        name: FfiStr,
+        factory: FfiControlPluginFactory,
+    ) -> FfiStatus {
+        let registered = &mut *(registrar as *mut Registered);
+        registered
+            .buttons
+            .push((name.as_str().unwrap().to_string(), factory));
+        FfiStatus::OK
+    }
+
+    unsafe extern "C" fn collect_knob(
+        registrar: *mut c_void,
+        name: FfiStr,
+        factory: FfiControlPluginFactory,
+    ) -> FfiStatus {
+        let registered = &mut *(registrar as *mut Registered);
+        registered
+            .knobs
+            .push((name.as_str().unwrap().to_string(), factory));
+        FfiStatus::OK
+    }
+
+    #[tokio::test]
+    async fn it_runs_plugins_across_the_abi() {
+        let (_device, _virtual_device, emitter, view) = connect_test_page().await;
+
+        let screens = Registered::collect().screens;
+        assert_eq!(screens.len(), 1);
+        assert_eq!(screens[0].0, "red");
+
+        let ctx = PluginScreenContext::new(
+            view.clone(),
+            Region::Key(KeyLocation::new(1, 0)),
+            Spawner::new(Handle::current()),
+        )
+        .unwrap();
+        let plugin = screens[0].1.create(ctx).unwrap();
+
+        // Nothing is drawn until the page is shown
+        sleep(Duration::from_millis(20)).await;
+        assert_eq!(emitter.snapshot().pixel(190, 45), [0, 0, 0, 0xff]);
+
+        view.show().unwrap();
+        let started = Instant::now();
+        while emitter.snapshot().pixel(190, 45) != [0xff, 0, 0, 0xff] {
+            assert!(started.elapsed() < Duration::from_secs(1));
+            sleep(Duration::from_millis(1)).await;
+        }
+
+        let touch = TouchEvent {
+            tx_id: 0,
+            dir: PressDirection::Down,
+            touch_id: 1,
+            x: 10,
+            y: 20,
+            screen: Screen::Center,
+        };
+        plugin.on_touch(&touch).unwrap();
+        assert_eq!(TOUCHES.load(Ordering::SeqCst), 1);
+
+        // A panic in the plugin comes back as an error
+        let touch = TouchEvent { x: 0, ..touch };
+        assert!(plugin.on_touch(&touch).is_err());
+
+        // Once the plugin and its tasks are done, the host's context is released
+        unsafe { plugin.release() };
+        let started = Instant::now();
+        while Arc::strong_count(&view) > 1 {
+            assert!(started.elapsed() < Duration::from_secs(1));
+            sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn it_runs_button_and_knob_plugins_across_the_abi() {
+        let (_device, virtual_device, _emitter, view) = connect_test_page().await;
+
+        let registered = Registered::collect();
+        assert_eq!(registered.buttons[0].0, "orange");
+        assert_eq!(registered.knobs[0].0, "volume");
+
+        let spawner = Spawner::new(Handle::current());
+        let ctx = PluginButtonContext::new(view.clone(), Button::Circle2, spawner.clone()).unwrap();
+        let button = registered.buttons[0].1.create_button(ctx).unwrap();
+        let ctx = PluginKnobContext::new(view.clone(), Knob::Knob3, spawner).unwrap();
+        let knob = registered.knobs[0].1.create_knob(ctx).unwrap();
+
+        view.show().unwrap();
+        let started = Instant::now();
+        while virtual_device.button_colors().get(&(Button::Circle2 as u8))
+            != Some(&Rgb::new(0xff, 0x80, 0))
+        {
+            assert!(started.elapsed() < Duration::from_secs(1));
+            sleep(Duration::from_millis(1)).await;
+        }
+
+        button
+            .on_press(&ButtonPressEvent {
+                tx_id: 0,
+                button: Button::Circle2,
+                dir: PressDirection::Down,
+            })
+            .unwrap();
+        assert_eq!(PRESSES.load(Ordering::SeqCst), 1);
+
+        let rotation = KnobRotateEvent {
+            tx_id: 0,
+            knob: Knob::Knob3,
+            value: 2,
+        };
+        knob.on_rotate(&rotation).unwrap();
+        assert_eq!(ROTATIONS.load(Ordering::SeqCst), 2);
+
+        unsafe { button.release() };
+
+        // Hiding the knob cancels the tasks it spawned, which releases the host's context
+        knob.on_lifecycle(Lifecycle::Hidden).unwrap();
+        assert_eq!(HIDDEN.load(Ordering::SeqCst), 1);
+        let started = Instant::now();
+        while Arc::strong_count(&view) > 1 {
+            assert!(started.elapsed() < Duration::from_secs(1));
+            sleep(Duration::from_millis(1)).await;
+        }
+
+        unsafe { knob.release() };
+    }
+}
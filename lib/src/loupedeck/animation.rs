@@ -0,0 +1,234 @@
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::{AnimationDecoder, Frames};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+use super::drawing::read_source;
+use crate::{
+    convert_rgba_to_rgb565, fit_image, render_image, ImageOptions, ImageSource, LoupedeckError,
+    PluginScreenContext, Result,
+};
+
+/// What browsers wait for frames that don't say how long to show them.
+const DEFAULT_FRAME_DELAY: Duration = Duration::from_millis(100);
+
+/// One frame of an animation, as little-endian RGB565 pixels.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnimationFrame {
+    pub data: Vec<u8>,
+    /// How long the frame stays up before the next one is drawn.
+    pub delay: Duration,
+}
+
+/// A sequence of frames for a key, e.g. a recording or building indicator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Animation {
+    pub frames: Vec<AnimationFrame>,
+    /// Start over after the last frame instead of leaving it up.
+    pub repeat: bool,
+}
+
+impl Animation {
+    /// A repeating animation from frames that are already sized for the key.
+    pub fn new(frames: Vec<AnimationFrame>) -> Self {
+        Self {
+            frames,
+            repeat: true,
+        }
+    }
+
+    /// Decodes a GIF or animated PNG and sizes every frame to `width` x `height`.
+    ///
+    /// Other images load as a single frame, so a still icon can stand in for an animation.
+    pub fn load(
+        source: impl Into<ImageSource>,
+        width: u16,
+        height: u16,
+        options: impl Into<ImageOptions>,
+    ) -> Result<Self> {
+        let options = options.into();
+        let (data, _) = read_source(source.into())?;
+        let error = |e: image::ImageError| LoupedeckError::Image(e.to_string());
+
+        if data.starts_with(b"GIF8") {
+            let frames = GifDecoder::new(data.as_slice()).map_err(error)?;
+            return decode_frames(frames.into_frames(), width, height, options).map(Self::new);
+        }
+
+        if data.starts_with(b"\x89PNG") {
+            let decoder = PngDecoder::new(data.as_slice()).map_err(error)?;
+            if decoder.is_apng() {
+                let frames = decoder.apng().into_frames();
+                return decode_frames(frames, width, height, options).map(Self::new);
+            }
+        }
+
+        let data = render_image(data, width, height, options)?;
+        Ok(Self::new(vec![AnimationFrame {
+            data,
+            delay: DEFAULT_FRAME_DELAY,
+        }]))
+    }
+
+    /// How long one pass through every frame takes.
+    pub fn duration(&self) -> Duration {
+        self.frames.iter().map(|frame| frame.delay).sum()
+    }
+}
+
+fn decode_frames(
+    frames: Frames,
+    width: u16,
+    height: u16,
+    options: ImageOptions,
+) -> Result<Vec<AnimationFrame>> {
+    frames
+        .map(|frame| {
+            let frame = frame.map_err(|e| LoupedeckError::Image(e.to_string()))?;
+            let (numerator, denominator) = frame.delay().numer_denom_ms();
+            let delay = match Duration::from_millis(numerator as u64) / denominator.max(1) {
+                Duration::ZERO => DEFAULT_FRAME_DELAY,
+                delay => delay,
+            };
+
+            let image = fit_image(
+                &frame.into_buffer(),
+                width as u32,
+                height as u32,
+                options.fit,
+            );
+
+            Ok(AnimationFrame {
+                data: convert_rgba_to_rgb565(&image, &options.pipeline),
+                delay,
+            })
+        })
+        .collect()
+}
+
+/// An animation playing on a key. Dropping it stops the animation.
+#[derive(Debug)]
+pub struct AnimationHandle {
+    task: JoinHandle<()>,
+}
+
+impl AnimationHandle {
+    pub(crate) fn play(ctx: PluginScreenContext, animation: Animation) -> Self {
+        // Playback pauses while the page is hidden, rather than being cancelled with the page's
+        // other tasks
+        let task = ctx.spawner().detached().spawn(async move {
+            if animation.frames.is_empty() {
+                return;
+            }
+
+            for (i, frame) in animation.frames.iter().enumerate().cycle() {
+                // Hold the current frame while the page is hidden, and carry on from it
+                ctx.wait_until_visible().await;
+                if let Err(e) = ctx.draw_rgb565(frame.data.clone()).await {
+                    println!("Error drawing animation frame: {}", e);
+                    return;
+                }
+
+                if !animation.repeat && i == animation.frames.len() - 1 {
+                    return;
+                }
+
+                sleep(frame.delay).await;
+            }
+        });
+
+        Self { task }
+    }
+
+    /// Whether the animation ended or failed to draw.
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    pub fn stop(self) {}
+}
+
+impl Drop for AnimationHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::codecs::gif::GifEncoder;
+    use image::{Delay, Frame, Rgba, RgbaImage};
+    use std::time::Duration;
+    use tokio::runtime::Handle;
+    use tokio::time::sleep;
+
+    use super::{Animation, AnimationFrame, DEFAULT_FRAME_DELAY};
+    use crate::{connect_test_page, Fit, KeyLocation, PluginScreenContext, Region, Spawner};
+
+    fn solid(color: u16) -> Vec<u8> {
+        color.to_le_bytes().repeat(90 * 90)
+    }
+
+    #[test]
+    fn it_decodes_gifs() {
+        let mut gif = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut gif);
+            for (color, delay) in [([255, 0, 0, 255], 50), ([0, 0, 255, 255], 0)] {
+                let image = RgbaImage::from_pixel(10, 10, Rgba(color));
+                let delay = Delay::from_numer_denom_ms(delay, 1);
+                encoder
+                    .encode_frame(Frame::from_parts(image, 0, 0, delay))
+                    .unwrap();
+            }
+        }
+
+        let animation = Animation::load(gif, 90, 90, Fit::Stretch).unwrap();
+        assert_eq!(animation.frames.len(), 2);
+        assert_eq!(animation.frames[0].data, solid(0xf800));
+        assert_eq!(animation.frames[1].data, solid(0x001f));
+        assert_eq!(animation.frames[0].delay, Duration::from_millis(50));
+        assert_eq!(animation.frames[1].delay, DEFAULT_FRAME_DELAY);
+        assert_eq!(animation.duration(), Duration::from_millis(150));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_pauses_while_the_page_is_hidden() {
+        let (_device, _virtual_device, emitter, view) = connect_test_page().await;
+        let ctx = PluginScreenContext::new(
+            view.clone(),
+            Region::Key(KeyLocation::new(0, 0)),
+            Spawner::new(Handle::current()),
+        )
+        .unwrap();
+
+        let frame = |color| AnimationFrame {
+            data: solid(color),
+            delay: Duration::from_millis(10),
+        };
+        let _animation = ctx.play_animation(Animation::new(vec![frame(0xf800), frame(0x001f)]));
+        let key_pixel = || emitter.snapshot().pixel(100, 45);
+        let (red, blue) = ([0xff, 0, 0, 0xff], [0, 0, 0xff, 0xff]);
+
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(key_pixel(), [0, 0, 0, 0xff]);
+
+        // Frames change every 10ms once the page is shown
+        view.show().unwrap();
+        sleep(Duration::from_millis(5)).await;
+        assert_eq!(key_pixel(), red);
+        sleep(Duration::from_millis(10)).await;
+        assert_eq!(key_pixel(), blue);
+
+        view.hide();
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(key_pixel(), blue);
+
+        // And carry on from there when it's shown again
+        view.show().unwrap();
+        sleep(Duration::from_millis(5)).await;
+        assert_eq!(key_pixel(), red);
+    }
+}
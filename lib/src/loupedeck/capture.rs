@@ -0,0 +1,619 @@
+use bytes::BytesMut;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, LineWriter, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::sync::oneshot;
+use tokio::time::{self, Duration};
+use tokio_util::codec::{Decoder, Encoder, FramedRead};
+
+use super::emulator::WS_UPGRADE_ACCEPT;
+use super::{
+    memory_transport, parse_serial_message, DeviceInfo, DeviceModel, Event, FrameCodec, Transport,
+};
+use crate::{LoupedeckError, Result};
+
+// How long a replay waits for the host to send a frame the capture says it sent.
+const REPLAY_HOST_FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Which way a captured frame travelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameDirection {
+    /// Sent by the device to the host.
+    In,
+    /// Sent by the host to the device.
+    Out,
+}
+
+/// One websocket frame payload, as written to a capture file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedFrame {
+    /// Microseconds since the capture started.
+    pub timestamp_us: u64,
+    pub direction: FrameDirection,
+    /// The unmasked frame payload, as lowercase hex.
+    pub data: String,
+}
+
+impl CapturedFrame {
+    pub fn new(timestamp_us: u64, direction: FrameDirection, data: &[u8]) -> Self {
+        Self {
+            timestamp_us,
+            direction,
+            data: data.iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+
+    pub fn bytes(&self) -> Result<Vec<u8>> {
+        if !self.data.len().is_multiple_of(2) {
+            return Err(LoupedeckError::InvalidInput(format!(
+                "odd length frame data: {}",
+                self.data
+            )));
+        }
+
+        (0..self.data.len())
+            .step_by(2)
+            .map(|i| {
+                u8::from_str_radix(&self.data[i..i + 2], 16).map_err(|_| {
+                    LoupedeckError::InvalidInput(format!("invalid frame data: {}", self.data))
+                })
+            })
+            .collect()
+    }
+}
+
+/// What a capture was recorded from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaptureHeader {
+    /// The `DeviceModel::name` of the device.
+    pub model: String,
+    /// The device's info, once it has been asked for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info: Option<DeviceInfo>,
+}
+
+impl CaptureHeader {
+    pub fn new(model: &DeviceModel, info: Option<DeviceInfo>) -> Self {
+        Self {
+            model: model.name.to_string(),
+            info,
+        }
+    }
+
+    pub fn device_model(&self) -> Result<&'static DeviceModel> {
+        DeviceModel::from_name(&self.model).ok_or_else(|| {
+            LoupedeckError::InvalidInput(format!("unknown model in capture: {}", self.model))
+        })
+    }
+}
+
+/// A capture read back from a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    /// The last header in the file, or `None` for captures recorded without one.
+    pub header: Option<CaptureHeader>,
+    pub frames: Vec<CapturedFrame>,
+}
+
+/// One line of a capture file.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum CaptureLine {
+    Header { header: CaptureHeader },
+    Frame(CapturedFrame),
+}
+
+enum CaptureMessage {
+    Line(CaptureLine),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Where captured frames are written to, one JSON object per line.
+///
+/// Lines are written on a thread of their own so a slow disk doesn't stall the connection.
+/// Clones share the same writer and start time, so one capture can span reconnects.
+#[derive(Clone)]
+pub struct CaptureSink {
+    tx_message: mpsc::Sender<CaptureMessage>,
+    started: Instant,
+}
+
+impl std::fmt::Debug for CaptureSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CaptureSink")
+            .field("started", &self.started)
+            .finish()
+    }
+}
+
+impl CaptureSink {
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> Self {
+        let (tx_message, rx_message) = mpsc::channel();
+
+        // Runs until every clone of the sink is dropped
+        thread::spawn(move || {
+            for message in rx_message {
+                match message {
+                    CaptureMessage::Line(line) => {
+                        // A failing capture shouldn't take the connection down with it
+                        if let Err(e) = serde_json::to_vec(&line)
+                            .map_err(std::io::Error::from)
+                            .and_then(|mut bytes| {
+                                bytes.push(b'\n');
+                                writer.write_all(&bytes)
+                            })
+                        {
+                            println!("Error writing capture: {:?}", e);
+                        }
+                    }
+                    CaptureMessage::Flush(done) => {
+                        if let Err(e) = writer.flush() {
+                            println!("Error flushing capture: {:?}", e);
+                        }
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+
+        Self {
+            tx_message,
+            started: Instant::now(),
+        }
+    }
+
+    /// Creates (or truncates) a capture file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::new(LineWriter::new(File::create(path)?)))
+    }
+
+    /// Records what is connected, written when connecting and again once the info is known.
+    pub(crate) fn record_header(&self, header: CaptureHeader) {
+        self.write_line(CaptureLine::Header { header });
+    }
+
+    fn record(&self, direction: FrameDirection, data: &[u8]) {
+        let frame = CapturedFrame::new(self.started.elapsed().as_micros() as u64, direction, data);
+        self.write_line(CaptureLine::Frame(frame));
+    }
+
+    fn write_line(&self, line: CaptureLine) {
+        let _ = self.tx_message.send(CaptureMessage::Line(line));
+    }
+
+    /// Waits until everything recorded so far has been written out.
+    pub async fn flush(&self) {
+        let (tx_done, rx_done) = oneshot::channel();
+        if self.tx_message.send(CaptureMessage::Flush(tx_done)).is_ok() {
+            let _ = rx_done.await;
+        }
+    }
+}
+
+/// A transport that records every frame passing through it to a `CaptureSink`.
+pub struct CaptureTransport<T> {
+    inner: T,
+    sink: CaptureSink,
+    codec: FrameCodec,
+    inbound: BytesMut,
+    outbound: BytesMut,
+}
+
+impl<T: Transport> CaptureTransport<T> {
+    pub fn new(inner: T, sink: CaptureSink) -> Self {
+        Self {
+            inner,
+            sink,
+            codec: FrameCodec::host(),
+            inbound: BytesMut::new(),
+            outbound: BytesMut::new(),
+        }
+    }
+
+    fn capture(&mut self, direction: FrameDirection, bytes: &[u8]) {
+        let buffer = match direction {
+            FrameDirection::In => &mut self.inbound,
+            FrameDirection::Out => &mut self.outbound,
+        };
+        buffer.extend_from_slice(bytes);
+
+        // The decoder skips anything that isn't a frame, like the websocket upgrade
+        while let Ok(Some(frame)) = self.codec.decode(buffer) {
+            self.sink.record(direction, &frame);
+        }
+    }
+}
+
+impl<T: Transport> AsyncRead for CaptureTransport<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let already_filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = poll {
+            self.capture(FrameDirection::In, &buf.filled()[already_filled..]);
+        }
+
+        poll
+    }
+}
+
+impl<T: Transport> AsyncWrite for CaptureTransport<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(written)) = poll {
+            self.capture(FrameDirection::Out, &buf[..written]);
+        }
+
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<T: Transport> Transport for CaptureTransport<T> {}
+
+/// Reads a capture written by a `CaptureSink`, skipping blank lines.
+///
+/// Later headers replace earlier ones, but keep the earlier info if they have none.
+pub fn read_capture<R: BufRead>(reader: R) -> Result<Capture> {
+    let mut capture = Capture {
+        header: None,
+        frames: Vec::new(),
+    };
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str(&line)
+            .map_err(|e| LoupedeckError::InvalidInput(format!("invalid capture line: {}", e)))?
+        {
+            CaptureLine::Header { mut header } => {
+                if header.info.is_none() {
+                    header.info = capture.header.and_then(|previous| previous.info);
+                }
+                capture.header = Some(header);
+            }
+            CaptureLine::Frame(frame) => capture.frames.push(frame),
+        }
+    }
+
+    Ok(capture)
+}
+
+/// Parses every frame the device sent in a capture, in order.
+pub fn parse_capture(frames: &[CapturedFrame], model: &DeviceModel) -> Vec<Result<Option<Event>>> {
+    frames
+        .iter()
+        .filter(|frame| frame.direction == FrameDirection::In)
+        .map(|frame| parse_serial_message(&frame.bytes()?, model))
+        .collect()
+}
+
+/// Plays the device's side of a capture back over an in-memory transport.
+///
+/// The returned end can be handed to `Device::connect_transport`. Frames the device sent
+/// are written as captured, while frames the host sent are waited for (and discarded) so
+/// responses aren't sent before the request that triggered them. With `realtime` set the
+/// original gaps between frames are kept. The transport closes once the capture runs out.
+pub fn replay_transport(frames: Vec<CapturedFrame>, realtime: bool) -> DuplexStream {
+    let (host, mut replay) = memory_transport();
+
+    tokio::spawn(async move {
+        let mut buf = vec![0; 1024];
+        match replay.read(buf.as_mut_slice()).await {
+            Ok(read) if String::from_utf8_lossy(&buf[..read]).contains("Upgrade: websocket") => {}
+            _ => return,
+        }
+
+        if replay
+            .write_all(WS_UPGRADE_ACCEPT.as_bytes())
+            .await
+            .is_err()
+        {
+            return;
+        }
+
+        let (reader, mut writer) = tokio::io::split(replay);
+        let mut host_frames = FramedRead::new(reader, FrameCodec::device());
+        let mut codec = FrameCodec::device();
+        let mut last_timestamp = frames.first().map_or(0, |frame| frame.timestamp_us);
+
+        for frame in frames {
+            if realtime {
+                let gap = frame.timestamp_us.saturating_sub(last_timestamp);
+                time::sleep(Duration::from_micros(gap)).await;
+            }
+            last_timestamp = frame.timestamp_us;
+
+            match frame.direction {
+                FrameDirection::Out => {
+                    let _ = time::timeout(REPLAY_HOST_FRAME_TIMEOUT, host_frames.next()).await;
+                }
+                FrameDirection::In => {
+                    let Ok(data) = frame.bytes() else {
+                        continue;
+                    };
+
+                    let mut encoded = BytesMut::new();
+                    if codec.encode(data, &mut encoded).is_err()
+                        || writer.write_all(&encoded).await.is_err()
+                    {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+
+    host
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_capture, parse_serial_message, read_capture, replay_transport, CaptureHeader,
+        CaptureSink, CapturedFrame, FrameDirection,
+    };
+    use crate::{
+        memory_transport, Device, DeviceInfo, Event, Knob, ScriptedEvent, VirtualDevice,
+        LOUPEDECK_LIVE, LOUPEDECK_LIVE_S,
+    };
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::broadcast;
+    use tokio::time::{timeout, Duration};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Holds every write until the test lets it through.
+    struct GatedWriter {
+        gate: std::sync::mpsc::Receiver<()>,
+        buffer: SharedBuffer,
+    }
+
+    impl Write for GatedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let _ = self.gate.recv_timeout(std::time::Duration::from_secs(5));
+            self.buffer.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    async fn next_knob_rotation(rx_event: &mut broadcast::Receiver<Event>) {
+        timeout(Duration::from_secs(1), async {
+            while !matches!(rx_event.recv().await, Ok(Event::KnobRotate(_))) {}
+        })
+        .await
+        .expect("knob rotation was never received");
+    }
+
+    fn info() -> DeviceInfo {
+        DeviceInfo {
+            serial: "LDD0123456789".to_string(),
+            version: "0.2.5".to_string(),
+        }
+    }
+
+    #[test]
+    fn it_round_trips_frame_data() {
+        let frame = CapturedFrame::new(12, FrameDirection::Out, &[0x03, 0x03, 0xab]);
+        assert_eq!(frame.data, "0303ab");
+        assert_eq!(frame.bytes().unwrap(), vec![0x03, 0x03, 0xab]);
+
+        let line = serde_json::to_string(&frame).unwrap();
+        assert_eq!(
+            line,
+            r#"{"timestamp_us":12,"direction":"out","data":"0303ab"}"#
+        );
+        let capture = read_capture(format!("{}\n\n", line).as_bytes()).unwrap();
+        assert_eq!(capture.frames, vec![frame]);
+        assert_eq!(capture.header, None);
+
+        assert!(CapturedFrame::new(0, FrameDirection::In, &[])
+            .bytes()
+            .unwrap()
+            .is_empty());
+        assert!(read_capture("not json\n".as_bytes()).is_err());
+    }
+
+    #[tokio::test]
+    async fn it_writes_off_the_async_runtime() {
+        let buffer = SharedBuffer::default();
+        let (tx_gate, gate) = std::sync::mpsc::channel();
+        let sink = CaptureSink::new(GatedWriter {
+            gate,
+            buffer: buffer.clone(),
+        });
+
+        sink.record(FrameDirection::In, &[0x01]);
+        assert!(buffer.0.lock().unwrap().is_empty());
+
+        tx_gate.send(()).unwrap();
+        sink.flush().await;
+        let capture = read_capture(buffer.0.lock().unwrap().as_slice()).unwrap();
+        assert_eq!(capture.frames[0].data, "01");
+    }
+
+    #[test]
+    fn it_keeps_the_last_header() {
+        let capture = read_capture(
+            concat!(
+                r#"{"header":{"model":"Loupedeck Live S"}}"#,
+                "\n",
+                r#"{"timestamp_us":12,"direction":"out","data":"0303ab"}"#,
+                "\n",
+                r#"{"header":{"model":"Loupedeck Live S","info":{"serial":"LDD0123456789","version":"0.2.5"}}}"#,
+                "\n",
+                r#"{"header":{"model":"Loupedeck Live S"}}"#,
+                "\n",
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(capture.frames.len(), 1);
+        let header = capture.header.unwrap();
+        assert_eq!(header.info, Some(info()));
+        assert_eq!(header.device_model().unwrap(), &LOUPEDECK_LIVE_S);
+
+        let unknown = CaptureHeader {
+            model: "Loupedeck".to_string(),
+            info: None,
+        };
+        assert!(unknown.device_model().is_err());
+    }
+
+    #[tokio::test]
+    async fn it_captures_and_replays_a_session() {
+        let buffer = SharedBuffer::default();
+        let (host, virtual_end) = memory_transport();
+        let virtual_device = VirtualDevice::spawn(virtual_end, info());
+
+        let sink = CaptureSink::new(buffer.clone());
+        let mut device = Device::new("virtual".to_string());
+        device.set_capture(sink.clone());
+        device.connect_transport(host).await.unwrap();
+        device.get_info().await.unwrap();
+
+        let mut rx_event = device.tx_event.as_ref().unwrap().subscribe();
+        virtual_device
+            .inject(ScriptedEvent::KnobRotate {
+                knob: Knob::Knob0,
+                value: 1,
+            })
+            .await
+            .unwrap();
+        next_knob_rotation(&mut rx_event).await;
+        device.disconnect();
+        sink.flush().await;
+
+        let capture = read_capture(buffer.0.lock().unwrap().as_slice()).unwrap();
+        assert_eq!(
+            capture.header,
+            Some(CaptureHeader::new(&LOUPEDECK_LIVE, Some(info())))
+        );
+        let frames = capture.frames;
+        assert!(frames
+            .iter()
+            .any(|frame| frame.direction == FrameDirection::Out));
+        assert!(frames
+            .windows(2)
+            .all(|pair| pair[0].timestamp_us <= pair[1].timestamp_us));
+
+        let events = parse_capture(&frames, &LOUPEDECK_LIVE);
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Ok(Some(Event::KnobRotate(_))))));
+
+        let mut replayed = Device::new("replay".to_string());
+        replayed
+            .connect_transport(replay_transport(frames, false))
+            .await
+            .unwrap();
+        let mut rx_event = replayed.tx_event.as_ref().unwrap().subscribe();
+
+        let replayed_info = replayed.get_info().await.unwrap();
+        assert_eq!(replayed_info.serial, "LDD0123456789");
+
+        next_knob_rotation(&mut rx_event).await;
+
+        replayed.disconnect();
+    }
+
+    #[tokio::test]
+    async fn it_replays_other_models_from_the_header() {
+        let buffer = SharedBuffer::default();
+        let (host, virtual_end) = memory_transport();
+        let virtual_device = VirtualDevice::spawn_model(virtual_end, info(), &LOUPEDECK_LIVE_S);
+
+        let sink = CaptureSink::new(buffer.clone());
+        let mut device = Device::with_model("virtual".to_string(), &LOUPEDECK_LIVE_S);
+        device.set_capture(sink.clone());
+        device.connect_transport(host).await.unwrap();
+        device.get_info().await.unwrap();
+
+        let mut rx_event = device.tx_event.as_ref().unwrap().subscribe();
+        virtual_device
+            .inject(ScriptedEvent::KnobRotate {
+                knob: Knob::Knob1,
+                value: 1,
+            })
+            .await
+            .unwrap();
+        next_knob_rotation(&mut rx_event).await;
+        device.disconnect();
+        sink.flush().await;
+
+        // Without the info replies, the header is all a replay has to go on
+        let capture = read_capture(buffer.0.lock().unwrap().as_slice()).unwrap();
+        let frames: Vec<CapturedFrame> = capture
+            .frames
+            .into_iter()
+            .filter(|frame| {
+                frame.direction == FrameDirection::In
+                    && !matches!(
+                        parse_serial_message(&frame.bytes().unwrap(), &LOUPEDECK_LIVE_S),
+                        Ok(Some(Event::SerialIn(_) | Event::VersionIn(_)))
+                    )
+            })
+            .collect();
+        let header = capture.header.unwrap();
+        let model = header.device_model().unwrap();
+        assert_eq!(model, &LOUPEDECK_LIVE_S);
+
+        let mut replayed = Device::with_model("replay".to_string(), model);
+        replayed
+            .connect_transport(replay_transport(frames, false))
+            .await
+            .unwrap();
+        replayed.set_info(header.info.unwrap());
+        let mut rx_event = replayed.tx_event.as_ref().unwrap().subscribe();
+
+        assert_eq!(replayed.info(), Some(&info()));
+        assert_eq!(replayed.model(), &LOUPEDECK_LIVE_S);
+        next_knob_rotation(&mut rx_event).await;
+
+        replayed.disconnect();
+    }
+}
@@ -0,0 +1,254 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::io::Result;
+use tokio_util::codec::{Decoder, Encoder};
+
+pub(crate) const FRAME_DELIMITER: u8 = 0x82;
+pub(crate) const MAX_SHORT_LENGTH: usize = 125;
+
+const MASK_BIT: u8 = 0x80;
+const LENGTH_16: u8 = 126;
+const LENGTH_64: u8 = 127;
+const MASK_LENGTH: usize = 4;
+
+// Nothing the device sends or receives comes close to this, so a longer length means we
+// locked onto a stray delimiter and should keep looking for the real one.
+const MAX_FRAME_LENGTH: usize = 1024 * 1024;
+
+/// Incremental codec for the binary websocket frames the Loupedeck protocol is wrapped in.
+///
+/// Decoding accepts short, 16-bit and 64-bit lengths with or without a mask, buffers
+/// partial frames until they are complete, and skips bytes that don't start a frame.
+/// Encoding masks frames when acting as the host, like `construct_message_payload` does.
+#[derive(Debug, Clone, Default)]
+pub struct FrameCodec {
+    masked: bool,
+}
+
+impl FrameCodec {
+    /// Codec for the host end of the connection, which masks outgoing frames.
+    pub fn host() -> Self {
+        Self { masked: true }
+    }
+
+    /// Codec for the device end of the connection, which sends unmasked frames.
+    pub fn device() -> Self {
+        Self { masked: false }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Vec<u8>;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>> {
+        loop {
+            // Resynchronise on the next delimiter, dropping anything in front of it
+            match src.iter().position(|b| *b == FRAME_DELIMITER) {
+                Some(start) => src.advance(start),
+                None => {
+                    src.clear();
+                    return Ok(None);
+                }
+            }
+
+            if src.len() < 2 {
+                return Ok(None);
+            }
+
+            let length_byte = src[1];
+            let (length, mut header_length) = match length_byte & !MASK_BIT {
+                LENGTH_16 => {
+                    if src.len() < 4 {
+                        return Ok(None);
+                    }
+                    (u16::from_be_bytes([src[2], src[3]]) as u64, 4)
+                }
+                LENGTH_64 => {
+                    if src.len() < 10 {
+                        return Ok(None);
+                    }
+                    let mut length = [0x00; 8];
+                    length.copy_from_slice(&src[2..10]);
+                    (u64::from_be_bytes(length), 10)
+                }
+                length => (length as u64, 2),
+            };
+
+            if length > MAX_FRAME_LENGTH as u64 {
+                src.advance(1);
+                continue;
+            }
+
+            let length = length as usize;
+            let masked = length_byte & MASK_BIT != 0;
+            if masked {
+                header_length += MASK_LENGTH;
+            }
+
+            if src.len() < header_length + length {
+                src.reserve(header_length + length - src.len());
+                return Ok(None);
+            }
+
+            let mut mask = [0x00; MASK_LENGTH];
+            if masked {
+                mask.copy_from_slice(&src[header_length - MASK_LENGTH..header_length]);
+            }
+
+            src.advance(header_length);
+            let mut message = src.split_to(length).to_vec();
+
+            if masked {
+                for (i, byte) in message.iter_mut().enumerate() {
+                    *byte ^= mask[i % MASK_LENGTH];
+                }
+            }
+
+            return Ok(Some(message));
+        }
+    }
+}
+
+impl Encoder<Vec<u8>> for FrameCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, message: Vec<u8>, dst: &mut BytesMut) -> Result<()> {
+        let mask_bit = if self.masked { MASK_BIT } else { 0x00 };
+
+        dst.reserve(message.len() + 14);
+        dst.put_u8(FRAME_DELIMITER);
+
+        if message.len() <= MAX_SHORT_LENGTH {
+            dst.put_u8(mask_bit | message.len() as u8);
+        } else {
+            dst.put_u8(mask_bit | LENGTH_64);
+            dst.put_u64(message.len() as u64);
+        }
+
+        if self.masked {
+            // The device accepts an all-zero mask, which leaves the payload untouched
+            dst.put_slice(&[0x00; MASK_LENGTH]);
+        }
+
+        dst.put_slice(&message);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameCodec;
+    use crate::loupedeck::construct_message_payload;
+    use bytes::BytesMut;
+    use proptest::prelude::*;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    fn message() -> impl Strategy<Value = Vec<u8>> {
+        prop_oneof![
+            prop::collection::vec(any::<u8>(), 3..=125),
+            prop::collection::vec(any::<u8>(), 126..=300),
+            prop::collection::vec(any::<u8>(), 16000..=16300),
+        ]
+    }
+
+    fn decode_all(codec: &mut FrameCodec, src: &mut BytesMut) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        while let Some(message) = codec.decode(src).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[test]
+    fn it_decodes_device_frames() {
+        let mut src = BytesMut::from(&[0x82, 0x05, 0x05, 0x00, 0x00, 0x08, 0x00][..]);
+        let message = FrameCodec::host().decode(&mut src).unwrap();
+
+        assert_eq!(message, Some(vec![0x05, 0x00, 0x00, 0x08, 0x00]));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn it_decodes_16_bit_lengths() {
+        let mut src = BytesMut::from(&[0x82, 0x7e, 0x00, 0x80][..]);
+        src.extend_from_slice(&[0xab; 0x80]);
+
+        let message = FrameCodec::host().decode(&mut src).unwrap();
+        assert_eq!(message, Some(vec![0xab; 0x80]));
+    }
+
+    #[test]
+    fn it_unmasks_frames() {
+        let mut src = BytesMut::from(&[0x82, 0x83, 0x01, 0x02, 0x03, 0x04, 0x01, 0x02, 0x03][..]);
+        let message = FrameCodec::device().decode(&mut src).unwrap();
+
+        assert_eq!(message, Some(vec![0x00, 0x00, 0x00]));
+    }
+
+    #[test]
+    fn it_waits_for_partial_frames() {
+        let mut codec = FrameCodec::host();
+        let mut src = BytesMut::from(&[0x82, 0x05, 0x05, 0x00][..]);
+
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+
+        src.extend_from_slice(&[0x00, 0x08, 0x00]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(vec![0x05, 0x00, 0x00, 0x08, 0x00])
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn it_round_trips_the_message_payload_encoder(mut message in message(), tx_id in any::<u8>()) {
+            let payload = construct_message_payload(message.clone(), tx_id);
+            message[2] = tx_id;
+
+            let mut src = BytesMut::from(payload.as_slice());
+            prop_assert_eq!(FrameCodec::device().decode(&mut src).unwrap(), Some(message));
+            prop_assert!(src.is_empty());
+        }
+
+        #[test]
+        fn it_encodes_like_the_message_payload_encoder(message in message()) {
+            let tx_id = message[2];
+            let mut dst = BytesMut::new();
+            FrameCodec::host().encode(message.clone(), &mut dst).unwrap();
+
+            prop_assert_eq!(dst.to_vec(), construct_message_payload(message, tx_id));
+        }
+
+        #[test]
+        fn it_decodes_frames_split_at_any_point(
+            messages in prop::collection::vec(message(), 1..4),
+            split in any::<prop::sample::Index>(),
+        ) {
+            let mut codec = FrameCodec::device();
+            let mut encoded = BytesMut::new();
+            for message in &messages {
+                codec.encode(message.clone(), &mut encoded).unwrap();
+            }
+
+            let split = split.index(encoded.len());
+            let mut src = BytesMut::from(&encoded[..split]);
+            let mut decoded = decode_all(&mut codec, &mut src);
+            src.extend_from_slice(&encoded[split..]);
+            decoded.extend(decode_all(&mut codec, &mut src));
+
+            prop_assert_eq!(decoded, messages);
+        }
+
+        #[test]
+        fn it_resynchronises_after_garbage(
+            garbage in prop::collection::vec(any::<u8>().prop_filter("not a delimiter", |b| *b != 0x82), 0..64),
+            message in message(),
+        ) {
+            let mut src = BytesMut::from(garbage.as_slice());
+            FrameCodec::host().encode(message.clone(), &mut src).unwrap();
+
+            prop_assert_eq!(decode_all(&mut FrameCodec::host(), &mut src), vec![message]);
+        }
+    }
+}
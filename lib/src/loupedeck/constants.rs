@@ -1,10 +1,13 @@
 use serde::{Deserialize, Serialize};
 
+use crate::LoupedeckError;
+
 #[derive(Debug, Clone, PartialEq)]
 #[repr(u16)]
 pub enum MessageHeader {
     Confirm = 0x0302,
     SerialOut = 0x0303,
+    McuOut = 0x030d,
     VersionOut = 0x0307,
     Tick = 0x0400,
     SetBrightness = 0x0409,
@@ -18,17 +21,22 @@ pub enum MessageHeader {
     SetColor = 0x0702,
     TouchDown = 0x094d,
     TouchUp = 0x096d,
+    WheelTouchDown = 0x0952,
+    WheelTouchUp = 0x0972,
     VersionIn = 0x0c07,
     MCU = 0x180d,
     SerialIn = 0x1f03,
     WriteFrameBuffer = 0xff10,
 }
 
-impl From<u16> for MessageHeader {
-    fn from(value: u16) -> MessageHeader {
-        match value {
+impl TryFrom<u16> for MessageHeader {
+    type Error = LoupedeckError;
+
+    fn try_from(value: u16) -> Result<MessageHeader, LoupedeckError> {
+        let header = match value {
             0x0302 => MessageHeader::Confirm,
             0x0303 => MessageHeader::SerialOut,
+            0x030d => MessageHeader::McuOut,
             0x0307 => MessageHeader::VersionOut,
             0x0400 => MessageHeader::Tick,
             0x0409 => MessageHeader::SetBrightness,
@@ -42,12 +50,16 @@ impl From<u16> for MessageHeader {
             0x0702 => MessageHeader::SetColor,
             0x094d => MessageHeader::TouchDown,
             0x096d => MessageHeader::TouchUp,
+            0x0952 => MessageHeader::WheelTouchDown,
+            0x0972 => MessageHeader::WheelTouchUp,
             0x0c07 => MessageHeader::VersionIn,
             0x180d => MessageHeader::MCU,
             0x1f03 => MessageHeader::SerialIn,
             0xff10 => MessageHeader::WriteFrameBuffer,
-            _ => panic!("Unknown message header: {}", value),
-        }
+            _ => return Err(LoupedeckError::protocol(&value.to_be_bytes())),
+        };
+
+        Ok(header)
     }
 }
 
@@ -57,9 +69,10 @@ impl From<MessageHeader> for Vec<u8> {
     }
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(u8)]
 pub enum Button {
+    Wheel = 0x00,
     Knob0 = 0x01,
     Knob1 = 0x02,
     Knob2 = 0x03,
@@ -74,11 +87,25 @@ pub enum Button {
     Circle5 = 0x0c,
     Circle6 = 0x0d,
     Circle7 = 0x0e,
+    // Only on the CT
+    CtHome = 0x0f,
+    Enter = 0x10,
+    Undo = 0x11,
+    Save = 0x12,
+    Keyboard = 0x13,
+    FnLeft = 0x14,
+    A = 0x15,
+    FnRight = 0x16,
+    B = 0x17,
+    C = 0x18,
+    D = 0x19,
+    E = 0x1a,
 }
 
 impl Button {
     pub fn from_u8(value: u8) -> Option<Button> {
         match value {
+            0x00 => Some(Button::Wheel),
             0x01 => Some(Button::Knob0),
             0x02 => Some(Button::Knob1),
             0x03 => Some(Button::Knob2),
@@ -93,17 +120,32 @@ impl Button {
             0x0c => Some(Button::Circle5),
             0x0d => Some(Button::Circle6),
             0x0e => Some(Button::Circle7),
+            0x0f => Some(Button::CtHome),
+            0x10 => Some(Button::Enter),
+            0x11 => Some(Button::Undo),
+            0x12 => Some(Button::Save),
+            0x13 => Some(Button::Keyboard),
+            0x14 => Some(Button::FnLeft),
+            0x15 => Some(Button::A),
+            0x16 => Some(Button::FnRight),
+            0x17 => Some(Button::B),
+            0x18 => Some(Button::C),
+            0x19 => Some(Button::D),
+            0x1a => Some(Button::E),
             _ => None,
         }
     }
 }
 
-#[derive(Debug, Serialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(u16)]
 pub enum Screen {
     Left,
     Center,
     Right,
+    // The single screen on devices that don't split it up, like the Live S
+    Main,
+    Wheel,
 }
 
 impl From<Screen> for u16 {
@@ -112,17 +154,26 @@ impl From<Screen> for u16 {
             Screen::Left => 0x004C,
             Screen::Center => 0x0041,
             Screen::Right => 0x0052,
+            Screen::Main => 0x004D,
+            Screen::Wheel => 0x0057,
         }
     }
 }
 
-impl From<u8> for Screen {
-    fn from(value: u8) -> Screen {
+impl TryFrom<u8> for Screen {
+    type Error = LoupedeckError;
+
+    fn try_from(value: u8) -> Result<Screen, LoupedeckError> {
         match value {
-            0x4C => Screen::Left,
-            0x41 => Screen::Center,
-            0x52 => Screen::Right,
-            _ => panic!("Unknown screen: {}", value),
+            0x4C => Ok(Screen::Left),
+            0x41 => Ok(Screen::Center),
+            0x52 => Ok(Screen::Right),
+            0x4D => Ok(Screen::Main),
+            0x57 => Ok(Screen::Wheel),
+            _ => Err(LoupedeckError::InvalidInput(format!(
+                "Unknown screen: {:#04x}",
+                value
+            ))),
         }
     }
 }
@@ -130,18 +181,12 @@ impl From<u8> for Screen {
 impl From<Screen> for Vec<u8> {
     fn from(value: Screen) -> Vec<u8> {
         let base = u16::from(value);
-        vec![((base.clone() as u16) >> 8) as u8, base.clone() as u8]
+        vec![(base >> 8) as u8, base as u8]
     }
 }
 
-struct Display {
-    id: u16,
-    width: u16,
-    height: u16,
-}
-
 // See https://github.com/foxxyz/loupedeck/blob/master/constants.js#L50
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[repr(u8)]
 pub enum Haptic {
     Short = 0x01,
@@ -174,20 +219,10 @@ pub enum Haptic {
     VeryLong = 0x76, // 10 sec high freq (!)
 }
 
-impl Screen {
-    pub fn from_x_coor(x: u16) -> Option<Screen> {
-        match x {
-            0..=60 => Some(Screen::Left),
-            61..=420 => Some(Screen::Center),
-            421..=480 => Some(Screen::Right),
-            _ => None,
-        }
-    }
-}
-
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(u8)]
 pub enum Knob {
+    Wheel = 0x00,
     Knob0 = 0x01,
     Knob1 = 0x02,
     Knob2 = 0x03,
@@ -199,6 +234,7 @@ pub enum Knob {
 impl Knob {
     pub fn from_u8(value: u8) -> Option<Knob> {
         match value {
+            0x00 => Some(Knob::Wheel),
             0x01 => Some(Knob::Knob0),
             0x02 => Some(Knob::Knob1),
             0x03 => Some(Knob::Knob2),
@@ -210,7 +246,7 @@ impl Knob {
     }
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[repr(u8)]
 pub enum PressDirection {
     Up = 0x00,
@@ -227,21 +263,21 @@ impl PressDirection {
     }
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ButtonPressEvent {
     pub tx_id: u8,
     pub button: Button,
     pub dir: PressDirection,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct KnobRotateEvent {
     pub tx_id: u8,
     pub knob: Knob,
     pub value: i8,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TouchEvent {
     pub tx_id: u8,
     pub dir: PressDirection,
@@ -277,6 +313,18 @@ pub struct DrawInEvent {
     pub tx_id: u8,
 }
 
+#[derive(Debug, Serialize, Clone)]
+pub struct McuInEvent {
+    pub tx_id: u8,
+    pub mcu_id: String,
+}
+
+/// Keepalive the device sends periodically while it is connected.
+#[derive(Debug, Serialize, Clone)]
+pub struct TickEvent {
+    pub tx_id: u8,
+}
+
 #[derive(Debug, Serialize, Clone)]
 pub enum Event {
     ButtonPress(ButtonPressEvent),
@@ -287,9 +335,25 @@ pub enum Event {
     VersionIn(VersionInEvent),
     ConfirmFrameBufferIn(ConfirmFrameBufferInEvent),
     DrawIn(DrawInEvent),
+    McuIn(McuInEvent),
+    Tick(TickEvent),
 }
 
-pub static KEY_SIZE: u16 = 90;
+pub const MAX_BRIGHTNESS: u8 = 10;
+
+/// Colour of a button's LED.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub fn new(r: u8, g: u8, b: u8) -> Rgb {
+        Rgb { r, g, b }
+    }
+}
 
 #[derive(Debug, Serialize, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
 pub struct KeyLocation {
@@ -301,10 +365,4 @@ impl KeyLocation {
     pub fn new(x: u8, y: u8) -> KeyLocation {
         KeyLocation { x, y }
     }
-
-    pub fn from_location(raw_x: u16, raw_y: u16) -> KeyLocation {
-        let x = ((raw_x - 60) / KEY_SIZE) as u8;
-        let y = (raw_y / KEY_SIZE) as u8;
-        KeyLocation { x, y }
-    }
 }
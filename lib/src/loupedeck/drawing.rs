@@ -0,0 +1,285 @@
+use image::imageops::{self, FilterType};
+use image::{Rgba, RgbaImage};
+use resvg::{tiny_skia, usvg};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::{LoupedeckError, PixelPipeline, Result};
+
+/// Where to load an image from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageSource {
+    Path(PathBuf),
+    /// An encoded PNG, JPEG, GIF or SVG file.
+    Bytes(Vec<u8>),
+}
+
+impl From<PathBuf> for ImageSource {
+    fn from(path: PathBuf) -> Self {
+        ImageSource::Path(path)
+    }
+}
+
+impl From<&Path> for ImageSource {
+    fn from(path: &Path) -> Self {
+        ImageSource::Path(path.to_path_buf())
+    }
+}
+
+impl From<&str> for ImageSource {
+    fn from(path: &str) -> Self {
+        ImageSource::Path(path.into())
+    }
+}
+
+impl From<Vec<u8>> for ImageSource {
+    fn from(bytes: Vec<u8>) -> Self {
+        ImageSource::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for ImageSource {
+    fn from(bytes: &[u8]) -> Self {
+        ImageSource::Bytes(bytes.to_vec())
+    }
+}
+
+/// How an image is sized to a region with a different aspect ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fit {
+    /// Scale to fit inside the region, leaving black bars.
+    #[default]
+    Contain,
+    /// Scale to cover the region, cropping the overflow from the middle.
+    Fill,
+    /// Scale each axis to the region, ignoring the aspect ratio.
+    Stretch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ImageOptions {
+    pub fit: Fit,
+    /// How the image is turned into RGB565, e.g. to dither gradients and photos.
+    pub pipeline: PixelPipeline,
+}
+
+impl From<Fit> for ImageOptions {
+    fn from(fit: Fit) -> Self {
+        Self {
+            fit,
+            pipeline: PixelPipeline::default(),
+        }
+    }
+}
+
+/// Loads an image and renders it to `width` x `height` little-endian RGB565 pixels.
+///
+/// SVGs are rasterized at the target size rather than scaled afterwards.
+pub fn render_image(
+    source: impl Into<ImageSource>,
+    width: u16,
+    height: u16,
+    options: impl Into<ImageOptions>,
+) -> Result<Vec<u8>> {
+    let options = options.into();
+    let (data, is_svg) = read_source(source.into())?;
+
+    let image = if is_svg {
+        rasterize_svg(&data, width as u32, height as u32, options.fit)?
+    } else {
+        let image = image::load_from_memory(&data)
+            .map_err(|e| LoupedeckError::Image(e.to_string()))?
+            .into_rgba8();
+        fit_image(&image, width as u32, height as u32, options.fit)
+    };
+
+    Ok(convert_rgba_to_rgb565(&image, &options.pipeline))
+}
+
+/// The encoded file, and whether it's an SVG rather than a raster image.
+pub(crate) fn read_source(source: ImageSource) -> Result<(Vec<u8>, bool)> {
+    match source {
+        ImageSource::Path(path) => {
+            let is_svg = path
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("svg"));
+            Ok((std::fs::read(path)?, is_svg))
+        }
+        ImageSource::Bytes(data) => {
+            let is_svg = looks_like_svg(&data);
+            Ok((data, is_svg))
+        }
+    }
+}
+
+fn looks_like_svg(data: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&data[..data.len().min(256)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+
+    head.starts_with("<svg") || (head.starts_with("<?xml") && head.contains("<svg"))
+}
+
+/// Scales and crops an image to exactly `width` x `height`.
+pub fn fit_image(image: &RgbaImage, width: u32, height: u32, fit: Fit) -> RgbaImage {
+    let (sx, sy) = scale(
+        image.width() as f32,
+        image.height() as f32,
+        width,
+        height,
+        fit,
+    );
+    let scaled_width = ((image.width() as f32 * sx).round() as u32).max(1);
+    let scaled_height = ((image.height() as f32 * sy).round() as u32).max(1);
+    let scaled = imageops::resize(image, scaled_width, scaled_height, FilterType::Triangle);
+
+    match fit {
+        Fit::Stretch => scaled,
+        Fit::Fill => imageops::crop_imm(
+            &scaled,
+            scaled_width.saturating_sub(width) / 2,
+            scaled_height.saturating_sub(height) / 2,
+            width,
+            height,
+        )
+        .to_image(),
+        Fit::Contain => {
+            let mut canvas = RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 0]));
+            imageops::overlay(
+                &mut canvas,
+                &scaled,
+                (width.saturating_sub(scaled_width) / 2) as i64,
+                (height.saturating_sub(scaled_height) / 2) as i64,
+            );
+            canvas
+        }
+    }
+}
+
+fn scale(image_width: f32, image_height: f32, width: u32, height: u32, fit: Fit) -> (f32, f32) {
+    let sx = width as f32 / image_width;
+    let sy = height as f32 / image_height;
+
+    match fit {
+        Fit::Contain => (sx.min(sy), sx.min(sy)),
+        Fit::Fill => (sx.max(sy), sx.max(sy)),
+        Fit::Stretch => (sx, sy),
+    }
+}
+
+fn rasterize_svg(data: &[u8], width: u32, height: u32, fit: Fit) -> Result<RgbaImage> {
+    let tree = usvg::Tree::from_data(data, &usvg::Options::default())
+        .map_err(|e| LoupedeckError::Image(e.to_string()))?;
+    let size = tree.size();
+    let (sx, sy) = scale(size.width(), size.height(), width, height, fit);
+
+    let mut pixmap = tiny_skia::Pixmap::new(width, height).ok_or_else(|| {
+        LoupedeckError::Image(format!("Can't rasterize an SVG to {}x{}", width, height))
+    })?;
+    let transform = tiny_skia::Transform::from_row(
+        sx,
+        0.0,
+        0.0,
+        sy,
+        (width as f32 - size.width() * sx) / 2.0,
+        (height as f32 - size.height() * sy) / 2.0,
+    );
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    let rgba = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let pixel = pixel.demultiply();
+            [pixel.red(), pixel.green(), pixel.blue(), pixel.alpha()]
+        })
+        .collect();
+
+    Ok(RgbaImage::from_raw(width, height, rgba).expect("pixmap matches its size"))
+}
+
+/// Converts an image to RGB565 with `pipeline`.
+pub fn convert_rgba_to_rgb565(image: &RgbaImage, pipeline: &PixelPipeline) -> Vec<u8> {
+    pipeline.convert_rgba(image.as_raw(), image.width() as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::{convert_rgba_to_rgb565, fit_image, render_image, Fit};
+    use crate::{Dither, PixelPipeline, Snapshot};
+
+    fn pixel(data: &[u8], width: usize, x: usize, y: usize) -> u16 {
+        let i = (y * width + x) * 2;
+        u16::from_le_bytes([data[i], data[i + 1]])
+    }
+
+    #[test]
+    fn it_fits_images_to_a_region() {
+        let image = RgbaImage::from_pixel(200, 100, Rgba([255, 0, 0, 255]));
+
+        let contained = fit_image(&image, 90, 90, Fit::Contain);
+        assert_eq!(contained.dimensions(), (90, 90));
+        assert_eq!(contained.get_pixel(45, 0).0[3], 0);
+        assert_eq!(contained.get_pixel(45, 45).0, [255, 0, 0, 255]);
+
+        let filled = fit_image(&image, 90, 90, Fit::Fill);
+        assert_eq!(filled.dimensions(), (90, 90));
+        assert!(filled.pixels().all(|pixel| pixel.0 == [255, 0, 0, 255]));
+
+        assert_eq!(
+            fit_image(&image, 60, 270, Fit::Stretch).dimensions(),
+            (60, 270)
+        );
+    }
+
+    #[test]
+    fn it_decodes_pngs() {
+        let png = Snapshot::from_rgb565(2, 2, &[0xf800, 0x07e0, 0x001f, 0xffff])
+            .to_png()
+            .unwrap();
+
+        let data = render_image(png, 2, 2, Fit::Stretch).unwrap();
+        assert_eq!(data.len(), 8);
+        assert_eq!(pixel(&data, 2, 0, 0), 0xf800);
+        assert_eq!(pixel(&data, 2, 1, 1), 0xffff);
+
+        assert!(render_image(vec![1, 2, 3], 2, 2, Fit::Stretch).is_err());
+    }
+
+    #[test]
+    fn it_rasterizes_svgs() {
+        let svg = br##"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="20">
+            <rect width="10" height="20" fill="#0000ff"/>
+        </svg>"##;
+
+        let data = render_image(&svg[..], 90, 90, Fit::Contain).unwrap();
+        assert_eq!(data.len(), 90 * 90 * 2);
+        assert_eq!(pixel(&data, 90, 45, 45), 0x001f);
+        assert_eq!(pixel(&data, 90, 5, 45), 0x0000);
+
+        let data = render_image(&svg[..], 90, 90, Fit::Fill).unwrap();
+        assert_eq!(pixel(&data, 90, 0, 0), 0x001f);
+    }
+
+    #[test]
+    fn it_dithers_gradients() {
+        // A gray between two RGB565 levels
+        let image = RgbaImage::from_pixel(16, 16, Rgba([0x84 + 4, 0x84 + 4, 0x84 + 4, 255]));
+
+        let plain = convert_rgba_to_rgb565(&image, &PixelPipeline::default());
+        assert!(plain.chunks(2).all(|pixel| pixel == plain[..2].to_vec()));
+
+        let pipeline = PixelPipeline {
+            dither: Dither::FloydSteinberg,
+            ..PixelPipeline::default()
+        };
+        let dithered = convert_rgba_to_rgb565(&image, &pipeline);
+        let reds: Vec<u16> = dithered
+            .chunks(2)
+            .map(|pixel| u16::from_le_bytes([pixel[0], pixel[1]]) >> 11)
+            .collect();
+        assert!(reds.contains(&16) && reds.contains(&17));
+    }
+}
@@ -0,0 +1,633 @@
+use bytes::BytesMut;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf};
+use tokio::sync::mpsc;
+use tokio::time;
+use tokio_util::codec::{Encoder, FramedRead};
+
+use crate::{
+    memory_transport, Button, Device, DeviceInfo, DeviceModel, FrameCodec, Knob, LoupedeckError,
+    MessageHeader, PressDirection, Result, Rgb, Screen, ScreenLayout, Snapshot, Transport,
+    LOUPEDECK_LIVE,
+};
+
+const SERIAL_NUMBER_LENGTH: usize = 28;
+const VERSION_PADDING_LENGTH: usize = 6;
+const MCU_ID: [u8; 12] = [
+    0x00, 0x2e, 0x00, 0x3c, 0x31, 0x39, 0x51, 0x0c, 0x34, 0x37, 0x32, 0x39,
+];
+const MCU_PADDING_LENGTH: usize = 9;
+
+pub(crate) const WS_UPGRADE_ACCEPT: &str = "HTTP/1.1 101 Switching Protocols\r
+Upgrade: websocket\r
+Connection: Upgrade\r
+Sec-WebSocket-Accept: ALtlZo9FMEUEQleXJmq++ukUQ1s=\r
+\r
+";
+
+/// An input a `VirtualDevice` can send to the host as if it came from the hardware.
+#[derive(Debug, Clone)]
+pub enum ScriptedEvent {
+    ButtonPress { button: Button, dir: PressDirection },
+    KnobRotate { knob: Knob, value: i8 },
+    TouchDown { x: u16, y: u16, touch_id: u8 },
+    TouchUp { x: u16, y: u16, touch_id: u8 },
+    Tick,
+    Wait(Duration),
+}
+
+struct VirtualDeviceState {
+    model: &'static DeviceModel,
+    // Pixels written with WriteFrameBuffer but not yet presented with DrawOut
+    back_buffer: Vec<u16>,
+    front_buffer: Vec<u16>,
+    vibrations: Vec<u8>,
+    brightness: Option<u8>,
+    button_colors: HashMap<u8, Rgb>,
+    resets: usize,
+    messages: Vec<Vec<u8>>,
+}
+
+impl VirtualDeviceState {
+    fn new(model: &'static DeviceModel) -> Self {
+        let (width, height) = model.surface_size();
+        let size = width as usize * height as usize;
+
+        Self {
+            model,
+            back_buffer: vec![0; size],
+            front_buffer: vec![0; size],
+            vibrations: Vec::new(),
+            brightness: None,
+            button_colors: HashMap::new(),
+            resets: 0,
+            messages: Vec::new(),
+        }
+    }
+
+    fn surface_width(&self) -> usize {
+        self.model.surface_size().0 as usize
+    }
+
+    // Only screens on the main surface are emulated, so draws to the CT's wheel are dropped
+    fn layout(&self, screen: &Screen) -> Option<&'static ScreenLayout> {
+        self.model
+            .screens
+            .iter()
+            .find(|layout| layout.screen == *screen)
+    }
+
+    fn write_frame_buffer(&mut self, data: &[u8]) {
+        if data.len() < 10 {
+            return;
+        }
+
+        let layout = match Screen::try_from(data[1])
+            .ok()
+            .and_then(|screen| self.layout(&screen))
+        {
+            Some(layout) => layout,
+            None => return,
+        };
+        let x = u16::from_be_bytes([data[2], data[3]]);
+        let y = u16::from_be_bytes([data[4], data[5]]);
+        let width = u16::from_be_bytes([data[6], data[7]]);
+        let height = u16::from_be_bytes([data[8], data[9]]);
+        let surface_width = self.surface_width();
+
+        if width == 0 {
+            return;
+        }
+
+        for (i, px) in data[10..].chunks_exact(2).enumerate() {
+            let px_x = x as usize + i % width as usize;
+            let px_y = y as usize + i / width as usize;
+
+            if px_x >= layout.width as usize
+                || px_y >= layout.height as usize
+                || px_y >= y as usize + height as usize
+            {
+                continue;
+            }
+
+            let index = (layout.y as usize + px_y) * surface_width + layout.x as usize + px_x;
+            self.back_buffer[index] = self.model.byte_order.decode([px[0], px[1]]);
+        }
+    }
+
+    fn present(&mut self, screen: &Screen) {
+        let layout = match self.layout(screen) {
+            Some(layout) => layout,
+            None => return,
+        };
+        let surface_width = self.surface_width();
+
+        for y in layout.y as usize..(layout.y + layout.height) as usize {
+            let start = y * surface_width + layout.x as usize;
+            let end = start + layout.width as usize;
+            self.front_buffer[start..end].copy_from_slice(&self.back_buffer[start..end]);
+        }
+    }
+}
+
+/// A software Loupedeck that speaks the device side of the serial protocol.
+///
+/// It answers the websocket upgrade and info requests, keeps a framebuffer of what the
+/// host has drawn, and can send button, knob and touch input back to the host.
+#[derive(Clone)]
+pub struct VirtualDevice {
+    state: Arc<Mutex<VirtualDeviceState>>,
+    tx_outgoing: mpsc::Sender<Vec<u8>>,
+}
+
+/// Connects a `Device` to a newly spawned `VirtualDevice` over an in-memory transport.
+pub async fn connect_virtual_device(info: DeviceInfo) -> Result<(Device, VirtualDevice)> {
+    connect_virtual_model(info, &LOUPEDECK_LIVE).await
+}
+
+/// Connects a virtual Loupedeck Live, with a page on it for plugins to draw to.
+#[cfg(test)]
+pub(crate) async fn connect_test_page() -> (
+    Device,
+    VirtualDevice,
+    crate::ExternalDeviceEventEmitter,
+    Arc<crate::PageView>,
+) {
+    let (device, virtual_device) = connect_virtual_device(DeviceInfo {
+        serial: "LDD1".to_string(),
+        version: "0.1.0".to_string(),
+    })
+    .await
+    .unwrap();
+    let emitter = device.create_external_event_emitter().unwrap();
+    let view = crate::PageView::new(emitter.clone());
+
+    (device, virtual_device, emitter, view)
+}
+
+/// Like `connect_virtual_device`, but emulating the given model.
+pub async fn connect_virtual_model(
+    info: DeviceInfo,
+    model: &'static DeviceModel,
+) -> Result<(Device, VirtualDevice)> {
+    let (host, virtual_end) = memory_transport();
+    let virtual_device = VirtualDevice::spawn_model(virtual_end, info, model);
+
+    let mut device = Device::with_model("virtual".to_string(), model);
+    device.connect_transport(host).await?;
+
+    Ok((device, virtual_device))
+}
+
+impl VirtualDevice {
+    /// Starts emulating a Loupedeck Live on the given transport, using the ambient tokio runtime.
+    pub fn spawn<T: Transport>(transport: T, info: DeviceInfo) -> VirtualDevice {
+        VirtualDevice::spawn_model(transport, info, &LOUPEDECK_LIVE)
+    }
+
+    /// Starts emulating the given model on the given transport.
+    pub fn spawn_model<T: Transport>(
+        transport: T,
+        info: DeviceInfo,
+        model: &'static DeviceModel,
+    ) -> VirtualDevice {
+        let state = Arc::new(Mutex::new(VirtualDeviceState::new(model)));
+        let (tx_outgoing, mut rx_outgoing) = mpsc::channel::<Vec<u8>>(100);
+        let (mut reader, mut writer) = tokio::io::split(transport);
+
+        tokio::spawn(async move {
+            while let Some(message) = rx_outgoing.recv().await {
+                if writer.write_all(&message).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let reader_state = state.clone();
+        let tx_reply = tx_outgoing.clone();
+
+        tokio::spawn(async move {
+            if accept_upgrade(&mut reader, &tx_reply).await.is_err() {
+                return;
+            }
+
+            let mut frames = FramedRead::new(reader, FrameCodec::device());
+
+            while let Some(Ok(message)) = frames.next().await {
+                let reply = handle_message(&reader_state, &info, &message);
+                if let Some(reply) = reply {
+                    if tx_reply.send(frame(reply)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        VirtualDevice { state, tx_outgoing }
+    }
+
+    /// Sends a single input event to the host.
+    pub async fn inject(&self, event: ScriptedEvent) -> Result<()> {
+        let message = match event {
+            ScriptedEvent::ButtonPress { button, dir } => {
+                let dir = match dir {
+                    PressDirection::Down => 0x00,
+                    PressDirection::Up => 0x01,
+                };
+                vec![0x05, 0x00, 0x00, button as u8, dir]
+            }
+            ScriptedEvent::KnobRotate { knob, value } => {
+                vec![0x05, 0x01, 0x00, knob as u8, value as u8]
+            }
+            ScriptedEvent::TouchDown { x, y, touch_id } => touch_message(0x4d, x, y, touch_id),
+            ScriptedEvent::TouchUp { x, y, touch_id } => touch_message(0x6d, x, y, touch_id),
+            ScriptedEvent::Tick => Vec::from(MessageHeader::Tick)
+                .into_iter()
+                .chain([0x00])
+                .collect(),
+            ScriptedEvent::Wait(duration) => {
+                time::sleep(duration).await;
+                return Ok(());
+            }
+        };
+
+        self.tx_outgoing
+            .send(frame(message))
+            .await
+            .map_err(|_| LoupedeckError::Disconnected)
+    }
+
+    /// Sends a sequence of input events to the host, in order.
+    pub async fn play(&self, script: &[ScriptedEvent]) -> Result<()> {
+        for event in script {
+            self.inject(event.clone()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// What is currently shown on the whole touch surface, as RGB565 pixels.
+    pub fn framebuffer(&self) -> Vec<u16> {
+        self.state.lock().unwrap().front_buffer.clone()
+    }
+
+    /// What is currently shown on a single screen, as RGB565 pixels.
+    pub fn screen_buffer(&self, screen: Screen) -> Vec<u16> {
+        let state = self.state.lock().unwrap();
+        let surface_width = state.surface_width();
+        let layout = match state.layout(&screen) {
+            Some(layout) => layout,
+            None => return Vec::new(),
+        };
+
+        (layout.y as usize..(layout.y + layout.height) as usize)
+            .flat_map(|y| {
+                let start = y * surface_width + layout.x as usize;
+                state.front_buffer[start..start + layout.width as usize].to_vec()
+            })
+            .collect()
+    }
+
+    /// Renders what is currently shown on the main touch surface.
+    pub fn snapshot(&self) -> Snapshot {
+        let state = self.state.lock().unwrap();
+        let (width, height) = state.model.surface_size();
+
+        Snapshot::from_rgb565(width, height, &state.front_buffer)
+    }
+
+    /// The RGB565 pixel currently shown at the given surface coordinates.
+    pub fn pixel(&self, x: u16, y: u16) -> u16 {
+        let state = self.state.lock().unwrap();
+        state.front_buffer[y as usize * state.surface_width() + x as usize]
+    }
+
+    /// The model being emulated.
+    pub fn model(&self) -> &'static DeviceModel {
+        self.state.lock().unwrap().model
+    }
+
+    /// Every haptic level the host has requested, in order.
+    pub fn vibrations(&self) -> Vec<u8> {
+        self.state.lock().unwrap().vibrations.clone()
+    }
+
+    /// The last brightness the host set, if it set one.
+    pub fn brightness(&self) -> Option<u8> {
+        self.state.lock().unwrap().brightness
+    }
+
+    /// The colour the host last set for each button, by button id.
+    pub fn button_colors(&self) -> HashMap<u8, Rgb> {
+        self.state.lock().unwrap().button_colors.clone()
+    }
+
+    /// How many times the host has asked the device to reset.
+    pub fn resets(&self) -> usize {
+        self.state.lock().unwrap().resets
+    }
+
+    /// Every message the host has sent since the websocket upgrade, without framing.
+    pub fn messages(&self) -> Vec<Vec<u8>> {
+        self.state.lock().unwrap().messages.clone()
+    }
+}
+
+fn touch_message(action: u8, x: u16, y: u16, touch_id: u8) -> Vec<u8> {
+    let mut message = vec![0x09, action, 0x00, 0x00];
+    message.extend_from_slice(&x.to_be_bytes());
+    message.extend_from_slice(&y.to_be_bytes());
+    message.push(touch_id);
+    message
+}
+
+fn frame(message: Vec<u8>) -> Vec<u8> {
+    let mut framed = BytesMut::new();
+    FrameCodec::device()
+        .encode(message, &mut framed)
+        .expect("encoding into memory cannot fail");
+    framed.to_vec()
+}
+
+fn handle_message(
+    state: &Mutex<VirtualDeviceState>,
+    info: &DeviceInfo,
+    message: &[u8],
+) -> Option<Vec<u8>> {
+    if message.len() < 3 {
+        return None;
+    }
+
+    let mut state = state.lock().unwrap();
+    state.messages.push(message.to_vec());
+
+    let header = u16::from_be_bytes([message[0], message[1]]);
+    let tx_id = message[2];
+
+    if header == MessageHeader::SerialOut as u16 {
+        let mut reply = Vec::from(MessageHeader::SerialIn);
+        reply.push(tx_id);
+        reply.extend_from_slice(
+            format!("{:<width$}", info.serial, width = SERIAL_NUMBER_LENGTH).as_bytes(),
+        );
+        Some(reply)
+    } else if header == MessageHeader::VersionOut as u16 {
+        let mut reply = Vec::from(MessageHeader::VersionIn);
+        reply.push(tx_id);
+        reply.extend(
+            info.version
+                .split('.')
+                .map(|v| v.parse::<u8>().unwrap_or(0)),
+        );
+        reply.resize(reply.len() + VERSION_PADDING_LENGTH, 0x00);
+        Some(reply)
+    } else if header == MessageHeader::WriteFrameBuffer as u16 {
+        state.write_frame_buffer(&message[3..]);
+        Some(vec![0x04, 0x10, tx_id])
+    } else if header == MessageHeader::DrawOut as u16 && message.len() >= 5 {
+        if let Ok(screen) = Screen::try_from(message[4]) {
+            state.present(&screen);
+        }
+        Some(vec![0x04, 0x0f, tx_id])
+    } else if header == MessageHeader::SetVibration as u16 && message.len() >= 4 {
+        state.vibrations.push(message[3]);
+        None
+    } else if header == MessageHeader::SetBrightness as u16 && message.len() >= 4 {
+        state.brightness = Some(message[3]);
+        None
+    } else if header == MessageHeader::SetColor as u16 && message.len() >= 7 {
+        let color = Rgb::new(message[4], message[5], message[6]);
+        state.button_colors.insert(message[3], color);
+        None
+    } else if header == MessageHeader::Reset as u16 {
+        state.resets += 1;
+        None
+    } else if header == MessageHeader::McuOut as u16 {
+        let mut reply = Vec::from(MessageHeader::MCU);
+        reply.push(tx_id);
+        reply.extend_from_slice(&MCU_ID);
+        reply.resize(reply.len() + MCU_PADDING_LENGTH, 0x00);
+        Some(reply)
+    } else {
+        None
+    }
+}
+
+async fn accept_upgrade<T: Transport>(
+    reader: &mut ReadHalf<T>,
+    tx_reply: &mpsc::Sender<Vec<u8>>,
+) -> Result<()> {
+    let mut buf = vec![0; 1024];
+    let read = reader.read(buf.as_mut_slice()).await?;
+
+    if !String::from_utf8_lossy(&buf[..read]).contains("Upgrade: websocket") {
+        return Err(LoupedeckError::Handshake(
+            "Expected a websocket upgrade request".to_string(),
+        ));
+    }
+
+    tx_reply
+        .send(WS_UPGRADE_ACCEPT.as_bytes().to_vec())
+        .await
+        .map_err(|_| LoupedeckError::Disconnected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect_virtual_device, connect_virtual_model, ScriptedEvent};
+    use crate::{
+        Button, DeviceInfo, Event, Haptic, Knob, LoupedeckError, PressDirection, Rgb, Screen,
+        LOUPEDECK_LIVE_S, MAX_BRIGHTNESS,
+    };
+    use tokio::time::{timeout, Duration};
+
+    fn info() -> DeviceInfo {
+        DeviceInfo {
+            serial: "LDD0123456789".to_string(),
+            version: "0.2.5".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn it_answers_info_requests() {
+        let (mut device, _virtual_device) = connect_virtual_device(info()).await.unwrap();
+
+        let device_info = device.get_info().await.unwrap();
+        assert_eq!(device_info.serial, "LDD0123456789");
+        assert_eq!(device_info.version, "0.2.5");
+
+        device.disconnect();
+    }
+
+    #[tokio::test]
+    async fn it_records_what_was_drawn() {
+        let (mut device, virtual_device) = connect_virtual_device(info()).await.unwrap();
+
+        let red: Vec<u8> = [0x00, 0xf8].repeat(90 * 90);
+        device.draw_key(1, 2, red).await.unwrap();
+
+        timeout(Duration::from_secs(1), async {
+            while virtual_device.pixel(60 + 90, 180) != 0xf800 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("key was never presented");
+
+        let center = virtual_device.screen_buffer(Screen::Center);
+        assert_eq!(center.len(), 360 * 270);
+        assert_eq!(center[180 * 360 + 90], 0xf800);
+        assert_eq!(center[180 * 360 + 89], 0x0000);
+        assert_eq!(virtual_device.pixel(0, 0), 0x0000);
+
+        device.disconnect();
+    }
+
+    #[tokio::test]
+    async fn it_lays_out_keys_for_the_emulated_model() {
+        let (mut device, virtual_device) = connect_virtual_model(info(), &LOUPEDECK_LIVE_S)
+            .await
+            .unwrap();
+
+        let blue: Vec<u8> = [0x1f, 0x00].repeat(90 * 90);
+        device.draw_key(4, 0, blue.clone()).await.unwrap();
+
+        timeout(Duration::from_secs(1), async {
+            while virtual_device.pixel(15 + 4 * 90, 0) != 0x001f {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("key was never presented");
+
+        assert_eq!(virtual_device.framebuffer().len(), 480 * 270);
+        assert_eq!(virtual_device.pixel(15 + 4 * 90 - 1, 0), 0x0000);
+        assert!(matches!(
+            device.draw_key(5, 0, blue).await,
+            Err(LoupedeckError::InvalidInput(_))
+        ));
+
+        device.disconnect();
+    }
+
+    #[tokio::test]
+    async fn it_records_device_settings() {
+        let (mut device, virtual_device) = connect_virtual_device(info()).await.unwrap();
+
+        device.set_brightness(7).await.unwrap();
+        device
+            .set_button_color(Button::Circle2, Rgb::new(0xff, 0x80, 0x00))
+            .await
+            .unwrap();
+        device.reset().await.unwrap();
+
+        assert!(matches!(
+            device.set_brightness(MAX_BRIGHTNESS + 1).await,
+            Err(LoupedeckError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            device
+                .set_button_color(Button::Enter, Rgb::new(0, 0, 0))
+                .await,
+            Err(LoupedeckError::InvalidInput(_))
+        ));
+
+        // Replies to a request come after everything that was sent before it
+        assert_eq!(
+            device.get_mcu_id().await.unwrap(),
+            "002E003C3139510C34373239"
+        );
+        assert_eq!(virtual_device.brightness(), Some(7));
+        assert_eq!(
+            virtual_device.button_colors().get(&(Button::Circle2 as u8)),
+            Some(&Rgb::new(0xff, 0x80, 0x00))
+        );
+        assert_eq!(virtual_device.resets(), 1);
+
+        let emitter = device.create_external_event_emitter().unwrap();
+        emitter.set_brightness(3).await.unwrap();
+        assert_eq!(
+            emitter.get_mcu_id().await.unwrap(),
+            "002E003C3139510C34373239"
+        );
+        assert_eq!(virtual_device.brightness(), Some(3));
+
+        device.disconnect();
+    }
+
+    #[tokio::test]
+    async fn it_records_vibrations() {
+        let (mut device, virtual_device) = connect_virtual_device(info()).await.unwrap();
+
+        device.vibrate(Haptic::Long).await.unwrap();
+
+        timeout(Duration::from_secs(1), async {
+            while virtual_device.vibrations().is_empty() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("vibration was never received");
+
+        assert_eq!(virtual_device.vibrations(), vec![Haptic::Long as u8]);
+
+        device.disconnect();
+    }
+
+    #[tokio::test]
+    async fn it_injects_scripted_input() {
+        let (mut device, virtual_device) = connect_virtual_device(info()).await.unwrap();
+        let mut rx_event = device.tx_event.as_ref().unwrap().subscribe();
+
+        virtual_device
+            .play(&[
+                ScriptedEvent::ButtonPress {
+                    button: Button::Circle1,
+                    dir: PressDirection::Down,
+                },
+                ScriptedEvent::KnobRotate {
+                    knob: Knob::Knob2,
+                    value: -1,
+                },
+                ScriptedEvent::TouchDown {
+                    x: 100,
+                    y: 50,
+                    touch_id: 3,
+                },
+                ScriptedEvent::Tick,
+            ])
+            .await
+            .unwrap();
+
+        match rx_event.recv().await.unwrap() {
+            Event::ButtonPress(evt) => {
+                assert!(matches!(evt.button, Button::Circle1));
+                assert!(matches!(evt.dir, PressDirection::Down));
+            }
+            evt => panic!("Unexpected event {:?}", evt),
+        }
+
+        match rx_event.recv().await.unwrap() {
+            Event::KnobRotate(evt) => {
+                assert!(matches!(evt.knob, Knob::Knob2));
+                assert_eq!(evt.value, -1);
+            }
+            evt => panic!("Unexpected event {:?}", evt),
+        }
+
+        match rx_event.recv().await.unwrap() {
+            Event::TouchEvent(evt) => {
+                assert_eq!((evt.x, evt.y, evt.touch_id), (100, 50, 3));
+                assert_eq!(evt.screen, Screen::Center);
+            }
+            evt => panic!("Unexpected event {:?}", evt),
+        }
+
+        assert!(matches!(rx_event.recv().await.unwrap(), Event::Tick(_)));
+
+        device.disconnect();
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::BTreeMap;
+
+use super::{DeviceModel, Screen, Snapshot};
+use crate::{LoupedeckError, Result};
+
+/// A rectangle on a screen, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Rect {
+    pub const fn new(x: u16, y: u16, width: u16, height: u16) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    // In u32, so rectangles reaching past `u16::MAX` can't wrap around
+    fn right(&self) -> u32 {
+        self.x as u32 + self.width as u32
+    }
+
+    fn bottom(&self) -> u32 {
+        self.y as u32 + self.height as u32
+    }
+
+    /// Whether the two share at least one pixel.
+    pub fn intersects(&self, other: &Rect) -> bool {
+        (self.x as u32) < other.right()
+            && (other.x as u32) < self.right()
+            && (self.y as u32) < other.bottom()
+            && (other.y as u32) < self.bottom()
+    }
+
+    /// Whether the two overlap or share an edge, so they can be drawn as one.
+    pub(crate) fn touches(&self, other: &Rect) -> bool {
+        self.x as u32 <= other.right()
+            && other.x as u32 <= self.right()
+            && self.y as u32 <= other.bottom()
+            && other.y as u32 <= self.bottom()
+    }
+
+    /// The part both cover, if any.
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        if !self.intersects(other) {
+            return None;
+        }
+
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+
+        Some(Rect::new(
+            x,
+            y,
+            (self.right().min(other.right()) - x as u32) as u16,
+            (self.bottom().min(other.bottom()) - y as u32) as u16,
+        ))
+    }
+
+    pub fn contains(&self, x: u16, y: u16) -> bool {
+        x >= self.x && (x as u32) < self.right() && y >= self.y && (y as u32) < self.bottom()
+    }
+
+    /// The smallest rectangle covering both.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+
+        Rect::new(
+            x,
+            y,
+            (self.right().max(other.right()) - x as u32).min(u16::MAX as u32) as u16,
+            (self.bottom().max(other.bottom()) - y as u32).min(u16::MAX as u32) as u16,
+        )
+    }
+}
+
+/// What the host last sent to one screen.
+#[derive(Debug)]
+struct ScreenShadow {
+    /// Where the screen sits on the main touch surface, if it's part of it.
+    surface_origin: Option<(u16, u16)>,
+    width: u16,
+    height: u16,
+    // `None` until something is drawn, since we can't know what the device is showing
+    pixels: Vec<Option<u16>>,
+}
+
+/// Shadow copies of a device's screens, in RGB565.
+///
+/// Draws are compared against the shadow so only the pixels that changed need to be sent.
+#[derive(Debug)]
+pub struct Framebuffer {
+    screens: BTreeMap<Screen, ScreenShadow>,
+}
+
+impl Framebuffer {
+    pub fn new(model: &DeviceModel) -> Self {
+        let surface = model
+            .screens
+            .iter()
+            .map(|layout| (layout, Some((layout.x, layout.y))));
+
+        let screens = surface
+            .chain(model.wheel.iter().map(|layout| (layout, None)))
+            .map(|(layout, surface_origin)| {
+                let shadow = ScreenShadow {
+                    surface_origin,
+                    width: layout.width,
+                    height: layout.height,
+                    pixels: vec![None; layout.width as usize * layout.height as usize],
+                };
+
+                (layout.screen.clone(), shadow)
+            })
+            .collect();
+
+        Self { screens }
+    }
+
+    fn shadow(&self, screen: &Screen) -> Result<&ScreenShadow> {
+        self.screens
+            .get(screen)
+            .ok_or_else(|| LoupedeckError::InvalidInput(format!("No {:?} screen", screen)))
+    }
+
+    fn shadow_mut(&mut self, screen: &Screen) -> Result<&mut ScreenShadow> {
+        self.screens
+            .get_mut(screen)
+            .ok_or_else(|| LoupedeckError::InvalidInput(format!("No {:?} screen", screen)))
+    }
+
+    /// Updates the shadow with little-endian RGB565 pixels for `region`.
+    ///
+    /// Returns the smallest rectangle covering every pixel that changed, or `None` if the
+    /// screen already shows exactly this.
+    pub fn write(&mut self, screen: &Screen, region: Rect, data: &[u8]) -> Result<Option<Rect>> {
+        let shadow = self.shadow_mut(screen)?;
+
+        if region.right() > shadow.width as u32 || region.bottom() > shadow.height as u32 {
+            return Err(LoupedeckError::InvalidInput(format!(
+                "{:?} doesn't fit on the {}x{} {:?} screen",
+                region, shadow.width, shadow.height, screen
+            )));
+        }
+
+        let expected_len = region.width as usize * region.height as usize * 2;
+        if data.len() != expected_len {
+            return Err(LoupedeckError::InvalidInput(format!(
+                "Expected a {} byte buffer for a {}x{} region but got {} bytes",
+                expected_len,
+                region.width,
+                region.height,
+                data.len()
+            )));
+        }
+
+        let mut changed: Option<Rect> = None;
+
+        for row in 0..region.height {
+            for column in 0..region.width {
+                let i = (row as usize * region.width as usize + column as usize) * 2;
+                let pixel = u16::from_le_bytes([data[i], data[i + 1]]);
+
+                let (x, y) = (region.x + column, region.y + row);
+                let current = &mut shadow.pixels[y as usize * shadow.width as usize + x as usize];
+
+                if *current != Some(pixel) {
+                    *current = Some(pixel);
+
+                    let pixel_rect = Rect::new(x, y, 1, 1);
+                    changed = Some(changed.map_or(pixel_rect, |rect| rect.union(&pixel_rect)));
+                }
+            }
+        }
+
+        Ok(changed)
+    }
+
+    /// Little-endian RGB565 pixels for a region, with undrawn pixels left black.
+    pub fn read(&self, screen: &Screen, region: Rect) -> Result<Vec<u8>> {
+        let shadow = self.shadow(screen)?;
+
+        if region.right() > shadow.width as u32 || region.bottom() > shadow.height as u32 {
+            return Err(LoupedeckError::InvalidInput(format!(
+                "{:?} doesn't fit on the {:?} screen",
+                region, screen
+            )));
+        }
+
+        let mut data = Vec::with_capacity(region.width as usize * region.height as usize * 2);
+        for y in region.y..region.y + region.height {
+            let start = y as usize * shadow.width as usize + region.x as usize;
+            for pixel in &shadow.pixels[start..start + region.width as usize] {
+                data.extend_from_slice(&pixel.unwrap_or(0).to_le_bytes());
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Renders one screen, with undrawn pixels left black.
+    pub fn snapshot_screen(&self, screen: &Screen) -> Result<Snapshot> {
+        let shadow = self.shadow(screen)?;
+        let pixels: Vec<u16> = shadow.pixels.iter().map(|p| p.unwrap_or(0)).collect();
+
+        Ok(Snapshot::from_rgb565(shadow.width, shadow.height, &pixels))
+    }
+
+    /// Renders the whole main touch surface, e.g. all 480x270 pixels of a Live.
+    pub fn snapshot_surface(&self) -> Snapshot {
+        let (width, height) = self
+            .screens
+            .values()
+            .filter_map(|shadow| {
+                let (x, y) = shadow.surface_origin?;
+                Some((x + shadow.width, y + shadow.height))
+            })
+            .fold((0, 0), |(w, h), (right, bottom)| {
+                (w.max(right), h.max(bottom))
+            });
+
+        let mut pixels = vec![0; width as usize * height as usize];
+        for shadow in self.screens.values() {
+            let Some((x, y)) = shadow.surface_origin else {
+                continue;
+            };
+
+            for (row, line) in shadow.pixels.chunks(shadow.width as usize).enumerate() {
+                let start = (y as usize + row) * width as usize + x as usize;
+                for (target, pixel) in pixels[start..start + line.len()].iter_mut().zip(line) {
+                    *target = pixel.unwrap_or(0);
+                }
+            }
+        }
+
+        Snapshot::from_rgb565(width, height, &pixels)
+    }
+
+    /// Forgets what part of a screen shows, e.g. after sending it failed, so the next draw
+    /// there is sent in full.
+    pub fn invalidate_rect(&mut self, screen: &Screen, region: Rect) -> Result<()> {
+        let shadow = self.shadow_mut(screen)?;
+        let right = (region.right() as usize).min(shadow.width as usize);
+        let bottom = (region.bottom() as usize).min(shadow.height as usize);
+
+        for y in region.y as usize..bottom {
+            let row = y * shadow.width as usize;
+            shadow.pixels[row + (region.x as usize).min(right)..row + right].fill(None);
+        }
+
+        Ok(())
+    }
+
+    /// Forgets what every screen shows, e.g. after the device was reset.
+    pub fn invalidate(&mut self) {
+        for shadow in self.screens.values_mut() {
+            shadow.pixels.fill(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Framebuffer, Rect};
+    use crate::{LoupedeckError, Screen, LOUPEDECK_LIVE};
+
+    fn solid(width: u16, height: u16, color: u16) -> Vec<u8> {
+        color.to_le_bytes().repeat(width as usize * height as usize)
+    }
+
+    #[test]
+    fn it_finds_the_changed_region() {
+        let mut framebuffer = Framebuffer::new(&LOUPEDECK_LIVE);
+        let key = Rect::new(90, 90, 90, 90);
+
+        assert_eq!(
+            framebuffer
+                .write(&Screen::Center, key, &solid(90, 90, 0xf800))
+                .unwrap(),
+            Some(key)
+        );
+        assert_eq!(
+            framebuffer
+                .write(&Screen::Center, key, &solid(90, 90, 0xf800))
+                .unwrap(),
+            None
+        );
+
+        let mut image = solid(90, 90, 0xf800);
+        for (x, y) in [(10, 20), (30, 40)] {
+            let i = (y * 90 + x) * 2;
+            image[i..i + 2].copy_from_slice(&0x07e0u16.to_le_bytes());
+        }
+
+        assert_eq!(
+            framebuffer.write(&Screen::Center, key, &image).unwrap(),
+            Some(Rect::new(100, 110, 21, 21))
+        );
+        assert_eq!(
+            framebuffer
+                .read(&Screen::Center, Rect::new(120, 130, 1, 1))
+                .unwrap(),
+            vec![0xe0, 0x07]
+        );
+    }
+
+    #[test]
+    fn it_rejects_regions_off_the_screen() {
+        let mut framebuffer = Framebuffer::new(&LOUPEDECK_LIVE);
+
+        assert!(matches!(
+            framebuffer.write(&Screen::Left, Rect::new(0, 0, 90, 90), &solid(90, 90, 0)),
+            Err(LoupedeckError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            framebuffer.write(&Screen::Main, Rect::new(0, 0, 1, 1), &solid(1, 1, 0)),
+            Err(LoupedeckError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            framebuffer.write(&Screen::Center, Rect::new(0, 0, 2, 2), &solid(1, 1, 0)),
+            Err(LoupedeckError::InvalidInput(_))
+        ));
+
+        // Regions reaching past the end of the coordinate space don't wrap around
+        let wrapping = Rect::new(u16::MAX, 0, 2, 1);
+        assert!(matches!(
+            framebuffer.write(&Screen::Center, wrapping, &solid(2, 1, 0)),
+            Err(LoupedeckError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            framebuffer.read(&Screen::Center, Rect::new(0, u16::MAX, 1, 2)),
+            Err(LoupedeckError::InvalidInput(_))
+        ));
+        assert!(!wrapping.contains(0, 0));
+        assert!(!wrapping.intersects(&Rect::new(0, 0, 90, 90)));
+    }
+
+    #[test]
+    fn it_compares_rects() {
+        let key = Rect::new(0, 0, 90, 90);
+
+        assert!(key.intersects(&Rect::new(89, 89, 1, 1)));
+        assert!(!key.intersects(&Rect::new(90, 0, 90, 90)));
+        assert!(key.touches(&Rect::new(90, 0, 90, 90)));
+        assert!(!key.touches(&Rect::new(91, 0, 90, 90)));
+        assert_eq!(
+            key.union(&Rect::new(90, 180, 90, 90)),
+            Rect::new(0, 0, 180, 270)
+        );
+        assert_eq!(
+            key.intersection(&Rect::new(60, 30, 90, 90)),
+            Some(Rect::new(60, 30, 30, 60))
+        );
+        assert_eq!(key.intersection(&Rect::new(90, 0, 90, 90)), None);
+        assert!(key.contains(89, 0) && !key.contains(90, 0));
+    }
+
+    #[test]
+    fn it_snapshots_the_surface() {
+        let mut framebuffer = Framebuffer::new(&LOUPEDECK_LIVE);
+        framebuffer
+            .write(&Screen::Left, Rect::new(0, 0, 1, 1), &solid(1, 1, 0xf800))
+            .unwrap();
+        framebuffer
+            .write(&Screen::Center, Rect::new(0, 0, 1, 1), &solid(1, 1, 0x07e0))
+            .unwrap();
+        framebuffer
+            .write(
+                &Screen::Right,
+                Rect::new(59, 269, 1, 1),
+                &solid(1, 1, 0x001f),
+            )
+            .unwrap();
+
+        let surface = framebuffer.snapshot_surface();
+        assert_eq!((surface.width, surface.height), (480, 270));
+        assert_eq!(surface.pixel(0, 0), [0xff, 0x00, 0x00, 0xff]);
+        assert_eq!(surface.pixel(60, 0), [0x00, 0xff, 0x00, 0xff]);
+        assert_eq!(surface.pixel(479, 269), [0x00, 0x00, 0xff, 0xff]);
+        assert_eq!(surface.pixel(1, 0), [0x00, 0x00, 0x00, 0xff]);
+
+        let center = framebuffer.snapshot_screen(&Screen::Center).unwrap();
+        assert_eq!((center.width, center.height), (360, 270));
+        assert_eq!(center.pixel(0, 0), [0x00, 0xff, 0x00, 0xff]);
+    }
+
+    #[test]
+    fn it_forgets_everything_when_invalidated() {
+        let mut framebuffer = Framebuffer::new(&LOUPEDECK_LIVE);
+        let key = Rect::new(0, 0, 90, 90);
+
+        framebuffer
+            .write(&Screen::Center, key, &solid(90, 90, 0))
+            .unwrap();
+        framebuffer.invalidate();
+
+        assert_eq!(
+            framebuffer
+                .write(&Screen::Center, key, &solid(90, 90, 0))
+                .unwrap(),
+            Some(key)
+        );
+    }
+
+    #[test]
+    fn it_forgets_part_of_a_screen() {
+        let mut framebuffer = Framebuffer::new(&LOUPEDECK_LIVE);
+        let key = Rect::new(0, 0, 90, 90);
+        let part = Rect::new(10, 20, 5, 5);
+
+        framebuffer
+            .write(&Screen::Center, key, &solid(90, 90, 0))
+            .unwrap();
+        framebuffer.invalidate_rect(&Screen::Center, part).unwrap();
+
+        assert_eq!(
+            framebuffer
+                .write(&Screen::Center, key, &solid(90, 90, 0))
+                .unwrap(),
+            Some(part)
+        );
+    }
+}
@@ -1,58 +1,134 @@
-use mio_serial::SerialPort;
+use futures::StreamExt;
 use raqote::DrawTarget;
-use std::collections::HashSet;
-use std::io::prelude::*;
-use std::io::Result;
-use tokio::runtime::Runtime;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::runtime::Handle;
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
-use tokio::sync::oneshot;
-use tokio::sync::oneshot::error::RecvError;
+use tokio::sync::watch;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
 use tokio::time;
-use tokio_serial::SerialPortBuilderExt;
-use tokio_serial::SerialStream;
+use tokio_util::codec::FramedRead;
+
+use crate::{LoupedeckError, Result};
 
 mod plugin;
 pub use plugin::*;
 
+mod abi;
+pub use abi::*;
+
 mod constants;
 pub use constants::*;
 
-fn parse_serial_message(message: &[u8]) -> Result<Option<Event>> {
+mod transport;
+pub use transport::*;
+
+mod codec;
+pub use codec::*;
+
+mod emulator;
+pub use emulator::*;
+
+mod request;
+use request::PendingRequests;
+
+mod model;
+pub use model::*;
+
+mod capture;
+pub use capture::*;
+
+mod framebuffer;
+pub use framebuffer::*;
+
+mod scheduler;
+pub use scheduler::*;
+
+mod snapshot;
+pub use snapshot::*;
+
+mod drawing;
+pub use drawing::*;
+
+mod animation;
+pub use animation::*;
+
+mod text;
+pub use text::*;
+
+mod pixel;
+pub use pixel::*;
+
+fn parse_serial_message(message: &[u8], model: &DeviceModel) -> Result<Option<Event>> {
+    if message.len() < 3 {
+        return Err(LoupedeckError::protocol(message));
+    }
+
     let header: u16 = u16::from_be_bytes([message[0], message[1]]);
     // println!("Message type: {:?}", header);
 
-    let message_type = MessageHeader::from(header);
+    let message_type =
+        MessageHeader::try_from(header).map_err(|_| LoupedeckError::protocol(message))?;
     let tx_id = message[2];
 
     match message_type {
         MessageHeader::ButtonPress => {
-            let button = Button::from_u8(message[3]).expect("Invalid button address");
-            let dir = PressDirection::from_u8(message[4]).expect("Invalid button direction");
-            Ok(Some(Event::ButtonPress(ButtonPressEvent {
-                button,
-                dir,
-                tx_id,
-            })))
+            let button = message
+                .get(3)
+                .filter(|b| model.has_button(**b))
+                .and_then(|b| Button::from_u8(*b));
+            let dir = message.get(4).and_then(|d| PressDirection::from_u8(*d));
+
+            match (button, dir) {
+                (Some(button), Some(dir)) => Ok(Some(Event::ButtonPress(ButtonPressEvent {
+                    button,
+                    dir,
+                    tx_id,
+                }))),
+                _ => Err(LoupedeckError::protocol(message)),
+            }
         }
         MessageHeader::KnobRotate => {
-            let knob = Knob::from_u8(message[3]).expect("Invalid knob address");
-            Ok(Some(Event::KnobRotate(KnobRotateEvent {
-                tx_id,
-                knob,
-                value: message[4] as i8,
-            })))
+            let knob = message
+                .get(3)
+                .filter(|k| model.has_knob(**k))
+                .and_then(|k| Knob::from_u8(*k));
+
+            match (knob, message.get(4)) {
+                (Some(knob), Some(value)) => Ok(Some(Event::KnobRotate(KnobRotateEvent {
+                    tx_id,
+                    knob,
+                    value: *value as i8,
+                }))),
+                _ => Err(LoupedeckError::protocol(message)),
+            }
         }
-        MessageHeader::TouchDown | MessageHeader::TouchUp => {
+        MessageHeader::TouchDown
+        | MessageHeader::TouchUp
+        | MessageHeader::WheelTouchDown
+        | MessageHeader::WheelTouchUp => {
+            if message.len() < 9 {
+                return Err(LoupedeckError::protocol(message));
+            }
+
             let x = u16::from_be_bytes([message[4], message[5]]);
             let y = u16::from_be_bytes([message[6], message[7]]);
             let touch_id = message[8];
-            let dir = if message_type == MessageHeader::TouchDown {
-                PressDirection::Down
-            } else {
-                PressDirection::Up
+            let dir = match message_type {
+                MessageHeader::TouchDown | MessageHeader::WheelTouchDown => PressDirection::Down,
+                _ => PressDirection::Up,
             };
-            let screen = Screen::from_x_coor(x).expect("Invalid screen");
+            let layout = match message_type {
+                MessageHeader::WheelTouchDown | MessageHeader::WheelTouchUp => model.wheel.as_ref(),
+                _ => model.screen_at(x, y),
+            };
+            let screen = layout
+                .map(|layout| layout.screen.clone())
+                .ok_or_else(|| LoupedeckError::protocol(message))?;
 
             Ok(Some(Event::TouchEvent(TouchEvent {
                 tx_id,
@@ -65,34 +141,49 @@ fn parse_serial_message(message: &[u8]) -> Result<Option<Event>> {
         }
         MessageHeader::SerialIn => Ok(Some(Event::SerialIn(SerialInEvent {
             tx_id,
-            serial_number: String::from_utf8(message[3..].to_vec())
-                .unwrap()
-                .trim()
-                .to_string(),
-        }))),
-        MessageHeader::VersionIn => Ok(Some(Event::VersionIn(VersionInEvent {
-            tx_id,
-            version: format!("{}.{}.{}", message[3], message[4], message[5]).to_string(),
+            serial_number: String::from_utf8_lossy(&message[3..]).trim().to_string(),
         }))),
+        MessageHeader::VersionIn => {
+            if message.len() < 6 {
+                return Err(LoupedeckError::protocol(message));
+            }
+
+            Ok(Some(Event::VersionIn(VersionInEvent {
+                tx_id,
+                version: format!("{}.{}.{}", message[3], message[4], message[5]),
+            })))
+        }
         MessageHeader::ConfirmFrameBuffer => Ok(Some(Event::ConfirmFrameBufferIn(
             ConfirmFrameBufferInEvent { tx_id },
         ))),
         MessageHeader::DrawIn => Ok(Some(Event::DrawIn(DrawInEvent { tx_id }))),
+        MessageHeader::MCU => {
+            // The id is followed by zero padding
+            let id = &message[3..];
+            let end = id.iter().rposition(|b| *b != 0x00).map_or(0, |i| i + 1);
+
+            Ok(Some(Event::McuIn(McuInEvent {
+                tx_id,
+                mcu_id: id[..end].iter().map(|b| format!("{:02X}", b)).collect(),
+            })))
+        }
+        MessageHeader::Tick => Ok(Some(Event::Tick(TickEvent { tx_id }))),
         _ => Ok(Some(Event::Other(FallbackEvent { tx_id }))),
     }
 }
 
-fn get_tx_id(evt: Event) -> Option<u8> {
+fn get_tx_id(evt: &Event) -> Option<u8> {
     match evt {
-        Event::ButtonPress(ButtonPressEvent { tx_id, .. }) => Some(tx_id),
-        Event::KnobRotate(KnobRotateEvent { tx_id, .. }) => Some(tx_id),
-        Event::TouchEvent(TouchEvent { tx_id, .. }) => Some(tx_id),
-        Event::Other(FallbackEvent { tx_id }) => Some(tx_id),
-        Event::SerialIn(SerialInEvent { tx_id, .. }) => Some(tx_id),
-        Event::VersionIn(VersionInEvent { tx_id, .. }) => Some(tx_id),
-        Event::ConfirmFrameBufferIn(ConfirmFrameBufferInEvent { tx_id }) => Some(tx_id),
-        Event::DrawIn(DrawInEvent { tx_id }) => Some(tx_id),
-        _ => panic!("Invalid event"),
+        Event::ButtonPress(ButtonPressEvent { tx_id, .. }) => Some(*tx_id),
+        Event::KnobRotate(KnobRotateEvent { tx_id, .. }) => Some(*tx_id),
+        Event::TouchEvent(TouchEvent { tx_id, .. }) => Some(*tx_id),
+        Event::Other(FallbackEvent { tx_id }) => Some(*tx_id),
+        Event::SerialIn(SerialInEvent { tx_id, .. }) => Some(*tx_id),
+        Event::VersionIn(VersionInEvent { tx_id, .. }) => Some(*tx_id),
+        Event::ConfirmFrameBufferIn(ConfirmFrameBufferInEvent { tx_id }) => Some(*tx_id),
+        Event::DrawIn(DrawInEvent { tx_id }) => Some(*tx_id),
+        Event::McuIn(McuInEvent { tx_id, .. }) => Some(*tx_id),
+        Event::Tick(TickEvent { tx_id }) => Some(*tx_id),
     }
 }
 
@@ -106,18 +197,30 @@ Sec-WebSocket-Key: 123abc
 
 const WS_UPGRADE_RESPONSE: &str = "HTTP/1.1";
 
-#[derive(Debug, Clone)]
+/// How long a request waits for the device to respond unless told otherwise.
+pub const DEFAULT_REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DeviceInfo {
-    serial: String,
-    version: String,
+    pub serial: String,
+    pub version: String,
 }
 
 pub struct Device {
     pub port: String,
-    pub(crate) runtime: Option<Runtime>,
+    runtime: Option<Handle>,
+    io_task: Option<JoinHandle<()>>,
     tx_pending_send: Option<mpsc::Sender<Vec<u8>>>,
     pub tx_event: Option<broadcast::Sender<Event>>,
-    next_tx_id: u8,
+    tx_connected: Option<Arc<watch::Sender<bool>>>,
+    info: Option<DeviceInfo>,
+    model: &'static DeviceModel,
+    pending_requests: PendingRequests,
+    request_timeout: time::Duration,
+    capture: Option<CaptureSink>,
+    render_config: RenderConfig,
+    scheduler: Arc<Mutex<RenderScheduler>>,
+    frame_ready: Arc<Notify>,
 }
 
 fn construct_draw_buffer_payload(
@@ -128,11 +231,15 @@ fn construct_draw_buffer_payload(
     height: u16,
     buffer: &[u8],
 ) -> Result<Vec<u8>> {
-    if (width * height * 2) as usize != buffer.len() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "Invalid buffer length",
-        ));
+    let expected_len = width as usize * height as usize * 2;
+    if expected_len != buffer.len() {
+        return Err(LoupedeckError::InvalidInput(format!(
+            "Expected a {} byte buffer for a {}x{} region but got {} bytes",
+            expected_len,
+            width,
+            height,
+            buffer.len()
+        )));
     }
 
     let mut buff = Vec::new();
@@ -143,77 +250,168 @@ fn construct_draw_buffer_payload(
     buff.append(&mut height.to_be_bytes().to_vec());
     buff.append(&mut buffer.to_vec());
 
-    return Ok(buff);
+    Ok(buff)
 }
 
 //
 // 82 ff 00 00 00 00 00 00 3f 55 00 00 00 00 ff 10 02 00 41 00 00 00 00 00 5a 00 5a
 
-fn is_redraw_event(msg: &[u8]) -> Option<Screen> {
-    let mut screen_id: Option<u8> = None;
+/// Minimum gap between consecutive writes, which the firmware needs to keep up.
+const WRITE_GAP: time::Duration = time::Duration::from_millis(2);
 
-    if msg.len() > 18 && msg[1] == 0xff && msg[14] == 0xff && msg[15] == 0x10 {
-        screen_id = Some(msg[18]);
-    } else if msg.len() > 10 && msg[6] == 0xff && msg[7] == 0x10 {
-        screen_id = Some(msg[10]);
+async fn write_paced<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &[u8],
+    last_write: &mut Option<time::Instant>,
+) -> std::io::Result<()> {
+    if let Some(last_write) = *last_write {
+        time::sleep_until(last_write + WRITE_GAP).await;
     }
 
-    if screen_id.is_none() {
-        return None;
+    writer.write_all(message).await?;
+    *last_write = Some(time::Instant::now());
+
+    Ok(())
+}
+
+/// Writes each message, then redraws the screens they touched.
+async fn write_messages<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    messages: Vec<Vec<u8>>,
+    last_write: &mut Option<time::Instant>,
+) -> std::io::Result<()> {
+    let mut redrawn_screens = BTreeSet::new();
+
+    for message in messages {
+        write_paced(writer, &message, last_write).await?;
+
+        if let Some(redraw) = is_redraw_event(message.as_slice()) {
+            redrawn_screens.insert(redraw);
+        }
+    }
+
+    for screen in redrawn_screens {
+        let redraw_payload =
+            construct_basic_message(Vec::from(MessageHeader::DrawOut), Vec::from(screen));
+
+        write_paced(
+            writer,
+            &construct_message_payload(redraw_payload, 1),
+            last_write,
+        )
+        .await?;
     }
 
-    return Some(Screen::from(screen_id.unwrap()));
+    Ok(())
 }
 
-fn construct_basic_message(action: Vec<u8>, data: Vec<u8>) -> Result<Vec<u8>> {
-    let mut header: Vec<u8> = Vec::with_capacity(3);
-    header.push(action[0]);
-    header.push(action[1]);
-    header.push(0x01);
+/// `WriteFrameBuffer` messages for the next frame.
+fn frame_messages(scheduler: &Mutex<RenderScheduler>, model: &DeviceModel) -> Vec<Vec<u8>> {
+    scheduler
+        .lock()
+        .unwrap()
+        .next_frame()
+        .into_iter()
+        .filter_map(|(screen, rect, mut data)| {
+            model.byte_order.reorder(&mut data);
+
+            let payload = construct_draw_buffer_payload(
+                screen,
+                rect.x,
+                rect.y,
+                rect.width,
+                rect.height,
+                &data,
+            )
+            .ok()?;
+
+            Some(Vec::from(ExternalMessage::new(
+                MessageHeader::WriteFrameBuffer,
+                payload,
+            )))
+        })
+        .collect()
+}
+
+fn is_redraw_event(msg: &[u8]) -> Option<Screen> {
+    let screen_id = if msg.len() > 18 && msg[1] == 0xff && msg[14] == 0xff && msg[15] == 0x10 {
+        msg[18]
+    } else if msg.len() > 10 && msg[6] == 0xff && msg[7] == 0x10 {
+        msg[10]
+    } else {
+        return None;
+    };
+
+    Screen::try_from(screen_id).ok()
+}
+
+fn construct_basic_message(action: Vec<u8>, data: Vec<u8>) -> Vec<u8> {
+    let header: Vec<u8> = vec![action[0], action[1], 0x01];
 
     let mut message: Vec<u8> = Vec::with_capacity(header.len() + data.len());
     message.extend_from_slice(&header);
     message.extend_from_slice(&data);
 
-    return Ok(message);
+    message
+}
+
+fn construct_brightness_payload(level: u8) -> Result<Vec<u8>> {
+    if level > MAX_BRIGHTNESS {
+        return Err(LoupedeckError::InvalidInput(format!(
+            "Brightness must be between 0 and {}, got {}",
+            MAX_BRIGHTNESS, level
+        )));
+    }
+
+    Ok(vec![level])
+}
+
+fn construct_button_color_payload(
+    model: &DeviceModel,
+    button: Button,
+    color: Rgb,
+) -> Result<Vec<u8>> {
+    let id = button as u8;
+    if !model.has_button(id) {
+        return Err(LoupedeckError::InvalidInput(format!(
+            "{} has no button {:#04x}",
+            model.name, id
+        )));
+    }
+
+    Ok(vec![id, color.r, color.g, color.b])
+}
+
+fn mcu_id_from_response(evt: Event) -> Result<String> {
+    match evt {
+        Event::McuIn(McuInEvent { mcu_id, .. }) => Ok(mcu_id),
+        evt => Err(LoupedeckError::UnexpectedResponse(Box::new(evt))),
+    }
 }
 
 fn construct_message_payload(message_buffer: Vec<u8>, tx_id: u8) -> Vec<u8> {
     let mut prefix_buff: Vec<u8>;
 
-    if message_buffer.len() > 0xff {
-        prefix_buff = Vec::with_capacity(14);
-
-        for i in 0..14 {
-            prefix_buff.push(0x00);
-        }
+    if message_buffer.len() > MAX_SHORT_LENGTH {
+        prefix_buff = vec![0x00; 14];
 
-        prefix_buff[0] = 0x82;
+        prefix_buff[0] = FRAME_DELIMITER;
         prefix_buff[1] = 0xFF;
 
-        let offset = 6;
+        let offset = 2;
         // println!("Message length: {:?}", message_buffer.len());
 
-        (message_buffer.len() as u32)
+        (message_buffer.len() as u64)
             .to_be_bytes()
             .iter()
             .enumerate()
             .for_each(|(i, b)| {
                 prefix_buff[offset + i] = *b;
             });
-
-        // prefix_buff[2 + offset] = (len >> 24) as u8;
-        // prefix_buff[3 + offset] = (len >> 16) as u8;
-        // prefix_buff[4 + offset] = (len >> 8) as u8;
-        // prefix_buff[5 + offset] = len as u8;
     } else {
-        prefix_buff = Vec::with_capacity(6);
-
-        for i in 0..6 {
-            prefix_buff.push(0x00);
-        }
+        prefix_buff = vec![0x00; 6];
 
-        prefix_buff[0] = 0x82;
+        prefix_buff[0] = FRAME_DELIMITER;
         prefix_buff[1] = 0x80 + message_buffer.len() as u8;
     }
 
@@ -226,51 +424,82 @@ fn construct_message_payload(message_buffer: Vec<u8>, tx_id: u8) -> Vec<u8> {
     message
 }
 
-fn to_hex(bytes: &[u8]) -> String {
-    bytes
-        .iter()
-        .map(|b| format!("{:02x}", b))
-        .collect::<Vec<String>>()
-        .join(" ")
-}
-
 #[derive(Debug, Clone)]
 pub struct ExternalMessage {
     action: Vec<u8>,
     data: Vec<u8>,
+    tx_id: u8,
+}
+
+impl ExternalMessage {
+    fn new(header: MessageHeader, data: Vec<u8>) -> Self {
+        Self {
+            action: Vec::from(header),
+            data,
+            tx_id: 0x01,
+        }
+    }
 }
 
 impl From<ExternalMessage> for Vec<u8> {
     fn from(external_message: ExternalMessage) -> Vec<u8> {
-        let mut header: Vec<u8> = Vec::with_capacity(3);
-        header.push(external_message.action[0]);
-        header.push(external_message.action[1]);
-        header.push(0x01);
+        let header: Vec<u8> = vec![external_message.action[0], external_message.action[1], 0x01];
 
         let mut message: Vec<u8> = Vec::with_capacity(header.len() + external_message.data.len());
         message.extend_from_slice(&header);
         message.extend_from_slice(&external_message.data);
 
-        return construct_message_payload(message, 1);
+        construct_message_payload(message, external_message.tx_id)
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct ExternalDeviceEventEmitter {
     tx_event: mpsc::Sender<ExternalMessage>,
+    model: &'static DeviceModel,
+    pending_requests: PendingRequests,
+    request_timeout: time::Duration,
+    scheduler: Arc<Mutex<RenderScheduler>>,
+    frame_ready: Arc<Notify>,
 }
 
 impl ExternalDeviceEventEmitter {
-    fn new(tx_event: mpsc::Sender<ExternalMessage>) -> Self {
-        Self { tx_event }
+    fn new(
+        tx_event: mpsc::Sender<ExternalMessage>,
+        model: &'static DeviceModel,
+        pending_requests: PendingRequests,
+        request_timeout: time::Duration,
+        scheduler: Arc<Mutex<RenderScheduler>>,
+        frame_ready: Arc<Notify>,
+    ) -> Self {
+        Self {
+            tx_event,
+            model,
+            pending_requests,
+            request_timeout,
+            scheduler,
+            frame_ready,
+        }
+    }
+
+    /// The layout of the device this emitter draws to.
+    pub fn model(&self) -> &'static DeviceModel {
+        self.model
     }
 
     async fn send_message(&self, message: ExternalMessage) -> Result<()> {
         // println!("Sending message: {:?}", message);
-        self.tx_event.send(message).await;
-        Ok(())
+        self.tx_event
+            .send(message)
+            .await
+            .map_err(|_| LoupedeckError::Disconnected)
     }
 
+    /// Draws little-endian RGB565 pixels to a region of a screen.
+    ///
+    /// Only the pixels that changed are sent, with the next frame. They're queued as
+    /// background draws, which the scheduler moves ahead of the others if they overlap
+    /// where the screen was touched in the last half second.
     pub async fn draw_rgb565(
         &self,
         screen: Screen,
@@ -280,14 +509,25 @@ impl ExternalDeviceEventEmitter {
         height: u16,
         data: Vec<u8>,
     ) -> Result<()> {
-        let buff_res =
-            construct_draw_buffer_payload(screen.clone(), x, y, width, height, data.as_slice());
+        self.queue_rgb565(screen, Rect::new(x, y, width, height), &data)
+    }
 
-        self.send_message(ExternalMessage {
-            action: Vec::from(MessageHeader::WriteFrameBuffer),
-            data: buff_res.unwrap(),
-        })
-        .await;
+    /// Queues pixels for the next frame without waiting, for callers holding a lock.
+    pub(crate) fn queue_rgb565(&self, screen: Screen, region: Rect, data: &[u8]) -> Result<()> {
+        if self.tx_event.is_closed() {
+            return Err(LoupedeckError::Disconnected);
+        }
+
+        let changed = self.scheduler.lock().unwrap().draw(
+            screen,
+            region,
+            data,
+            RenderPriority::Background,
+        )?;
+
+        if changed {
+            self.frame_ready.notify_one();
+        }
 
         Ok(())
     }
@@ -306,43 +546,173 @@ impl ExternalDeviceEventEmitter {
             .await
     }
 
+    /// Loads an image, sizes it to the region and draws it.
+    pub async fn draw_image(
+        &self,
+        screen: Screen,
+        region: Rect,
+        source: impl Into<ImageSource>,
+        options: impl Into<ImageOptions>,
+    ) -> Result<()> {
+        let data = render_image(source, region.width, region.height, options)?;
+        let Rect {
+            x,
+            y,
+            width,
+            height,
+        } = region;
+        self.draw_rgb565(screen, x, y, width, height, data).await
+    }
+
+    /// Draws an image across a whole screen, e.g. one of the Live's side strips.
+    pub async fn draw_screen_image(
+        &self,
+        screen: Screen,
+        source: impl Into<ImageSource>,
+        options: impl Into<ImageOptions>,
+    ) -> Result<()> {
+        let layout = self.model.screen_layout(&screen).ok_or_else(|| {
+            LoupedeckError::InvalidInput(format!("{} has no {:?} screen", self.model.name, screen))
+        })?;
+
+        let region = Rect::new(0, 0, layout.width, layout.height);
+        self.draw_image(screen, region, source, options).await
+    }
+
     pub async fn vibrate(&self, level: Haptic) -> Result<()> {
-        let mut vib_header = Vec::new();
-        vib_header.push(0x04);
-        vib_header.push(0x1b);
+        self.send_message(ExternalMessage::new(
+            MessageHeader::SetVibration,
+            vec![level as u8],
+        ))
+        .await
+    }
+
+    /// Sets the brightness of every screen, from 0 to `MAX_BRIGHTNESS`.
+    pub async fn set_brightness(&self, level: u8) -> Result<()> {
+        let data = construct_brightness_payload(level)?;
+        self.send_message(ExternalMessage::new(MessageHeader::SetBrightness, data))
+            .await
+    }
+
+    pub async fn set_button_color(&self, button: Button, color: Rgb) -> Result<()> {
+        let data = construct_button_color_payload(self.model, button, color)?;
+        self.send_message(ExternalMessage::new(MessageHeader::SetColor, data))
+            .await
+    }
 
-        let mut data = Vec::new();
-        data.push(level as u8);
+    pub async fn reset(&self) -> Result<()> {
+        self.send_message(ExternalMessage::new(MessageHeader::Reset, vec![]))
+            .await?;
+        self.scheduler.lock().unwrap().invalidate();
+        Ok(())
+    }
+
+    pub fn render_stats(&self) -> RenderStats {
+        self.scheduler.lock().unwrap().stats()
+    }
+
+    /// Renders what the device's main touch surface shows, including draws not sent yet.
+    pub fn snapshot(&self) -> Snapshot {
+        self.scheduler
+            .lock()
+            .unwrap()
+            .framebuffer()
+            .snapshot_surface()
+    }
+
+    /// Reads the unique id of the device's microcontroller.
+    pub async fn get_mcu_id(&self) -> Result<String> {
+        let request = self.pending_requests.register()?;
 
         self.send_message(ExternalMessage {
-            action: vib_header,
-            data,
+            tx_id: request.tx_id,
+            ..ExternalMessage::new(MessageHeader::McuOut, vec![])
         })
-        .await;
+        .await?;
 
-        Ok(())
+        mcu_id_from_response(request.response(self.request_timeout).await?)
     }
 }
 
 impl Device {
+    /// Creates a device that is assumed to be a Loupedeck Live until it connects.
     pub fn new(port: String) -> Device {
+        Device::with_model(port, &LOUPEDECK_LIVE)
+    }
+
+    pub fn with_model(port: String, model: &'static DeviceModel) -> Device {
         Device {
             port,
             runtime: None,
+            io_task: None,
             tx_pending_send: None,
             tx_event: None,
-            next_tx_id: 2,
+            tx_connected: None,
+            info: None,
+            model,
+            pending_requests: PendingRequests::new(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            capture: None,
+            render_config: RenderConfig::default(),
+            scheduler: Arc::new(Mutex::new(RenderScheduler::new(
+                model,
+                RenderConfig::default(),
+            ))),
+            frame_ready: Arc::new(Notify::new()),
         }
     }
 
-    pub fn create_external_event_emitter(&self) -> Option<ExternalDeviceEventEmitter> {
-        let runtime_ref = self.runtime.as_ref();
+    /// Sets how long requests wait for a response before failing with `Timeout`.
+    pub fn set_request_timeout(&mut self, timeout: time::Duration) {
+        self.request_timeout = timeout;
+    }
 
-        if runtime_ref.is_none() {
-            return None;
-        }
+    /// Sets how draws are batched into frames, from the next connection on.
+    pub fn set_render_config(&mut self, config: RenderConfig) {
+        self.render_config = config;
+    }
+
+    pub fn render_stats(&self) -> RenderStats {
+        self.scheduler.lock().unwrap().stats()
+    }
+
+    /// Renders what the device's main touch surface shows, including draws not sent yet.
+    pub fn snapshot(&self) -> Snapshot {
+        self.scheduler
+            .lock()
+            .unwrap()
+            .framebuffer()
+            .snapshot_surface()
+    }
 
-        let runtime = runtime_ref.unwrap();
+    /// Renders what one screen shows, including draws not sent yet.
+    pub fn snapshot_screen(&self, screen: &Screen) -> Result<Snapshot> {
+        self.scheduler
+            .lock()
+            .unwrap()
+            .framebuffer()
+            .snapshot_screen(screen)
+    }
+
+    /// Runs the device's tasks on `runtime` instead of the one `connect` is called from.
+    pub fn set_runtime(&mut self, runtime: Handle) {
+        self.runtime = Some(runtime);
+    }
+
+    /// Records every frame of the next connection to `sink`.
+    pub fn set_capture(&mut self, sink: CaptureSink) {
+        self.capture = Some(sink);
+    }
+
+    /// Records every frame of the next connection to a JSON lines file at `path`.
+    pub fn capture_to<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<()> {
+        self.set_capture(CaptureSink::create(path)?);
+        Ok(())
+    }
+
+    pub fn create_external_event_emitter(&self) -> Option<ExternalDeviceEventEmitter> {
+        self.io_task.as_ref()?;
+        let runtime = self.runtime.as_ref()?;
 
         let (tx_ext_message, mut rx_ext_message): (
             mpsc::Sender<ExternalMessage>,
@@ -352,88 +722,160 @@ impl Device {
         let tx_pending_send = self.tx_pending_send.as_ref().unwrap().clone();
 
         runtime.spawn(async move {
-            loop {
-                // Try to write everything we can from the port
-                while let Ok(ext_message) = rx_ext_message.try_recv() {
-                    let message = Vec::from(ext_message);
-                    // println!("Sending external message: {:?}", to_hex(&message));
-                    tx_pending_send.send(message).await.unwrap();
+            while let Some(ext_message) = rx_ext_message.recv().await {
+                if tx_pending_send.send(Vec::from(ext_message)).await.is_err() {
+                    return;
                 }
             }
         });
 
-        Some(ExternalDeviceEventEmitter::new(tx_ext_message))
+        Some(ExternalDeviceEventEmitter::new(
+            tx_ext_message,
+            self.model,
+            self.pending_requests.clone(),
+            self.request_timeout,
+            self.scheduler.clone(),
+            self.frame_ready.clone(),
+        ))
     }
 
+    pub fn model(&self) -> &'static DeviceModel {
+        self.model
+    }
+
+    /// Opens the serial port for this device and connects to it.
+    ///
+    /// The device model is detected from the port's USB product id when it is recognised.
     pub async fn connect(&mut self) -> Result<()> {
-        if self.runtime.is_some() {
+        if self.io_task.is_some() {
             return Ok(());
         }
 
         println!("Connecting to Loupedeck on port {}", self.port);
 
-        let mut port = tokio_serial::new(self.port.clone(), 9600)
-            .open_native_async()
-            .expect("Failed to open port");
+        if let Some(model) = detect_model(&self.port) {
+            self.model = model;
+        }
 
-        println!("Waiting for writable");
-        port.writable().await;
+        let port = open_serial_transport(&self.port)?;
+        self.connect_transport(port).await
+    }
+
+    /// Connects to the device over an already opened transport.
+    pub async fn connect_transport<T: Transport>(&mut self, transport: T) -> Result<()> {
+        if self.io_task.is_some() {
+            return Ok(());
+        }
 
-        port.try_write(WS_UPGRADE_HEADER.as_bytes())
-            .expect("Failed to write to port");
+        match self.capture.clone() {
+            Some(sink) => {
+                sink.record_header(CaptureHeader::new(self.model, self.info.clone()));
+                self.upgrade(CaptureTransport::new(transport, sink)).await
+            }
+            None => self.upgrade(transport).await,
+        }
+    }
 
-        println!("Waiting for readable");
-        port.readable().await;
+    async fn upgrade<T: Transport>(&mut self, mut transport: T) -> Result<()> {
         let mut buf = vec![0; 1024];
 
-        port.try_read(buf.as_mut_slice())
-            .expect("Failed to read from port");
-
-        let res = String::from_utf8(buf).unwrap();
+        // A port that never answers mustn't hang whoever is connecting
+        let read = time::timeout(self.request_timeout, async {
+            transport.write_all(WS_UPGRADE_HEADER.as_bytes()).await?;
+            transport.read(buf.as_mut_slice()).await
+        })
+        .await
+        .map_err(|_| LoupedeckError::Timeout)??;
+        let res = String::from_utf8_lossy(&buf[..read]);
 
         if !res.contains(WS_UPGRADE_RESPONSE) {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Failed to upgrade to websocket",
-            ));
+            return Err(LoupedeckError::Handshake(format!(
+                "Unexpected upgrade response: {:?}",
+                res
+            )));
         }
 
-        self.start_polling(port);
+        // Anything after the end of the upgrade response is already part of the first frame
+        let frames_start = buf[..read]
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map_or(read, |end| end + 4);
 
-        return Ok(());
+        self.start_polling(transport, &buf[frames_start..read]);
+
+        Ok(())
+    }
+
+    /// Watches whether the connection is still up.
+    ///
+    /// The value flips to `false` once reading from or writing to the transport fails,
+    /// e.g. because the device was unplugged.
+    pub fn watch_connection(&self) -> Option<watch::Receiver<bool>> {
+        self.tx_connected.as_ref().map(|tx| tx.subscribe())
     }
 
     pub fn disconnect(&mut self) {
         println!("Disconnecting from loupedeck on port {}", self.port);
         self.tx_event = None;
         self.tx_pending_send = None;
+        self.pending_requests.cancel_all();
 
-        let runtime = self.runtime.take();
+        if let Some(tx_connected) = self.tx_connected.take() {
+            tx_connected.send_replace(false);
+        }
 
-        if let Some(runtime) = runtime {
-            runtime.shutdown_background();
+        if let Some(io_task) = self.io_task.take() {
+            io_task.abort();
         }
     }
 
-    pub async fn vibrate(&mut self, level: Haptic) {
-        let mut vib_header = Vec::new();
-        vib_header.push(0x04);
-        vib_header.push(0x1b);
+    pub async fn vibrate(&mut self, level: Haptic) -> Result<()> {
+        self.send_message(MessageHeader::SetVibration, vec![level as u8])
+            .await
+    }
+
+    /// Sets the brightness of every screen, from 0 to `MAX_BRIGHTNESS`.
+    pub async fn set_brightness(&mut self, level: u8) -> Result<()> {
+        let data = construct_brightness_payload(level)?;
+        self.send_message(MessageHeader::SetBrightness, data).await
+    }
 
-        let mut data = Vec::new();
-        data.push(level as u8);
+    pub async fn set_button_color(&mut self, button: Button, color: Rgb) -> Result<()> {
+        let data = construct_button_color_payload(self.model, button, color)?;
+        self.send_message(MessageHeader::SetColor, data).await
+    }
 
-        self.send_message(vib_header, data, false).await;
+    pub async fn reset(&mut self) -> Result<()> {
+        self.send_message(MessageHeader::Reset, vec![]).await?;
+        self.scheduler.lock().unwrap().invalidate();
+        Ok(())
     }
 
-    pub async fn draw_key(&mut self, key_x: u16, key_y: u16, img: Vec<u8>) {
-        let x: u16 = KEY_SIZE * key_x;
-        let y: u16 = KEY_SIZE * key_y;
-        let width: u16 = 90;
-        let height: u16 = 90;
+    /// Reads the unique id of the device's microcontroller.
+    pub async fn get_mcu_id(&mut self) -> Result<String> {
+        let response = self
+            .request(MessageHeader::McuOut, vec![], self.request_timeout)
+            .await?;
 
-        self.draw_buffer(Screen::Center, x, y, width, height, img.as_slice())
-            .await;
+        mcu_id_from_response(response)
+    }
+
+    pub async fn draw_key(&mut self, key_x: u8, key_y: u8, img: Vec<u8>) -> Result<()> {
+        let model = self.model;
+        let key = KeyLocation::new(key_x, key_y);
+        let (x, y) = model.key_origin(key).ok_or_else(|| {
+            LoupedeckError::InvalidInput(format!("{} has no key at {:?}", model.name, key))
+        })?;
+
+        self.draw_buffer(
+            model.key_screen.clone(),
+            x,
+            y,
+            model.key_size,
+            model.key_size,
+            img.as_slice(),
+        )
+        .await
     }
 
     pub async fn draw_buffer(
@@ -445,238 +887,280 @@ impl Device {
         height: u16,
         buffer: &[u8],
     ) -> Result<()> {
-        let buff_res = construct_draw_buffer_payload(screen.clone(), x, y, width, height, buffer);
-
-        if buff_res.is_err() {
-            return Err(buff_res.unwrap_err());
+        if self.tx_pending_send.is_none() {
+            return Err(LoupedeckError::Disconnected);
         }
 
-        self.send_message(
-            Vec::from(MessageHeader::WriteFrameBuffer),
-            buff_res.unwrap(),
-            true,
-        )
-        .await;
+        // Only send the part of the region that isn't already on the screen
+        let changed = self.scheduler.lock().unwrap().draw_now(
+            &screen,
+            Rect::new(x, y, width, height),
+            buffer,
+        )?;
 
-        Ok(())
-    }
-
-    pub async fn get_info(&mut self) -> Result<DeviceInfo> {
-        let serial_evt = self
-            .send_message(Vec::from(MessageHeader::SerialOut), vec![], true)
-            .await;
-        let version_evt = self
-            .send_message(Vec::from(MessageHeader::VersionOut), vec![], true)
-            .await;
-
-        let mut version_number: Option<String> = None;
-        let mut serial: Option<String> = None;
-
-        if serial_evt.is_some() {
-            let serial_evt_result = serial_evt.unwrap();
-            if serial_evt_result.is_ok() {
-                let serial_evt_event = serial_evt_result.unwrap();
-                if let Event::SerialIn(SerialInEvent { serial_number, .. }) = serial_evt_event {
-                    serial = Some(serial_number);
-                }
-            }
-        }
-
-        if version_evt.is_some() {
-            let version_in_result = version_evt.unwrap();
-            if version_in_result.is_ok() {
-                let version_in_event = version_in_result.unwrap();
-                if let Event::VersionIn(VersionInEvent { version, .. }) = version_in_event {
-                    version_number = Some(version);
-                }
-            }
+        let Some((rect, mut data)) = changed else {
+            return Ok(());
+        };
+        self.model.byte_order.reorder(&mut data);
+
+        let buff = construct_draw_buffer_payload(
+            screen.clone(),
+            rect.x,
+            rect.y,
+            rect.width,
+            rect.height,
+            &data,
+        )?;
+
+        let confirmed = match self
+            .request(MessageHeader::WriteFrameBuffer, buff, self.request_timeout)
+            .await
+        {
+            Ok(Event::ConfirmFrameBufferIn(_)) => Ok(()),
+            Ok(evt) => Err(LoupedeckError::UnexpectedResponse(Box::new(evt))),
+            Err(e) => Err(e),
+        };
+
+        // The device may not show the pixels, so a retry mustn't be skipped as unchanged
+        if confirmed.is_err() {
+            self.scheduler
+                .lock()
+                .unwrap()
+                .invalidate_rect(&screen, rect)?;
         }
 
-        if version_number.is_none() || serial.is_none() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Failed to get device info",
-            ));
-        }
+        confirmed
+    }
 
-        return Ok(DeviceInfo {
-            serial: serial.unwrap(),
-            version: version_number.unwrap(),
-        });
+    pub async fn get_info(&mut self) -> Result<DeviceInfo> {
+        let serial = match self
+            .request(MessageHeader::SerialOut, vec![], self.request_timeout)
+            .await?
+        {
+            Event::SerialIn(SerialInEvent { serial_number, .. }) => serial_number,
+            evt => return Err(LoupedeckError::UnexpectedResponse(Box::new(evt))),
+        };
+
+        let version = match self
+            .request(MessageHeader::VersionOut, vec![], self.request_timeout)
+            .await?
+        {
+            Event::VersionIn(VersionInEvent { version, .. }) => version,
+            evt => return Err(LoupedeckError::UnexpectedResponse(Box::new(evt))),
+        };
+
+        let info = DeviceInfo { serial, version };
+        self.set_info(info.clone());
+
+        Ok(info)
     }
 
-    async fn send_message(
-        &mut self,
-        action: Vec<u8>,
-        data: Vec<u8>,
-        expect_event: bool,
-    ) -> Option<std::result::Result<Event, RecvError>> {
-        let message = construct_basic_message(action, data);
-        return self.send(message.unwrap(), expect_event).await;
+    /// The info from the last successful `get_info` call.
+    pub fn info(&self) -> Option<&DeviceInfo> {
+        self.info.as_ref()
     }
 
-    async fn send(
-        &mut self,
-        message_buffer: Vec<u8>,
-        expect_event: bool,
-    ) -> Option<std::result::Result<Event, RecvError>> {
-        let (response_tx, response_rx): (oneshot::Sender<Event>, oneshot::Receiver<Event>) =
-            oneshot::channel();
-
-        let mut tx_id: u8 = 0x01;
-        if expect_event {
-            tx_id = self.next_tx_id;
-            self.next_tx_id += 1;
+    /// Sets the info without asking the device, e.g. when replaying a capture that has it.
+    pub fn set_info(&mut self, info: DeviceInfo) {
+        if let Some(sink) = &self.capture {
+            sink.record_header(CaptureHeader::new(self.model, Some(info.clone())));
         }
+        self.info = Some(info);
+    }
 
-        let runtime = self.runtime.as_ref().unwrap();
-        let tx_pending_send = self.tx_pending_send.as_ref().unwrap().clone();
-        let message = construct_message_payload(message_buffer, tx_id);
+    /// Sends a message and waits up to `timeout` for the device to respond to it.
+    ///
+    /// The transaction id is released if the request times out or the future is dropped.
+    pub async fn request(
+        &self,
+        header: MessageHeader,
+        data: Vec<u8>,
+        timeout: time::Duration,
+    ) -> Result<Event> {
+        let tx_pending_send = self
+            .tx_pending_send
+            .clone()
+            .ok_or(LoupedeckError::Disconnected)?;
+
+        let request = self.pending_requests.register()?;
+        let message = construct_basic_message(Vec::from(header), data);
+
+        tx_pending_send
+            .send(construct_message_payload(message, request.tx_id))
+            .await
+            .map_err(|_| LoupedeckError::Disconnected)?;
 
-        if self.tx_event.is_some() && expect_event {
-            let mut rx_event = self.tx_event.clone().unwrap().subscribe();
+        request.response(timeout).await
+    }
 
-            runtime.spawn(async move {
-                while let Ok(res) = rx_event.recv().await {
-                    let evt_tx_id = get_tx_id(res.clone());
+    /// Sends a message that the device doesn't respond to.
+    async fn send_message(&self, header: MessageHeader, data: Vec<u8>) -> Result<()> {
+        let tx_pending_send = self
+            .tx_pending_send
+            .clone()
+            .ok_or(LoupedeckError::Disconnected)?;
 
-                    if evt_tx_id.is_some() && evt_tx_id.unwrap() == tx_id {
-                        response_tx.send(res).unwrap();
-                        break;
-                    }
-                }
-            });
-        }
+        let message = construct_basic_message(Vec::from(header), data);
 
-        runtime.spawn(async move {
-            tx_pending_send.send(message).await;
-        });
-
-        if expect_event {
-            return Some(response_rx.await);
-        } else {
-            return None;
-        }
+        tx_pending_send
+            .send(construct_message_payload(message, 0x01))
+            .await
+            .map_err(|_| LoupedeckError::Disconnected)
     }
 
-    fn start_polling(&mut self, mut serial: SerialStream) {
-        let (tx_event, mut rx_event) = broadcast::channel(10);
+    fn start_polling<T: Transport>(&mut self, transport: T, buffered: &[u8]) {
+        let (tx_event, _) = broadcast::channel(10);
         let (tx_pending_send, mut rx_pending_send) = mpsc::channel(100);
-        self.runtime = Some(Runtime::new().unwrap());
+
+        let tx_connected = Arc::new(watch::channel(true).0);
 
         self.tx_pending_send = Some(tx_pending_send.clone());
         self.tx_event = Some(tx_event.clone());
+        self.tx_connected = Some(tx_connected.clone());
+        self.scheduler = Arc::new(Mutex::new(RenderScheduler::new(
+            self.model,
+            self.render_config.clone(),
+        )));
+        self.frame_ready = Arc::new(Notify::new());
 
         println!("Starting polling on port {}", self.port);
 
-        let runtime = self.runtime.as_ref().unwrap();
+        let runtime = self.runtime.get_or_insert_with(Handle::current).clone();
+        let pending_requests = self.pending_requests.clone();
+        let model = self.model;
+        let scheduler = self.scheduler.clone();
+        let frame_ready = self.frame_ready.clone();
+        let (reader, mut writer) = tokio::io::split(transport);
+        let mut frames = FramedRead::new(reader, FrameCodec::host());
+        frames.read_buffer_mut().extend_from_slice(buffered);
 
-        runtime.spawn(async move {
-            loop {
-                // Send any pending messages
-
-                let mut redrawn_screens = HashSet::new();
-
-                // Try to write everything we can from the port
-                while let Ok(message) = rx_pending_send.try_recv() {
-                    time::sleep(time::Duration::from_millis(2)).await;
+        self.io_task = Some(runtime.spawn(async move {
+            let mut last_write: Option<time::Instant> = None;
+            let mut flush_at: Option<time::Instant> = None;
 
-                    loop {
-                        let write_res = serial.write_all(&message);
+            loop {
+                tokio::select! {
+                    message = rx_pending_send.recv() => {
+                        let Some(message) = message else {
+                            break;
+                        };
+
+                        // Send everything that's queued up in one go, so screens are only redrawn once
+                        let mut messages = vec![message];
+                        while let Ok(message) = rx_pending_send.try_recv() {
+                            messages.push(message);
+                        }
 
-                        match write_res {
-                            Ok(_) => {
-                                if let Some(redraw) = is_redraw_event(message.as_slice()) {
-                                    redrawn_screens.insert(redraw);
-                                }
-                                break;
-                            }
-                            Err(e) => {
-                                println!("Error writing to serial: {:?}", e);
-                                break;
-                            }
+                        if let Err(e) = write_messages(&mut writer, messages, &mut last_write).await {
+                            println!("Error writing to transport: {:?}", e);
+                            break;
                         }
                     }
-                }
-
-                if !redrawn_screens.is_empty() {
-                    // Redraw any screens that have been updated
-                    for screen in redrawn_screens {
-                        let redraw_payload = construct_basic_message(
-                            Vec::from(MessageHeader::DrawOut),
-                            Vec::from(screen),
-                        )
-                        .unwrap();
-
-                        let redraw_message = construct_message_payload(redraw_payload, 1);
-
-                        tx_pending_send.send(redraw_message).await;
+                    _ = frame_ready.notified(), if flush_at.is_none() => {
+                        flush_at = Some(scheduler.lock().unwrap().next_tick(time::Instant::now()));
                     }
-                }
-
-                loop {
-                    let mut single_byte: Vec<u8> = vec![0; 1];
-                    let res = serial.try_read(single_byte.as_mut_slice());
+                    _ = time::sleep_until(flush_at.unwrap_or_else(time::Instant::now)), if flush_at.is_some() => {
+                        let messages = frame_messages(&scheduler, model);
+                        if let Err(e) = write_messages(&mut writer, messages, &mut last_write).await {
+                            println!("Error writing to transport: {:?}", e);
+                            break;
+                        }
 
-                    if res.is_err() {
-                        break;
+                        // Anything that didn't fit in this frame goes out with the next one
+                        let scheduler = scheduler.lock().unwrap();
+                        flush_at = scheduler
+                            .has_pending()
+                            .then(|| scheduler.next_tick(time::Instant::now()));
                     }
+                    frame = frames.next() => {
+                        let data = match frame {
+                            Some(Ok(data)) => data,
+                            Some(Err(e)) => {
+                                println!("Error reading from transport: {:?}", e);
+                                break;
+                            }
+                            None => break,
+                        };
+
+                        match parse_serial_message(&data, model) {
+                            Ok(Some(event)) => {
+                                if let Event::TouchEvent(touch) = &event {
+                                    if let Some((screen, region)) = touched_region(model, &touch.screen, touch.x, touch.y) {
+                                        scheduler.lock().unwrap().touched(screen, region);
+                                    }
+                                }
 
-                    if single_byte[0] == 0x82 {
-                        // Found delimiter
-                        serial
-                            .try_read(single_byte.as_mut_slice())
-                            .expect("Found no length data");
-                        let length = single_byte[0] as usize;
-                        // println!("Found message with length {}", length);
-                        let mut data: Vec<u8> = vec![0; length];
-                        serial
-                            .try_read(data.as_mut_slice())
-                            .expect("Found no data!");
-                        let event = parse_serial_message(&data);
-
-                        if event.is_ok() {
-                            let evt_unrw = event.unwrap();
-                            if evt_unrw.is_some() {
-                                tx_event.send(evt_unrw.unwrap());
+                                pending_requests.resolve(&event);
+                                let _ = tx_event.send(event);
                             }
+                            Ok(None) => {}
+                            Err(e) => println!("Ignoring message from device: {}", e),
                         }
                     }
                 }
-
-                time::sleep(time::Duration::from_millis(5)).await
             }
-        });
+
+            // Nothing else is coming back, so don't leave requests waiting on a response
+            pending_requests.cancel_all();
+            tx_connected.send_replace(false);
+        }));
     }
 }
 
-const LOUPEDECK_VENDOR_ID: u16 = 11970;
+impl Drop for Device {
+    fn drop(&mut self) {
+        if let Some(io_task) = self.io_task.take() {
+            io_task.abort();
+        }
+    }
+}
 
-pub fn get_loupedeck_ports() -> Vec<String> {
-    let mut ports = Vec::new();
+/// A serial port with a recognised Loupedeck-family device attached.
+#[derive(Debug, Clone)]
+pub struct LoupedeckPort {
+    pub port: String,
+    pub model: &'static DeviceModel,
+}
 
+pub fn get_loupedeck_devices() -> Vec<LoupedeckPort> {
     tokio_serial::available_ports()
-        .unwrap()
-        .iter()
-        .for_each(|port| {
-            if let tokio_serial::SerialPortType::UsbPort(port_info) = &port.port_type {
-                if port_info.vid == LOUPEDECK_VENDOR_ID {
-                    ports.push(port.port_name.to_string());
-                }
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|port| match &port.port_type {
+            tokio_serial::SerialPortType::UsbPort(port_info) => {
+                DeviceModel::from_usb_ids(port_info.vid, port_info.pid).map(|model| LoupedeckPort {
+                    port: port.port_name.clone(),
+                    model,
+                })
             }
-        });
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn get_loupedeck_ports() -> Vec<String> {
+    get_loupedeck_devices()
+        .into_iter()
+        .map(|device| device.port)
+        .collect()
+}
 
-    return ports;
+fn detect_model(port: &str) -> Option<&'static DeviceModel> {
+    get_loupedeck_devices()
+        .into_iter()
+        .find(|device| device.port == port)
+        .map(|device| device.model)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::construct_draw_buffer_payload;
+    use super::{construct_draw_buffer_payload, parse_serial_message};
+    use crate::{Event, LoupedeckError, Screen, LOUPEDECK_CT, LOUPEDECK_LIVE, LOUPEDECK_LIVE_S};
 
     #[test]
     fn it_create_draw_buffer_red_key_payload() {
         let mut half_red: Vec<u8> = Vec::with_capacity(90 * 90 * 2);
-        for i in 0..(90 * 90) {
+        for _ in 0..(90 * 90) {
             half_red.push(0x00);
             half_red.push(0xF8);
         }
@@ -696,57 +1180,383 @@ mod tests {
         assert_eq!(header, payload.drain(0..header.len()).collect::<Vec<u8>>());
         // assert_eq!(payload.len(), 16213);
     }
+
+    #[test]
+    fn it_rejects_draw_buffers_of_the_wrong_size() {
+        let payload = construct_draw_buffer_payload(crate::Screen::Center, 0, 0, 90, 90, &[0x00]);
+
+        assert!(matches!(payload, Err(LoupedeckError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn it_parses_button_presses() {
+        let event = parse_serial_message(&[0x05, 0x00, 0x00, 0x08, 0x00], &LOUPEDECK_LIVE).unwrap();
+
+        assert!(matches!(event, Some(Event::ButtonPress(_))));
+    }
+
+    #[test]
+    fn it_reports_unknown_headers_as_protocol_errors() {
+        let event = parse_serial_message(&[0xab, 0xcd, 0x00, 0x01], &LOUPEDECK_LIVE);
+
+        match event {
+            Err(LoupedeckError::Protocol { header, bytes }) => {
+                assert_eq!(header, 0xabcd);
+                assert_eq!(bytes, vec![0xab, 0xcd, 0x00, 0x01]);
+            }
+            other => panic!("Expected a protocol error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_reports_malformed_messages_as_protocol_errors() {
+        // Unknown button id
+        assert!(matches!(
+            parse_serial_message(&[0x05, 0x00, 0x00, 0x7f, 0x00], &LOUPEDECK_LIVE),
+            Err(LoupedeckError::Protocol { header: 0x0500, .. })
+        ));
+
+        // Touch event that was cut short
+        assert!(matches!(
+            parse_serial_message(&[0x09, 0x4d, 0x00, 0x00, 0x01], &LOUPEDECK_LIVE),
+            Err(LoupedeckError::Protocol { header: 0x094d, .. })
+        ));
+
+        // Not even a header
+        assert!(matches!(
+            parse_serial_message(&[0x05], &LOUPEDECK_LIVE),
+            Err(LoupedeckError::Protocol { .. })
+        ));
+    }
+
+    #[test]
+    fn it_parses_input_for_each_model() {
+        // Knob 0x00 is the CT's wheel
+        assert!(parse_serial_message(&[0x05, 0x01, 0x00, 0x00, 0x01], &LOUPEDECK_CT).is_ok());
+        assert!(parse_serial_message(&[0x05, 0x01, 0x00, 0x00, 0x01], &LOUPEDECK_LIVE).is_err());
+
+        // The Live S only has two knobs
+        assert!(parse_serial_message(&[0x05, 0x01, 0x00, 0x03, 0x01], &LOUPEDECK_LIVE_S).is_err());
+
+        let touch = [0x09, 0x4d, 0x00, 0x00, 0x00, 0x14, 0x00, 0x14, 0x01];
+        match parse_serial_message(&touch, &LOUPEDECK_LIVE_S).unwrap() {
+            Some(Event::TouchEvent(evt)) => assert_eq!(evt.screen, Screen::Main),
+            evt => panic!("Expected a touch event, got {:?}", evt),
+        }
+        match parse_serial_message(&touch, &LOUPEDECK_LIVE).unwrap() {
+            Some(Event::TouchEvent(evt)) => assert_eq!(evt.screen, Screen::Left),
+            evt => panic!("Expected a touch event, got {:?}", evt),
+        }
+
+        let wheel_touch = [0x09, 0x52, 0x00, 0x00, 0x00, 0x14, 0x00, 0x14, 0x01];
+        match parse_serial_message(&wheel_touch, &LOUPEDECK_CT).unwrap() {
+            Some(Event::TouchEvent(evt)) => assert_eq!(evt.screen, Screen::Wheel),
+            evt => panic!("Expected a touch event, got {:?}", evt),
+        }
+        assert!(parse_serial_message(&wheel_touch, &LOUPEDECK_LIVE).is_err());
+    }
+
+    #[test]
+    fn it_rejects_unknown_screens() {
+        assert_eq!(Screen::try_from(0x41).unwrap(), Screen::Center);
+        assert!(Screen::try_from(0x00).is_err());
+    }
 }
 
-pub fn convert_draw_target_to_rgb565(dt: DrawTarget) -> Vec<u8> {
-    let mut result: Vec<u8> = Vec::new();
+#[cfg(test)]
+mod device_tests {
+    use super::{connect_virtual_device, memory_transport, Device, DeviceInfo, Haptic, Screen};
+    use crate::LoupedeckError;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::time::timeout;
+
+    const UPGRADE_RESPONSE: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\n\r\n";
+
+    async fn accept_upgrade(peer: &mut DuplexStream) {
+        let mut buf = vec![0; 1024];
+        let read = peer.read(&mut buf).await.unwrap();
+        assert!(String::from_utf8_lossy(&buf[..read]).contains("Upgrade: websocket"));
+        peer.write_all(UPGRADE_RESPONSE).await.unwrap();
+    }
+
+    async fn read_bytes(peer: &mut DuplexStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        peer.read_exact(&mut buf).await.unwrap();
+        buf
+    }
 
-    let draw_data = dt.get_data();
-    for px in draw_data {
-        let rgb16 = convert_argb888_to_rgb565(*px);
-        result.append(&mut rgb16.to_le_bytes().to_vec());
+    async fn reply(peer: &mut DuplexStream, message: &[u8]) {
+        let mut frame = vec![0x82, message.len() as u8];
+        frame.extend_from_slice(message);
+        peer.write_all(&frame).await.unwrap();
     }
 
-    result
+    #[tokio::test]
+    async fn it_gets_info_over_a_memory_transport() {
+        let (host, mut peer) = memory_transport();
+
+        let peer_task = tokio::spawn(async move {
+            accept_upgrade(&mut peer).await;
+
+            let serial_out = read_bytes(&mut peer, 9).await;
+            assert_eq!(serial_out[6..8], [0x03, 0x03]);
+            let mut serial_in = vec![0x1f, 0x03, serial_out[8]];
+            serial_in.extend_from_slice(format!("{:<28}", "LDD1234567").as_bytes());
+            reply(&mut peer, &serial_in).await;
+
+            let version_out = read_bytes(&mut peer, 9).await;
+            assert_eq!(version_out[6..8], [0x03, 0x07]);
+            let mut version_in = vec![0x0c, 0x07, version_out[8], 0x00, 0x03, 0x02];
+            version_in.extend_from_slice(&[0x00; 6]);
+            reply(&mut peer, &version_in).await;
+        });
+
+        let mut device = Device::new("memory".to_string());
+        device.connect_transport(host).await.unwrap();
+
+        let info = device.get_info().await.unwrap();
+        assert_eq!(info.serial, "LDD1234567");
+        assert_eq!(info.version, "0.3.2");
+        assert_eq!(device.info().unwrap().serial, "LDD1234567");
+
+        peer_task.await.unwrap();
+        device.disconnect();
+    }
+
+    #[tokio::test]
+    async fn it_times_out_when_the_device_never_responds() {
+        let (host, mut peer) = memory_transport();
+
+        let peer_task = tokio::spawn(async move {
+            accept_upgrade(&mut peer).await;
+            read_bytes(&mut peer, 9).await;
+            peer
+        });
+
+        let mut device = Device::new("memory".to_string());
+        device.set_request_timeout(Duration::from_millis(50));
+        device.connect_transport(host).await.unwrap();
+
+        let info = device.get_info().await;
+        assert!(matches!(info, Err(LoupedeckError::Timeout)));
+
+        peer_task.await.unwrap();
+        device.disconnect();
+    }
+
+    #[tokio::test]
+    async fn it_times_out_when_the_upgrade_is_never_answered() {
+        let (host, _peer) = memory_transport();
+
+        let mut device = Device::new("memory".to_string());
+        device.set_request_timeout(Duration::from_millis(50));
+
+        let connected = device.connect_transport(host).await;
+        assert!(matches!(connected, Err(LoupedeckError::Timeout)));
+        assert!(device.watch_connection().is_none());
+    }
+
+    #[tokio::test]
+    async fn it_vibrates_over_a_memory_transport() {
+        let (host, mut peer) = memory_transport();
+
+        let peer_task = tokio::spawn(async move {
+            accept_upgrade(&mut peer).await;
+            read_bytes(&mut peer, 10).await
+        });
+
+        let mut device = Device::new("memory".to_string());
+        device.connect_transport(host).await.unwrap();
+        device.vibrate(Haptic::Medium).await.unwrap();
+
+        assert_eq!(
+            peer_task.await.unwrap(),
+            vec![0x82, 0x84, 0x00, 0x00, 0x00, 0x00, 0x04, 0x1b, 0x01, 0x0a]
+        );
+        device.disconnect();
+    }
+
+    #[tokio::test]
+    async fn it_draws_a_buffer_over_a_memory_transport() {
+        let (host, mut peer) = memory_transport();
+
+        let peer_task = tokio::spawn(async move {
+            accept_upgrade(&mut peer).await;
+
+            let draw = read_bytes(&mut peer, 14 + 13 + 90 * 90 * 2).await;
+            assert_eq!(draw[0..2], [0x82, 0xff]);
+            assert_eq!(draw[14..16], [0xff, 0x10]);
+            assert_eq!(
+                draw[17..27],
+                [0x00, 0x41, 0x00, 0x5a, 0x00, 0x00, 0x00, 0x5a, 0x00, 0x5a]
+            );
+            reply(&mut peer, &[0x04, 0x10, draw[16]]).await;
+
+            read_bytes(&mut peer, 11).await
+        });
+
+        let mut device = Device::new("memory".to_string());
+        device.connect_transport(host).await.unwrap();
+        device
+            .draw_buffer(Screen::Center, 90, 0, 90, 90, &[0xff; 90 * 90 * 2])
+            .await
+            .unwrap();
+
+        let redraw = peer_task.await.unwrap();
+        assert_eq!(redraw[6..], [0x05, 0x0f, 0x01, 0x00, 0x41]);
+        device.disconnect();
+    }
+
+    #[tokio::test]
+    async fn it_resends_draws_that_failed() {
+        let (host, mut peer) = memory_transport();
+        let draw_len = 14 + 13 + 90 * 90 * 2;
+
+        let peer_task = tokio::spawn(async move {
+            accept_upgrade(&mut peer).await;
+
+            // The first write and its redraw are never confirmed, so it times out
+            read_bytes(&mut peer, draw_len).await;
+            read_bytes(&mut peer, 11).await;
+
+            let retry = read_bytes(&mut peer, draw_len).await;
+            assert_eq!(retry[14..16], [0xff, 0x10]);
+            reply(&mut peer, &[0x04, 0x10, retry[16]]).await;
+            peer
+        });
+
+        let mut device = Device::new("memory".to_string());
+        device.set_request_timeout(Duration::from_millis(50));
+        device.connect_transport(host).await.unwrap();
+
+        let white = [0xff; 90 * 90 * 2];
+        let failed = device
+            .draw_buffer(Screen::Center, 90, 0, 90, 90, &white)
+            .await;
+        assert!(matches!(failed, Err(LoupedeckError::Timeout)));
+
+        device
+            .draw_buffer(Screen::Center, 90, 0, 90, 90, &white)
+            .await
+            .unwrap();
+
+        timeout(Duration::from_secs(1), peer_task)
+            .await
+            .unwrap()
+            .unwrap();
+        device.disconnect();
+    }
+
+    #[tokio::test]
+    async fn it_only_sends_pixels_that_changed() {
+        let (mut device, virtual_device) = connect_virtual_device(DeviceInfo {
+            serial: "LDD1".to_string(),
+            version: "0.1.0".to_string(),
+        })
+        .await
+        .unwrap();
+        let emitter = device.create_external_event_emitter().unwrap();
+        let red = [0x00, 0xf8].repeat(90 * 90);
+
+        let count = |header: [u8; 2]| {
+            virtual_device
+                .messages()
+                .iter()
+                .filter(|message| message[0..2] == header)
+                .count()
+        };
+
+        // Neighbouring keys drawn in a burst go out as one write and one redraw
+        for x in [0, 90] {
+            emitter
+                .draw_rgb565(Screen::Center, x, 0, 90, 90, red.clone())
+                .await
+                .unwrap();
+        }
+
+        timeout(Duration::from_secs(1), async {
+            while count([0x05, 0x0f]) == 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("frame was never flushed");
+        assert_eq!(count([0xff, 0x10]), 1);
+        assert_eq!(virtual_device.pixel(60 + 179, 89), 0xf800);
+        assert_eq!(emitter.render_stats().bytes_sent, 180 * 90 * 2);
+        assert_eq!(emitter.snapshot(), virtual_device.snapshot());
+
+        // Drawing the same pixels again is a no-op
+        emitter
+            .draw_rgb565(Screen::Center, 0, 0, 90, 90, red.clone())
+            .await
+            .unwrap();
+        device
+            .draw_buffer(Screen::Center, 90, 0, 90, 90, &red)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(count([0xff, 0x10]), 1);
+
+        // Only the changed pixel is sent
+        let mut dot = red.clone();
+        dot[(10 * 90 + 20) * 2..][..2].copy_from_slice(&[0xff, 0xff]);
+        device
+            .draw_buffer(Screen::Center, 90, 0, 90, 90, &dot)
+            .await
+            .unwrap();
+
+        let messages = virtual_device.messages();
+        let write = messages
+            .iter()
+            .rfind(|message| message[0..2] == [0xff, 0x10])
+            .unwrap();
+        assert_eq!(
+            write[3..13],
+            [0x00, 0x41, 0x00, 0x6e, 0x00, 0x0a, 0x00, 0x01, 0x00, 0x01]
+        );
+
+        device.disconnect();
+    }
 }
 
-fn convert_argb888_to_rgb565(argb: u32) -> u16 {
-    let r = ((argb >> 16) & 0xff) as u16;
-    let g = ((argb >> 8) & 0xff) as u16;
-    let b = (argb & 0xff) as u16;
-    ((r >> 3) << 11) | ((g >> 2) << 5) | (b >> 3)
+pub fn convert_draw_target_to_rgb565(dt: DrawTarget) -> Vec<u8> {
+    PixelPipeline::default().convert_draw_target(&dt)
 }
 
 #[cfg(test)]
 mod draw_tests {
     use raqote::{DrawOptions, DrawTarget, SolidSource, Source};
 
-    use crate::convert_draw_target_to_rgb565;
-
-    use super::convert_argb888_to_rgb565;
+    use crate::{convert_draw_target_to_rgb565, PixelPipeline};
 
     #[test]
     fn it_converts() {
-        assert_eq!(convert_argb888_to_rgb565(0xFF000000), 0);
-        assert_eq!(convert_argb888_to_rgb565(0xFFFFFFFF), 0xffff);
-        assert_eq!(convert_argb888_to_rgb565(0xFF0000FF), 0x001f);
-        assert_eq!(convert_argb888_to_rgb565(0xFFFF0000), 0xf800);
-        assert_eq!(convert_argb888_to_rgb565(0xFF00FF00), 0x07e0);
+        let convert = |argb| {
+            let data = PixelPipeline::default().convert_premultiplied_argb(&[argb], 1);
+            u16::from_le_bytes([data[0], data[1]])
+        };
+
+        assert_eq!(convert(0xFF000000), 0);
+        assert_eq!(convert(0xFFFFFFFF), 0xffff);
+        assert_eq!(convert(0xFF0000FF), 0x001f);
+        assert_eq!(convert(0xFFFF0000), 0xf800);
+        assert_eq!(convert(0xFF00FF00), 0x07e0);
     }
 
     #[test]
     fn it_works_for_dt_red() {
         let mut dt = DrawTarget::new(1, 1);
 
-        let mut solidRed: SolidSource =
-            SolidSource::from_unpremultiplied_argb(255, 0xFF, 0x00, 0x00);
+        let solid_red: SolidSource = SolidSource::from_unpremultiplied_argb(255, 0xFF, 0x00, 0x00);
 
         dt.fill_rect(
             0.0,
             0.0,
             1.0,
             1.0,
-            &Source::Solid(solidRed),
+            &Source::Solid(solid_red),
             &DrawOptions::new(),
         );
 
@@ -756,7 +1566,7 @@ mod draw_tests {
     fn it_works_for_dt_green() {
         let mut dt = DrawTarget::new(1, 1);
 
-        let mut solidGreen: SolidSource =
+        let solid_green: SolidSource =
             SolidSource::from_unpremultiplied_argb(255, 0x00, 0xFF, 0x00);
 
         dt.fill_rect(
@@ -764,7 +1574,7 @@ mod draw_tests {
             0.0,
             1.0,
             1.0,
-            &Source::Solid(solidGreen),
+            &Source::Solid(solid_green),
             &DrawOptions::new(),
         );
 
@@ -775,15 +1585,14 @@ mod draw_tests {
     fn it_works_for_dt_blue() {
         let mut dt = DrawTarget::new(1, 1);
 
-        let mut solidBlue: SolidSource =
-            SolidSource::from_unpremultiplied_argb(255, 0x00, 0x00, 0xFF);
+        let solid_blue: SolidSource = SolidSource::from_unpremultiplied_argb(255, 0x00, 0x00, 0xFF);
 
         dt.fill_rect(
             0.0,
             0.0,
             1.0,
             1.0,
-            &Source::Solid(solidBlue),
+            &Source::Solid(solid_blue),
             &DrawOptions::new(),
         );
 
@@ -0,0 +1,532 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{ByteOrder, KeyLocation, Knob, Rect, Screen};
+
+pub const LOUPEDECK_VENDOR_ID: u16 = 0x2ec2;
+pub const RAZER_VENDOR_ID: u16 = 0x1532;
+
+/// Where a screen sits on a device's touch surface, in pixels.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScreenLayout {
+    pub screen: Screen,
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl ScreenLayout {
+    const fn new(screen: Screen, x: u16, y: u16, width: u16, height: u16) -> Self {
+        Self {
+            screen,
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn contains(&self, x: u16, y: u16) -> bool {
+        let (x, y) = (x as u32, y as u32);
+        x >= self.x as u32
+            && x < self.x as u32 + self.width as u32
+            && y >= self.y as u32
+            && y < self.y as u32 + self.height as u32
+    }
+}
+
+/// Part of a device's display that a plugin draws to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Region {
+    Key(KeyLocation),
+    /// A block of keys, from the top left key to the bottom right one.
+    Span {
+        from: KeyLocation,
+        to: KeyLocation,
+    },
+    /// A whole screen, e.g. one of the Live's side strips.
+    Screen(Screen),
+    /// The whole main touch surface, across every screen on it.
+    Surface,
+    /// The part of a side strip next to a knob, or the screen in the CT's wheel.
+    Knob(Knob),
+}
+
+/// The piece of a region that lies on one screen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionPart {
+    pub screen: Screen,
+    /// Where the piece is on its screen.
+    pub rect: Rect,
+    /// Where the piece starts within the region.
+    pub offset: (u16, u16),
+}
+
+/// Where a region is on a particular device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionLayout {
+    pub width: u16,
+    pub height: u16,
+    pub parts: Vec<RegionPart>,
+    /// The region's rectangle on the main touch surface, unless it's on the wheel.
+    pub surface: Option<Rect>,
+}
+
+/// Describes the hardware layout of one Loupedeck-family device.
+///
+/// Touch coordinates are relative to the whole touch surface, while draw coordinates are
+/// relative to the screen being drawn to.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DeviceModel {
+    pub name: &'static str,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Screens that make up the main touch surface.
+    pub screens: &'static [ScreenLayout],
+    /// The round screen in the middle of the CT's wheel, which reports its own touches.
+    pub wheel: Option<ScreenLayout>,
+    /// The screen the key grid is drawn on.
+    pub key_screen: Screen,
+    /// Offset of the key grid within `key_screen`.
+    pub key_offset: (u16, u16),
+    pub key_size: u16,
+    pub columns: u8,
+    pub rows: u8,
+    /// Ids the device reports knob rotations with.
+    pub knobs: &'static [u8],
+    /// Ids the device reports button presses with.
+    pub buttons: &'static [u8],
+    /// How the device's screens expect RGB565 pixels.
+    pub byte_order: ByteOrder,
+}
+
+const LIVE_SCREENS: &[ScreenLayout] = &[
+    ScreenLayout::new(Screen::Left, 0, 0, 60, 270),
+    ScreenLayout::new(Screen::Center, 60, 0, 360, 270),
+    ScreenLayout::new(Screen::Right, 420, 0, 60, 270),
+];
+
+const LIVE_BUTTONS: &[u8] = &[
+    0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+];
+
+pub static LOUPEDECK_LIVE: DeviceModel = DeviceModel {
+    name: "Loupedeck Live",
+    vendor_id: LOUPEDECK_VENDOR_ID,
+    product_id: 0x0004,
+    screens: LIVE_SCREENS,
+    wheel: None,
+    key_screen: Screen::Center,
+    key_offset: (0, 0),
+    key_size: 90,
+    columns: 4,
+    rows: 3,
+    knobs: &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+    buttons: LIVE_BUTTONS,
+    byte_order: ByteOrder::LittleEndian,
+};
+
+pub static LOUPEDECK_LIVE_S: DeviceModel = DeviceModel {
+    name: "Loupedeck Live S",
+    vendor_id: LOUPEDECK_VENDOR_ID,
+    product_id: 0x0006,
+    screens: &[ScreenLayout::new(Screen::Main, 0, 0, 480, 270)],
+    wheel: None,
+    key_screen: Screen::Main,
+    key_offset: (15, 0),
+    key_size: 90,
+    columns: 5,
+    rows: 3,
+    knobs: &[0x01, 0x02],
+    buttons: &[0x01, 0x02, 0x07, 0x08, 0x09, 0x0a],
+    byte_order: ByteOrder::LittleEndian,
+};
+
+pub static LOUPEDECK_CT: DeviceModel = DeviceModel {
+    name: "Loupedeck CT",
+    vendor_id: LOUPEDECK_VENDOR_ID,
+    product_id: 0x0003,
+    screens: LIVE_SCREENS,
+    wheel: Some(ScreenLayout::new(Screen::Wheel, 0, 0, 240, 240)),
+    key_screen: Screen::Center,
+    key_offset: (0, 0),
+    key_size: 90,
+    columns: 4,
+    rows: 3,
+    knobs: &[0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+    buttons: &[
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a,
+    ],
+    byte_order: ByteOrder::LittleEndian,
+};
+
+pub static RAZER_STREAM_CONTROLLER: DeviceModel = DeviceModel {
+    name: "Razer Stream Controller",
+    vendor_id: RAZER_VENDOR_ID,
+    product_id: 0x0d06,
+    screens: LIVE_SCREENS,
+    wheel: None,
+    key_screen: Screen::Center,
+    key_offset: (0, 0),
+    key_size: 90,
+    columns: 4,
+    rows: 3,
+    knobs: &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+    buttons: LIVE_BUTTONS,
+    byte_order: ByteOrder::LittleEndian,
+};
+
+pub static DEVICE_MODELS: &[&DeviceModel] = &[
+    &LOUPEDECK_LIVE,
+    &LOUPEDECK_LIVE_S,
+    &LOUPEDECK_CT,
+    &RAZER_STREAM_CONTROLLER,
+];
+
+impl DeviceModel {
+    pub fn from_usb_ids(vendor_id: u16, product_id: u16) -> Option<&'static DeviceModel> {
+        DEVICE_MODELS
+            .iter()
+            .find(|model| model.vendor_id == vendor_id && model.product_id == product_id)
+            .copied()
+    }
+
+    /// Looks a model up by its `name`, e.g. one read back from a capture.
+    pub fn from_name(name: &str) -> Option<&'static DeviceModel> {
+        DEVICE_MODELS
+            .iter()
+            .find(|model| model.name == name)
+            .copied()
+    }
+
+    /// Width and height of the main touch surface.
+    pub fn surface_size(&self) -> (u16, u16) {
+        self.screens.iter().fold((0, 0), |(width, height), layout| {
+            (
+                width.max(layout.x + layout.width),
+                height.max(layout.y + layout.height),
+            )
+        })
+    }
+
+    pub fn screen_layout(&self, screen: &Screen) -> Option<&ScreenLayout> {
+        self.screens
+            .iter()
+            .chain(self.wheel.iter())
+            .find(|layout| layout.screen == *screen)
+    }
+
+    /// The screen under a point on the main touch surface.
+    pub fn screen_at(&self, x: u16, y: u16) -> Option<&ScreenLayout> {
+        self.screens.iter().find(|layout| layout.contains(x, y))
+    }
+
+    /// The key under a point on the main touch surface.
+    pub fn key_at(&self, x: u16, y: u16) -> Option<KeyLocation> {
+        let layout = self.screen_layout(&self.key_screen)?;
+        let x = x.checked_sub(layout.x + self.key_offset.0)? / self.key_size;
+        let y = y.checked_sub(layout.y + self.key_offset.1)? / self.key_size;
+
+        if x >= self.columns as u16 || y >= self.rows as u16 {
+            return None;
+        }
+
+        Some(KeyLocation::new(x as u8, y as u8))
+    }
+
+    /// Top left corner of a key, relative to `key_screen`.
+    pub fn key_origin(&self, key: KeyLocation) -> Option<(u16, u16)> {
+        if key.x >= self.columns || key.y >= self.rows {
+            return None;
+        }
+
+        Some((
+            self.key_offset.0 + key.x as u16 * self.key_size,
+            self.key_offset.1 + key.y as u16 * self.key_size,
+        ))
+    }
+
+    /// A key's rectangle on the main touch surface.
+    fn key_rect(&self, key: KeyLocation) -> Option<Rect> {
+        let layout = self.screen_layout(&self.key_screen)?;
+        let (x, y) = self.key_origin(key)?;
+
+        Some(Rect::new(
+            layout.x + x,
+            layout.y + y,
+            self.key_size,
+            self.key_size,
+        ))
+    }
+
+    /// The third of a side strip next to a knob, on the main touch surface.
+    ///
+    /// The first three knobs sit next to the left strip, top to bottom, and the rest next to
+    /// the right one.
+    fn knob_rect(&self, knob: Knob) -> Option<Rect> {
+        if knob == Knob::Wheel || !self.has_knob(knob as u8) {
+            return None;
+        }
+
+        let index = (knob as u8 - Knob::Knob0 as u8) as u16;
+        let (screen, row) = match index {
+            0..=2 => (Screen::Left, index),
+            _ => (Screen::Right, index - 3),
+        };
+        let layout = self.screen_layout(&screen)?;
+        let height = layout.height / 3;
+
+        Some(Rect::new(
+            layout.x,
+            layout.y + row * height,
+            layout.width,
+            height,
+        ))
+    }
+
+    /// Where a region is on this device, or `None` if the device doesn't have it.
+    pub fn region_layout(&self, region: &Region) -> Option<RegionLayout> {
+        let surface = match region {
+            Region::Key(key) => self.key_rect(*key)?,
+            Region::Span { from, to } if from.x <= to.x && from.y <= to.y => {
+                self.key_rect(*from)?.union(&self.key_rect(*to)?)
+            }
+            Region::Span { .. } => return None,
+            Region::Screen(screen) => {
+                let layout = self.screen_layout(screen)?;
+                if self.wheel.as_ref() == Some(layout) {
+                    return Some(RegionLayout {
+                        width: layout.width,
+                        height: layout.height,
+                        parts: vec![RegionPart {
+                            screen: screen.clone(),
+                            rect: Rect::new(0, 0, layout.width, layout.height),
+                            offset: (0, 0),
+                        }],
+                        surface: None,
+                    });
+                }
+
+                Rect::new(layout.x, layout.y, layout.width, layout.height)
+            }
+            Region::Surface => {
+                let (width, height) = self.surface_size();
+                Rect::new(0, 0, width, height)
+            }
+            Region::Knob(Knob::Wheel) if self.has_knob(Knob::Wheel as u8) => {
+                return self.region_layout(&Region::Screen(Screen::Wheel));
+            }
+            Region::Knob(knob) => self.knob_rect(*knob)?,
+        };
+
+        let parts = self
+            .screens
+            .iter()
+            .filter_map(|layout| {
+                let screen_rect = Rect::new(layout.x, layout.y, layout.width, layout.height);
+                let part = surface.intersection(&screen_rect)?;
+
+                Some(RegionPart {
+                    screen: layout.screen.clone(),
+                    rect: Rect::new(
+                        part.x - layout.x,
+                        part.y - layout.y,
+                        part.width,
+                        part.height,
+                    ),
+                    offset: (part.x - surface.x, part.y - surface.y),
+                })
+            })
+            .collect();
+
+        Some(RegionLayout {
+            width: surface.width,
+            height: surface.height,
+            parts,
+            surface: Some(surface),
+        })
+    }
+
+    pub fn has_button(&self, id: u8) -> bool {
+        self.buttons.contains(&id)
+    }
+
+    pub fn has_knob(&self, id: u8) -> bool {
+        self.knobs.contains(&id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        DeviceModel, Region, LOUPEDECK_CT, LOUPEDECK_LIVE, LOUPEDECK_LIVE_S,
+        RAZER_STREAM_CONTROLLER,
+    };
+    use crate::{KeyLocation, Knob, Rect, Screen};
+
+    #[test]
+    fn it_detects_models_from_usb_ids() {
+        assert_eq!(
+            DeviceModel::from_usb_ids(0x2ec2, 0x0004),
+            Some(&LOUPEDECK_LIVE)
+        );
+        assert_eq!(
+            DeviceModel::from_usb_ids(0x2ec2, 0x0006),
+            Some(&LOUPEDECK_LIVE_S)
+        );
+        assert_eq!(
+            DeviceModel::from_usb_ids(0x2ec2, 0x0003),
+            Some(&LOUPEDECK_CT)
+        );
+        assert_eq!(
+            DeviceModel::from_usb_ids(0x1532, 0x0d06),
+            Some(&RAZER_STREAM_CONTROLLER)
+        );
+        assert_eq!(DeviceModel::from_usb_ids(0x1532, 0x0004), None);
+    }
+
+    #[test]
+    fn it_looks_models_up_by_name() {
+        for model in super::DEVICE_MODELS {
+            assert_eq!(DeviceModel::from_name(model.name), Some(*model));
+        }
+        assert_eq!(DeviceModel::from_name("Loupedeck"), None);
+    }
+
+    #[test]
+    fn it_maps_live_touches() {
+        assert_eq!(LOUPEDECK_LIVE.surface_size(), (480, 270));
+        assert_eq!(
+            LOUPEDECK_LIVE.screen_at(10, 10).unwrap().screen,
+            Screen::Left
+        );
+        assert_eq!(
+            LOUPEDECK_LIVE.screen_at(60, 10).unwrap().screen,
+            Screen::Center
+        );
+        assert_eq!(
+            LOUPEDECK_LIVE.screen_at(479, 10).unwrap().screen,
+            Screen::Right
+        );
+        assert!(LOUPEDECK_LIVE.screen_at(480, 10).is_none());
+        assert!(LOUPEDECK_LIVE.screen_at(u16::MAX, u16::MAX).is_none());
+
+        assert_eq!(LOUPEDECK_LIVE.key_at(10, 10), None);
+        assert_eq!(LOUPEDECK_LIVE.key_at(60, 0), Some(KeyLocation::new(0, 0)));
+        assert_eq!(
+            LOUPEDECK_LIVE.key_at(419, 269),
+            Some(KeyLocation::new(3, 2))
+        );
+        assert_eq!(LOUPEDECK_LIVE.key_at(420, 100), None);
+    }
+
+    #[test]
+    fn it_maps_live_s_touches() {
+        assert_eq!(
+            LOUPEDECK_LIVE_S.screen_at(0, 0).unwrap().screen,
+            Screen::Main
+        );
+        assert_eq!(LOUPEDECK_LIVE_S.key_at(10, 10), None);
+        assert_eq!(LOUPEDECK_LIVE_S.key_at(15, 0), Some(KeyLocation::new(0, 0)));
+        assert_eq!(
+            LOUPEDECK_LIVE_S.key_at(464, 200),
+            Some(KeyLocation::new(4, 2))
+        );
+        assert_eq!(LOUPEDECK_LIVE_S.key_at(465, 200), None);
+    }
+
+    #[test]
+    fn it_finds_key_origins() {
+        assert_eq!(
+            LOUPEDECK_LIVE.key_origin(KeyLocation::new(1, 2)),
+            Some((90, 180))
+        );
+        assert_eq!(LOUPEDECK_LIVE.key_origin(KeyLocation::new(4, 0)), None);
+        assert_eq!(
+            LOUPEDECK_LIVE_S.key_origin(KeyLocation::new(4, 0)),
+            Some((375, 0))
+        );
+    }
+
+    #[test]
+    fn it_lays_out_regions() {
+        let strip = LOUPEDECK_LIVE
+            .region_layout(&Region::Screen(Screen::Right))
+            .unwrap();
+        assert_eq!((strip.width, strip.height), (60, 270));
+        assert_eq!(strip.surface, Some(Rect::new(420, 0, 60, 270)));
+        assert_eq!(strip.parts.len(), 1);
+        assert_eq!(strip.parts[0].rect, Rect::new(0, 0, 60, 270));
+
+        let span = LOUPEDECK_LIVE
+            .region_layout(&Region::Span {
+                from: KeyLocation::new(1, 0),
+                to: KeyLocation::new(2, 1),
+            })
+            .unwrap();
+        assert_eq!((span.width, span.height), (180, 180));
+        assert_eq!(span.parts[0].screen, Screen::Center);
+        assert_eq!(span.parts[0].rect, Rect::new(90, 0, 180, 180));
+
+        let surface = LOUPEDECK_LIVE.region_layout(&Region::Surface).unwrap();
+        assert_eq!((surface.width, surface.height), (480, 270));
+        assert_eq!(
+            surface
+                .parts
+                .iter()
+                .map(|part| (part.screen.clone(), part.offset))
+                .collect::<Vec<_>>(),
+            vec![
+                (Screen::Left, (0, 0)),
+                (Screen::Center, (60, 0)),
+                (Screen::Right, (420, 0))
+            ]
+        );
+
+        let wheel = LOUPEDECK_CT
+            .region_layout(&Region::Screen(Screen::Wheel))
+            .unwrap();
+        assert_eq!((wheel.width, wheel.surface), (240, None));
+        assert_eq!(
+            LOUPEDECK_CT.region_layout(&Region::Knob(Knob::Wheel)),
+            Some(wheel)
+        );
+
+        let knob = LOUPEDECK_LIVE
+            .region_layout(&Region::Knob(Knob::Knob4))
+            .unwrap();
+        assert_eq!(knob.surface, Some(Rect::new(420, 90, 60, 90)));
+        assert_eq!(knob.parts[0].screen, Screen::Right);
+        assert_eq!(knob.parts[0].rect, Rect::new(0, 90, 60, 90));
+        assert!(LOUPEDECK_LIVE
+            .region_layout(&Region::Knob(Knob::Wheel))
+            .is_none());
+        assert!(LOUPEDECK_LIVE_S
+            .region_layout(&Region::Knob(Knob::Knob0))
+            .is_none());
+
+        assert!(LOUPEDECK_LIVE_S
+            .region_layout(&Region::Screen(Screen::Left))
+            .is_none());
+        assert!(LOUPEDECK_LIVE
+            .region_layout(&Region::Span {
+                from: KeyLocation::new(2, 0),
+                to: KeyLocation::new(1, 0),
+            })
+            .is_none());
+    }
+
+    #[test]
+    fn it_describes_inputs() {
+        assert!(LOUPEDECK_CT.has_knob(0x00));
+        assert!(!LOUPEDECK_LIVE.has_knob(0x00));
+        assert!(LOUPEDECK_CT.has_button(0x1a));
+        assert!(!LOUPEDECK_LIVE_S.has_button(0x03));
+        assert_eq!(
+            LOUPEDECK_CT.screen_layout(&Screen::Wheel).unwrap().width,
+            240
+        );
+    }
+}
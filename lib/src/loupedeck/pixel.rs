@@ -0,0 +1,347 @@
+use raqote::DrawTarget;
+use serde::{Deserialize, Serialize};
+
+/// How a device expects the two bytes of an RGB565 pixel.
+///
+/// Pixels are little-endian everywhere inside the library, and only reordered on the way
+/// to a device that wants them the other way around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ByteOrder {
+    #[default]
+    LittleEndian,
+    BigEndian,
+}
+
+impl ByteOrder {
+    pub fn encode(self, pixel: u16) -> [u8; 2] {
+        match self {
+            ByteOrder::LittleEndian => pixel.to_le_bytes(),
+            ByteOrder::BigEndian => pixel.to_be_bytes(),
+        }
+    }
+
+    pub fn decode(self, bytes: [u8; 2]) -> u16 {
+        match self {
+            ByteOrder::LittleEndian => u16::from_le_bytes(bytes),
+            ByteOrder::BigEndian => u16::from_be_bytes(bytes),
+        }
+    }
+
+    /// Reorders little-endian pixels for the device.
+    pub(crate) fn reorder(self, data: &mut [u8]) {
+        if self == ByteOrder::BigEndian {
+            for pixel in data.chunks_exact_mut(2) {
+                pixel.swap(0, 1);
+            }
+        }
+    }
+}
+
+/// How to spread the rounding error of going down to 5 and 6 bit channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Dither {
+    #[default]
+    None,
+    /// A fixed 4x4 Bayer pattern, which stays put when an animation redraws.
+    Ordered,
+    /// Error diffusion, which looks smoother on still images.
+    FloydSteinberg,
+}
+
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Turns 8-bit colour into the RGB565 pixels the screens take.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PixelPipeline {
+    /// Colour that transparent pixels are drawn over.
+    pub background: [u8; 3],
+    /// Brightens (above 1) or darkens (below 1) mid tones before quantizing, e.g. to keep dark
+    /// themes from crushing into black.
+    pub gamma: f32,
+    pub dither: Dither,
+}
+
+impl Default for PixelPipeline {
+    fn default() -> Self {
+        Self {
+            background: [0, 0, 0],
+            gamma: 1.0,
+            dither: Dither::None,
+        }
+    }
+}
+
+impl PixelPipeline {
+    /// Converts premultiplied ARGB pixels, like raqote draws.
+    pub fn convert_premultiplied_argb(&self, pixels: &[u32], width: usize) -> Vec<u8> {
+        let background = self.background.map(|channel| channel as f32);
+
+        let colors = pixels
+            .iter()
+            .map(|argb| {
+                let transparency = 1.0 - (argb >> 24) as f32 / 255.0;
+                let channel = |shift: u32, i: usize| {
+                    ((argb >> shift) & 0xff) as f32 + background[i] * transparency
+                };
+                [channel(16, 0), channel(8, 1), channel(0, 2)]
+            })
+            .collect();
+
+        self.quantize(colors, width)
+    }
+
+    /// Converts rows of RGBA pixels with straight (not premultiplied) alpha.
+    pub fn convert_rgba(&self, rgba: &[u8], width: usize) -> Vec<u8> {
+        let background = self.background.map(|channel| channel as f32);
+
+        let colors = rgba
+            .chunks_exact(4)
+            .map(|pixel| {
+                let alpha = pixel[3] as f32 / 255.0;
+                [0, 1, 2].map(|i| pixel[i] as f32 * alpha + background[i] * (1.0 - alpha))
+            })
+            .collect();
+
+        self.quantize(colors, width)
+    }
+
+    pub fn convert_draw_target(&self, target: &DrawTarget) -> Vec<u8> {
+        self.convert_premultiplied_argb(target.get_data(), target.width() as usize)
+    }
+
+    fn quantize(&self, mut colors: Vec<[f32; 3]>, width: usize) -> Vec<u8> {
+        const BITS: [u32; 3] = [5, 6, 5];
+
+        if self.gamma != 1.0 {
+            let exponent = 1.0 / self.gamma.max(f32::EPSILON);
+            for channel in colors.iter_mut().flatten() {
+                *channel = 255.0 * (channel.clamp(0.0, 255.0) / 255.0).powf(exponent);
+            }
+        }
+
+        let width = width.max(1);
+        let len = colors.len();
+        let mut result = Vec::with_capacity(len * 2);
+
+        for i in 0..len {
+            let (x, y) = (i % width, i / width);
+            let mut pixel = 0u16;
+            let mut error = [0.0; 3];
+
+            for (c, bits) in BITS.into_iter().enumerate() {
+                let max = ((1 << bits) - 1) as f32;
+                let mut value = colors[i][c];
+                if self.dither == Dither::Ordered {
+                    let threshold = (BAYER_4X4[y % 4][x % 4] as f32 + 0.5) / 16.0 - 0.5;
+                    value += threshold * 255.0 / max;
+                }
+
+                let level = (value.clamp(0.0, 255.0) * max / 255.0).round() as u16;
+                error[c] = colors[i][c] - expand(level, bits) as f32;
+                pixel = (pixel << bits) | level;
+            }
+
+            result.extend_from_slice(&pixel.to_le_bytes());
+
+            if self.dither == Dither::FloydSteinberg {
+                let mut diffuse = |target: usize, weight: f32| {
+                    for (channel, error) in colors[target].iter_mut().zip(error) {
+                        *channel += error * weight;
+                    }
+                };
+
+                if x + 1 < width {
+                    diffuse(i + 1, 7.0 / 16.0);
+                }
+                if i + width < len {
+                    if x > 0 {
+                        diffuse(i + width - 1, 3.0 / 16.0);
+                    }
+                    diffuse(i + width, 5.0 / 16.0);
+                    if x + 1 < width {
+                        diffuse(i + width + 1, 1.0 / 16.0);
+                    }
+                }
+            }
+        }
+
+        result
+    }
+}
+
+/// What a 5 or 6 bit level looks like at 8 bits, repeating the high bits in the low ones.
+fn expand(level: u16, bits: u32) -> u16 {
+    (level << (8 - bits)) | (level >> (2 * bits - 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::Path;
+
+    use super::{ByteOrder, Dither, PixelPipeline};
+    use crate::{convert_rgb565_to_rgba, Snapshot};
+
+    fn pixels(data: &[u8]) -> Vec<u16> {
+        data.chunks(2)
+            .map(|pixel| u16::from_le_bytes([pixel[0], pixel[1]]))
+            .collect()
+    }
+
+    /// A horizontal ramp of one channel, `height` rows tall.
+    fn ramp(width: usize, height: usize, channel: usize) -> Vec<u8> {
+        let row: Vec<u8> = (0..width)
+            .flat_map(|x| {
+                let mut pixel = [0, 0, 0, 0xff];
+                pixel[channel] = (x * 255 / (width - 1)) as u8;
+                pixel
+            })
+            .collect();
+        row.repeat(height)
+    }
+
+    #[test]
+    fn it_rounds_to_the_nearest_level() {
+        let pipeline = PixelPipeline::default();
+
+        // Truncating would give 0x0000
+        assert_eq!(
+            pixels(&pipeline.convert_rgba(&[0x07, 0x03, 0x07, 0xff], 1)),
+            vec![0x0821]
+        );
+        assert_eq!(
+            pixels(&pipeline.convert_rgba(&[0xfe, 0xfe, 0xfe, 0xff], 1)),
+            vec![0xffff]
+        );
+    }
+
+    #[test]
+    fn it_composites_over_the_background() {
+        let pipeline = PixelPipeline {
+            background: [0, 0, 0xff],
+            ..PixelPipeline::default()
+        };
+
+        // Half transparent white, premultiplied as raqote stores it
+        assert_eq!(
+            pixels(&pipeline.convert_premultiplied_argb(&[0x80808080], 1)),
+            vec![0x841f]
+        );
+        assert_eq!(
+            pixels(&pipeline.convert_rgba(&[0xff, 0xff, 0xff, 0x80], 1)),
+            vec![0x841f]
+        );
+        assert_eq!(
+            pixels(&pipeline.convert_premultiplied_argb(&[0x00000000], 1)),
+            vec![0x001f]
+        );
+    }
+
+    #[test]
+    fn it_matches_a_reference_ramp() {
+        // A ramp of 0, 36, 72, ... 255 red, as it should look on the device
+        let reference: Vec<u16> = [0, 4, 9, 13, 18, 22, 27, 31]
+            .iter()
+            .map(|level| level << 11)
+            .collect();
+
+        let converted = PixelPipeline::default().convert_rgba(&ramp(8, 1, 0), 8);
+        assert_eq!(pixels(&converted), reference);
+    }
+
+    #[test]
+    fn it_dithers_away_banding() {
+        let (width, height) = (16, 16);
+
+        // How far the average shown over a patch of flat red strays from the real colour,
+        // at the worst red
+        let worst_error = |dither| {
+            let pipeline = PixelPipeline {
+                dither,
+                ..PixelPipeline::default()
+            };
+
+            (0..=255u8)
+                .map(|red| {
+                    let patch = [red, 0, 0, 0xff].repeat(width * height);
+                    let shown = pixels(&pipeline.convert_rgba(&patch, width));
+                    let average = shown
+                        .iter()
+                        .map(|pixel| convert_rgb565_to_rgba(*pixel)[0] as f32)
+                        .sum::<f32>()
+                        / shown.len() as f32;
+                    (average - red as f32).abs()
+                })
+                .fold(0.0, f32::max)
+        };
+
+        let banded = worst_error(Dither::None);
+        assert!(banded > 3.0);
+        assert!(worst_error(Dither::Ordered) < banded / 2.0);
+        assert!(worst_error(Dither::FloydSteinberg) < banded / 2.0);
+    }
+
+    #[test]
+    fn it_corrects_gamma() {
+        let pipeline = PixelPipeline {
+            gamma: 2.2,
+            ..PixelPipeline::default()
+        };
+
+        let shown = pixels(&pipeline.convert_rgba(&[0x40, 0x00, 0xff, 0xff], 1))[0];
+        let [red, green, blue, _] = convert_rgb565_to_rgba(shown);
+        // 255 * (64 / 255) ^ (1 / 2.2)
+        assert!((red as i32 - 136).abs() <= 4);
+        assert_eq!((green, blue), (0, 0xff));
+    }
+
+    /// Converts each image in `testdata/pixel` with each kind of dithering, and compares the
+    /// result with the reviewed PNGs next to it. `UPDATE_GOLDENS=1` writes them anew.
+    #[test]
+    fn it_matches_reference_images() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/pixel");
+
+        for name in ["gradient", "dark-theme"] {
+            let input = image::open(dir.join(format!("{}.png", name)))
+                .unwrap()
+                .into_rgba8();
+            let (width, height) = (input.width() as u16, input.height() as u16);
+
+            for (dither, suffix) in [
+                (Dither::None, "none"),
+                (Dither::Ordered, "ordered"),
+                (Dither::FloydSteinberg, "floyd-steinberg"),
+            ] {
+                let pipeline = PixelPipeline {
+                    dither,
+                    ..PixelPipeline::default()
+                };
+                let converted = pipeline.convert_rgba(input.as_raw(), width as usize);
+                let snapshot = Snapshot::from_rgb565(width, height, &pixels(&converted));
+
+                let golden = dir.join(format!("{}-{}.png", name, suffix));
+                if env::var_os("UPDATE_GOLDENS").is_some() {
+                    snapshot.save_png(&golden).unwrap();
+                    continue;
+                }
+
+                let expected = image::open(&golden).unwrap().into_rgba8();
+                assert!(
+                    snapshot.rgba == expected.into_raw(),
+                    "{} doesn't match",
+                    golden.display()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn it_orders_bytes() {
+        assert_eq!(ByteOrder::BigEndian.encode(0xf800), [0xf8, 0x00]);
+        assert_eq!(ByteOrder::BigEndian.decode([0xf8, 0x00]), 0xf800);
+
+        let mut data = vec![0x00, 0xf8, 0x1f, 0x00];
+        ByteOrder::BigEndian.reorder(&mut data);
+        assert_eq!(data, vec![0xf8, 0x00, 0x00, 0x1f]);
+    }
+}
@@ -1,8 +1,19 @@
-use std::io::Result;
-
 use raqote::DrawTarget;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::runtime::Handle;
+use tokio::sync::watch;
+use tokio::task::{AbortHandle, JoinHandle};
 
-use crate::{KeyLocation, Screen, KEY_SIZE};
+use super::abi::{plugin_spawner, RemoteControl, RemoteHost};
+use crate::{
+    Animation, AnimationHandle, Button, ButtonPressEvent, ExternalDeviceEventEmitter, Haptic, Knob,
+    KnobRotateEvent, LoupedeckError, Region, RegionLayout, Result, Rgb,
+};
 
 #[macro_export]
 macro_rules! export_plugin {
@@ -10,86 +21,725 @@ macro_rules! export_plugin {
         #[doc(hidden)]
         #[no_mangle]
         pub static plugin_declaration: $crate::PluginDeclaration = $crate::PluginDeclaration {
-            rustc_version: $crate::RUSTC_VERSION,
-            core_version: $crate::CORE_VERSION,
-            plugin_id: $plugin_id,
-            register: $register,
+            abi_version: $crate::PLUGIN_ABI_VERSION,
+            core_version: $crate::FfiStr::from_static($crate::CORE_VERSION),
+            plugin_id: $crate::FfiStr::from_static($plugin_id),
+            register: {
+                unsafe extern "C" fn register_plugin(
+                    registrar: $crate::FfiRegistrar,
+                ) -> $crate::FfiStatus {
+                    $crate::register_plugin(registrar, $register)
+                }
+                register_plugin
+            },
         };
     };
 }
 
-pub struct LDPluginRequirement {
-    exclusive: bool,
-}
-
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PluginType {
     Screen,
     Button,
     Knob,
 }
 
+/// Tasks spawned for a page or a plugin instance, so they can be cancelled together.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TaskScope(Arc<Mutex<Vec<AbortHandle>>>);
+
+impl TaskScope {
+    fn track(&self, task: AbortHandle) {
+        let mut tasks = self.0.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(task);
+    }
+
+    pub(crate) fn cancel(&self) {
+        for task in self.0.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+/// Spawns plugin tasks onto the runtime the controller is running on.
+///
+/// A context's spawner is tied to the plugin's page, and its tasks are cancelled whenever
+/// the page is hidden or taken down.
+#[derive(Debug, Clone)]
+pub struct Spawner {
+    runtime: Handle,
+    tasks: Option<TaskScope>,
+}
+
+impl Spawner {
+    pub(crate) fn new(runtime: Handle) -> Self {
+        Self {
+            runtime,
+            tasks: None,
+        }
+    }
+
+    /// The same spawner, with its tasks cancelled along with `tasks`.
+    pub(crate) fn scoped(&self, tasks: TaskScope) -> Self {
+        Self {
+            runtime: self.runtime.clone(),
+            tasks: Some(tasks),
+        }
+    }
+
+    /// The same spawner, for tasks that whoever spawned them stops.
+    pub(crate) fn detached(&self) -> Self {
+        Self::new(self.runtime.clone())
+    }
+
+    /// Spawns a task, which keeps running until it finishes or is aborted.
+    ///
+    /// Plugins link their own copy of tokio, whose timers and IO don't know about the host's
+    /// runtime. This is generic so it's compiled into the plugin, and enters the runtime
+    /// from the plugin's side every time the task is polled.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let task = self.runtime.spawn(InRuntime {
+            runtime: self.runtime.clone(),
+            future: Box::pin(future),
+        });
+
+        if let Some(tasks) = &self.tasks {
+            tasks.track(task.abort_handle());
+        }
+        task
+    }
+}
+
+struct InRuntime<F> {
+    runtime: Handle,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for InRuntime<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = &mut *self;
+        let _guard = this.runtime.enter();
+        this.future.as_mut().poll(cx)
+    }
+}
+
+#[derive(Debug)]
+struct DrawnRegion {
+    layout: RegionLayout,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PageState {
+    Hidden,
+    Visible,
+    /// Taken down for good, after which its plugins can't reach the device.
+    Unmounted,
+}
+
+/// What a page's plugins last drew, and whether the page is on screen.
+///
+/// Pages stay alive while hidden so their plugins can carry on where they left off. Draws
+/// made in the meantime are kept here instead of reaching the device, and replayed when the
+/// page is shown again.
+#[derive(Debug)]
+pub(crate) struct PageView {
+    emitter: ExternalDeviceEventEmitter,
+    drawn: Mutex<HashMap<Region, DrawnRegion>>,
+    colors: Mutex<HashMap<Button, Rgb>>,
+    state: watch::Sender<PageState>,
+    tasks: TaskScope,
+}
+
+impl PageView {
+    pub(crate) fn new(emitter: ExternalDeviceEventEmitter) -> Arc<Self> {
+        Arc::new(Self {
+            emitter,
+            drawn: Mutex::new(HashMap::new()),
+            colors: Mutex::new(HashMap::new()),
+            state: watch::channel(PageState::Hidden).0,
+            tasks: TaskScope::default(),
+        })
+    }
+
+    fn is_visible(&self) -> bool {
+        *self.state.borrow() == PageState::Visible
+    }
+
+    fn check_mounted(&self) -> Result<()> {
+        match *self.state.borrow() {
+            PageState::Unmounted => Err(LoupedeckError::Inactive),
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits for the page to be shown, or taken down.
+    async fn wait_until_visible(&self) {
+        let mut state = self.state.subscribe();
+        let _ = state.wait_for(|state| *state != PageState::Hidden).await;
+    }
+
+    /// Vibrates the device, but only for the page it's showing.
+    async fn vibrate(&self, level: Haptic) -> Result<()> {
+        if !self.is_visible() {
+            return Err(LoupedeckError::Inactive);
+        }
+
+        self.emitter.vibrate(level).await
+    }
+
+    fn draw(&self, region: &Region, layout: &RegionLayout, data: Vec<u8>) -> Result<()> {
+        let expected_len = layout.width as usize * layout.height as usize * 2;
+        if data.len() != expected_len {
+            return Err(LoupedeckError::InvalidInput(format!(
+                "Expected a {} byte buffer for the {}x{} {:?} region but got {} bytes",
+                expected_len,
+                layout.width,
+                layout.height,
+                region,
+                data.len()
+            )));
+        }
+
+        let mut drawn = self.drawn.lock().unwrap();
+        self.check_mounted()?;
+        if self.is_visible() {
+            self.queue(layout, &data)?;
+        }
+
+        drawn.insert(
+            region.clone(),
+            DrawnRegion {
+                layout: layout.clone(),
+                data,
+            },
+        );
+        Ok(())
+    }
+
+    /// Splits a region's pixels between the screens it covers.
+    fn queue(&self, layout: &RegionLayout, data: &[u8]) -> Result<()> {
+        for part in &layout.parts {
+            if part.rect.width == layout.width && part.rect.height == layout.height {
+                self.emitter
+                    .queue_rgb565(part.screen.clone(), part.rect, data)?;
+                continue;
+            }
+
+            let mut piece =
+                Vec::with_capacity(part.rect.width as usize * part.rect.height as usize * 2);
+            for row in part.offset.1..part.offset.1 + part.rect.height {
+                let start = (row as usize * layout.width as usize + part.offset.0 as usize) * 2;
+                piece.extend_from_slice(&data[start..start + part.rect.width as usize * 2]);
+            }
+
+            self.emitter
+                .queue_rgb565(part.screen.clone(), part.rect, &piece)?;
+        }
+
+        Ok(())
+    }
+
+    /// Puts the page on screen, redrawing everything its plugins drew.
+    pub(crate) fn show(&self) -> Result<()> {
+        let drawn = self.drawn.lock().unwrap();
+        self.check_mounted()?;
+        self.state.send_replace(PageState::Visible);
+
+        for region in drawn.values() {
+            self.queue(&region.layout, &region.data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Takes the page off screen, and cancels the tasks its plugins spawned.
+    pub(crate) fn hide(&self) {
+        let _drawn = self.drawn.lock().unwrap();
+        self.state.send_if_modified(|state| {
+            let visible = *state == PageState::Visible;
+            if visible {
+                *state = PageState::Hidden;
+            }
+            visible
+        });
+        self.tasks.cancel();
+    }
+
+    /// Takes the page down for good, so its plugins' tasks stop and they can't reach the
+    /// device any more.
+    pub(crate) fn unmount(&self) {
+        let _drawn = self.drawn.lock().unwrap();
+        self.state.send_replace(PageState::Unmounted);
+        self.tasks.cancel();
+    }
+
+    /// Sets a button's LED, or keeps the colour for when the page is shown if it's hidden.
+    async fn set_color(&self, button: Button, color: Rgb) -> Result<()> {
+        let visible = {
+            let mut colors = self.colors.lock().unwrap();
+            self.check_mounted()?;
+            colors.insert(button, color);
+            self.is_visible()
+        };
+
+        if visible {
+            self.emitter.set_button_color(button, color).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Lights the buttons this page's plugins coloured, and turns off the ones `previous`
+    /// lit that this page doesn't use.
+    pub(crate) async fn show_colors(&self, previous: Option<&PageView>) -> Result<()> {
+        let colors = self.colors.lock().unwrap().clone();
+        let stale: Vec<Button> = previous
+            .map(|previous| previous.colors.lock().unwrap().keys().copied().collect())
+            .unwrap_or_default();
+
+        for button in stale {
+            if !colors.contains_key(&button) {
+                self.emitter
+                    .set_button_color(button, Rgb::new(0, 0, 0))
+                    .await?;
+            }
+        }
+
+        for (button, color) in colors {
+            self.emitter.set_button_color(button, color).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Where a context's draws and vibrations go.
+#[derive(Debug, Clone)]
+enum Host {
+    /// The controller, in the same binary as the plugin.
+    Local {
+        view: Arc<PageView>,
+        layout: RegionLayout,
+    },
+    /// The controller, on the other side of the plugin library's C ABI.
+    Remote(Arc<RemoteHost>),
+}
+
 #[derive(Debug, Clone)]
 pub struct PluginScreenContext {
-    device_event_emitter: crate::ExternalDeviceEventEmitter,
-    position: Screen,
-    key_id: KeyLocation,
+    region: Region,
+    size: (u16, u16),
+    spawner: Spawner,
+    host: Host,
 }
 
 impl PluginScreenContext {
-    pub(crate) fn new(
-        device_event_emitter: crate::ExternalDeviceEventEmitter,
-        position: Screen,
-        key_id: KeyLocation,
-    ) -> Self {
-        println!("PluginScreenContext::new {:?}", key_id);
+    pub(crate) fn new(view: Arc<PageView>, region: Region, spawner: Spawner) -> Result<Self> {
+        println!("PluginScreenContext::new {:?}", region);
 
-        Self {
-            position,
-            key_id,
-            device_event_emitter,
+        let model = view.emitter.model();
+        let layout = model.region_layout(&region).ok_or_else(|| {
+            LoupedeckError::InvalidInput(format!("{} has no {:?} region", model.name, region))
+        })?;
+
+        Ok(Self {
+            region,
+            size: (layout.width, layout.height),
+            spawner: spawner.scoped(view.tasks.clone()),
+            host: Host::Local { view, layout },
+        })
+    }
+
+    /// A plugin library's context, backed by the host's, whose tasks are cancelled with
+    /// `tasks`.
+    pub(crate) fn remote(host: RemoteHost, tasks: TaskScope) -> Result<Self> {
+        Ok(Self {
+            region: host.region()?,
+            size: host.size(),
+            spawner: plugin_spawner().scoped(tasks),
+            host: Host::Remote(Arc::new(host)),
+        })
+    }
+
+    /// Where the plugin should spawn any background work, like redrawing on a timer.
+    pub fn spawner(&self) -> &Spawner {
+        &self.spawner
+    }
+
+    /// The part of the display this plugin draws to.
+    pub fn region(&self) -> &Region {
+        &self.region
+    }
+
+    /// Where the region is on the device, for contexts the controller made itself.
+    pub(crate) fn layout(&self) -> Option<&RegionLayout> {
+        match &self.host {
+            Host::Local { layout, .. } => Some(layout),
+            Host::Remote(_) => None,
         }
     }
 
+    /// Width and height of this plugin's region, which every draw has to fill.
+    pub fn size(&self) -> (u16, u16) {
+        self.size
+    }
+
     pub async fn draw_target(&self, target: DrawTarget) -> Result<()> {
-        let x: u16 = KEY_SIZE * (self.key_id.x as u16);
-        let y: u16 = KEY_SIZE * (self.key_id.y as u16);
+        self.draw_rgb565(crate::convert_draw_target_to_rgb565(target))
+            .await
+    }
+
+    /// Draws the region, or keeps the pixels for when the page is shown if it's hidden.
+    pub async fn draw_rgb565(&self, data: Vec<u8>) -> Result<()> {
+        self.draw_rgb565_now(data)
+    }
+
+    pub(crate) fn draw_rgb565_now(&self, data: Vec<u8>) -> Result<()> {
+        match &self.host {
+            Host::Local { view, layout } => view.draw(&self.region, layout, data),
+            Host::Remote(host) => host.draw_rgb565(&data),
+        }
+    }
+
+    /// Loads a PNG, JPEG, GIF or SVG file and draws it across this plugin's region.
+    pub async fn draw_image(
+        &self,
+        source: impl Into<crate::ImageSource>,
+        options: impl Into<crate::ImageOptions>,
+    ) -> Result<()> {
+        let (width, height) = self.size();
+
+        self.draw_rgb565(crate::render_image(source, width, height, options)?)
+            .await
+    }
+
+    /// Lays out text to fill this plugin's region and draws it.
+    pub async fn draw_text(&self, text: &str, style: &crate::TextStyle) -> Result<()> {
+        let (width, height) = self.size();
+        let data =
+            crate::convert_draw_target_to_rgb565(crate::render_text(text, width, height, style)?);
+
+        self.draw_rgb565(data).await
+    }
+
+    /// Plays an animation on this plugin's region until the handle is dropped.
+    ///
+    /// Playback pauses on the current frame while the page is hidden.
+    pub fn play_animation(&self, animation: Animation) -> AnimationHandle {
+        AnimationHandle::play(self.clone(), animation)
+    }
+
+    /// Whether this plugin's page is the one the device is showing.
+    pub fn is_visible(&self) -> bool {
+        match &self.host {
+            Host::Local { view, .. } => view.is_visible(),
+            Host::Remote(host) => host.is_visible(),
+        }
+    }
+
+    /// Waits for the page to be shown, or returns straight away once it's been taken down.
+    pub async fn wait_until_visible(&self) {
+        match &self.host {
+            Host::Local { view, .. } => view.wait_until_visible().await,
+            Host::Remote(host) => host.wait_until_visible().await,
+        }
+    }
+
+    /// Vibrates the device, unless the page isn't the one it's showing.
+    pub async fn vibrate(&self, level: Haptic) -> Result<()> {
+        // println!("Sending vibration: {:?}", level);
+        match &self.host {
+            Host::Local { view, .. } => view.vibrate(level).await,
+            Host::Remote(host) => host.vibrate(level).await,
+        }
+    }
+}
+
+/// Where a button or knob context's vibrations and LED colours go.
+#[derive(Debug, Clone)]
+enum ControlHost {
+    Local(Arc<PageView>),
+    /// The controller, on the other side of the plugin library's C ABI. It already knows
+    /// which control the context is for.
+    Remote(Arc<RemoteControl>),
+}
+
+/// What button and knob contexts have in common.
+#[derive(Debug, Clone)]
+pub(crate) struct ControlContext {
+    spawner: Spawner,
+    host: ControlHost,
+}
+
+impl ControlContext {
+    fn local(view: Arc<PageView>, spawner: Spawner) -> Self {
+        Self {
+            spawner: spawner.scoped(view.tasks.clone()),
+            host: ControlHost::Local(view),
+        }
+    }
+
+    /// A plugin library's context, backed by the host's, whose tasks are cancelled with
+    /// `tasks`.
+    pub(crate) fn remote(host: RemoteControl, tasks: TaskScope) -> Self {
+        Self {
+            spawner: plugin_spawner().scoped(tasks),
+            host: ControlHost::Remote(Arc::new(host)),
+        }
+    }
+
+    pub(crate) fn spawner(&self) -> &Spawner {
+        &self.spawner
+    }
+
+    pub(crate) fn is_visible(&self) -> bool {
+        match &self.host {
+            ControlHost::Local(view) => view.is_visible(),
+            ControlHost::Remote(host) => host.is_visible(),
+        }
+    }
+
+    pub(crate) async fn wait_until_visible(&self) {
+        match &self.host {
+            ControlHost::Local(view) => view.wait_until_visible().await,
+            ControlHost::Remote(host) => host.wait_until_visible().await,
+        }
+    }
+
+    pub(crate) async fn vibrate(&self, level: Haptic) -> Result<()> {
+        match &self.host {
+            ControlHost::Local(view) => view.vibrate(level).await,
+            ControlHost::Remote(host) => host.vibrate(level).await,
+        }
+    }
+
+    pub(crate) async fn set_color(&self, button: Button, color: Rgb) -> Result<()> {
+        match &self.host {
+            ControlHost::Local(view) => view.set_color(button, color).await,
+            ControlHost::Remote(host) => host.set_color(color).await,
+        }
+    }
+}
+
+/// What a button plugin is given to light up and vibrate for its button.
+#[derive(Debug, Clone)]
+pub struct PluginButtonContext {
+    button: Button,
+    control: ControlContext,
+}
 
-        self.device_event_emitter
-            .draw_target(self.position.clone(), x, y, KEY_SIZE, KEY_SIZE, target)
-            .await;
+impl PluginButtonContext {
+    pub(crate) fn new(view: Arc<PageView>, button: Button, spawner: Spawner) -> Result<Self> {
+        let model = view.emitter.model();
+        if !model.has_button(button as u8) {
+            return Err(LoupedeckError::InvalidInput(format!(
+                "{} has no {:?} button",
+                model.name, button
+            )));
+        }
+
+        Ok(Self {
+            button,
+            control: ControlContext::local(view, spawner),
+        })
+    }
+
+    pub(crate) fn remote(button: Button, control: ControlContext) -> Self {
+        Self { button, control }
+    }
+
+    pub(crate) fn control(&self) -> &ControlContext {
+        &self.control
+    }
+
+    /// The button this plugin is bound to.
+    pub fn button(&self) -> Button {
+        self.button
+    }
+
+    pub fn spawner(&self) -> &Spawner {
+        self.control.spawner()
+    }
+
+    /// Whether this plugin's page is the one the device is showing.
+    pub fn is_visible(&self) -> bool {
+        self.control.is_visible()
+    }
+
+    pub async fn wait_until_visible(&self) {
+        self.control.wait_until_visible().await
+    }
+
+    pub async fn vibrate(&self, level: Haptic) -> Result<()> {
+        self.control.vibrate(level).await
+    }
+
+    /// Sets the button's LED, or keeps the colour for when the page is shown if it's hidden.
+    pub async fn set_color(&self, color: Rgb) -> Result<()> {
+        self.control.set_color(self.button, color).await
+    }
+}
+
+/// What a knob plugin is given to draw next to and vibrate for its knob.
+#[derive(Debug, Clone)]
+pub struct PluginKnobContext {
+    knob: Knob,
+    control: ControlContext,
+    strip: Option<PluginScreenContext>,
+}
+
+impl PluginKnobContext {
+    pub(crate) fn new(view: Arc<PageView>, knob: Knob, spawner: Spawner) -> Result<Self> {
+        let model = view.emitter.model();
+        if !model.has_knob(knob as u8) {
+            return Err(LoupedeckError::InvalidInput(format!(
+                "{} has no {:?} knob",
+                model.name, knob
+            )));
+        }
+
+        let strip =
+            PluginScreenContext::new(view.clone(), Region::Knob(knob), spawner.clone()).ok();
+
+        Ok(Self {
+            knob,
+            control: ControlContext::local(view, spawner),
+            strip,
+        })
+    }
+
+    pub(crate) fn remote(
+        knob: Knob,
+        control: ControlContext,
+        strip: Option<PluginScreenContext>,
+    ) -> Self {
+        Self {
+            knob,
+            control,
+            strip,
+        }
+    }
 
+    pub(crate) fn control(&self) -> &ControlContext {
+        &self.control
+    }
+
+    /// The knob this plugin is bound to.
+    pub fn knob(&self) -> Knob {
+        self.knob
+    }
+
+    /// The part of the display next to the knob, if the device has one there.
+    pub fn strip(&self) -> Option<&PluginScreenContext> {
+        self.strip.as_ref()
+    }
+
+    pub fn spawner(&self) -> &Spawner {
+        self.control.spawner()
+    }
+
+    /// Whether this plugin's page is the one the device is showing.
+    pub fn is_visible(&self) -> bool {
+        self.control.is_visible()
+    }
+
+    pub async fn wait_until_visible(&self) {
+        self.control.wait_until_visible().await
+    }
+
+    pub async fn vibrate(&self, level: Haptic) -> Result<()> {
+        self.control.vibrate(level).await
+    }
+}
+
+/// A point in a plugin instance's life that the controller tells it about.
+///
+/// WASM plugins get these as the numbers below.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Lifecycle {
+    Mount = 0,
+    Visible = 1,
+    Hidden = 2,
+    Unmount = 3,
+    Shutdown = 4,
+}
+
+/// Hooks a plugin instance can implement to follow its page, which all do nothing by default.
+///
+/// Tasks spawned through a context are cancelled when its page is hidden, so anything that
+/// should only run while the page is showing can start in `on_visible`.
+pub trait PluginLifecycle {
+    /// The instance was put on its page, which may not be showing yet.
+    fn on_mount(&self) -> Result<()> {
         Ok(())
     }
 
-    pub async fn draw_rgb565(&self, data: Vec<u8>) -> Result<()> {
-        let x: u16 = KEY_SIZE * (self.key_id.x as u16);
-        let y: u16 = KEY_SIZE * (self.key_id.y as u16);
+    /// The page is now the one the device is showing.
+    fn on_visible(&self) -> Result<()> {
+        Ok(())
+    }
 
-        self.device_event_emitter
-            .draw_rgb565(self.position.clone(), x, y, KEY_SIZE, KEY_SIZE, data)
-            .await;
+    /// Another page replaced this one, and the tasks spawned through the context were cancelled.
+    fn on_hidden(&self) -> Result<()> {
+        Ok(())
+    }
 
+    /// The page was taken down, e.g. because its config changed or the device went away. The
+    /// instance is dropped next, and its context can't reach the device any more.
+    fn on_unmount(&self) -> Result<()> {
         Ok(())
     }
 
-    pub async fn vibrate(&self, level: crate::Haptic) -> Result<()> {
-        // println!("Sending vibration: {:?}", level);
-        self.device_event_emitter.vibrate(level).await;
+    /// The controller is shutting down, e.g. for the plugin to save its state. The instance
+    /// is unmounted after.
+    fn on_shutdown(&self) -> Result<()> {
         Ok(())
     }
+
+    /// Runs the hook for `hook`, which plugins running elsewhere forward instead.
+    fn on_lifecycle(&self, hook: Lifecycle) -> Result<()> {
+        match hook {
+            Lifecycle::Mount => self.on_mount(),
+            Lifecycle::Visible => self.on_visible(),
+            Lifecycle::Hidden => self.on_hidden(),
+            Lifecycle::Unmount => self.on_unmount(),
+            Lifecycle::Shutdown => self.on_shutdown(),
+        }
+    }
 }
 
-pub trait ScreenPlugin {
+pub trait ScreenPlugin: PluginLifecycle {
     fn on_touch(&self, position: crate::TouchEvent) -> Result<()>;
 }
 
-#[derive(Debug, Clone, PartialEq)]
+pub trait ButtonPlugin: PluginLifecycle + Send + Sync {
+    fn on_press(&self, event: ButtonPressEvent) -> Result<()>;
+}
+
+pub trait KnobPlugin: PluginLifecycle + Send + Sync {
+    fn on_rotate(&self, event: KnobRotateEvent) -> Result<()>;
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct ScreenPluginOptions {
     pub exclusive: bool,
 }
 
 pub type ScreenPluginFactory = fn(ctx: PluginScreenContext) -> Box<dyn ScreenPlugin>;
 
+pub type ButtonPluginFactory = fn(ctx: PluginButtonContext) -> Box<dyn ButtonPlugin>;
+
+pub type KnobPluginFactory = fn(ctx: PluginKnobContext) -> Box<dyn KnobPlugin>;
+
 pub trait PluginRegistrar {
     fn register_screen(
         &mut self,
@@ -97,12 +747,167 @@ pub trait PluginRegistrar {
         options: ScreenPluginOptions,
         create: ScreenPluginFactory,
     ) -> Result<()>;
+
+    fn register_button(&mut self, name: &str, create: ButtonPluginFactory) -> Result<()>;
+
+    fn register_knob(&mut self, name: &str, create: KnobPluginFactory) -> Result<()>;
 }
 
-#[derive(Copy, Clone)]
-pub struct PluginDeclaration {
-    pub rustc_version: &'static str,
-    pub core_version: &'static str,
-    pub plugin_id: &'static str,
-    pub register: unsafe extern "C" fn(&mut dyn PluginRegistrar),
+#[cfg(test)]
+mod tests {
+    use super::{PageView, PluginButtonContext, PluginKnobContext, PluginScreenContext, Spawner};
+    use crate::{
+        connect_test_page, Button, Haptic, KeyLocation, Knob, LoupedeckError, Region, Rgb, Screen,
+        VirtualDevice,
+    };
+    use std::time::Instant;
+    use tokio::runtime::Handle;
+    use tokio::time::{sleep, Duration};
+
+    async fn wait_for_color(virtual_device: &VirtualDevice, button: Button, color: Rgb) {
+        let started = Instant::now();
+        while virtual_device.button_colors().get(&(button as u8)) != Some(&color) {
+            assert!(started.elapsed() < Duration::from_secs(1));
+            sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[test]
+    fn it_spawns_from_outside_the_runtime() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let spawner = Spawner::new(runtime.handle().clone());
+
+        let task = std::thread::spawn(move || {
+            spawner.spawn(async {
+                sleep(Duration::from_millis(1)).await;
+                5
+            })
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(runtime.block_on(task).unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn it_keeps_draws_for_hidden_pages() {
+        let (_device, _virtual_device, emitter, view) = connect_test_page().await;
+        let ctx = PluginScreenContext::new(
+            view.clone(),
+            Region::Key(KeyLocation::new(1, 0)),
+            Spawner::new(Handle::current()),
+        )
+        .unwrap();
+
+        assert!(!ctx.is_visible());
+        ctx.draw_rgb565([0x00, 0xf8].repeat(90 * 90)).await.unwrap();
+        assert_eq!(emitter.snapshot().pixel(190, 45), [0, 0, 0, 0xff]);
+        assert!(ctx.draw_rgb565(vec![0; 2]).await.is_err());
+
+        view.show().unwrap();
+        ctx.wait_until_visible().await;
+        assert_eq!(emitter.snapshot().pixel(190, 45), [0xff, 0, 0, 0xff]);
+
+        view.hide();
+        ctx.draw_rgb565([0x1f, 0x00].repeat(90 * 90)).await.unwrap();
+        assert_eq!(emitter.snapshot().pixel(190, 45), [0xff, 0, 0, 0xff]);
+    }
+
+    #[tokio::test]
+    async fn it_stops_plugins_of_inactive_pages() {
+        let (_device, _virtual_device, _emitter, view) = connect_test_page().await;
+        let ctx = PluginScreenContext::new(
+            view.clone(),
+            Region::Key(KeyLocation::new(1, 0)),
+            Spawner::new(Handle::current()),
+        )
+        .unwrap();
+
+        view.show().unwrap();
+        ctx.vibrate(Haptic::Short).await.unwrap();
+        let task = ctx.spawner().spawn(std::future::pending::<()>());
+
+        // Hidden pages' tasks are cancelled, and they can't vibrate the device
+        view.hide();
+        assert!(task.await.unwrap_err().is_cancelled());
+        assert!(matches!(
+            ctx.vibrate(Haptic::Short).await,
+            Err(LoupedeckError::Inactive)
+        ));
+
+        // Once it's taken down, nothing gets through
+        view.unmount();
+        ctx.wait_until_visible().await;
+        assert!(matches!(
+            ctx.draw_rgb565([0x00, 0xf8].repeat(90 * 90)).await,
+            Err(LoupedeckError::Inactive)
+        ));
+        assert!(view.show().is_err());
+    }
+
+    #[tokio::test]
+    async fn it_draws_across_screens() {
+        let (_device, _virtual_device, emitter, view) = connect_test_page().await;
+        view.show().unwrap();
+        let context = |region| {
+            PluginScreenContext::new(view.clone(), region, Spawner::new(Handle::current())).unwrap()
+        };
+
+        let strip = context(Region::Screen(Screen::Right));
+        assert_eq!(strip.size(), (60, 270));
+        strip
+            .draw_rgb565([0x00, 0xf8].repeat(60 * 270))
+            .await
+            .unwrap();
+        assert_eq!(emitter.snapshot().pixel(450, 200), [0xff, 0, 0, 0xff]);
+        assert_eq!(emitter.snapshot().pixel(419, 200), [0, 0, 0, 0xff]);
+
+        // The left half of the surface is blue, and the right half green
+        let surface = context(Region::Surface);
+        let row = [[0x1f, 0x00].repeat(240), [0xe0, 0x07].repeat(240)].concat();
+        surface.draw_rgb565(row.repeat(270)).await.unwrap();
+
+        let snapshot = emitter.snapshot();
+        assert_eq!(snapshot.pixel(10, 10), [0, 0, 0xff, 0xff]);
+        assert_eq!(snapshot.pixel(239, 10), [0, 0, 0xff, 0xff]);
+        assert_eq!(snapshot.pixel(240, 10), [0, 0xff, 0, 0xff]);
+        assert_eq!(snapshot.pixel(470, 260), [0, 0xff, 0, 0xff]);
+
+        assert!(PluginScreenContext::new(
+            view,
+            Region::Screen(Screen::Main),
+            Spawner::new(Handle::current())
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn it_keeps_button_colors_for_hidden_pages() {
+        let (_device, virtual_device, emitter, first) = connect_test_page().await;
+        let spawner = Spawner::new(Handle::current());
+        let second = PageView::new(emitter);
+
+        let ctx =
+            PluginButtonContext::new(first.clone(), Button::Circle1, spawner.clone()).unwrap();
+        ctx.set_color(Rgb::new(0xff, 0, 0)).await.unwrap();
+        sleep(Duration::from_millis(20)).await;
+        assert!(virtual_device.button_colors().is_empty());
+
+        first.show().unwrap();
+        first.show_colors(None).await.unwrap();
+        wait_for_color(&virtual_device, Button::Circle1, Rgb::new(0xff, 0, 0)).await;
+
+        // The next page turns off the buttons it doesn't use
+        first.hide();
+        second.show().unwrap();
+        second.show_colors(Some(&first)).await.unwrap();
+        wait_for_color(&virtual_device, Button::Circle1, Rgb::new(0, 0, 0)).await;
+
+        // Only the CT has an enter button or a wheel
+        assert!(PluginButtonContext::new(second.clone(), Button::Enter, spawner.clone()).is_err());
+        assert!(PluginKnobContext::new(second.clone(), Knob::Wheel, spawner.clone()).is_err());
+
+        let knob = PluginKnobContext::new(second, Knob::Knob0, spawner).unwrap();
+        assert_eq!(knob.strip().unwrap().size(), (60, 90));
+    }
 }
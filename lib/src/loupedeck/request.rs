@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use super::get_tx_id;
+use crate::{Event, LoupedeckError, Result};
+
+// 0x00 is used by events the device sends on its own and 0x01 by messages that don't
+// expect a reply, so requests are numbered from 0x02 upwards.
+const FIRST_TX_ID: u8 = 0x02;
+
+/// Requests that are waiting on a response from the device, keyed by transaction id.
+#[derive(Debug, Clone)]
+pub(crate) struct PendingRequests {
+    inner: Arc<Mutex<PendingRequestsInner>>,
+}
+
+#[derive(Debug)]
+struct PendingRequestsInner {
+    next_tx_id: u8,
+    waiting: HashMap<u8, oneshot::Sender<Event>>,
+}
+
+impl PendingRequests {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(PendingRequestsInner {
+                next_tx_id: FIRST_TX_ID,
+                waiting: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Reserves the next free transaction id for a request.
+    pub(crate) fn register(&self) -> Result<PendingRequest> {
+        let mut inner = self.inner.lock().unwrap();
+        let available = (u8::MAX - FIRST_TX_ID) as usize + 1;
+
+        for _ in 0..available {
+            let tx_id = inner.next_tx_id;
+            inner.next_tx_id = tx_id.checked_add(1).unwrap_or(FIRST_TX_ID);
+
+            if inner.waiting.contains_key(&tx_id) {
+                continue;
+            }
+
+            let (response_tx, response_rx) = oneshot::channel();
+            inner.waiting.insert(tx_id, response_tx);
+
+            return Ok(PendingRequest {
+                tx_id,
+                response_rx,
+                requests: self.clone(),
+            });
+        }
+
+        Err(LoupedeckError::NoFreeTransactionId)
+    }
+
+    /// Hands an event to the request waiting on its transaction id, if there is one.
+    pub(crate) fn resolve(&self, event: &Event) -> bool {
+        let tx_id = match get_tx_id(event) {
+            Some(tx_id) if tx_id >= FIRST_TX_ID => tx_id,
+            _ => return false,
+        };
+
+        match self.inner.lock().unwrap().waiting.remove(&tx_id) {
+            Some(response_tx) => response_tx.send(event.clone()).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Fails every outstanding request, e.g. because the connection went away.
+    pub(crate) fn cancel_all(&self) {
+        self.inner.lock().unwrap().waiting.clear();
+    }
+
+    fn cancel(&self, tx_id: u8) {
+        self.inner.lock().unwrap().waiting.remove(&tx_id);
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.inner.lock().unwrap().waiting.len()
+    }
+}
+
+/// A transaction id reserved for a single request.
+///
+/// Dropping it releases the id, so a caller that gives up on a response doesn't leak it.
+#[derive(Debug)]
+pub(crate) struct PendingRequest {
+    pub(crate) tx_id: u8,
+    response_rx: oneshot::Receiver<Event>,
+    requests: PendingRequests,
+}
+
+impl PendingRequest {
+    pub(crate) async fn response(mut self, timeout: Duration) -> Result<Event> {
+        match tokio::time::timeout(timeout, &mut self.response_rx).await {
+            Ok(Ok(event)) => Ok(event),
+            Ok(Err(_)) => Err(LoupedeckError::Disconnected),
+            Err(_) => Err(LoupedeckError::Timeout),
+        }
+    }
+}
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        self.requests.cancel(self.tx_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PendingRequests, FIRST_TX_ID};
+    use crate::{DrawInEvent, Event, LoupedeckError};
+    use std::time::Duration;
+
+    fn draw_in(tx_id: u8) -> Event {
+        Event::DrawIn(DrawInEvent { tx_id })
+    }
+
+    #[test]
+    fn it_wraps_around_without_using_reserved_ids() {
+        let requests = PendingRequests::new();
+
+        let ids: Vec<u8> = (0..600)
+            .map(|_| requests.register().unwrap().tx_id)
+            .collect();
+
+        assert!(ids.iter().all(|id| *id >= FIRST_TX_ID));
+        assert_eq!(ids[0], FIRST_TX_ID);
+        assert_eq!(ids[253], 0xff);
+        assert_eq!(ids[254], FIRST_TX_ID);
+    }
+
+    #[test]
+    fn it_skips_ids_that_are_still_waiting() {
+        let requests = PendingRequests::new();
+        let held = requests.register().unwrap();
+
+        let mut ids = Vec::new();
+        for _ in 0..254 {
+            ids.push(requests.register().unwrap().tx_id);
+        }
+
+        assert!(!ids.contains(&held.tx_id));
+    }
+
+    #[test]
+    fn it_runs_out_of_ids() {
+        let requests = PendingRequests::new();
+        let _held: Vec<_> = (0..254).map(|_| requests.register().unwrap()).collect();
+
+        assert!(matches!(
+            requests.register(),
+            Err(LoupedeckError::NoFreeTransactionId)
+        ));
+    }
+
+    #[test]
+    fn it_releases_ids_when_dropped() {
+        let requests = PendingRequests::new();
+        let request = requests.register().unwrap();
+        assert_eq!(requests.len(), 1);
+
+        drop(request);
+        assert_eq!(requests.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn it_resolves_matching_responses() {
+        let requests = PendingRequests::new();
+        let request = requests.register().unwrap();
+
+        assert!(!requests.resolve(&draw_in(0x00)));
+        assert!(!requests.resolve(&draw_in(0x01)));
+        assert!(requests.resolve(&draw_in(request.tx_id)));
+
+        let response = request.response(Duration::from_secs(1)).await.unwrap();
+        assert!(matches!(response, Event::DrawIn(_)));
+        assert_eq!(requests.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn it_times_out() {
+        let requests = PendingRequests::new();
+        let request = requests.register().unwrap();
+
+        let response = request.response(Duration::from_millis(10)).await;
+
+        assert!(matches!(response, Err(LoupedeckError::Timeout)));
+        assert_eq!(requests.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn it_fails_requests_when_cancelled() {
+        let requests = PendingRequests::new();
+        let request = requests.register().unwrap();
+
+        requests.cancel_all();
+
+        let response = request.response(Duration::from_secs(1)).await;
+        assert!(matches!(response, Err(LoupedeckError::Disconnected)));
+    }
+}
@@ -0,0 +1,516 @@
+use std::collections::VecDeque;
+use tokio::time::{Duration, Instant};
+
+use super::{DeviceModel, Framebuffer, Rect, Screen};
+use crate::Result;
+
+/// How long after a touch draws to the touched area count as interactive.
+const INTERACTIVE_WINDOW: Duration = Duration::from_millis(500);
+
+/// How far back `RenderStats::bytes_per_sec` looks.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Which draws go out first when there's more to send than fits in a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RenderPriority {
+    /// Periodic refreshes, like a clock ticking over.
+    Background,
+    /// Feedback for something the user just did.
+    Interactive,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderConfig {
+    /// How many frames are sent to the device per second, at most. Zero counts as one.
+    pub fps: u32,
+    /// Pixel bytes sent per frame, at most. Whatever doesn't fit waits for the next frame,
+    /// although at least one draw is always sent.
+    pub frame_budget: Option<usize>,
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self {
+            fps: 60,
+            frame_budget: None,
+        }
+    }
+}
+
+/// A snapshot of what the scheduler has been up to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RenderStats {
+    /// Draws waiting for a frame.
+    pub queued: usize,
+    /// Draws that were replaced by a newer draw to the same region before being sent.
+    pub dropped: u64,
+    pub frames: u64,
+    pub bytes_sent: u64,
+    /// Pixel bytes sent over the last second.
+    pub bytes_per_sec: u64,
+}
+
+#[derive(Debug)]
+struct QueuedDraw {
+    screen: Screen,
+    /// The region that was drawn to, which later draws to the same region replace.
+    region: Rect,
+    /// The part of `region` that actually changed.
+    changed: Rect,
+    priority: RenderPriority,
+}
+
+/// Batches draws into frames sent at a fixed rate.
+///
+/// Frames fall on a fixed grid of ticks, like vsync. Draws are diffed against a shadow
+/// `Framebuffer`, a draw replaces any queued draw to the same region, and draws to areas
+/// the user just touched are sent ahead of everything else.
+#[derive(Debug)]
+pub struct RenderScheduler {
+    framebuffer: Framebuffer,
+    config: RenderConfig,
+    epoch: Instant,
+    queue: Vec<QueuedDraw>,
+    touched: Vec<(Screen, Rect, Instant)>,
+    dropped: u64,
+    frames: u64,
+    bytes_sent: u64,
+    recent: VecDeque<(Instant, usize)>,
+}
+
+impl RenderScheduler {
+    pub fn new(model: &DeviceModel, config: RenderConfig) -> Self {
+        Self {
+            framebuffer: Framebuffer::new(model),
+            config,
+            epoch: Instant::now(),
+            queue: Vec::new(),
+            touched: Vec::new(),
+            dropped: 0,
+            frames: 0,
+            bytes_sent: 0,
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(1) / self.config.fps.max(1)
+    }
+
+    /// The first frame tick after `now`.
+    pub fn next_tick(&self, now: Instant) -> Instant {
+        let interval = self.interval().as_nanos();
+        let ticks = now.saturating_duration_since(self.epoch).as_nanos() / interval + 1;
+
+        self.epoch + Duration::from_nanos((ticks * interval) as u64)
+    }
+
+    /// Marks part of a screen as touched, so draws to it are interactive for a while.
+    pub fn touched(&mut self, screen: Screen, region: Rect) {
+        let now = Instant::now();
+        self.touched
+            .retain(|(_, _, at)| now.duration_since(*at) < INTERACTIVE_WINDOW);
+        self.touched.push((screen, region, now));
+    }
+
+    fn is_touched(&self, screen: &Screen, region: &Rect) -> bool {
+        let now = Instant::now();
+
+        self.touched.iter().any(|(touched_screen, touched, at)| {
+            touched_screen == screen
+                && touched.intersects(region)
+                && now.duration_since(*at) < INTERACTIVE_WINDOW
+        })
+    }
+
+    /// Queues little-endian RGB565 pixels for a region of a screen.
+    ///
+    /// Returns `false` if the screen already shows exactly this, in which case nothing is
+    /// queued.
+    pub fn draw(
+        &mut self,
+        screen: Screen,
+        region: Rect,
+        data: &[u8],
+        priority: RenderPriority,
+    ) -> Result<bool> {
+        let changed = match self.framebuffer.write(&screen, region, data)? {
+            Some(changed) => changed,
+            None => return Ok(false),
+        };
+
+        let priority = if self.is_touched(&screen, &region) {
+            RenderPriority::Interactive
+        } else {
+            priority
+        };
+
+        match self
+            .queue
+            .iter_mut()
+            .find(|queued| queued.screen == screen && queued.region == region)
+        {
+            Some(queued) => {
+                queued.changed = queued.changed.union(&changed);
+                queued.priority = queued.priority.max(priority);
+                self.dropped += 1;
+            }
+            None => self.queue.push(QueuedDraw {
+                screen,
+                region,
+                changed,
+                priority,
+            }),
+        }
+
+        Ok(true)
+    }
+
+    /// Updates the shadow and returns the pixels that changed, to be sent straight away.
+    pub fn draw_now(
+        &mut self,
+        screen: &Screen,
+        region: Rect,
+        data: &[u8],
+    ) -> Result<Option<(Rect, Vec<u8>)>> {
+        let changed = match self.framebuffer.write(screen, region, data)? {
+            Some(changed) => changed,
+            None => return Ok(None),
+        };
+
+        let pixels = self.framebuffer.read(screen, changed)?;
+        self.record_sent(pixels.len());
+
+        Ok(Some((changed, pixels)))
+    }
+
+    /// What the device shows once everything queued has been sent.
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.framebuffer
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /// Takes the draws for the next frame, highest priority first, along with their pixels.
+    ///
+    /// Neighbouring draws with the same priority are merged into a single region.
+    pub fn next_frame(&mut self) -> Vec<(Screen, Rect, Vec<u8>)> {
+        if self.queue.is_empty() {
+            return Vec::new();
+        }
+
+        // Stable, so draws of the same priority keep the order they were made in
+        self.queue
+            .sort_by_key(|queued| std::cmp::Reverse(queued.priority));
+
+        let mut bytes = 0;
+        let mut taken = 0;
+        for queued in &self.queue {
+            let size = queued.changed.width as usize * queued.changed.height as usize * 2;
+            let over_budget = self
+                .config
+                .frame_budget
+                .is_some_and(|budget| bytes + size > budget);
+
+            if taken > 0 && over_budget {
+                break;
+            }
+
+            bytes += size;
+            taken += 1;
+        }
+
+        let mut merged: Vec<(Screen, Rect, RenderPriority)> = Vec::new();
+        for queued in self.queue.drain(..taken) {
+            let mut rect = queued.changed;
+
+            while let Some(i) = merged.iter().position(|(screen, other, priority)| {
+                *screen == queued.screen && *priority == queued.priority && other.touches(&rect)
+            }) {
+                rect = rect.union(&merged.remove(i).1);
+            }
+
+            let at = merged
+                .iter()
+                .position(|(_, _, priority)| *priority < queued.priority)
+                .unwrap_or(merged.len());
+            merged.insert(at, (queued.screen, rect, queued.priority));
+        }
+
+        let frame: Vec<(Screen, Rect, Vec<u8>)> = merged
+            .into_iter()
+            .filter_map(|(screen, rect, _)| {
+                let pixels = self.framebuffer.read(&screen, rect).ok()?;
+                Some((screen, rect, pixels))
+            })
+            .collect();
+
+        self.frames += 1;
+        self.record_sent(frame.iter().map(|(_, _, pixels)| pixels.len()).sum());
+
+        frame
+    }
+
+    fn record_sent(&mut self, bytes: usize) {
+        let now = Instant::now();
+        self.bytes_sent += bytes as u64;
+        self.recent.push_back((now, bytes));
+        // Also pruned here, since nothing has to ask for stats
+        self.forget_sent_before(now);
+    }
+
+    fn forget_sent_before(&mut self, now: Instant) {
+        while self
+            .recent
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > RATE_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+    }
+
+    /// Forgets what part of a screen shows, for when sending it to the device failed.
+    pub fn invalidate_rect(&mut self, screen: &Screen, region: Rect) -> Result<()> {
+        self.framebuffer.invalidate_rect(screen, region)
+    }
+
+    /// Forgets what every screen shows and drops anything queued, e.g. after a reset.
+    pub fn invalidate(&mut self) {
+        self.framebuffer.invalidate();
+        self.queue.clear();
+    }
+
+    pub fn stats(&mut self) -> RenderStats {
+        self.forget_sent_before(Instant::now());
+
+        RenderStats {
+            queued: self.queue.len(),
+            dropped: self.dropped,
+            frames: self.frames,
+            bytes_sent: self.bytes_sent,
+            bytes_per_sec: self.recent.iter().map(|(_, bytes)| *bytes as u64).sum(),
+        }
+    }
+}
+
+/// The part of the device a touch landed on: the key under it, or else its whole screen.
+pub(crate) fn touched_region(
+    model: &DeviceModel,
+    screen: &Screen,
+    x: u16,
+    y: u16,
+) -> Option<(Screen, Rect)> {
+    if *screen == model.key_screen {
+        if let Some((key_x, key_y)) = model.key_at(x, y).and_then(|key| model.key_origin(key)) {
+            let key = Rect::new(key_x, key_y, model.key_size, model.key_size);
+            return Some((screen.clone(), key));
+        }
+    }
+
+    model
+        .screen_layout(screen)
+        .map(|layout| (screen.clone(), Rect::new(0, 0, layout.width, layout.height)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{touched_region, RenderConfig, RenderPriority, RenderScheduler};
+    use crate::{Rect, Screen, LOUPEDECK_LIVE};
+    use tokio::time::{advance, Duration, Instant};
+
+    fn solid(size: u16, color: u16) -> Vec<u8> {
+        color.to_le_bytes().repeat(size as usize * size as usize)
+    }
+
+    fn key(x: u16, y: u16) -> Rect {
+        Rect::new(x * 90, y * 90, 90, 90)
+    }
+
+    fn scheduler(frame_budget: Option<usize>) -> RenderScheduler {
+        RenderScheduler::new(
+            &LOUPEDECK_LIVE,
+            RenderConfig {
+                fps: 50,
+                frame_budget,
+            },
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_ticks_on_a_fixed_grid() {
+        let scheduler = scheduler(None);
+        let start = Instant::now();
+
+        assert_eq!(scheduler.interval(), Duration::from_millis(20));
+        assert_eq!(
+            scheduler.next_tick(start + Duration::from_millis(5)),
+            start + Duration::from_millis(20)
+        );
+        assert_eq!(
+            scheduler.next_tick(start + Duration::from_millis(20)),
+            start + Duration::from_millis(40)
+        );
+
+        let slowest = RenderScheduler::new(
+            &LOUPEDECK_LIVE,
+            RenderConfig {
+                fps: 0,
+                frame_budget: None,
+            },
+        );
+        assert_eq!(slowest.interval(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_drops_superseded_draws() {
+        let mut scheduler = scheduler(None);
+
+        assert!(scheduler
+            .draw(
+                Screen::Center,
+                key(0, 0),
+                &solid(90, 0xf800),
+                RenderPriority::Background
+            )
+            .unwrap());
+        assert!(scheduler
+            .draw(
+                Screen::Center,
+                key(0, 0),
+                &solid(90, 0x07e0),
+                RenderPriority::Background
+            )
+            .unwrap());
+        assert!(!scheduler
+            .draw(
+                Screen::Center,
+                key(0, 0),
+                &solid(90, 0x07e0),
+                RenderPriority::Background
+            )
+            .unwrap());
+
+        let stats = scheduler.stats();
+        assert_eq!((stats.queued, stats.dropped), (1, 1));
+
+        let frame = scheduler.next_frame();
+        assert_eq!(frame.len(), 1);
+        assert_eq!(frame[0].2[0..2], [0xe0, 0x07]);
+
+        let stats = scheduler.stats();
+        assert_eq!(stats.queued, 0);
+        assert_eq!(stats.frames, 1);
+        assert_eq!(stats.bytes_sent, 90 * 90 * 2);
+        assert_eq!(stats.bytes_per_sec, 90 * 90 * 2);
+
+        advance(Duration::from_secs(2)).await;
+        assert_eq!(scheduler.stats().bytes_per_sec, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_only_keeps_recent_sends() {
+        let mut scheduler = scheduler(None);
+
+        // Ten seconds at 60 fps, without anyone asking for stats
+        for frame in 0..10 * 60 {
+            let color = if frame % 2 == 0 { 0xf800 } else { 0x07e0 };
+            scheduler
+                .draw_now(&Screen::Center, key(0, 0), &solid(90, color))
+                .unwrap();
+            advance(Duration::from_millis(1000 / 60)).await;
+        }
+
+        // Only about the last second's worth
+        assert!(scheduler.recent.len() <= 64);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_sends_touched_keys_first() {
+        let mut scheduler = scheduler(Some(90 * 90 * 2));
+
+        for x in 0..3 {
+            scheduler
+                .draw(
+                    Screen::Center,
+                    key(x, 0),
+                    &solid(90, 0xf800),
+                    RenderPriority::Background,
+                )
+                .unwrap();
+        }
+
+        scheduler.touched(Screen::Center, key(2, 1));
+        scheduler
+            .draw(
+                Screen::Center,
+                key(2, 1),
+                &solid(90, 0xffff),
+                RenderPriority::Background,
+            )
+            .unwrap();
+
+        // Only one key fits in a frame, so the touched one goes first
+        let frame = scheduler.next_frame();
+        assert_eq!(frame.len(), 1);
+        assert_eq!(frame[0].1, key(2, 1));
+        assert_eq!(scheduler.stats().queued, 3);
+
+        // The touch wears off
+        advance(Duration::from_secs(1)).await;
+        scheduler
+            .draw(
+                Screen::Center,
+                key(3, 2),
+                &solid(90, 0xffff),
+                RenderPriority::Background,
+            )
+            .unwrap();
+        assert_eq!(scheduler.next_frame()[0].1, key(0, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn it_merges_neighbouring_draws() {
+        let mut scheduler = scheduler(None);
+
+        for x in 0..2 {
+            scheduler
+                .draw(
+                    Screen::Center,
+                    key(x, 0),
+                    &solid(90, 0xf800),
+                    RenderPriority::Background,
+                )
+                .unwrap();
+        }
+        scheduler
+            .draw(
+                Screen::Center,
+                key(3, 2),
+                &solid(90, 0xf800),
+                RenderPriority::Interactive,
+            )
+            .unwrap();
+
+        let frame: Vec<Rect> = scheduler
+            .next_frame()
+            .into_iter()
+            .map(|(_, rect, _)| rect)
+            .collect();
+        assert_eq!(frame, vec![key(3, 2), Rect::new(0, 0, 180, 90)]);
+    }
+
+    #[test]
+    fn it_maps_touches_to_regions() {
+        assert_eq!(
+            touched_region(&LOUPEDECK_LIVE, &Screen::Center, 60 + 95, 5),
+            Some((Screen::Center, key(1, 0)))
+        );
+        assert_eq!(
+            touched_region(&LOUPEDECK_LIVE, &Screen::Left, 5, 5),
+            Some((Screen::Left, Rect::new(0, 0, 60, 270)))
+        );
+        assert_eq!(touched_region(&LOUPEDECK_LIVE, &Screen::Wheel, 5, 5), None);
+    }
+}
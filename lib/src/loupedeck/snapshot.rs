@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use crate::{LoupedeckError, Result};
+
+/// An RGBA image of what a device shows, for reviewing layouts without the hardware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub width: u16,
+    pub height: u16,
+    /// Rows of 8-bit RGBA pixels, top to bottom.
+    pub rgba: Vec<u8>,
+}
+
+impl Snapshot {
+    /// Builds a snapshot from rows of RGB565 pixels.
+    pub fn from_rgb565(width: u16, height: u16, pixels: &[u16]) -> Self {
+        assert_eq!(pixels.len(), width as usize * height as usize);
+
+        Self {
+            width,
+            height,
+            rgba: pixels
+                .iter()
+                .flat_map(|pixel| convert_rgb565_to_rgba(*pixel))
+                .collect(),
+        }
+    }
+
+    /// The RGBA pixel at the given coordinates.
+    pub fn pixel(&self, x: u16, y: u16) -> [u8; 4] {
+        let i = (y as usize * self.width as usize + x as usize) * 4;
+        [
+            self.rgba[i],
+            self.rgba[i + 1],
+            self.rgba[i + 2],
+            self.rgba[i + 3],
+        ]
+    }
+
+    /// Encodes the snapshot as a PNG.
+    pub fn to_png(&self) -> Result<Vec<u8>> {
+        let mut png = Vec::new();
+        self.write_png(&mut png)?;
+        Ok(png)
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.write_png(BufWriter::new(File::create(path)?))
+    }
+
+    fn write_png<W: std::io::Write>(&self, writer: W) -> Result<()> {
+        let mut encoder = png::Encoder::new(writer, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&self.rgba))
+            .map_err(|e| LoupedeckError::Image(e.to_string()))
+    }
+}
+
+/// Expands an RGB565 pixel to opaque 8-bit RGBA, the reverse of
+/// `convert_draw_target_to_rgb565`.
+pub fn convert_rgb565_to_rgba(pixel: u16) -> [u8; 4] {
+    let r = (pixel >> 11) as u8 & 0x1f;
+    let g = (pixel >> 5) as u8 & 0x3f;
+    let b = pixel as u8 & 0x1f;
+
+    // Repeat the high bits in the low ones so full intensity maps to 0xff
+    [
+        (r << 3) | (r >> 2),
+        (g << 2) | (g >> 4),
+        (b << 3) | (b >> 2),
+        0xff,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{convert_rgb565_to_rgba, Snapshot};
+
+    #[test]
+    fn it_expands_rgb565() {
+        assert_eq!(convert_rgb565_to_rgba(0x0000), [0x00, 0x00, 0x00, 0xff]);
+        assert_eq!(convert_rgb565_to_rgba(0xffff), [0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(convert_rgb565_to_rgba(0xf800), [0xff, 0x00, 0x00, 0xff]);
+        assert_eq!(convert_rgb565_to_rgba(0x07e0), [0x00, 0xff, 0x00, 0xff]);
+        assert_eq!(convert_rgb565_to_rgba(0x001f), [0x00, 0x00, 0xff, 0xff]);
+        assert_eq!(convert_rgb565_to_rgba(0x8410), [0x84, 0x82, 0x84, 0xff]);
+    }
+
+    #[test]
+    fn it_encodes_a_png() {
+        let snapshot = Snapshot::from_rgb565(2, 1, &[0xf800, 0x001f]);
+        assert_eq!(snapshot.pixel(1, 0), [0x00, 0x00, 0xff, 0xff]);
+
+        let png = snapshot.to_png().unwrap();
+        let mut reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+        let mut decoded = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut decoded).unwrap();
+
+        assert_eq!((info.width, info.height), (2, 1));
+        assert_eq!(decoded, snapshot.rgba);
+    }
+}
@@ -0,0 +1,693 @@
+use font_kit::family_name::FamilyName;
+use font_kit::font::Font;
+use font_kit::handle::Handle;
+use font_kit::hinting::HintingOptions;
+use font_kit::outline::OutlineSink;
+use font_kit::properties::{Properties, Weight};
+use font_kit::source::SystemSource;
+use pathfinder_geometry::line_segment::LineSegment2F;
+use pathfinder_geometry::vector::Vector2F;
+use raqote::{DrawOptions, DrawTarget, PathBuilder, SolidSource, Source, StrokeStyle};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::{LoupedeckError, Result};
+
+/// Where to load a font from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FontSource {
+    /// An installed font. "monospace", "sans-serif" and "serif" pick the system default.
+    System {
+        family: String,
+        bold: bool,
+    },
+    /// A TTF or OTF file shipped with a plugin.
+    Path(PathBuf),
+    Bytes(Arc<Vec<u8>>),
+}
+
+impl FontSource {
+    pub fn system(family: &str) -> Self {
+        FontSource::System {
+            family: family.to_string(),
+            bold: false,
+        }
+    }
+
+    pub fn bold(self) -> Self {
+        match self {
+            FontSource::System { family, .. } => FontSource::System { family, bold: true },
+            source => source,
+        }
+    }
+}
+
+/// Fonts tried for characters the main font doesn't have, mostly emoji.
+///
+/// Only glyphs with outlines can be drawn, so bitmap emoji fonts like Noto Color Emoji and
+/// Apple Color Emoji are left out in favour of ones that have them.
+pub fn default_fallback_fonts() -> Vec<FontSource> {
+    ["Noto Emoji", "Symbola", "Segoe UI Emoji", "DejaVu Sans"]
+        .into_iter()
+        .map(FontSource::system)
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HorizontalAlign {
+    Left,
+    #[default]
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VerticalAlign {
+    Top,
+    #[default]
+    Middle,
+    Bottom,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Outline {
+    pub color: SolidSource,
+    pub width: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shadow {
+    pub color: SolidSource,
+    pub offset: (f32, f32),
+}
+
+/// Largest text size in pixels, well above any Loupedeck screen.
+pub const MAX_TEXT_SIZE: f32 = 1024.0;
+
+/// How to lay out and paint a piece of text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextStyle {
+    pub font: FontSource,
+    pub fallback_fonts: Vec<FontSource>,
+    /// Size in pixels.
+    pub size: f32,
+    /// Smallest size to shrink to when the text doesn't fit. Equal to `size` to never shrink.
+    pub min_size: f32,
+    /// Distance between baselines, as a multiple of the size.
+    pub line_height: f32,
+    pub align: HorizontalAlign,
+    pub vertical_align: VerticalAlign,
+    /// Break lines at spaces to stay inside the region.
+    pub wrap: bool,
+    /// Space left empty on every side of the region.
+    pub padding: f32,
+    pub color: SolidSource,
+    pub background: Option<SolidSource>,
+    pub outline: Option<Outline>,
+    pub shadow: Option<Shadow>,
+}
+
+impl TextStyle {
+    /// Rejects sizes text can't be laid out at.
+    pub fn validate(&self) -> Result<()> {
+        for (name, size) in [("size", self.size), ("min_size", self.min_size)] {
+            if !size.is_finite() || size <= 0.0 || size > MAX_TEXT_SIZE {
+                return Err(LoupedeckError::InvalidInput(format!(
+                    "Text {} must be between 0 and {}, got {}",
+                    name, MAX_TEXT_SIZE, size
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        Self {
+            font: FontSource::system("sans-serif"),
+            fallback_fonts: default_fallback_fonts(),
+            size: 18.0,
+            min_size: 8.0,
+            line_height: 1.2,
+            align: HorizontalAlign::Center,
+            vertical_align: VerticalAlign::Middle,
+            wrap: true,
+            padding: 4.0,
+            color: SolidSource::from_unpremultiplied_argb(0xff, 0xff, 0xff, 0xff),
+            background: Some(SolidSource::from_unpremultiplied_argb(
+                0xff, 0x00, 0x00, 0x00,
+            )),
+            outline: None,
+            shadow: None,
+        }
+    }
+}
+
+/// A font file and the index of the face to use from it.
+type FontData = (Arc<Vec<u8>>, u32);
+
+// Finding a system font means scanning the font directories, so it's only done once per
+// process. Loaded faces can't move between threads, so those are kept per thread.
+static FONT_DATA: OnceLock<Mutex<HashMap<FontSource, Option<FontData>>>> = OnceLock::new();
+
+thread_local! {
+    static FONTS: RefCell<HashMap<FontSource, Font>> = RefCell::new(HashMap::new());
+}
+
+fn read_font(path: &Path) -> Result<Arc<Vec<u8>>> {
+    std::fs::read(path)
+        .map(Arc::new)
+        .map_err(|e| LoupedeckError::Font(format!("{}: {}", path.display(), e)))
+}
+
+fn font_data(source: &FontSource) -> Result<Option<FontData>> {
+    let mut cache = FONT_DATA.get_or_init(Default::default).lock().unwrap();
+
+    if let Some(data) = cache.get(source) {
+        return Ok(data.clone());
+    }
+
+    let data = match source {
+        FontSource::System { family, bold } => {
+            let family = match family.as_str() {
+                "monospace" => FamilyName::Monospace,
+                "sans-serif" => FamilyName::SansSerif,
+                "serif" => FamilyName::Serif,
+                family => FamilyName::Title(family.to_string()),
+            };
+            let mut properties = Properties::new();
+            if *bold {
+                properties.weight(Weight::BOLD);
+            }
+
+            // Only an exact family counts, otherwise every fallback would find the default font
+            let handle = SystemSource::new()
+                .select_best_match(std::slice::from_ref(&family), &properties)
+                .ok()
+                .filter(|handle| match (&family, handle.load()) {
+                    (FamilyName::Title(title), Ok(font)) => font.family_name() == *title,
+                    (_, loaded) => loaded.is_ok(),
+                });
+
+            match handle {
+                Some(Handle::Path { path, font_index }) => Some((read_font(&path)?, font_index)),
+                Some(Handle::Memory { bytes, font_index }) => Some((bytes, font_index)),
+                None => None,
+            }
+        }
+        FontSource::Path(path) => Some((read_font(path)?, 0)),
+        FontSource::Bytes(bytes) => Some((bytes.clone(), 0)),
+    };
+
+    cache.insert(source.clone(), data.clone());
+    Ok(data)
+}
+
+/// Loads a font, or returns `None` for a system font that isn't installed.
+pub fn load_font(source: &FontSource) -> Result<Option<Font>> {
+    if let Some(font) = FONTS.with(|fonts| fonts.borrow().get(source).cloned()) {
+        return Ok(Some(font));
+    }
+
+    let Some((bytes, font_index)) = font_data(source)? else {
+        return Ok(None);
+    };
+    let font = Font::from_bytes(bytes, font_index)
+        .map_err(|e| LoupedeckError::Font(format!("{:?}: {}", source, e)))?;
+
+    FONTS.with(|fonts| fonts.borrow_mut().insert(source.clone(), font.clone()));
+    Ok(Some(font))
+}
+
+/// Whether a font can draw a character, which bitmap glyphs like color emoji can't.
+fn has_glyph(font: &Font, c: char) -> Option<u32> {
+    let id = font.glyph_for_char(c)?;
+    if c.is_whitespace() {
+        return Some(id);
+    }
+
+    let mut outline = OutlineCheck(false);
+    let _ = font.outline(id, HintingOptions::None, &mut outline);
+    outline.0.then_some(id)
+}
+
+/// Notes whether a glyph has any outline at all.
+struct OutlineCheck(bool);
+
+impl OutlineSink for OutlineCheck {
+    fn move_to(&mut self, _to: Vector2F) {
+        self.0 = true;
+    }
+
+    fn line_to(&mut self, _to: Vector2F) {}
+
+    fn quadratic_curve_to(&mut self, _ctrl: Vector2F, _to: Vector2F) {}
+
+    fn cubic_curve_to(&mut self, _ctrl: LineSegment2F, _to: Vector2F) {}
+
+    fn close(&mut self) {}
+}
+
+struct Glyph {
+    font: usize,
+    id: u32,
+    x: f32,
+    advance: f32,
+    space: bool,
+}
+
+#[derive(Default)]
+struct Line {
+    glyphs: Vec<Glyph>,
+}
+
+impl Line {
+    fn end(&self) -> f32 {
+        self.glyphs
+            .last()
+            .map_or(0.0, |glyph| glyph.x + glyph.advance)
+    }
+
+    /// Width without trailing spaces, which are allowed to hang past the edge.
+    fn width(&self) -> f32 {
+        self.glyphs
+            .iter()
+            .rfind(|glyph| !glyph.space)
+            .map_or(0.0, |glyph| glyph.x + glyph.advance)
+    }
+}
+
+/// Text broken into lines at one size.
+struct Layout {
+    lines: Vec<Line>,
+    size: f32,
+    ascent: f32,
+    descent: f32,
+    line_height: f32,
+}
+
+impl Layout {
+    fn width(&self) -> f32 {
+        self.lines.iter().map(Line::width).fold(0.0, f32::max)
+    }
+
+    fn height(&self) -> f32 {
+        self.line_height * self.lines.len() as f32
+    }
+}
+
+/// The main font followed by whichever fallbacks are installed.
+fn load_fonts(style: &TextStyle) -> Result<Vec<Font>> {
+    let font = load_font(&style.font)?
+        .ok_or_else(|| LoupedeckError::Font(format!("{:?} isn't installed", style.font)))?;
+
+    let mut fonts = vec![font];
+    for fallback in &style.fallback_fonts {
+        fonts.extend(load_font(fallback)?);
+    }
+
+    Ok(fonts)
+}
+
+fn lay_out(fonts: &[Font], text: &str, size: f32, style: &TextStyle, max_width: f32) -> Layout {
+    let mut lines = Vec::new();
+
+    for paragraph in text.split('\n') {
+        let mut line = Line::default();
+
+        for word in paragraph.split_inclusive(' ') {
+            let glyphs: Vec<Glyph> = word
+                .chars()
+                .filter_map(|c| {
+                    let (font, id) = fonts
+                        .iter()
+                        .enumerate()
+                        .find_map(|(i, font)| Some((i, has_glyph(font, c)?)))?;
+                    let units = fonts[font].advance(id).map_or(0.0, |advance| advance.x());
+
+                    Some(Glyph {
+                        font,
+                        id,
+                        x: 0.0,
+                        advance: units * size / fonts[font].metrics().units_per_em as f32,
+                        space: c.is_whitespace(),
+                    })
+                })
+                .collect();
+
+            let word_width: f32 = glyphs
+                .iter()
+                .filter(|glyph| !glyph.space)
+                .map(|glyph| glyph.advance)
+                .sum();
+            if style.wrap && line.width() > 0.0 && line.end() + word_width > max_width {
+                lines.push(std::mem::take(&mut line));
+            }
+
+            for mut glyph in glyphs {
+                // Words too long for a line of their own are broken anywhere
+                if style.wrap
+                    && !glyph.space
+                    && line.width() > 0.0
+                    && line.end() + glyph.advance > max_width
+                {
+                    lines.push(std::mem::take(&mut line));
+                }
+
+                glyph.x = line.end();
+                line.glyphs.push(glyph);
+            }
+        }
+
+        lines.push(line);
+    }
+
+    let metrics = fonts[0].metrics();
+    let em = metrics.units_per_em as f32;
+    Layout {
+        lines,
+        size,
+        ascent: metrics.ascent / em * size,
+        descent: -metrics.descent / em * size,
+        line_height: size * style.line_height,
+    }
+}
+
+/// Most sizes `fit` tries before settling for the last one.
+const MAX_SHRINK_STEPS: usize = 256;
+
+/// Lays out text at the largest size that fits, down to `style.min_size`.
+fn fit(fonts: &[Font], text: &str, style: &TextStyle, width: f32, height: f32) -> Layout {
+    // Text taller than the region never fits, so don't start above it
+    let mut size = style.size.min(height.max(style.min_size));
+
+    for _ in 0..MAX_SHRINK_STEPS {
+        let layout = lay_out(fonts, text, size, style, width);
+        let fits = layout.width() <= width && layout.height() <= height;
+        if fits || size - 1.0 < style.min_size {
+            return layout;
+        }
+        size -= 1.0;
+    }
+
+    lay_out(fonts, text, size, style, width)
+}
+
+/// Collects a glyph outline into a raqote path, flipping it the right way up.
+struct PathSink {
+    builder: PathBuilder,
+    origin: (f32, f32),
+    scale: f32,
+}
+
+impl PathSink {
+    fn point(&self, v: Vector2F) -> (f32, f32) {
+        (
+            self.origin.0 + v.x() * self.scale,
+            self.origin.1 - v.y() * self.scale,
+        )
+    }
+}
+
+impl OutlineSink for PathSink {
+    fn move_to(&mut self, to: Vector2F) {
+        let (x, y) = self.point(to);
+        self.builder.move_to(x, y);
+    }
+
+    fn line_to(&mut self, to: Vector2F) {
+        let (x, y) = self.point(to);
+        self.builder.line_to(x, y);
+    }
+
+    fn quadratic_curve_to(&mut self, ctrl: Vector2F, to: Vector2F) {
+        let ((cx, cy), (x, y)) = (self.point(ctrl), self.point(to));
+        self.builder.quad_to(cx, cy, x, y);
+    }
+
+    fn cubic_curve_to(&mut self, ctrl: LineSegment2F, to: Vector2F) {
+        let (c1, c2, end) = (
+            self.point(ctrl.from()),
+            self.point(ctrl.to()),
+            self.point(to),
+        );
+        self.builder.cubic_to(c1.0, c1.1, c2.0, c2.1, end.0, end.1);
+    }
+
+    fn close(&mut self) {
+        self.builder.close();
+    }
+}
+
+/// Draws text into a region of a draw target.
+///
+/// The text shrinks one pixel at a time from `style.size` towards `style.min_size` until it
+/// fits, and is clipped if it still doesn't.
+pub fn draw_text(
+    target: &mut DrawTarget,
+    text: &str,
+    region: (f32, f32, f32, f32),
+    style: &TextStyle,
+) -> Result<()> {
+    let (x, y, width, height) = region;
+    style.validate()?;
+    let fonts = load_fonts(style)?;
+
+    if let Some(background) = style.background {
+        target.fill_rect(
+            x,
+            y,
+            width,
+            height,
+            &Source::Solid(background),
+            &DrawOptions::new(),
+        );
+    }
+
+    let (inner_width, inner_height) = (width - style.padding * 2.0, height - style.padding * 2.0);
+    let layout = fit(&fonts, text, style, inner_width, inner_height);
+
+    let top = y
+        + style.padding
+        + match style.vertical_align {
+            VerticalAlign::Top => 0.0,
+            VerticalAlign::Middle => (inner_height - layout.height()) / 2.0,
+            VerticalAlign::Bottom => inner_height - layout.height(),
+        };
+
+    let mut path = PathSink {
+        builder: PathBuilder::new(),
+        origin: (0.0, 0.0),
+        scale: 0.0,
+    };
+    for (i, line) in layout.lines.iter().enumerate() {
+        let left = x
+            + style.padding
+            + match style.align {
+                HorizontalAlign::Left => 0.0,
+                HorizontalAlign::Center => (inner_width - line.width()) / 2.0,
+                HorizontalAlign::Right => inner_width - line.width(),
+            };
+        let baseline = top
+            + layout.line_height * i as f32
+            + (layout.line_height - layout.ascent - layout.descent) / 2.0
+            + layout.ascent;
+
+        for glyph in &line.glyphs {
+            let font = &fonts[glyph.font];
+            path.origin = (left + glyph.x, baseline);
+            path.scale = layout.size / font.metrics().units_per_em as f32;
+            let _ = font.outline(glyph.id, HintingOptions::None, &mut path);
+        }
+    }
+
+    let path = path.builder.finish();
+    target.push_clip_rect(raqote::IntRect::new(
+        raqote::IntPoint::new(x as i32, y as i32),
+        raqote::IntPoint::new((x + width) as i32, (y + height) as i32),
+    ));
+
+    if let Some(shadow) = style.shadow {
+        let (dx, dy) = shadow.offset;
+        let shifted = path
+            .clone()
+            .transform(&raqote::Transform::translation(dx, dy));
+        target.fill(&shifted, &Source::Solid(shadow.color), &DrawOptions::new());
+    }
+
+    if let Some(outline) = style.outline {
+        let stroke = StrokeStyle {
+            width: outline.width * 2.0,
+            join: raqote::LineJoin::Round,
+            ..StrokeStyle::default()
+        };
+        target.stroke(
+            &path,
+            &Source::Solid(outline.color),
+            &stroke,
+            &DrawOptions::new(),
+        );
+    }
+
+    target.fill(&path, &Source::Solid(style.color), &DrawOptions::new());
+    target.pop_clip();
+
+    Ok(())
+}
+
+/// Renders text on a `width` x `height` target, e.g. a whole key.
+pub fn render_text(text: &str, width: u16, height: u16, style: &TextStyle) -> Result<DrawTarget> {
+    let mut target = DrawTarget::new(width as i32, height as i32);
+    draw_text(
+        &mut target,
+        text,
+        (0.0, 0.0, width as f32, height as f32),
+        style,
+    )?;
+
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use raqote::SolidSource;
+
+    use super::{fit, lay_out, load_font, load_fonts, render_text, FontSource, Outline, TextStyle};
+    use crate::{convert_draw_target_to_rgb565, LoupedeckError};
+
+    fn style() -> TextStyle {
+        TextStyle {
+            font: FontSource::system("monospace"),
+            size: 14.0,
+            ..TextStyle::default()
+        }
+    }
+
+    #[test]
+    fn it_wraps_at_spaces() {
+        let style = style();
+        let fonts = load_fonts(&style).unwrap();
+
+        let layout = lay_out(&fonts, "one two three", 14.0, &style, 50.0);
+        assert_eq!(layout.lines.len(), 3);
+        assert!(layout.width() <= 50.0);
+
+        let layout = lay_out(&fonts, "one two\nthree", 14.0, &style, 1000.0);
+        assert_eq!(layout.lines.len(), 2);
+
+        let layout = lay_out(&fonts, "abcdefghijkl", 14.0, &style, 40.0);
+        assert!(layout.lines.len() > 1);
+        assert!(layout.width() <= 40.0);
+    }
+
+    #[test]
+    fn it_shrinks_text_to_fit() {
+        let style = TextStyle {
+            wrap: false,
+            ..style()
+        };
+        let fonts = load_fonts(&style).unwrap();
+
+        let layout = fit(&fonts, "Recording", &style, 60.0, 60.0);
+        assert!(layout.size < 14.0);
+        assert!(layout.width() <= 60.0);
+
+        assert_eq!(fit(&fonts, "Rec", &style, 82.0, 82.0).size, 14.0);
+    }
+
+    #[test]
+    fn it_rejects_sizes_it_cant_lay_out() {
+        for size in [f32::NAN, f32::INFINITY, 1e9, 0.0, -4.0] {
+            let invalid = TextStyle { size, ..style() };
+            assert!(matches!(
+                render_text("Hi", 90, 90, &invalid),
+                Err(LoupedeckError::InvalidInput(_))
+            ));
+            let invalid = TextStyle {
+                min_size: size,
+                ..style()
+            };
+            assert!(matches!(
+                render_text("Hi", 90, 90, &invalid),
+                Err(LoupedeckError::InvalidInput(_))
+            ));
+        }
+
+        // Big but valid sizes start shrinking from the region height
+        let style = TextStyle {
+            size: 1000.0,
+            ..style()
+        };
+        let fonts = load_fonts(&style).unwrap();
+        assert!(fit(&fonts, "Hi", &style, 90.0, 90.0).size <= 90.0);
+        assert!(render_text("Hi", 90, 90, &style).is_ok());
+    }
+
+    #[test]
+    fn it_renders_centered_text_with_an_outline() {
+        let red = SolidSource::from_unpremultiplied_argb(0xff, 0xff, 0x00, 0x00);
+        let style = TextStyle {
+            outline: Some(Outline {
+                color: red,
+                width: 2.0,
+            }),
+            ..style()
+        };
+
+        let pixels: Vec<u16> =
+            convert_draw_target_to_rgb565(render_text("H", 90, 90, &style).unwrap())
+                .chunks(2)
+                .map(|pixel| u16::from_le_bytes([pixel[0], pixel[1]]))
+                .collect();
+        let columns_with = |color: u16| -> Vec<usize> {
+            (0..90)
+                .filter(|x| (0..90).any(|y| pixels[y * 90 + x] == color))
+                .collect()
+        };
+
+        let white = columns_with(0xffff);
+        assert!(!white.is_empty() && !columns_with(0xf800).is_empty());
+        assert!(white.iter().all(|x| (30..60).contains(x)));
+        assert_eq!(pixels[0], 0x0000);
+    }
+
+    #[test]
+    fn it_falls_back_to_an_emoji_font() {
+        // The monospace font has no emoji, so they come from a fallback
+        let style = style();
+        let fonts = load_fonts(&style).unwrap();
+        let layout = lay_out(&fonts, "\u{1f600}", 14.0, &style, 90.0);
+        assert!(layout.lines[0].glyphs.iter().all(|glyph| glyph.font > 0));
+
+        let pixels =
+            convert_draw_target_to_rgb565(render_text("\u{1f600}", 90, 90, &style).unwrap());
+        assert!(pixels.chunks(2).any(|pixel| pixel != [0, 0]));
+    }
+
+    #[test]
+    fn it_reports_missing_fonts() {
+        assert!(load_font(&FontSource::system("No Such Font"))
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            load_font(&FontSource::Path("/no/such/font.ttf".into())),
+            Err(LoupedeckError::Font(_))
+        ));
+        assert!(matches!(
+            render_text(
+                "Hi",
+                90,
+                90,
+                &TextStyle {
+                    font: FontSource::system("No Such Font"),
+                    ..style()
+                }
+            ),
+            Err(LoupedeckError::Font(_))
+        ));
+    }
+}
@@ -0,0 +1,37 @@
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+use crate::{LoupedeckError, Result};
+
+const SERIAL_BAUD_RATE: u32 = 9600;
+const MEMORY_TRANSPORT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A bidirectional byte stream that a `Device` can speak the Loupedeck protocol over.
+///
+/// The websocket upgrade and framing are handled by the `Device`, so a transport only
+/// needs to move raw bytes in both directions.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl Transport for SerialStream {}
+
+impl Transport for DuplexStream {}
+
+impl Transport for Box<dyn Transport> {}
+
+/// Opens the serial port a physical Loupedeck is attached to.
+pub fn open_serial_transport(port: &str) -> Result<SerialStream> {
+    tokio_serial::new(port, SERIAL_BAUD_RATE)
+        .open_native_async()
+        .map_err(|e| match e.kind {
+            tokio_serial::ErrorKind::NoDevice => LoupedeckError::UnknownDevice(port.to_string()),
+            _ => LoupedeckError::Transport(e.into()),
+        })
+}
+
+/// Creates a connected pair of in-memory transports.
+///
+/// Bytes written to one end can be read from the other, which lets a `Device` be driven
+/// without any hardware attached.
+pub fn memory_transport() -> (DuplexStream, DuplexStream) {
+    tokio::io::duplex(MEMORY_TRANSPORT_BUFFER_SIZE)
+}
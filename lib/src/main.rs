@@ -6,6 +6,7 @@ use tokio::time;
 async fn main() {
     let mut ld = loupedeck::Device::new("COM3".to_string());
     ld.connect().await.expect("Failed to connect to loupedeck");
+    ld.get_info().await.expect("Failed to get device info");
 
     let mut controller = Controller::new();
 
@@ -34,11 +35,15 @@ async fn main() {
         },
     );
 
-    controller.start(ld);
+    controller.start(ld).expect("Failed to start controller");
 
     let page_config = PageConfig {
         name: "basic".to_string(),
+        device: DeviceTarget::Any,
         screen: screen_map,
+        regions: HashMap::new(),
+        buttons: HashMap::new(),
+        knobs: HashMap::new(),
     };
 
     controller
@@ -3,26 +3,25 @@
     windows_subsystem = "windows"
 )]
 
-#[global_allocator]
-static ALLOCATOR: System = System;
-
-use futures::executor::block_on;
 use loupedeck::{
-    get_loupedeck_ports, Controller, DeviceConnectionStatus, PageConfig, PluginIdentifier,
+    get_loupedeck_ports, Controller, DeviceConnectionStatus, DeviceManager, PageConfig,
+    PluginIdentifier,
 };
 use platform_dirs::AppDirs;
 use serde::Serialize;
 use std::fs;
-use std::{alloc::System, env, sync::Mutex};
+use std::sync::Arc;
+use std::{env, sync::Mutex};
 use tauri::utils::assets::EmbeddedAssets;
 use tauri::{CustomMenuItem, Manager, State, SystemTray, SystemTrayMenu, SystemTrayMenuItem};
 
 struct ConnectionState {
-    controller: Mutex<Controller>,
+    controller: Arc<Mutex<Controller>>,
 }
 #[derive(Debug, Serialize, Clone, PartialEq)]
 struct DeviceConnectionEvent {
     status: DeviceConnectionStatus,
+    serial: Option<String>,
 }
 
 #[tauri::command]
@@ -67,10 +66,40 @@ fn set_page_config(state: tauri::State<ConnectionState>, page_config: PageConfig
     controller.set_page(page_config);
 }
 
+// Async commands that borrow state have to return a Result
 #[tauri::command]
-fn set_active_page(state: tauri::State<ConnectionState>, page_name: String) {
-    let mut controller = state.controller.lock().unwrap();
-    block_on(controller.set_current_page(page_name));
+async fn set_active_page(
+    state: tauri::State<'_, ConnectionState>,
+    page_name: String,
+    serial: Option<String>,
+) -> Result<(), ()> {
+    // The controller is only locked while the page is set up, not while it's sent
+    let res = match serial {
+        Some(serial) => {
+            let shown = state
+                .controller
+                .lock()
+                .unwrap()
+                .set_device_page(&serial, page_name);
+            shown.await
+        }
+        None => {
+            let shown = state.controller.lock().unwrap().set_current_page(page_name);
+            shown.await
+        }
+    };
+
+    if let Err(e) = res {
+        println!("Error setting page: {}", e);
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+fn list_devices(state: tauri::State<ConnectionState>) -> Vec<String> {
+    let controller = state.controller.lock().unwrap();
+    return controller.list_devices();
 }
 
 fn get_default_plugin() -> Vec<String> {
@@ -96,39 +125,37 @@ fn get_default_plugin() -> Vec<String> {
 }
 
 #[tauri::command]
-fn connect_ld(port: String, window: tauri::Window) {
-    let app = window.app_handle();
+fn connect_ld(_port: String, window: tauri::Window) {
+    // The device manager connects on its own, so just report where it's at
+    let state: State<ConnectionState> = window.state();
+    let status = state.controller.lock().unwrap().get_connection_status().unwrap();
 
     window.emit_all(
         "device-connection-status",
         DeviceConnectionEvent {
-            status: DeviceConnectionStatus::Connecting,
+            status,
+            serial: None,
         },
     );
+}
 
-    tauri::async_runtime::spawn(async move {
-        let mut device = loupedeck::Device::new(port);
-
-        let state: tauri::State<ConnectionState> = app.state();
-        block_on(device.connect());
-
-        let mut controller = state.controller.lock().unwrap();
-        controller.start(device);
+fn build_window(context: &tauri::Context<EmbeddedAssets>) -> tauri::Builder<tauri::Wry> {
+    let mut controller = Controller::new();
+    // Commands run on the main thread, so devices and plugins need to be told where to run
+    controller.set_runtime(tauri::async_runtime::handle().inner().clone());
 
-        for plugin in get_default_plugin() {
-            let res = controller.load_plugin(&plugin);
+    for plugin in get_default_plugin() {
+        let res = controller.load_plugin(&plugin);
 
-            if res.is_err() {
-                println!("Error loading plugin: {:?}", res.err());
-            }
+        if res.is_err() {
+            println!("Error loading plugin: {:?}", res.err());
         }
-    });
-}
+    }
 
-fn build_window(context: &tauri::Context<EmbeddedAssets>) -> tauri::Builder<tauri::Wry> {
     let connection_state = ConnectionState {
-        controller: Mutex::new(Controller::new()),
+        controller: Arc::new(Mutex::new(controller)),
     };
+    let device_manager = DeviceManager::new(connection_state.controller.clone());
 
     let quit = CustomMenuItem::new("quit".to_string(), "Quit");
     let hide = CustomMenuItem::new("hide".to_string(), "Hide");
@@ -140,6 +167,25 @@ fn build_window(context: &tauri::Context<EmbeddedAssets>) -> tauri::Builder<taur
 
     return tauri::Builder::default()
         .manage(connection_state)
+        .setup(move |app| {
+            let app_handle = app.handle();
+            let mut rx_status = device_manager.subscribe();
+
+            tauri::async_runtime::spawn(device_manager.run());
+            tauri::async_runtime::spawn(async move {
+                while let Ok(change) = rx_status.recv().await {
+                    let _ = app_handle.emit_all(
+                        "device-connection-status",
+                        DeviceConnectionEvent {
+                            status: change.status,
+                            serial: change.serial,
+                        },
+                    );
+                }
+            });
+
+            Ok(())
+        })
         .system_tray(tray)
         .on_window_event(|window_event| {
             let event = window_event.event();
@@ -161,14 +207,22 @@ fn build_window(context: &tauri::Context<EmbeddedAssets>) -> tauri::Builder<taur
             get_page_names,
             get_page_config,
             set_page_config,
-            set_active_page
+            set_active_page,
+            list_devices
         ]);
 }
 
 fn main() {
     let context = tauri::generate_context!();
 
-    let app_builder = build_window(&context);
+    let app = build_window(&context)
+        .build(context)
+        .expect("error while building the app");
 
-    app_builder.run(context);
+    app.run(|app_handle, event| {
+        if let tauri::RunEvent::Exit = event {
+            let state: State<ConnectionState> = app_handle.state();
+            state.controller.lock().unwrap().shutdown();
+        }
+    });
 }